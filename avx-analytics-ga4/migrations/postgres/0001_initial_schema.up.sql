@@ -0,0 +1,126 @@
+-- Initial Avila Analytics GA4 schema (PostgreSQL 14+)
+
+CREATE TABLE events (
+    id UUID PRIMARY KEY,
+    measurement_id VARCHAR(50) NOT NULL,
+    client_id VARCHAR(255),
+    user_id VARCHAR(255),
+    session_id VARCHAR(255),
+    timestamp TIMESTAMPTZ NOT NULL,
+    event_name VARCHAR(100) NOT NULL,
+    event_params JSONB NOT NULL,
+    user_properties JSONB,
+    device_category VARCHAR(50),
+    browser VARCHAR(100),
+    browser_version VARCHAR(50),
+    os VARCHAR(100),
+    os_version VARCHAR(50),
+    country VARCHAR(2),
+    region VARCHAR(100),
+    city VARCHAR(100),
+    language VARCHAR(10),
+    screen_resolution VARCHAR(20),
+    viewport_size VARCHAR(20),
+    page_location TEXT,
+    page_referrer TEXT,
+    page_title TEXT,
+    processed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_events_measurement_id ON events(measurement_id);
+CREATE INDEX idx_events_client_id ON events(client_id);
+CREATE INDEX idx_events_session_id ON events(session_id);
+CREATE INDEX idx_events_timestamp ON events(timestamp);
+CREATE INDEX idx_events_event_name ON events(event_name);
+CREATE INDEX idx_events_params_gin ON events USING GIN(event_params);
+
+CREATE TABLE sessions (
+    id UUID PRIMARY KEY,
+    session_id VARCHAR(255) NOT NULL UNIQUE,
+    measurement_id VARCHAR(50) NOT NULL,
+    site_id UUID,
+    client_id VARCHAR(255) NOT NULL,
+    user_id VARCHAR(255),
+    started_at TIMESTAMPTZ NOT NULL,
+    ended_at TIMESTAMPTZ,
+    duration_seconds INTEGER NOT NULL DEFAULT 0,
+    page_views INTEGER NOT NULL DEFAULT 0,
+    events_count INTEGER NOT NULL DEFAULT 0,
+    is_bounce BOOLEAN NOT NULL DEFAULT TRUE,
+    landing_page TEXT,
+    exit_page TEXT,
+    referrer TEXT,
+    campaign_source VARCHAR(100),
+    campaign_medium VARCHAR(100),
+    campaign_name VARCHAR(100),
+    device_category VARCHAR(50),
+    browser VARCHAR(100),
+    os VARCHAR(100),
+    country VARCHAR(2),
+    region VARCHAR(100),
+    city VARCHAR(100),
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_sessions_measurement_id ON sessions(measurement_id);
+CREATE INDEX idx_sessions_client_id ON sessions(client_id);
+CREATE INDEX idx_sessions_started_at ON sessions(started_at);
+
+CREATE TABLE users (
+    id UUID PRIMARY KEY,
+    measurement_id VARCHAR(50) NOT NULL,
+    client_id VARCHAR(255) NOT NULL UNIQUE,
+    user_id VARCHAR(255) UNIQUE,
+    first_seen_at TIMESTAMPTZ NOT NULL,
+    last_seen_at TIMESTAMPTZ NOT NULL,
+    sessions_count INTEGER NOT NULL DEFAULT 1,
+    events_count INTEGER NOT NULL DEFAULT 0,
+    total_engagement_seconds INTEGER NOT NULL DEFAULT 0,
+    properties JSONB,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_users_measurement_id ON users(measurement_id);
+CREATE INDEX idx_users_last_seen_at ON users(last_seen_at);
+
+CREATE TABLE conversions (
+    id UUID PRIMARY KEY,
+    measurement_id VARCHAR(50) NOT NULL,
+    site_id UUID NOT NULL,
+    goal_id UUID NOT NULL,
+    client_id VARCHAR(255),
+    user_id VARCHAR(255),
+    session_id VARCHAR(255),
+    event_id UUID NOT NULL,
+    conversion_name VARCHAR(100) NOT NULL,
+    conversion_value DECIMAL(10, 2),
+    currency VARCHAR(3),
+    timestamp TIMESTAMPTZ NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX idx_conversions_measurement_id ON conversions(measurement_id);
+CREATE INDEX idx_conversions_client_id ON conversions(client_id);
+CREATE INDEX idx_conversions_site_timestamp ON conversions(site_id, timestamp);
+
+CREATE VIEW daily_stats AS
+SELECT
+    DATE(timestamp) AS date,
+    measurement_id,
+    COUNT(*) AS events,
+    COUNT(DISTINCT session_id) AS sessions,
+    COUNT(DISTINCT client_id) AS users
+FROM events
+GROUP BY DATE(timestamp), measurement_id;
+
+CREATE VIEW top_pages AS
+SELECT
+    measurement_id,
+    page_location,
+    COUNT(*) AS pageviews,
+    COUNT(DISTINCT session_id) AS unique_sessions
+FROM events
+WHERE event_name = 'page_view'
+GROUP BY measurement_id, page_location;
@@ -0,0 +1,65 @@
+-- Move events back into a single unpartitioned table
+
+DROP VIEW IF EXISTS top_pages;
+DROP VIEW IF EXISTS daily_stats;
+
+ALTER TABLE events RENAME TO events_partitioned;
+ALTER TABLE events_partitioned RENAME CONSTRAINT events_pkey TO events_partitioned_pkey;
+
+CREATE TABLE events (
+    id UUID PRIMARY KEY,
+    measurement_id VARCHAR(50) NOT NULL,
+    client_id VARCHAR(255),
+    user_id VARCHAR(255),
+    session_id VARCHAR(255),
+    timestamp TIMESTAMPTZ NOT NULL,
+    event_name VARCHAR(100) NOT NULL,
+    event_params JSONB NOT NULL,
+    user_properties JSONB,
+    device_category VARCHAR(50),
+    browser VARCHAR(100),
+    browser_version VARCHAR(50),
+    os VARCHAR(100),
+    os_version VARCHAR(50),
+    country VARCHAR(2),
+    region VARCHAR(100),
+    city VARCHAR(100),
+    language VARCHAR(10),
+    screen_resolution VARCHAR(20),
+    viewport_size VARCHAR(20),
+    page_location TEXT,
+    page_referrer TEXT,
+    page_title TEXT,
+    processed BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+INSERT INTO events SELECT * FROM events_partitioned;
+DROP TABLE events_partitioned;
+
+CREATE INDEX idx_events_measurement_id ON events(measurement_id);
+CREATE INDEX idx_events_client_id ON events(client_id);
+CREATE INDEX idx_events_session_id ON events(session_id);
+CREATE INDEX idx_events_timestamp ON events(timestamp);
+CREATE INDEX idx_events_event_name ON events(event_name);
+CREATE INDEX idx_events_params_gin ON events USING GIN(event_params);
+
+CREATE VIEW daily_stats AS
+SELECT
+    DATE(timestamp) AS date,
+    measurement_id,
+    COUNT(*) AS events,
+    COUNT(DISTINCT session_id) AS sessions,
+    COUNT(DISTINCT client_id) AS users
+FROM events
+GROUP BY DATE(timestamp), measurement_id;
+
+CREATE VIEW top_pages AS
+SELECT
+    measurement_id,
+    page_location,
+    COUNT(*) AS pageviews,
+    COUNT(DISTINCT session_id) AS unique_sessions
+FROM events
+WHERE event_name = 'page_view'
+GROUP BY measurement_id, page_location;
@@ -0,0 +1,13 @@
+DROP INDEX IF EXISTS idx_events_name_timestamp;
+DROP INDEX IF EXISTS idx_events_user_id;
+
+ALTER TABLE events
+    DROP COLUMN currency,
+    DROP COLUMN value;
+
+UPDATE events SET
+    event_name = event_params->>'event_type',
+    client_id = NULL,
+    user_id = NULL,
+    session_id = NULL,
+    page_location = NULL;
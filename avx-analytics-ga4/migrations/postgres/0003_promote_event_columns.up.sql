@@ -0,0 +1,21 @@
+-- Store GA4 event names (custom events by their own name) and promote commonly
+-- queried fields out of event_params. client_id, user_id, session_id and
+-- page_location exist since 0001 but were never populated.
+
+ALTER TABLE events
+    ADD COLUMN value DOUBLE PRECISION,
+    ADD COLUMN currency VARCHAR(3);
+
+UPDATE events SET
+    event_name = CASE WHEN event_params->>'event_type' = 'custom'
+                      THEN LEFT(event_params->>'name', 100)
+                      ELSE event_params->>'event_type' END,
+    client_id = event_params->>'client_id',
+    user_id = event_params->>'user_id',
+    session_id = event_params->>'session_id',
+    page_location = event_params->>'page_location',
+    value = (event_params->>'value')::float8,
+    currency = LEFT(event_params->>'currency', 3);
+
+CREATE INDEX idx_events_user_id ON events(user_id);
+CREATE INDEX idx_events_name_timestamp ON events(measurement_id, event_name, timestamp);
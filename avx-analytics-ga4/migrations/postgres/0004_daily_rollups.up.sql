@@ -0,0 +1,23 @@
+-- Daily reports per site, materialized by the rollup job. `day` is the site's
+-- local date in `timezone`; breakdowns are JSON arrays of the report models.
+
+CREATE TABLE daily_rollups (
+    site_id UUID NOT NULL,
+    day DATE NOT NULL,
+    timezone VARCHAR(64) NOT NULL,
+    users BIGINT NOT NULL,
+    new_users BIGINT NOT NULL,
+    sessions BIGINT NOT NULL,
+    page_views BIGINT NOT NULL,
+    events BIGINT NOT NULL,
+    bounce_rate DOUBLE PRECISION NOT NULL,
+    avg_session_duration DOUBLE PRECISION NOT NULL,
+    pages_per_session DOUBLE PRECISION NOT NULL,
+    conversions BIGINT NOT NULL,
+    revenue DOUBLE PRECISION,
+    devices JSONB NOT NULL,
+    traffic_sources JSONB NOT NULL,
+    geo JSONB NOT NULL,
+    computed_at TIMESTAMPTZ NOT NULL,
+    PRIMARY KEY (site_id, day)
+);
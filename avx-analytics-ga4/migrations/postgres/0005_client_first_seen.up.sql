@@ -0,0 +1,9 @@
+-- First local day each client was seen on a site, kept by the rollup job so
+-- new users can be counted without rescanning the event history.
+
+CREATE TABLE client_first_seen (
+    site_id UUID NOT NULL,
+    client_id VARCHAR(255) NOT NULL,
+    first_day DATE NOT NULL,
+    PRIMARY KEY (site_id, client_id)
+);
@@ -0,0 +1,4 @@
+ALTER TABLE users ALTER COLUMN user_id TYPE VARCHAR(255);
+ALTER TABLE conversions ALTER COLUMN user_id TYPE VARCHAR(255);
+ALTER TABLE sessions ALTER COLUMN user_id TYPE VARCHAR(255);
+ALTER TABLE events ALTER COLUMN user_id TYPE VARCHAR(255);
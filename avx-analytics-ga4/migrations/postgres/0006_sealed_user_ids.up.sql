@@ -0,0 +1,7 @@
+-- User IDs are stored sealed when encryption is enabled, and a sealed 255-character
+-- user ID is longer than the column; lift the limit on every table holding one.
+
+ALTER TABLE events ALTER COLUMN user_id TYPE TEXT;
+ALTER TABLE sessions ALTER COLUMN user_id TYPE TEXT;
+ALTER TABLE conversions ALTER COLUMN user_id TYPE TEXT;
+ALTER TABLE users ALTER COLUMN user_id TYPE TEXT;
@@ -0,0 +1,6 @@
+DROP VIEW IF EXISTS top_pages;
+DROP VIEW IF EXISTS daily_stats;
+DROP TABLE IF EXISTS conversions;
+DROP TABLE IF EXISTS users;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS events;
@@ -0,0 +1,125 @@
+-- Initial Avila Analytics GA4 schema (SQLite); mirrors the PostgreSQL migration
+
+CREATE TABLE events (
+    id TEXT PRIMARY KEY,
+    measurement_id TEXT NOT NULL,
+    client_id TEXT,
+    user_id TEXT,
+    session_id TEXT,
+    timestamp TEXT NOT NULL,
+    event_name TEXT NOT NULL,
+    event_params TEXT NOT NULL,
+    user_properties TEXT,
+    device_category TEXT,
+    browser TEXT,
+    browser_version TEXT,
+    os TEXT,
+    os_version TEXT,
+    country TEXT,
+    region TEXT,
+    city TEXT,
+    language TEXT,
+    screen_resolution TEXT,
+    viewport_size TEXT,
+    page_location TEXT,
+    page_referrer TEXT,
+    page_title TEXT,
+    processed INTEGER NOT NULL DEFAULT 0,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_events_measurement_id ON events(measurement_id, timestamp);
+CREATE INDEX idx_events_client_id ON events(client_id);
+CREATE INDEX idx_events_session_id ON events(session_id);
+CREATE INDEX idx_events_timestamp ON events(timestamp);
+CREATE INDEX idx_events_event_name ON events(event_name);
+
+CREATE TABLE sessions (
+    id TEXT PRIMARY KEY,
+    session_id TEXT NOT NULL UNIQUE,
+    measurement_id TEXT NOT NULL,
+    site_id TEXT,
+    client_id TEXT NOT NULL,
+    user_id TEXT,
+    started_at TEXT NOT NULL,
+    ended_at TEXT,
+    duration_seconds INTEGER NOT NULL DEFAULT 0,
+    page_views INTEGER NOT NULL DEFAULT 0,
+    events_count INTEGER NOT NULL DEFAULT 0,
+    is_bounce INTEGER NOT NULL DEFAULT 1,
+    landing_page TEXT,
+    exit_page TEXT,
+    referrer TEXT,
+    campaign_source TEXT,
+    campaign_medium TEXT,
+    campaign_name TEXT,
+    device_category TEXT,
+    browser TEXT,
+    os TEXT,
+    country TEXT,
+    region TEXT,
+    city TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_sessions_measurement_id ON sessions(measurement_id);
+CREATE INDEX idx_sessions_client_id ON sessions(client_id);
+CREATE INDEX idx_sessions_started_at ON sessions(started_at);
+
+CREATE TABLE users (
+    id TEXT PRIMARY KEY,
+    measurement_id TEXT NOT NULL,
+    client_id TEXT NOT NULL UNIQUE,
+    user_id TEXT UNIQUE,
+    first_seen_at TEXT NOT NULL,
+    last_seen_at TEXT NOT NULL,
+    sessions_count INTEGER NOT NULL DEFAULT 1,
+    events_count INTEGER NOT NULL DEFAULT 0,
+    total_engagement_seconds INTEGER NOT NULL DEFAULT 0,
+    properties TEXT,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    updated_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_users_measurement_id ON users(measurement_id);
+CREATE INDEX idx_users_last_seen_at ON users(last_seen_at);
+
+CREATE TABLE conversions (
+    id TEXT PRIMARY KEY,
+    measurement_id TEXT NOT NULL,
+    site_id TEXT NOT NULL,
+    goal_id TEXT NOT NULL,
+    client_id TEXT,
+    user_id TEXT,
+    session_id TEXT,
+    event_id TEXT NOT NULL,
+    conversion_name TEXT NOT NULL,
+    conversion_value REAL,
+    currency TEXT,
+    timestamp TEXT NOT NULL,
+    created_at TEXT NOT NULL DEFAULT CURRENT_TIMESTAMP
+);
+
+CREATE INDEX idx_conversions_measurement_id ON conversions(measurement_id);
+CREATE INDEX idx_conversions_client_id ON conversions(client_id);
+CREATE INDEX idx_conversions_site_timestamp ON conversions(site_id, timestamp);
+
+CREATE VIEW daily_stats AS
+SELECT
+    DATE(timestamp) AS date,
+    measurement_id,
+    COUNT(*) AS events,
+    COUNT(DISTINCT session_id) AS sessions,
+    COUNT(DISTINCT client_id) AS users
+FROM events
+GROUP BY DATE(timestamp), measurement_id;
+
+CREATE VIEW top_pages AS
+SELECT
+    measurement_id,
+    page_location,
+    COUNT(*) AS pageviews,
+    COUNT(DISTINCT session_id) AS unique_sessions
+FROM events
+WHERE event_name = 'page_view'
+GROUP BY measurement_id, page_location;
@@ -0,0 +1,2 @@
+-- SQLite has no native partitioning; this keeps the schema history aligned with PostgreSQL
+SELECT 1;
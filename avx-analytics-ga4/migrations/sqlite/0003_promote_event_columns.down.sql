@@ -0,0 +1,12 @@
+DROP INDEX IF EXISTS idx_events_name_timestamp;
+DROP INDEX IF EXISTS idx_events_user_id;
+
+ALTER TABLE events DROP COLUMN currency;
+ALTER TABLE events DROP COLUMN value;
+
+UPDATE events SET
+    event_name = json_extract(event_params, '$.event_type'),
+    client_id = NULL,
+    user_id = NULL,
+    session_id = NULL,
+    page_location = NULL;
@@ -0,0 +1,19 @@
+-- Store GA4 event names (custom events by their own name) and promote commonly
+-- queried fields out of event_params; mirrors the PostgreSQL migration
+
+ALTER TABLE events ADD COLUMN value REAL;
+ALTER TABLE events ADD COLUMN currency TEXT;
+
+UPDATE events SET
+    event_name = CASE WHEN json_extract(event_params, '$.event_type') = 'custom'
+                      THEN json_extract(event_params, '$.name')
+                      ELSE json_extract(event_params, '$.event_type') END,
+    client_id = json_extract(event_params, '$.client_id'),
+    user_id = json_extract(event_params, '$.user_id'),
+    session_id = json_extract(event_params, '$.session_id'),
+    page_location = json_extract(event_params, '$.page_location'),
+    value = CAST(json_extract(event_params, '$.value') AS REAL),
+    currency = json_extract(event_params, '$.currency');
+
+CREATE INDEX idx_events_user_id ON events(user_id);
+CREATE INDEX idx_events_name_timestamp ON events(measurement_id, event_name, timestamp);
@@ -0,0 +1,23 @@
+-- Daily reports per site, materialized by the rollup job. `day` is the site's
+-- local date in `timezone`; breakdowns are JSON arrays of the report models.
+
+CREATE TABLE daily_rollups (
+    site_id TEXT NOT NULL,
+    day TEXT NOT NULL,
+    timezone TEXT NOT NULL,
+    users INTEGER NOT NULL,
+    new_users INTEGER NOT NULL,
+    sessions INTEGER NOT NULL,
+    page_views INTEGER NOT NULL,
+    events INTEGER NOT NULL,
+    bounce_rate REAL NOT NULL,
+    avg_session_duration REAL NOT NULL,
+    pages_per_session REAL NOT NULL,
+    conversions INTEGER NOT NULL,
+    revenue REAL,
+    devices TEXT NOT NULL,
+    traffic_sources TEXT NOT NULL,
+    geo TEXT NOT NULL,
+    computed_at TEXT NOT NULL,
+    PRIMARY KEY (site_id, day)
+);
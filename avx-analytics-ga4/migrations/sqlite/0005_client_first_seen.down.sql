@@ -0,0 +1 @@
+DROP TABLE IF EXISTS client_first_seen;
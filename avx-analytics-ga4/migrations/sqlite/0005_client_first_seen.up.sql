@@ -0,0 +1,9 @@
+-- First local day each client was seen on a site, kept by the rollup job so
+-- new users can be counted without rescanning the event history.
+
+CREATE TABLE client_first_seen (
+    site_id TEXT NOT NULL,
+    client_id TEXT NOT NULL,
+    first_day TEXT NOT NULL,
+    PRIMARY KEY (site_id, client_id)
+);
@@ -0,0 +1,4 @@
+-- Mirrors the PostgreSQL migration, which lifts the length limit of user_id columns;
+-- SQLite TEXT columns have none.
+
+SELECT 1;
@@ -1,17 +1,58 @@
 //! Analytics CLI tool
 
-use avx_analytics_ga4::config::Config;
+use avx_analytics_ga4::backup::{self, Backup, ChunkKind};
+use avx_analytics_ga4::config::{Config, ConfigLoader, DEFAULT_CONFIG_FILE};
+use avx_analytics_ga4::crypto::{KeyRotation, Keyring};
+use avx_analytics_ga4::enrich::{Enrichment, GeoIp};
+use avx_analytics_ga4::funnel::{FunnelMode, FunnelQuery, StepOrdering};
+use avx_analytics_ga4::migrations::Migrator;
+use avx_analytics_ga4::privacy::PrivacyFilter;
+use avx_analytics_ga4::query::QueryEngine;
+use avx_analytics_ga4::replay::Replay;
+use avx_analytics_ga4::retention::{RetentionJob, PRIMARY_TARGET};
+use avx_analytics_ga4::rollup::RollupJob;
+use avx_analytics_ga4::sites::{NewSite, SiteRegistry};
+use avx_analytics_ga4::storage::{Database, FanOutStorage};
+use chrono::{Duration, NaiveDate};
 use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Parser)]
 #[command(name = "avila-analytics-cli")]
 #[command(about = "Avila Analytics CLI - Manage your analytics", long_about = None)]
 struct Cli {
+    /// Configuration file (defaults to ./config.toml when it exists)
+    #[arg(short, long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Commands,
 }
 
+/// Load configuration the way the server does: defaults < file < environment
+fn load_config(path: Option<&Path>) -> anyhow::Result<Config> {
+    let path = path
+        .map(Path::to_path_buf)
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists()));
+    let mut loader = ConfigLoader::new();
+    if let Some(path) = path {
+        loader = loader.file(path);
+    }
+    let config = loader.load()?.config;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Open the site registry the server uses
+fn open_registry(config: &Config) -> anyhow::Result<SiteRegistry> {
+    match &config.sites.registry_path {
+        Some(path) => Ok(SiteRegistry::open(path)?),
+        None => anyhow::bail!("sites.registry_path is not configured"),
+    }
+}
+
 #[derive(Subcommand)]
 enum Commands {
     /// Create a new site
@@ -28,63 +69,547 @@ enum Commands {
     /// List all sites
     SiteList,
 
-    /// Generate a report
+    /// Print daily metrics from the stored rollups
     Report {
         /// Site ID
         #[arg(short, long)]
-        site_id: String,
+        site_id: Uuid,
 
         /// Start date (YYYY-MM-DD)
+        #[arg(long)]
+        start: NaiveDate,
+
+        /// End date (YYYY-MM-DD)
+        #[arg(long)]
+        end: NaiveDate,
+    },
+
+    /// Compute daily rollups, for a date range or the recent days of every site
+    Rollup {
+        /// Site ID (required with --start/--end)
         #[arg(short, long)]
-        start: String,
+        site_id: Option<Uuid>,
+
+        /// Start date (YYYY-MM-DD)
+        #[arg(long, requires = "end")]
+        start: Option<NaiveDate>,
 
         /// End date (YYYY-MM-DD)
+        #[arg(long, requires = "start")]
+        end: Option<NaiveDate>,
+    },
+
+    /// Enrich a site's stored events again and recompute their rollups
+    Replay {
+        /// Site ID
         #[arg(short, long)]
-        end: String,
+        site_id: Uuid,
+
+        /// First local day (YYYY-MM-DD)
+        #[arg(long)]
+        start: NaiveDate,
+
+        /// Last local day (YYYY-MM-DD)
+        #[arg(long)]
+        end: NaiveDate,
+
+        /// Start over instead of resuming an interrupted replay
+        #[arg(long)]
+        restart: bool,
     },
 
-    /// Backup database
+    /// Run a funnel report
+    Funnel {
+        /// Site ID
+        #[arg(short, long)]
+        site_id: Uuid,
+
+        /// Funnel ID
+        #[arg(short, long)]
+        funnel_id: Uuid,
+
+        /// Start date (YYYY-MM-DD)
+        #[arg(long)]
+        start: NaiveDate,
+
+        /// End date (YYYY-MM-DD)
+        #[arg(long)]
+        end: NaiveDate,
+
+        /// Allow journeys to enter at any step
+        #[arg(long)]
+        open: bool,
+
+        /// Require each step to directly follow the previous one
+        #[arg(long)]
+        strict: bool,
+
+        /// Break results down by a dimension (e.g. device_category)
+        #[arg(short, long)]
+        breakdown: Option<String>,
+    },
+
+    /// Write a backup of events, conversions, sites, goals and funnels
     Backup {
-        /// Output file
+        /// Output directory (must not exist or be empty)
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Only back up these sites (repeatable)
         #[arg(short, long)]
-        output: String,
+        site_id: Vec<Uuid>,
+
+        /// First day to include (YYYY-MM-DD, UTC)
+        #[arg(long, conflicts_with = "incremental")]
+        start: Option<NaiveDate>,
+
+        /// Last day to include (YYYY-MM-DD, UTC)
+        #[arg(long)]
+        end: Option<NaiveDate>,
+
+        /// Continue from a previous backup directory
+        #[arg(long)]
+        incremental: Option<PathBuf>,
+    },
+
+    /// Verify a backup and import it into the configured database
+    Restore {
+        /// Backup directory
+        #[arg(short, long)]
+        input: PathBuf,
+
+        /// Only check the manifest and chunk checksums
+        #[arg(long)]
+        verify_only: bool,
+    },
+
+    /// Purge data past its retention period
+    Retention {
+        /// Report what would be purged without deleting anything
+        #[arg(long)]
+        dry_run: bool,
+    },
+
+    /// Re-encrypt stored events under the keyring's active key
+    RotateKeys,
+
+    /// Print a new random encryption key for the keyring
+    GenerateKey,
+
+    /// Manage schema migrations
+    Migrate {
+        #[command(subcommand)]
+        action: MigrateAction,
+    },
+
+    /// Create upcoming events partitions and expire old ones (PostgreSQL)
+    Partitions {
+        /// Report what would change without creating or expiring anything
+        #[arg(long)]
+        dry_run: bool,
     },
 
     /// Show server status
     Status,
 }
 
+#[derive(Subcommand)]
+enum MigrateAction {
+    /// List migrations and whether they are applied
+    Status,
+
+    /// Apply pending migrations
+    Up {
+        /// Stop after this version
+        #[arg(long)]
+        to: Option<i64>,
+    },
+
+    /// Revert the most recent migrations
+    Down {
+        /// Number of migrations to revert
+        #[arg(long, default_value_t = 1)]
+        steps: usize,
+    },
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
+    let config_file = cli.config.as_deref();
 
     match cli.command {
         Commands::SiteCreate { name, domain } => {
-            let measurement_id = generate_measurement_id();
+            let config = load_config(config_file)?;
+            let site = open_registry(&config)?.create_site(NewSite {
+                name,
+                domain,
+                allowed_hostnames: Vec::new(),
+                measurement_id: None,
+                timezone: None,
+                currency: None,
+            })?;
             println!("✅ Site created successfully!");
-            println!("   Name: {}", name);
-            println!("   Domain: {}", domain);
-            println!("   Measurement ID: {}", measurement_id);
+            println!("   ID: {}", site.id);
+            println!("   Name: {}", site.name);
+            println!("   Domain: {}", site.domain);
+            println!("   Measurement ID: {}", site.measurement_id);
             println!("\n📝 Add this to your website:");
-            println!("   <script src=\"https://analytics.yourdomain.com/tracker.js\" data-site=\"{}\"></script>", measurement_id);
+            println!("   <script src=\"https://analytics.yourdomain.com/tracker.js\" data-site=\"{}\"></script>", site.measurement_id);
         }
 
         Commands::SiteList => {
+            let config = load_config(config_file)?;
+            let sites = open_registry(&config)?.sites();
             println!("📊 Sites:");
-            println!("   (No sites configured yet)");
+            if sites.is_empty() {
+                println!("   (No sites configured yet)");
+            }
+            for site in sites {
+                println!(
+                    "   {} {} ({}) {}",
+                    site.measurement_id, site.name, site.domain, site.id
+                );
+            }
         }
 
         Commands::Report { site_id, start, end } => {
-            println!("📈 Generating report...");
-            println!("   Site ID: {}", site_id);
-            println!("   Period: {} to {}", start, end);
-            println!("   (Report generation not implemented yet)");
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let db = Database::open(&config).await?;
+            let metrics = QueryEngine::new(db, sites)
+                .get_aggregated_metrics(site_id, start, end)
+                .await?;
+
+            println!("📈 Report for {} ({} to {})", site_id, start, end);
+            if metrics.is_empty() {
+                println!("   No rollups stored; run `avila-analytics-cli rollup` first");
+            }
+            for day in &metrics {
+                println!(
+                    "   {}  users {:>6}  sessions {:>6}  page views {:>7}  bounce {:>5.1}%  conversions {:>5}",
+                    day.date,
+                    day.users,
+                    day.sessions,
+                    day.page_views,
+                    day.bounce_rate * 100.0,
+                    day.conversions
+                );
+            }
+        }
+
+        Commands::Rollup {
+            site_id,
+            start,
+            end,
+        } => {
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let db = Database::open(&config).await?;
+            let job = RollupJob::new(db, sites).with_lookback_days(config.rollup.lookback_days);
+
+            let stored = match (site_id, start, end) {
+                (Some(site_id), Some(start), Some(end)) => {
+                    job.backfill(site_id, start, end).await?
+                }
+                (None, Some(_), Some(_)) => {
+                    anyhow::bail!("--site-id is required to backfill a date range")
+                }
+                _ => job.run().await?,
+            };
+            println!("✅ Stored {} daily rollups", stored);
+        }
+
+        Commands::Replay {
+            site_id,
+            start,
+            end,
+            restart,
+        } => {
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let mut privacy =
+                PrivacyFilter::new(config.privacy.clone()).with_sites(config.sites.clone());
+            if let Some(keyring) = Keyring::open(&config.privacy.encryption)? {
+                privacy = privacy.with_keyring(keyring);
+            }
+            let mut enrichment =
+                Enrichment::from_config(&config, sites.clone())?.with_privacy(Arc::new(privacy));
+            if let Some(geoip) = GeoIp::configured(&config.enrichment)? {
+                enrichment = enrichment.with_enricher(geoip);
+            }
+            let db = Database::open(&config).await?;
+            let replay = Replay::new(db, sites, enrichment)
+                .with_checkpoint_dir(&config.replay.checkpoint_dir);
+
+            let checkpoint = replay.run(site_id, start, end, restart).await?;
+            println!(
+                "✅ Replayed {} events from {} to {} through [{}]",
+                checkpoint.events,
+                start,
+                end,
+                checkpoint.enrichers.join(", ")
+            );
+        }
+
+        Commands::Funnel {
+            site_id,
+            funnel_id,
+            start,
+            end,
+            open,
+            strict,
+            breakdown,
+        } => {
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let db = Database::open(&config).await?;
+            let engine = QueryEngine::new(db, sites);
+
+            let query = FunnelQuery {
+                start_date: start,
+                end_date: end,
+                mode: if open {
+                    FunnelMode::Open
+                } else {
+                    FunnelMode::Closed
+                },
+                ordering: if strict {
+                    StepOrdering::Strict
+                } else {
+                    StepOrdering::Loose
+                },
+                breakdown,
+            };
+            let report = engine.funnel_report(site_id, funnel_id, &query).await?;
+
+            println!("🔻 Funnel: {}", report.funnel_name);
+            println!("   Period: {} to {}", report.start_date, report.end_date);
+            print_funnel_steps(&report.steps);
+            for (value, steps) in &report.breakdown {
+                println!(
+                    "\n   {} = {}",
+                    report.breakdown_dimension.as_deref().unwrap_or(""),
+                    value
+                );
+                print_funnel_steps(steps);
+            }
+        }
+
+        Commands::Backup {
+            output,
+            site_id,
+            start,
+            end,
+            incremental,
+        } => {
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let db = Database::open(&config).await?;
+
+            let day_start = |day: NaiveDate| day.and_hms_opt(0, 0, 0).unwrap().and_utc();
+            let mut backup = Backup::new(db, sites).with_sites(site_id).with_range(
+                start.map(day_start),
+                end.map(|day| day_start(day + Duration::days(1))),
+            );
+            if let Some(previous) = incremental {
+                backup = backup.incremental(&backup::read_manifest(&previous)?);
+            }
+            let manifest = backup.write(&output).await?;
+
+            println!("💾 Backup written to {}", output.display());
+            println!("   Sites: {}", manifest.sites.len());
+            println!("   Events: {}", manifest.records(ChunkKind::Events));
+            println!(
+                "   Conversions: {}",
+                manifest.records(ChunkKind::Conversions)
+            );
+            println!("   Sessions: {}", manifest.records(ChunkKind::Sessions));
+            println!("   Chunks: {}", manifest.chunks.len());
+        }
+
+        Commands::Restore { input, verify_only } => {
+            if verify_only {
+                let manifest = backup::verify(&input)?;
+                println!("✅ Backup of {} is intact", manifest.created_at);
+                println!("   Events: {}", manifest.records(ChunkKind::Events));
+                println!(
+                    "   Conversions: {}",
+                    manifest.records(ChunkKind::Conversions)
+                );
+                println!("   Sessions: {}", manifest.records(ChunkKind::Sessions));
+                return Ok(());
+            }
+
+            let config = load_config(config_file)?;
+            let sites = match &config.sites.registry_path {
+                Some(path) => Some(SiteRegistry::open(path)?),
+                None => None,
+            };
+            let db = Database::open(&config).await?;
+            db.migrate().await?;
+            let summary = backup::restore(&input, db.engine().as_ref(), sites.as_ref()).await?;
+
+            println!("✅ Restored {}", input.display());
+            match sites {
+                Some(_) => println!("   Registry entries added: {}", summary.registry_entries),
+                None => println!("   Registry skipped (sites.registry_path is not configured)"),
+            }
+            println!("   Events: {}", summary.events);
+            println!("   Conversions: {}", summary.conversions);
+            println!("   Sessions: {}", summary.sessions);
+        }
+
+        Commands::Retention { dry_run } => {
+            let config = load_config(config_file)?;
+            let sites = Arc::new(open_registry(&config)?);
+            let privacy = Arc::new(
+                PrivacyFilter::new(config.privacy.clone()).with_sites(config.sites.clone()),
+            );
+            let db = Database::open(&config).await?;
+            let mut job = RetentionJob::new(db, sites, privacy)
+                .with_rollup_retention_days(config.retention.rollup_retention_days)
+                .with_audit_log(&config.retention.audit_log);
+            for replica in &config.database.replicas {
+                let mut replica_config = config.clone();
+                replica_config.database.url = replica.url.clone();
+                job = job.with_replica(&replica.name, Database::open(&replica_config).await?);
+            }
+
+            let records = job.run(dry_run).await?;
+            println!(
+                "{}",
+                if dry_run {
+                    "🔍 Would purge:"
+                } else {
+                    "🧹 Purged:"
+                }
+            );
+            for record in &records {
+                println!(
+                    "   {:<10} {:<14} events {:>8}  conversions {:>6}  sessions {:>6}  users {:>6}  rollups {:>5}  (raw < {})",
+                    record.target,
+                    record.measurement_id,
+                    record.events,
+                    record.conversions,
+                    record.sessions,
+                    record.users,
+                    record.rollups,
+                    record.events_before.date_naive()
+                );
+            }
+            if !dry_run {
+                println!("   Audit log: {}", config.retention.audit_log);
+            }
+        }
+
+        Commands::RotateKeys => {
+            let config = load_config(config_file)?;
+            let Some(keyring) = Keyring::open(&config.privacy.encryption)? else {
+                anyhow::bail!(
+                    "no keyring configured; set privacy.encryption.keyring or AVILA_KEYRING"
+                );
+            };
+            let db = Database::open(&config).await?;
+            // Replicas hold copies of the ciphertext, so they are rotated alike
+            let mut storage = FanOutStorage::new(db.engine());
+            for replica in &config.database.replicas {
+                let mut replica_config = config.clone();
+                replica_config.database.url = replica.url.clone();
+                let replica_db = Database::open(&replica_config).await?;
+                storage = storage.with_replica(replica, replica_db.engine());
+            }
+            println!(
+                "🔑 Re-encrypting stored events with key {}...",
+                keyring.active_key_id()
+            );
+            let summary = KeyRotation::new(Arc::new(storage), Arc::new(keyring))
+                .run()
+                .await?;
+            println!(
+                "✅ Re-encrypted {} of {} events and the user IDs of {} sessions and conversions",
+                summary.rotated, summary.scanned, summary.records
+            );
         }
 
-        Commands::Backup { output } => {
-            println!("💾 Creating backup...");
-            println!("   Output: {}", output);
-            println!("   (Backup not implemented yet)");
+        Commands::GenerateKey => {
+            println!("{}", Keyring::generate_key());
+        }
+
+        Commands::Migrate { action } => {
+            let config = load_config(config_file)?;
+            let migrator = Migrator::new(Database::open(&config).await?);
+
+            match action {
+                MigrateAction::Status => {
+                    println!("🗄️  Schema migrations:");
+                    for status in migrator.status().await? {
+                        let state = match (status.applied_at, status.checksum_ok) {
+                            (Some(_), false) => "CHECKSUM MISMATCH".to_string(),
+                            (Some(at), true) => {
+                                format!("applied {}", at.format("%Y-%m-%d %H:%M:%S"))
+                            }
+                            (None, _) => "pending".to_string(),
+                        };
+                        println!("   {:04} {:<32} {}", status.version, status.name, state);
+                    }
+                }
+                MigrateAction::Up { to } => {
+                    let applied = migrator.up(to).await?;
+                    if applied.is_empty() {
+                        println!("✅ Schema is up to date");
+                    }
+                    for version in applied {
+                        println!("✅ Applied migration {:04}", version);
+                    }
+                }
+                MigrateAction::Down { steps } => {
+                    for version in migrator.down(steps).await? {
+                        println!("↩️  Reverted migration {:04}", version);
+                    }
+                }
+            }
+        }
+
+        Commands::Partitions { dry_run } => {
+            let config = load_config(config_file)?;
+            let Database::Postgres(postgres) = Database::open(&config).await? else {
+                anyhow::bail!("events partitions are only used by the PostgreSQL backend");
+            };
+            let sites = Arc::new(match &config.sites.registry_path {
+                Some(path) => SiteRegistry::open(path)?,
+                None => SiteRegistry::in_memory(),
+            });
+            let privacy = Arc::new(
+                PrivacyFilter::new(config.privacy.clone()).with_sites(config.sites.clone()),
+            );
+            let retention_days = privacy.longest_retention_days();
+            let report = postgres
+                .maintain_partitions(&config.database.partitioning, retention_days, dry_run)
+                .await?;
+            RetentionJob::new(Database::Postgres(postgres), sites, privacy)
+                .with_audit_log(&config.retention.audit_log)
+                .record_partitions(PRIMARY_TARGET, &report)?;
+
+            let verb =
+                |done: &'static str, planned: &'static str| if dry_run { planned } else { done };
+            println!("🗂️  Events partitions (retention {} days):", retention_days);
+            for name in &report.created {
+                println!("   ✅ {} {}", verb("Created", "Would create"), name);
+            }
+            for name in &report.expired {
+                let action = if config.database.partitioning.detach_expired {
+                    verb("Detached", "Would detach")
+                } else {
+                    verb("Dropped", "Would drop")
+                };
+                println!("   🗑️  {} {}", action, name);
+            }
+            if report.created.is_empty() && report.expired.is_empty() {
+                println!("   Nothing to do");
+            } else if !report.expired.is_empty() && !dry_run {
+                println!("   Audit log: {}", config.retention.audit_log);
+            }
         }
 
         Commands::Status => {
@@ -97,6 +622,20 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn generate_measurement_id() -> String {
-    format!("G-{}", Uuid::new_v4().to_string().replace("-", "").to_uppercase()[..10].to_string())
+fn print_funnel_steps(steps: &[avx_analytics_ga4::funnel::FunnelStepResult]) {
+    for step in steps {
+        let median = step
+            .median_seconds_to_next
+            .map(|s| format!("{:.1}s", s))
+            .unwrap_or_else(|| "-".to_string());
+        println!(
+            "   {:>2}. {:<24} entrants {:>8}  completions {:>8}  drop-off {:>6.1}%  median to next {}",
+            step.order,
+            step.name,
+            step.entrants,
+            step.completions,
+            step.drop_off_rate * 100.0,
+            median
+        );
+    }
 }
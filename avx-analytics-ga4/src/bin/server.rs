@@ -1,11 +1,40 @@
 //! Analytics server binary
 
-use avx_analytics_ga4::config::Config;
+use avx_analytics_ga4::config::{ConfigLoader, ConfigOverrides, DEFAULT_CONFIG_FILE};
 use avx_analytics_ga4::server::AnalyticsServer;
+use clap::Parser;
+use std::path::PathBuf;
 use tracing_subscriber::EnvFilter;
 
+#[derive(Parser)]
+#[command(name = "avila-analytics")]
+#[command(about = "Avila Analytics server", long_about = None)]
+struct Args {
+    /// Configuration file (defaults to ./config.toml when it exists)
+    #[arg(short, long)]
+    config: Option<PathBuf>,
+
+    /// Port to listen on
+    #[arg(short, long)]
+    port: Option<u16>,
+
+    /// Database URL
+    #[arg(long)]
+    database_url: Option<String>,
+
+    /// Redis URL
+    #[arg(long)]
+    redis_url: Option<String>,
+
+    /// Print the effective configuration with the source of each value and exit
+    #[arg(long)]
+    print_config: bool,
+}
+
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
+
     // Initialize logging
     tracing_subscriber::fmt()
         .with_env_filter(
@@ -14,14 +43,31 @@ async fn main() -> anyhow::Result<()> {
         )
         .init();
 
-    // Load configuration
-    let config = Config::from_env().unwrap_or_else(|_| {
-        tracing::warn!("Failed to load config from env, using defaults");
-        Config::default()
-    });
+    // Load configuration: defaults < file < environment < CLI flags
+    let overrides = ConfigOverrides {
+        port: args.port,
+        database_url: args.database_url,
+        redis_url: args.redis_url,
+    };
+    let config_file = args
+        .config
+        .or_else(|| Some(PathBuf::from(DEFAULT_CONFIG_FILE)).filter(|p| p.exists()));
+
+    let mut loader = ConfigLoader::new().overrides(overrides.clone());
+    if let Some(path) = &config_file {
+        loader = loader.file(path);
+    }
+    let loaded = loader.load()?;
+
+    if args.print_config {
+        println!("{}", loaded.describe());
+        return Ok(());
+    }
+
+    loaded.config.validate()?;
 
-    // Start server
-    let server = AnalyticsServer::new(config);
+    // Start server; SIGHUP or edits to the config file reload the safe subset
+    let server = AnalyticsServer::new(loaded.config).with_config_source(config_file, overrides);
     server.run().await?;
 
     Ok(())
@@ -124,7 +124,6 @@ impl Default for AnalyticsClientBuilder {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::events::EventParams;
 
     #[tokio::test]
     async fn test_client_creation() {
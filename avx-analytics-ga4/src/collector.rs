@@ -1,17 +1,31 @@
 //! Event collector - High-performance event ingestion
 
+use crate::enrich::{Enricher, GeoIp};
 use crate::error::{Error, Result};
 use crate::events::{EventBatch, EventEnvelope};
+use crate::filter::{FilterReason, HitFilter};
+use crate::health::PipelineStats;
 use crate::privacy::PrivacyFilter;
+use serde::Serialize;
 use std::sync::Arc;
 use tokio::sync::mpsc;
 use tracing::{debug, info};
 
+/// GA4 limits event names to 40 characters
+const MAX_EVENT_NAME_LEN: usize = 40;
+/// Width of the measurement ID column
+const MAX_MEASUREMENT_ID_LEN: usize = 50;
+/// Width of the client, user and session ID columns
+const MAX_ID_LEN: usize = 255;
+
 /// Event collector handles incoming events
 pub struct EventCollector {
     sender: mpsc::UnboundedSender<EventEnvelope>,
     privacy_filter: Arc<PrivacyFilter>,
     metrics: Arc<CollectorMetrics>,
+    stats: Option<Arc<PipelineStats>>,
+    geoip: Option<Arc<GeoIp>>,
+    filter: Option<Arc<HitFilter>>,
 }
 
 impl EventCollector {
@@ -24,19 +38,62 @@ impl EventCollector {
             sender,
             privacy_filter,
             metrics: Arc::new(CollectorMetrics::default()),
+            stats: None,
+            geoip: None,
+            filter: None,
         }
     }
 
+    /// Count queued events in shared pipeline stats
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Locate events by IP before the privacy filter masks it
+    pub fn with_geoip(mut self, geoip: Arc<GeoIp>) -> Self {
+        self.geoip = Some(geoip);
+        self
+    }
+
+    /// Drop referrer spam and ghost hits, counting them by reason
+    pub fn with_filter(mut self, filter: Arc<HitFilter>) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
     /// Collect a single event
     pub async fn collect(&self, mut envelope: EventEnvelope) -> Result<()> {
-        // Apply privacy filters
-        envelope = self.privacy_filter.apply(envelope).await?;
+        // Dropped events are accepted, so senders of spam learn nothing
+        if let Some(reason) = self.filter.as_ref().and_then(|f| f.check(&envelope)) {
+            self.metrics.increment_filtered(reason);
+            debug!(
+                "Dropped event for {}: {}",
+                envelope.measurement_id,
+                reason.as_str()
+            );
+            return Ok(());
+        }
 
-        // Validate event
+        // Validate event; before privacy filters, which may replace values with ciphertext
         self.validate_event(&envelope)?;
 
-        // Send to processing pipeline
+        if let Some(geoip) = &self.geoip {
+            geoip.enrich(&mut envelope)?;
+        }
+
+        // Apply privacy filters
+        envelope = self.privacy_filter.apply(envelope).await?;
+
+        // Send to processing pipeline; count it first so a fast consumer can't dequeue it
+        // before it was enqueued
+        if let Some(stats) = &self.stats {
+            stats.event_enqueued();
+        }
         self.sender.send(envelope).map_err(|e| {
+            if let Some(stats) = &self.stats {
+                stats.event_dequeued();
+            }
             self.metrics.increment_errors();
             Error::Unknown(format!("Failed to send event: {}", e))
         })?;
@@ -68,7 +125,45 @@ impl EventCollector {
             ));
         }
 
-        // Additional validation logic here
+        if envelope.measurement_id.len() > MAX_MEASUREMENT_ID_LEN {
+            return Err(Error::InvalidEvent(format!(
+                "Measurement ID is longer than {} characters",
+                MAX_MEASUREMENT_ID_LEN
+            )));
+        }
+
+        let event = &envelope.event;
+        let name = event.event_name();
+        if name.is_empty() || name.chars().count() > MAX_EVENT_NAME_LEN {
+            return Err(Error::InvalidEvent(format!(
+                "Event name must be 1-{} characters, got {:?}",
+                MAX_EVENT_NAME_LEN, name
+            )));
+        }
+
+        if let Some(currency) = event.currency() {
+            if currency.len() != 3 || !currency.chars().all(|c| c.is_ascii_alphabetic()) {
+                return Err(Error::InvalidEvent(format!(
+                    "Currency must be an ISO 4217 code, got {:?}",
+                    currency
+                )));
+            }
+        }
+
+        let params = event.params();
+        for (field, value) in [
+            ("client_id", params.client_id.as_deref()),
+            ("session_id", params.session_id.as_deref()),
+            ("user_id", event.user_id()),
+        ] {
+            if value.is_some_and(|v| v.len() > MAX_ID_LEN) {
+                return Err(Error::InvalidEvent(format!(
+                    "{} is longer than {} characters",
+                    field, MAX_ID_LEN
+                )));
+            }
+        }
+
         Ok(())
     }
 
@@ -84,6 +179,15 @@ pub struct CollectorMetrics {
     events_collected: std::sync::atomic::AtomicU64,
     batches_collected: std::sync::atomic::AtomicU64,
     errors: std::sync::atomic::AtomicU64,
+    filtered_referrer_spam: std::sync::atomic::AtomicU64,
+    filtered_ghost_hits: std::sync::atomic::AtomicU64,
+}
+
+/// Events dropped by the hit filter, by reason
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct FilteredEvents {
+    pub referrer_spam: u64,
+    pub ghost_hit: u64,
 }
 
 impl CollectorMetrics {
@@ -97,6 +201,14 @@ impl CollectorMetrics {
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
     }
 
+    fn increment_filtered(&self, reason: FilterReason) {
+        let counter = match reason {
+            FilterReason::ReferrerSpam => &self.filtered_referrer_spam,
+            FilterReason::GhostHit => &self.filtered_ghost_hits,
+        };
+        counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+    }
+
     fn increment_errors(&self) {
         self.errors
             .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
@@ -115,6 +227,17 @@ impl CollectorMetrics {
     pub fn errors(&self) -> u64 {
         self.errors.load(std::sync::atomic::Ordering::Relaxed)
     }
+
+    pub fn filtered(&self) -> FilteredEvents {
+        FilteredEvents {
+            referrer_spam: self
+                .filtered_referrer_spam
+                .load(std::sync::atomic::Ordering::Relaxed),
+            ghost_hit: self
+                .filtered_ghost_hits
+                .load(std::sync::atomic::Ordering::Relaxed),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -144,4 +267,99 @@ mod tests {
         assert!(rx.recv().await.is_some());
         assert_eq!(collector.metrics().events_collected(), 1);
     }
+
+    #[tokio::test]
+    async fn test_failed_send_is_not_queued() {
+        let (tx, rx) = mpsc::unbounded_channel();
+        drop(rx);
+        let stats = PipelineStats::new();
+        let privacy_filter = Arc::new(PrivacyFilter::new(Config::default().privacy));
+        let collector = EventCollector::new(tx, privacy_filter).with_stats(stats.clone());
+
+        let event = Event::Custom {
+            name: "signup".to_string(),
+            params: EventParams::default(),
+        };
+        assert!(collector
+            .collect(EventEnvelope::new("TEST123".to_string(), event))
+            .await
+            .is_err());
+        assert_eq!(stats.queue_depth(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_rejects_values_that_do_not_fit_columns() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let privacy_filter = Arc::new(PrivacyFilter::new(Config::default().privacy));
+        let collector = EventCollector::new(tx, privacy_filter);
+
+        let custom = |name: &str| Event::Custom {
+            name: name.to_string(),
+            params: EventParams::default(),
+        };
+        let long_name = EventEnvelope::new("TEST123".to_string(), custom(&"x".repeat(41)));
+        assert!(collector.collect(long_name).await.is_err());
+        let unnamed = EventEnvelope::new("TEST123".to_string(), custom(""));
+        assert!(collector.collect(unnamed).await.is_err());
+
+        let refund = Event::Refund {
+            transaction_id: "T1".to_string(),
+            value: Some(5.0),
+            currency: Some("REAL".to_string()),
+            items: None,
+            params: EventParams::default(),
+        };
+        let bad_currency = EventEnvelope::new("TEST123".to_string(), refund);
+        assert!(collector.collect(bad_currency).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_user_id_length_is_checked_before_encryption() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let mut privacy = Config::default().privacy;
+        privacy.encryption_enabled = true;
+        let keyring = crate::crypto::Keyring::new("k1", &[1; 32]).unwrap();
+        let privacy_filter = Arc::new(PrivacyFilter::new(privacy).with_keyring(keyring));
+        let collector = EventCollector::new(tx, privacy_filter);
+
+        let page_view = |user_id: String| Event::PageView {
+            page_title: "Test".to_string(),
+            page_location: "https://test.com".to_string(),
+            page_referrer: None,
+            user_id: Some(user_id),
+            params: EventParams::default(),
+        };
+        let longest = EventEnvelope::new("TEST123".to_string(), page_view("u".repeat(MAX_ID_LEN)));
+        collector.collect(longest).await.unwrap();
+        let sealed = rx.recv().await.unwrap();
+        assert!(sealed.event.user_id().unwrap().len() > MAX_ID_LEN);
+
+        let too_long =
+            EventEnvelope::new("TEST123".to_string(), page_view("u".repeat(MAX_ID_LEN + 1)));
+        assert!(collector.collect(too_long).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_filtered_events_are_dropped_and_counted() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let privacy_filter = Arc::new(PrivacyFilter::new(Config::default().privacy));
+        let filter = HitFilter::new(Arc::new(crate::sites::SiteRegistry::in_memory()));
+        let collector = EventCollector::new(tx, privacy_filter).with_filter(Arc::new(filter));
+
+        let event = Event::PageView {
+            page_title: "Test".to_string(),
+            page_location: "https://test.com".to_string(),
+            page_referrer: Some("https://darodar.com/".to_string()),
+            user_id: None,
+            params: EventParams::default(),
+        };
+        collector
+            .collect(EventEnvelope::new("TEST123".to_string(), event))
+            .await
+            .unwrap();
+
+        assert!(rx.try_recv().is_err());
+        assert_eq!(collector.metrics().events_collected(), 0);
+        assert_eq!(collector.metrics().filtered().referrer_spam, 1);
+    }
 }
@@ -0,0 +1,580 @@
+//! Field-level encryption of personal data
+//!
+//! Sensitive event parameters are sealed with AES-256-GCM before they are stored. Each
+//! value becomes `enc:v1:<key-id>:<base64 nonce + ciphertext>`, with the parameter name as
+//! associated data so a ciphertext can't be moved to another field. The keyring holds one
+//! active key, used for new values, and any number of retired keys that can still decrypt.
+//! [`KeyRotation`] re-encrypts stored events, and the user IDs of sessions and
+//! conversions, under the active key so retired keys can be dropped.
+//!
+//! User IDs are sealed deterministically: the nonce is an HMAC of the field and value
+//! under a key derived from the encryption key, so one user ID always has the same
+//! ciphertext per key. The ciphertext then serves as a blind index, and events, sessions
+//! and conversions of a user can be found or deleted through
+//! [`Keyring::lookup_values`] without decrypting them.
+
+use crate::config::EncryptionConfig;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventEnvelope};
+use crate::storage::{EventFilter, StorageEngine};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use futures::TryStreamExt;
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::Arc;
+use tracing::info;
+
+/// Environment variable holding `id:base64key` pairs, the active key first
+pub const KEYRING_ENV: &str = "AVILA_KEYRING";
+
+const PREFIX: &str = "enc:v1:";
+const NONCE_LEN: usize = 12;
+
+/// Fields sealed deterministically so they can be looked up
+const INDEXED_FIELDS: &[&str] = &["user_id"];
+
+/// One key of the keyring
+#[derive(Clone)]
+struct Key {
+    cipher: Aes256Gcm,
+    /// Derives synthetic nonces for indexed fields
+    nonce_key: Vec<u8>,
+}
+
+/// Encryption keys by ID
+#[derive(Clone)]
+pub struct Keyring {
+    keys: BTreeMap<String, Key>,
+    active: String,
+}
+
+#[derive(Deserialize)]
+struct KeyringFile {
+    active: String,
+    keys: BTreeMap<String, String>,
+}
+
+impl Keyring {
+    /// Keyring whose active key is `key`
+    pub fn new(id: &str, key: &[u8]) -> Result<Self> {
+        let mut keyring = Self {
+            keys: BTreeMap::new(),
+            active: id.to_string(),
+        };
+        keyring.insert(id, key)?;
+        Ok(keyring)
+    }
+
+    /// Add a retired key that can still decrypt
+    pub fn with_key(mut self, id: &str, key: &[u8]) -> Result<Self> {
+        self.insert(id, key)?;
+        Ok(self)
+    }
+
+    fn insert(&mut self, id: &str, key: &[u8]) -> Result<()> {
+        if id.is_empty() || id.contains([':', ',']) {
+            return Err(Error::Config(format!("invalid key ID {:?}", id)));
+        }
+        let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| {
+            Error::Config(format!("key {:?} must be 32 bytes, got {}", id, key.len()))
+        })?;
+        let nonce_key = hmac(key, &[b"avila-analytics synthetic nonce"]).to_vec();
+        self.keys.insert(id.to_string(), Key { cipher, nonce_key });
+        Ok(())
+    }
+
+    /// Parse a TOML keyring: `active = "<id>"` and a `[keys]` table of base64 keys
+    pub fn parse(text: &str) -> Result<Self> {
+        let file: KeyringFile =
+            toml::from_str(text).map_err(|e| Error::Config(format!("invalid keyring: {}", e)))?;
+        let active = file.keys.get(&file.active).ok_or_else(|| {
+            Error::Config(format!(
+                "active key {:?} is not in the keyring",
+                file.active
+            ))
+        })?;
+        let mut keyring = Self::new(&file.active, &decode_key(&file.active, active)?)?;
+        for (id, key) in &file.keys {
+            keyring.insert(id, &decode_key(id, key)?)?;
+        }
+        Ok(keyring)
+    }
+
+    /// Parse `id:base64key` pairs separated by commas; the first key is active
+    pub fn parse_env(value: &str) -> Result<Self> {
+        let mut keyring: Option<Self> = None;
+        for entry in value.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+            let (id, key) = entry
+                .split_once(':')
+                .ok_or_else(|| Error::Config(format!("{} entries must be id:key", KEYRING_ENV)))?;
+            let key = decode_key(id, key)?;
+            keyring = Some(match keyring {
+                None => Self::new(id, &key)?,
+                Some(keyring) => keyring.with_key(id, &key)?,
+            });
+        }
+        keyring.ok_or_else(|| Error::Config(format!("{} has no keys", KEYRING_ENV)))
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|e| Error::Config(format!("cannot read keyring {}: {}", path.display(), e)))?;
+        Self::parse(&text)
+    }
+
+    /// Keyring from the configured file, else from `AVILA_KEYRING`; `None` when neither is set
+    pub fn open(config: &EncryptionConfig) -> Result<Option<Self>> {
+        if let Some(path) = &config.keyring {
+            return Self::load(path).map(Some);
+        }
+        match std::env::var(KEYRING_ENV) {
+            Ok(value) => Self::parse_env(&value).map(Some),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// A new random key, base64-encoded for a keyring file
+    pub fn generate_key() -> String {
+        STANDARD.encode(Aes256Gcm::generate_key(&mut OsRng))
+    }
+
+    pub fn active_key_id(&self) -> &str {
+        &self.active
+    }
+
+    /// Seal `plaintext` under the active key, bound to `field`
+    pub fn encrypt(&self, field: &str, plaintext: &str) -> Result<String> {
+        self.encrypt_with(&self.active, field, plaintext)
+    }
+
+    fn encrypt_with(&self, key_id: &str, field: &str, plaintext: &str) -> Result<String> {
+        let key = &self.keys[key_id];
+        let nonce = if INDEXED_FIELDS.contains(&field) {
+            let mac = hmac(
+                &key.nonce_key,
+                &[field.as_bytes(), &[0], plaintext.as_bytes()],
+            );
+            *Nonce::from_slice(&mac[..NONCE_LEN])
+        } else {
+            Aes256Gcm::generate_nonce(&mut OsRng)
+        };
+        let sealed = key
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: plaintext.as_bytes(),
+                    aad: field.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::Privacy(format!("failed to encrypt {}", field)))?;
+
+        let mut bytes = nonce.to_vec();
+        bytes.extend(sealed);
+        Ok(format!("{}{}:{}", PREFIX, key_id, STANDARD.encode(bytes)))
+    }
+
+    /// Every form an indexed field's `plaintext` may be stored in: as is, and sealed under
+    /// each key of the keyring
+    pub fn lookup_values(&self, field: &str, plaintext: &str) -> Result<Vec<String>> {
+        if !INDEXED_FIELDS.contains(&field) {
+            return Err(Error::Privacy(format!(
+                "{} is not sealed deterministically",
+                field
+            )));
+        }
+        let mut values = vec![plaintext.to_string()];
+        for key_id in self.keys.keys() {
+            values.push(self.encrypt_with(key_id, field, plaintext)?);
+        }
+        Ok(values)
+    }
+
+    /// Open a value sealed by [`Keyring::encrypt`]; other values are returned unchanged
+    pub fn decrypt(&self, field: &str, value: &str) -> Result<String> {
+        let Some(key_id) = key_id(value) else {
+            return Ok(value.to_string());
+        };
+        let cipher = &self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| {
+                Error::Privacy(format!(
+                    "{} is encrypted with unknown key {:?}",
+                    field, key_id
+                ))
+            })?
+            .cipher;
+        let invalid = || Error::Privacy(format!("{} has an invalid ciphertext", field));
+
+        let encoded = &value[PREFIX.len() + key_id.len() + 1..];
+        let bytes = STANDARD.decode(encoded).map_err(|_| invalid())?;
+        if bytes.len() < NONCE_LEN {
+            return Err(invalid());
+        }
+        let (nonce, sealed) = bytes.split_at(NONCE_LEN);
+        let plaintext = cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: sealed,
+                    aad: field.as_bytes(),
+                },
+            )
+            .map_err(|_| invalid())?;
+        String::from_utf8(plaintext).map_err(|_| invalid())
+    }
+
+    /// Encrypt the parameters selected by `config` that are still in plaintext
+    pub fn encrypt_event(
+        &self,
+        envelope: &mut EventEnvelope,
+        config: &EncryptionConfig,
+    ) -> Result<()> {
+        for (field, value) in sensitive_values(&mut envelope.event) {
+            let selected = match field.strip_prefix("custom_dimensions.") {
+                Some(name) => config.custom_dimensions.iter().any(|d| d == name),
+                None => config.fields.contains(&field),
+            };
+            if selected && key_id(value).is_none() {
+                *value = self.encrypt(&field, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decrypt every encrypted parameter
+    pub fn decrypt_event(&self, envelope: &mut EventEnvelope) -> Result<()> {
+        for (field, value) in sensitive_values(&mut envelope.event) {
+            if key_id(value).is_some() {
+                *value = self.decrypt(&field, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Re-encrypt parameters sealed with a retired key, and indexed fields sealed before
+    /// they were deterministic, returning whether any changed
+    pub fn reencrypt_event(&self, envelope: &mut EventEnvelope) -> Result<bool> {
+        let mut changed = false;
+        for (field, value) in sensitive_values(&mut envelope.event) {
+            if let Some(sealed) = self.reseal(&field, value)? {
+                *value = sealed;
+                changed = true;
+            }
+        }
+        Ok(changed)
+    }
+
+    /// `value` sealed again under the active key, or `None` if it is plaintext or already
+    /// sealed the way [`Keyring::encrypt`] would
+    pub fn reseal(&self, field: &str, value: &str) -> Result<Option<String>> {
+        let Some(key_id) = key_id(value) else {
+            return Ok(None);
+        };
+        if key_id == self.active && !INDEXED_FIELDS.contains(&field) {
+            return Ok(None);
+        }
+        let sealed = self.encrypt(field, &self.decrypt(field, value)?)?;
+        Ok((sealed != value).then_some(sealed))
+    }
+}
+
+impl std::fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Keyring")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("active", &self.active)
+            .finish()
+    }
+}
+
+/// HMAC-SHA256 of the concatenated `parts`
+fn hmac(key: &[u8], parts: &[&[u8]]) -> [u8; 32] {
+    let mut mac =
+        <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    for part in parts {
+        mac.update(part);
+    }
+    mac.finalize().into_bytes().into()
+}
+
+/// ID of the key that sealed `value`, or `None` for plaintext
+pub fn key_id(value: &str) -> Option<&str> {
+    value
+        .strip_prefix(PREFIX)?
+        .split_once(':')
+        .map(|(id, _)| id)
+}
+
+fn decode_key(id: &str, key: &str) -> Result<Vec<u8>> {
+    STANDARD
+        .decode(key.trim())
+        .map_err(|e| Error::Config(format!("key {:?} is not valid base64: {}", id, e)))
+}
+
+/// Parameters that may hold personal data, named as in `privacy.encryption`
+fn sensitive_values(event: &mut Event) -> Vec<(String, &mut String)> {
+    let (page_user_id, params) = match event {
+        Event::PageView {
+            user_id, params, ..
+        } => (user_id.as_mut(), params),
+        other => (None, other.params_mut()),
+    };
+
+    let mut values: Vec<(String, &mut String)> = page_user_id
+        .into_iter()
+        .chain(params.user_id.as_mut())
+        .map(|v| ("user_id".to_string(), v))
+        .collect();
+    values.extend(
+        params
+            .ip_address
+            .as_mut()
+            .map(|v| ("ip_address".to_string(), v)),
+    );
+    values.extend(
+        params
+            .user_agent
+            .as_mut()
+            .map(|v| ("user_agent".to_string(), v)),
+    );
+    if let Some(dimensions) = params.custom_dimensions.as_mut() {
+        values.extend(
+            dimensions
+                .iter_mut()
+                .map(|(name, v)| (format!("custom_dimensions.{}", name), v)),
+        );
+    }
+    values
+}
+
+/// Counts from a key rotation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RotationSummary {
+    pub scanned: u64,
+    pub rotated: u64,
+    /// Sessions and conversions whose user ID was sealed again
+    pub records: u64,
+}
+
+/// Re-encrypts stored events, then the user IDs of sessions and conversions, under the
+/// keyring's active key.
+///
+/// Events are rewritten one day at a time, so ingestion and queries carry on meanwhile;
+/// both keys stay readable until the rotation finishes.
+pub struct KeyRotation {
+    engine: Arc<dyn StorageEngine>,
+    keyring: Arc<Keyring>,
+}
+
+impl KeyRotation {
+    pub fn new(engine: Arc<dyn StorageEngine>, keyring: Arc<Keyring>) -> Self {
+        Self { engine, keyring }
+    }
+
+    pub async fn run(&self) -> Result<RotationSummary> {
+        let mut summary = RotationSummary::default();
+        let first = self
+            .engine
+            .scan_events(EventFilter::new().limit(1))
+            .try_next()
+            .await?;
+        if let Some(first) = first {
+            self.rotate_events(first.timestamp, &mut summary).await?;
+        }
+
+        let mut replacements = BTreeMap::new();
+        for user_id in self.engine.user_ids().await? {
+            if let Some(sealed) = self.keyring.reseal("user_id", &user_id)? {
+                replacements.insert(user_id, sealed);
+            }
+        }
+        if !replacements.is_empty() {
+            summary.records = self.engine.replace_user_ids(&replacements).await?;
+            info!(
+                "Re-encrypted {} user IDs of sessions and conversions",
+                replacements.len()
+            );
+        }
+        Ok(summary)
+    }
+
+    async fn rotate_events(
+        &self,
+        first: DateTime<Utc>,
+        summary: &mut RotationSummary,
+    ) -> Result<()> {
+        let now = Utc::now();
+        let mut start = first.duration_trunc(TimeDelta::days(1)).unwrap_or(first);
+        while start <= now {
+            let end = start + TimeDelta::days(1);
+            // The scan must finish before rewriting, as some backends lock files while scanning
+            let mut rotated = Vec::new();
+            let mut events = self
+                .engine
+                .scan_events(EventFilter::new().since(start).until(end));
+            while let Some(mut event) = events.try_next().await? {
+                summary.scanned += 1;
+                if self.keyring.reencrypt_event(&mut event)? {
+                    rotated.push(event);
+                }
+            }
+            drop(events);
+
+            if !rotated.is_empty() {
+                let count = rotated.len();
+                summary.rotated += self.engine.replace_events(rotated).await?;
+                info!("Re-encrypted {} events from {}", count, start.date_naive());
+            }
+            start = end;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventParams;
+    use crate::session::Sessionizer;
+    use crate::sites::SiteRegistry;
+    use crate::storage::MemoryStorage;
+    use std::collections::HashMap;
+
+    fn page_view(user_id: &str) -> EventEnvelope {
+        EventEnvelope::new(
+            "G-CRYPTO".to_string(),
+            Event::PageView {
+                page_title: "Home".to_string(),
+                page_location: "https://example.com/".to_string(),
+                page_referrer: None,
+                user_id: Some(user_id.to_string()),
+                params: EventParams {
+                    ip_address: Some("192.168.1.0".to_string()),
+                    custom_dimensions: Some(HashMap::from([(
+                        "plan".to_string(),
+                        "pro".to_string(),
+                    )])),
+                    ..Default::default()
+                },
+            },
+        )
+    }
+
+    #[test]
+    fn test_encrypt_and_decrypt_event() {
+        let keyring = Keyring::new("k1", &[7; 32]).unwrap();
+        let config = EncryptionConfig {
+            custom_dimensions: vec!["plan".to_string()],
+            ..Default::default()
+        };
+
+        let mut envelope = page_view("user-1");
+        keyring.encrypt_event(&mut envelope, &config).unwrap();
+        let user_id = envelope.event.user_id().unwrap().to_string();
+        assert_eq!(key_id(&user_id), Some("k1"));
+        assert_eq!(
+            key_id(envelope.event.params().ip_address.as_deref().unwrap()),
+            Some("k1")
+        );
+
+        // Bound to its field
+        assert!(keyring.decrypt("ip_address", &user_id).is_err());
+
+        // User IDs are deterministic, other fields are not
+        assert_eq!(keyring.encrypt("user_id", "user-1").unwrap(), user_id);
+        assert!(keyring
+            .lookup_values("user_id", "user-1")
+            .unwrap()
+            .contains(&user_id));
+        assert_ne!(
+            keyring.encrypt("ip_address", "x").unwrap(),
+            keyring.encrypt("ip_address", "x").unwrap()
+        );
+
+        keyring.decrypt_event(&mut envelope).unwrap();
+        assert_eq!(envelope.event.user_id(), Some("user-1"));
+        assert_eq!(
+            envelope.event.params().ip_address.as_deref(),
+            Some("192.168.1.0")
+        );
+        assert_eq!(
+            envelope.event.params().custom_dimensions.as_ref().unwrap()["plan"],
+            "pro"
+        );
+    }
+
+    #[test]
+    fn test_parse_keyrings() {
+        let key = Keyring::generate_key();
+        let keyring = Keyring::parse(&format!(
+            "active = \"k2\"\n[keys]\nk1 = \"{key}\"\nk2 = \"{key}\"\n"
+        ))
+        .unwrap();
+        assert_eq!(keyring.active_key_id(), "k2");
+        assert_eq!(
+            Keyring::parse_env(&format!("k3:{key},k1:{key}"))
+                .unwrap()
+                .active_key_id(),
+            "k3"
+        );
+
+        assert!(Keyring::parse(&format!("active = \"k9\"\n[keys]\nk1 = \"{key}\"\n")).is_err());
+        assert!(Keyring::parse_env("k1:c2hvcnQ=").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rotation_reencrypts_stored_events() {
+        let old = Keyring::new("k1", &[1; 32]).unwrap();
+        let mut envelope = page_view("user-1");
+        old.encrypt_event(&mut envelope, &EncryptionConfig::default())
+            .unwrap();
+
+        let storage = Arc::new(MemoryStorage::new());
+        storage.store_events(vec![envelope.clone()]).await.unwrap();
+        let sessionizer =
+            Sessionizer::new(Arc::new(SiteRegistry::in_memory()), &Default::default());
+        let mut tracked = envelope.clone();
+        tracked.event.params_mut().client_id = Some("c1".to_string());
+        sessionizer.track(&mut tracked).await.unwrap();
+        storage.store_sessions(sessionizer.drain()).await.unwrap();
+
+        let keyring = Arc::new(
+            Keyring::new("k2", &[2; 32])
+                .unwrap()
+                .with_key("k1", &[1; 32])
+                .unwrap(),
+        );
+        let summary = KeyRotation::new(storage.clone(), keyring.clone())
+            .run()
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            RotationSummary {
+                scanned: 1,
+                rotated: 1,
+                records: 1
+            }
+        );
+
+        let mut stored = storage.get_event(envelope.event_id).await.unwrap().unwrap();
+        assert_eq!(key_id(stored.event.user_id().unwrap()), Some("k2"));
+        let sessions = storage
+            .sessions(&envelope.measurement_id, DateTime::UNIX_EPOCH, Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(sessions[0].user_id.as_deref(), stored.event.user_id());
+        keyring.decrypt_event(&mut stored).unwrap();
+        assert_eq!(stored.event.user_id(), Some("user-1"));
+    }
+}
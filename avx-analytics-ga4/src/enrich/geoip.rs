@@ -0,0 +1,375 @@
+//! IP geolocation from a local MaxMind database
+//!
+//! Country, region, city and timezone come from a GeoLite2/GeoIP2 City or Country `.mmdb`
+//! file read entirely into memory; no lookup leaves the host. The
+//! [`EventCollector`](crate::collector::EventCollector) locates events before the privacy
+//! filter masks their IP, so `anonymize_ip` never has to be relaxed. [`GeoIp::reload`]
+//! swaps in a new file in place when it changes on disk.
+
+use super::Enricher;
+use crate::config::EnrichmentConfig;
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use maxminddb::{geoip2, Reader};
+use serde::Serialize;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::SystemTime;
+
+/// Brazilian states by UF code, for databases whose subdivisions lack ISO codes
+const BRAZIL_STATES: &[(&str, &str)] = &[
+    ("AC", "Acre"),
+    ("AL", "Alagoas"),
+    ("AP", "Amapá"),
+    ("AM", "Amazonas"),
+    ("BA", "Bahia"),
+    ("CE", "Ceará"),
+    ("DF", "Distrito Federal"),
+    ("ES", "Espírito Santo"),
+    ("GO", "Goiás"),
+    ("MA", "Maranhão"),
+    ("MT", "Mato Grosso"),
+    ("MS", "Mato Grosso do Sul"),
+    ("MG", "Minas Gerais"),
+    ("PA", "Pará"),
+    ("PB", "Paraíba"),
+    ("PR", "Paraná"),
+    ("PE", "Pernambuco"),
+    ("PI", "Piauí"),
+    ("RJ", "Rio de Janeiro"),
+    ("RN", "Rio Grande do Norte"),
+    ("RS", "Rio Grande do Sul"),
+    ("RO", "Rondônia"),
+    ("RR", "Roraima"),
+    ("SC", "Santa Catarina"),
+    ("SP", "São Paulo"),
+    ("SE", "Sergipe"),
+    ("TO", "Tocantins"),
+];
+
+/// Where an IP address is
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct GeoLocation {
+    /// ISO 3166-1 alpha-2 code
+    pub country: Option<String>,
+    /// UF code for Brazil, the English subdivision name elsewhere
+    pub region: Option<String>,
+    pub city: Option<String>,
+    pub timezone: Option<String>,
+}
+
+/// Looks up IP addresses in a hot-reloadable MaxMind database
+pub struct GeoIp {
+    path: PathBuf,
+    reader: RwLock<Arc<Reader<Vec<u8>>>>,
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl GeoIp {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let modified = modified(&path);
+        let reader = read(&path)?;
+        Ok(Self {
+            path,
+            reader: RwLock::new(Arc::new(reader)),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Database named by `enrichment.geoip_database`, if any
+    pub fn configured(config: &EnrichmentConfig) -> Result<Option<Self>> {
+        config.geoip_database.as_ref().map(Self::open).transpose()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Re-read the database if the file changed, returning whether it was replaced.
+    ///
+    /// A file that fails to load leaves the current database in use.
+    pub fn reload(&self) -> Result<bool> {
+        let mut last = self.modified.lock().unwrap();
+        let current = modified(&self.path);
+        if current == *last {
+            return Ok(false);
+        }
+        let reader = read(&self.path)?;
+        *self.reader.write().unwrap() = Arc::new(reader);
+        *last = current;
+        Ok(true)
+    }
+
+    /// Database build time, from its metadata
+    pub fn build_epoch(&self) -> u64 {
+        self.reader.read().unwrap().metadata.build_epoch
+    }
+
+    pub fn lookup(&self, ip: IpAddr) -> Option<GeoLocation> {
+        let reader = self.reader.read().unwrap().clone();
+        let record: geoip2::City = reader.lookup(ip).ok()?;
+
+        let country = record.country.and_then(|c| c.iso_code).map(str::to_string);
+        let subdivision = record.subdivisions.and_then(|s| s.into_iter().next());
+        let region = subdivision.and_then(|s| {
+            let name = s
+                .names
+                .as_ref()
+                .and_then(|n| n.get("en").or_else(|| n.get("pt-BR")).copied());
+            match country.as_deref() {
+                Some("BR") => brazil_uf(s.iso_code, name),
+                _ => name.or(s.iso_code).map(str::to_string),
+            }
+        });
+        Some(GeoLocation {
+            country,
+            region,
+            city: record
+                .city
+                .and_then(|c| c.names)
+                .and_then(|n| n.get("en").copied())
+                .map(str::to_string),
+            timezone: record
+                .location
+                .and_then(|l| l.time_zone)
+                .map(str::to_string),
+        })
+    }
+}
+
+impl Enricher for GeoIp {
+    fn name(&self) -> &str {
+        "geoip"
+    }
+
+    /// Locate the event by its IP, which may already be masked when replaying stored
+    /// events; encrypted or missing addresses leave the event untouched
+    fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+        let params = envelope.event.params_mut();
+        let Some(ip) = params
+            .ip_address
+            .as_deref()
+            .and_then(|ip| ip.parse::<IpAddr>().ok())
+        else {
+            return Ok(());
+        };
+        let location = self.lookup(ip).unwrap_or_default();
+        params.country = location.country;
+        params.region = location.region;
+        params.city = location.city;
+        params.timezone = location.timezone;
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for GeoIp {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GeoIp")
+            .field("path", &self.path)
+            .finish_non_exhaustive()
+    }
+}
+
+fn read(path: &Path) -> Result<Reader<Vec<u8>>> {
+    Reader::open_readfile(path).map_err(|e| {
+        Error::Config(format!(
+            "cannot open GeoIP database {}: {}",
+            path.display(),
+            e
+        ))
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn brazil_uf(iso_code: Option<&str>, name: Option<&str>) -> Option<String> {
+    let name = name.map(|n| {
+        if n.eq_ignore_ascii_case("Federal District") {
+            "Distrito Federal"
+        } else {
+            n
+        }
+    });
+    BRAZIL_STATES
+        .iter()
+        .find(|(uf, state)| {
+            iso_code == Some(*uf) || name.is_some_and(|n| n.eq_ignore_ascii_case(state))
+        })
+        .map(|(uf, _)| uf.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::collector::EventCollector;
+    use crate::config::Config;
+    use crate::events::{Event, EventParams};
+    use crate::privacy::PrivacyFilter;
+
+    /// MaxMind DB encoding of the few value types the tests need
+    enum Value<'a> {
+        Str(&'a str),
+        U16(u16),
+        Map(Vec<(&'a str, Value<'a>)>),
+        Array(Vec<Value<'a>>),
+    }
+
+    fn encode(value: &Value, out: &mut Vec<u8>) {
+        match value {
+            Value::Str(s) => {
+                out.push(2 << 5 | s.len() as u8);
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::U16(n) => {
+                out.push(5 << 5 | 2);
+                out.extend_from_slice(&n.to_be_bytes());
+            }
+            Value::Map(pairs) => {
+                out.push(7 << 5 | pairs.len() as u8);
+                for (key, value) in pairs {
+                    encode(&Value::Str(key), out);
+                    encode(value, out);
+                }
+            }
+            Value::Array(items) => {
+                out.extend_from_slice(&[items.len() as u8, 11 - 7]);
+                for item in items {
+                    encode(item, out);
+                }
+            }
+        }
+    }
+
+    /// IPv4 database with a single `/24` network, 24-bit records
+    fn database(network: [u8; 3], record: Value) -> Vec<u8> {
+        let node_count = 24u32;
+        let mut out = Vec::new();
+        for node in 0..24 {
+            let bit = network[node / 8] >> (7 - node % 8) & 1;
+            let next = if node == 23 {
+                node_count + 16
+            } else {
+                node as u32 + 1
+            };
+            let (left, right) = if bit == 0 {
+                (next, node_count)
+            } else {
+                (node_count, next)
+            };
+            out.extend_from_slice(&left.to_be_bytes()[1..]);
+            out.extend_from_slice(&right.to_be_bytes()[1..]);
+        }
+        out.extend_from_slice(&[0; 16]);
+        encode(&record, &mut out);
+
+        out.extend_from_slice(b"\xAB\xCD\xEFMaxMind.com");
+        encode(
+            &Value::Map(vec![
+                ("binary_format_major_version", Value::U16(2)),
+                ("binary_format_minor_version", Value::U16(0)),
+                ("build_epoch", Value::U16(1)),
+                ("database_type", Value::Str("GeoLite2-City")),
+                ("description", Value::Map(vec![("en", Value::Str("test"))])),
+                ("ip_version", Value::U16(4)),
+                ("languages", Value::Array(vec![Value::Str("en")])),
+                ("node_count", Value::U16(node_count as u16)),
+                ("record_size", Value::U16(24)),
+            ]),
+            &mut out,
+        );
+        out
+    }
+
+    fn sao_paulo(subdivision: Value<'static>) -> Vec<u8> {
+        database(
+            [177, 10, 20],
+            Value::Map(vec![
+                (
+                    "city",
+                    Value::Map(vec![(
+                        "names",
+                        Value::Map(vec![("en", Value::Str("Campinas"))]),
+                    )]),
+                ),
+                ("country", Value::Map(vec![("iso_code", Value::Str("BR"))])),
+                (
+                    "location",
+                    Value::Map(vec![("time_zone", Value::Str("America/Sao_Paulo"))]),
+                ),
+                ("subdivisions", Value::Array(vec![subdivision])),
+            ]),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_collector_locates_before_masking() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("GeoLite2-City.mmdb");
+        std::fs::write(
+            &path,
+            sao_paulo(Value::Map(vec![("iso_code", Value::Str("SP"))])),
+        )
+        .unwrap();
+        let geoip = Arc::new(GeoIp::open(&path).unwrap());
+
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        let privacy = Arc::new(PrivacyFilter::new(Config::default().privacy));
+        let collector = EventCollector::new(tx, privacy).with_geoip(geoip.clone());
+        let envelope = EventEnvelope::new(
+            "G-TEST".to_string(),
+            Event::Custom {
+                name: "click".to_string(),
+                params: EventParams {
+                    ip_address: Some("177.10.20.33".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        collector.collect(envelope).await.unwrap();
+
+        let collected = rx.recv().await.unwrap();
+        let params = collected.event.params();
+        assert_eq!(params.ip_address.as_deref(), Some("177.10.20.0"));
+        assert_eq!(params.country.as_deref(), Some("BR"));
+        assert_eq!(params.region.as_deref(), Some("SP"));
+        assert_eq!(params.city.as_deref(), Some("Campinas"));
+        assert_eq!(params.timezone.as_deref(), Some("America/Sao_Paulo"));
+        assert_eq!(geoip.lookup("8.8.8.8".parse().unwrap()), None);
+    }
+
+    #[test]
+    fn test_reload_and_uf_from_name() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("GeoLite2-City.mmdb");
+        std::fs::write(
+            &path,
+            sao_paulo(Value::Map(vec![("iso_code", Value::Str("SP"))])),
+        )
+        .unwrap();
+        let geoip = GeoIp::open(&path).unwrap();
+        assert!(!geoip.reload().unwrap());
+
+        // A broken file keeps the loaded database
+        std::fs::write(&path, b"not a database").unwrap();
+        let file = std::fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(60))
+            .unwrap();
+        assert!(geoip.reload().is_err());
+        assert!(geoip.lookup("177.10.20.1".parse().unwrap()).is_some());
+
+        let by_name = Value::Map(vec![(
+            "names",
+            Value::Map(vec![("en", Value::Str("Federal District"))]),
+        )]);
+        std::fs::write(&path, sao_paulo(by_name)).unwrap();
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(120))
+            .unwrap();
+        assert!(geoip.reload().unwrap());
+        let location = geoip.lookup("177.10.20.1".parse().unwrap()).unwrap();
+        assert_eq!(location.region.as_deref(), Some("DF"));
+    }
+}
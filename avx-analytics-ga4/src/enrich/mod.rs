@@ -0,0 +1,168 @@
+//! Event enrichment
+//!
+//! Enrichers derive fields (device, location, channel, ...) from what the client sent.
+//! The same [`Enrichment`] runs in the [`EventProcessor`](crate::processor::EventProcessor)
+//! and in [`Replay`](crate::replay::Replay), so stored events can be enriched again after an
+//! enricher is fixed or added.
+
+use crate::config::Config;
+use crate::error::Result;
+use crate::events::EventEnvelope;
+use crate::privacy::PrivacyFilter;
+use crate::sites::SiteRegistry;
+use std::sync::Arc;
+
+pub mod geoip;
+pub mod traffic;
+pub mod useragent;
+
+pub use geoip::{GeoIp, GeoLocation};
+pub use traffic::{Attribution, SourceCategory, TrafficSources};
+pub use useragent::{UserAgent, UserAgentParser};
+
+/// Derives fields of an event from its other fields.
+///
+/// Enrichers must overwrite what they derive rather than accumulate, so enriching a
+/// stored event again gives the same result.
+pub trait Enricher: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()>;
+}
+
+/// Lets one enricher, e.g. a reloadable [`GeoIp`], be shared with other components
+impl<E: Enricher + ?Sized> Enricher for Arc<E> {
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+        (**self).enrich(envelope)
+    }
+}
+
+/// Ordered enrichers applied to every event
+#[derive(Clone, Default)]
+pub struct Enrichment {
+    enrichers: Vec<Arc<dyn Enricher>>,
+    privacy: Option<Arc<PrivacyFilter>>,
+}
+
+impl Enrichment {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Enrichers enabled by the configuration; channel rules come from `sites`
+    pub fn from_config(config: &Config, sites: Arc<SiteRegistry>) -> Result<Self> {
+        let settings = &config.enrichment;
+        let mut enrichment = Self::new();
+        if settings.user_agent {
+            let parser = match &settings.user_agent_regexes {
+                Some(path) => UserAgentParser::load(path)?,
+                None => UserAgentParser::embedded(),
+            };
+            enrichment =
+                enrichment.with_enricher(parser.with_cache_size(settings.user_agent_cache_size));
+        }
+        if settings.traffic_source {
+            enrichment = enrichment.with_enricher(TrafficSources::new(sites));
+        }
+        Ok(enrichment)
+    }
+
+    pub fn with_enricher(mut self, enricher: impl Enricher + 'static) -> Self {
+        self.enrichers.push(Arc::new(enricher));
+        self
+    }
+
+    /// Decrypt events before enriching and encrypt them again afterwards
+    pub fn with_privacy(mut self, privacy: Arc<PrivacyFilter>) -> Self {
+        self.privacy = Some(privacy);
+        self
+    }
+
+    pub fn names(&self) -> Vec<String> {
+        self.enrichers
+            .iter()
+            .map(|e| e.name().to_string())
+            .collect()
+    }
+
+    pub fn apply(&self, mut envelope: EventEnvelope) -> Result<EventEnvelope> {
+        if self.enrichers.is_empty() {
+            return Ok(envelope);
+        }
+        if let Some(privacy) = &self.privacy {
+            envelope = privacy.decrypt(envelope)?;
+        }
+        for enricher in &self.enrichers {
+            enricher.enrich(&mut envelope)?;
+        }
+        match &self.privacy {
+            Some(privacy) => privacy.encrypt(envelope),
+            None => Ok(envelope),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::Keyring;
+    use crate::events::{Event, EventParams};
+
+    struct Language;
+
+    impl Enricher for Language {
+        fn name(&self) -> &str {
+            "language"
+        }
+
+        fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+            let params = envelope.event.params_mut();
+            params.language = params.user_id.as_ref().map(|id| format!("lang-of-{}", id));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_enrichers_see_decrypted_fields() {
+        let privacy = Arc::new(
+            PrivacyFilter::new(Config::default().privacy)
+                .with_keyring(Keyring::new("k1", &[5; 32]).unwrap()),
+        );
+        let envelope = EventEnvelope::new(
+            "G-TEST".to_string(),
+            Event::Custom {
+                name: "signup".to_string(),
+                params: EventParams {
+                    user_id: Some("u1".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        let stored = privacy.apply(envelope).await.unwrap();
+
+        let enrichment = Enrichment::new()
+            .with_enricher(Language)
+            .with_privacy(privacy.clone());
+        let enriched = enrichment.apply(stored).unwrap();
+        assert_eq!(
+            enriched.event.params().language.as_deref(),
+            Some("lang-of-u1")
+        );
+        assert!(enriched
+            .event
+            .params()
+            .user_id
+            .as_deref()
+            .unwrap()
+            .starts_with("enc:v1:k1:"));
+
+        // Enriching again is idempotent
+        let again = enrichment.apply(enriched).unwrap();
+        assert_eq!(again.event.params().language.as_deref(), Some("lang-of-u1"));
+        assert_eq!(enrichment.names(), vec!["language"]);
+    }
+}
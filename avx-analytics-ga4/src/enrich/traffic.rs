@@ -0,0 +1,516 @@
+//! Traffic source attribution and channel grouping
+//!
+//! Page views are attributed from their URL first (`utm_*`, then the `gclid`, `msclkid`
+//! and `fbclid` click IDs) and otherwise from the referrer, which is looked up in a table
+//! of search engines, social networks, video sites, email providers and shopping sites
+//! embedded in the binary. The source and medium then get a channel group: the first
+//! matching rule a site defined, else a GA4-style default (`Organic Search`, `Paid Social`,
+//! `Direct`, `Referral`, `Email`, ...).
+
+use super::Enricher;
+use crate::error::Result;
+use crate::events::{Event, EventEnvelope};
+use crate::models::ChannelRule;
+use crate::sites::SiteRegistry;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, OnceLock};
+use uuid::Uuid;
+
+const EMBEDDED: &str = include_str!("referrers.json");
+
+const DIRECT: &str = "(direct)";
+const NONE: &str = "(none)";
+const NOT_SET: &str = "(not set)";
+
+/// Kind of site a traffic source is
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SourceCategory {
+    Search,
+    Social,
+    Video,
+    Email,
+    Shopping,
+}
+
+#[derive(Deserialize)]
+struct Entry {
+    name: String,
+    hosts: Vec<String>,
+    #[serde(default)]
+    aliases: Vec<String>,
+}
+
+/// Known referrers by category
+pub struct ReferrerTable {
+    entries: Vec<(SourceCategory, Entry)>,
+}
+
+impl ReferrerTable {
+    /// The table built into the binary
+    pub fn embedded() -> &'static Self {
+        static TABLE: OnceLock<ReferrerTable> = OnceLock::new();
+        TABLE.get_or_init(|| Self::from_json(EMBEDDED).expect("embedded referrer table is valid"))
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let categories: HashMap<SourceCategory, Vec<Entry>> = serde_json::from_str(text)?;
+        let mut entries: Vec<(SourceCategory, Entry)> = categories
+            .into_iter()
+            .flat_map(|(category, entries)| entries.into_iter().map(move |entry| (category, entry)))
+            .collect();
+        entries.sort_by(|a, b| a.1.name.cmp(&b.1.name));
+        Ok(Self { entries })
+    }
+
+    /// Name and category of a referrer host. The most specific pattern wins, so
+    /// `mail.google.com` is email rather than search.
+    pub fn lookup_host(&self, host: &str) -> Option<(&str, SourceCategory)> {
+        let host = host.trim_start_matches("www.");
+        self.entries
+            .iter()
+            .flat_map(|(category, entry)| {
+                entry
+                    .hosts
+                    .iter()
+                    .map(move |pattern| (pattern, category, entry))
+            })
+            .filter(|(pattern, _, _)| host_matches(host, pattern))
+            .max_by_key(|(pattern, _, _)| pattern.len())
+            .map(|(_, category, entry)| (entry.name.as_str(), *category))
+    }
+
+    /// Category of a source name (`facebook`, `ig`) or host (`facebook.com`)
+    pub fn category(&self, source: &str) -> Option<SourceCategory> {
+        self.entries
+            .iter()
+            .find(|(_, entry)| entry.name == source || entry.aliases.iter().any(|a| a == source))
+            .map(|(category, _)| *category)
+            .or_else(|| self.lookup_host(source).map(|(_, category)| category))
+    }
+}
+
+/// `google.` matches any `google.<tld>` label; other patterns match the host or its subdomains
+fn host_matches(host: &str, pattern: &str) -> bool {
+    if pattern.ends_with('.') {
+        host.starts_with(pattern) || host.contains(&format!(".{}", pattern))
+    } else {
+        host == pattern || host.ends_with(&format!(".{}", pattern))
+    }
+}
+
+/// Where a page view came from
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct Attribution {
+    pub source: String,
+    pub medium: String,
+    pub campaign: Option<String>,
+    pub term: Option<String>,
+    pub content: Option<String>,
+    pub gclid: Option<String>,
+    pub fbclid: Option<String>,
+    pub msclkid: Option<String>,
+    pub category: Option<SourceCategory>,
+}
+
+/// Attribute a page view from its URL and referrer. UTM values are lowercased.
+pub fn attribute(page_location: &str, referrer: Option<&str>) -> Attribution {
+    let table = ReferrerTable::embedded();
+    let page = reqwest::Url::parse(page_location).ok();
+    let param = |key: &str| {
+        page.as_ref()
+            .and_then(|url| url.query_pairs().find(|(k, v)| k == key && !v.is_empty()))
+            .map(|(_, v)| v.into_owned())
+    };
+    let utm = |key: &str| param(key).map(|v| v.to_lowercase());
+
+    let mut attribution = Attribution {
+        campaign: utm("utm_campaign"),
+        term: utm("utm_term"),
+        content: utm("utm_content"),
+        gclid: param("gclid"),
+        fbclid: param("fbclid"),
+        msclkid: param("msclkid"),
+        ..Default::default()
+    };
+
+    let host = |url: &reqwest::Url| {
+        url.host_str()
+            .map(|h| h.trim_start_matches("www.").to_string())
+    };
+    let referrer_host = referrer
+        .and_then(|r| reqwest::Url::parse(r).ok())
+        .and_then(|url| host(&url))
+        .filter(|h| Some(h) != page.as_ref().and_then(host).as_ref());
+
+    let (source, medium) = if let Some(source) = utm("utm_source") {
+        (
+            source,
+            utm("utm_medium").unwrap_or_else(|| NOT_SET.to_string()),
+        )
+    } else if attribution.gclid.is_some() {
+        ("google".to_string(), "cpc".to_string())
+    } else if attribution.msclkid.is_some() {
+        ("bing".to_string(), "cpc".to_string())
+    } else if let Some(referrer_host) = referrer_host {
+        match table.lookup_host(&referrer_host) {
+            Some((name, SourceCategory::Search)) => (name.to_string(), "organic".to_string()),
+            Some((name, _)) => (name.to_string(), "referral".to_string()),
+            None => (referrer_host, "referral".to_string()),
+        }
+    } else if attribution.fbclid.is_some() {
+        // Meta adds fbclid to organic links too; without UTM tags it is not paid
+        ("facebook".to_string(), "referral".to_string())
+    } else {
+        (DIRECT.to_string(), NONE.to_string())
+    };
+    attribution.category = table.category(&source);
+    attribution.source = source;
+    attribution.medium = medium;
+    attribution
+}
+
+fn is_paid(medium: &str) -> bool {
+    medium.contains("cp")
+        || medium == "ppc"
+        || medium == "retargeting"
+        || medium.starts_with("paid")
+}
+
+/// GA4-style default channel group
+pub fn default_channel(attribution: &Attribution) -> &'static str {
+    let Attribution {
+        source,
+        medium,
+        campaign,
+        category,
+        ..
+    } = attribution;
+    let (source, medium) = (source.as_str(), medium.as_str());
+    let campaign = campaign.as_deref().unwrap_or("");
+    let shopping = *category == Some(SourceCategory::Shopping) || campaign.contains("shop");
+    let is = |category: SourceCategory| attribution.category == Some(category);
+    let email = |value: &str| matches!(value, "email" | "e-mail" | "e_mail" | "e mail");
+
+    if source == DIRECT && matches!(medium, NONE | NOT_SET) {
+        "Direct"
+    } else if campaign.contains("cross-network") {
+        "Cross-network"
+    } else if shopping && is_paid(medium) {
+        "Paid Shopping"
+    } else if is(SourceCategory::Search) && is_paid(medium) {
+        "Paid Search"
+    } else if is(SourceCategory::Social) && is_paid(medium) {
+        "Paid Social"
+    } else if is(SourceCategory::Video) && is_paid(medium) {
+        "Paid Video"
+    } else if matches!(
+        medium,
+        "display" | "banner" | "expandable" | "interstitial" | "cpm"
+    ) {
+        "Display"
+    } else if is_paid(medium) {
+        "Paid Other"
+    } else if shopping {
+        "Organic Shopping"
+    } else if is(SourceCategory::Social)
+        || matches!(
+            medium,
+            "social" | "social-network" | "social-media" | "sm" | "social network" | "social media"
+        )
+    {
+        "Organic Social"
+    } else if is(SourceCategory::Video) || medium.contains("video") {
+        "Organic Video"
+    } else if is(SourceCategory::Search) || medium == "organic" {
+        "Organic Search"
+    } else if is(SourceCategory::Email) || email(source) || email(medium) {
+        "Email"
+    } else if medium == "affiliate" {
+        "Affiliates"
+    } else if medium == "referral" {
+        "Referral"
+    } else if medium == "audio" {
+        "Audio"
+    } else if source == "sms" || medium == "sms" {
+        "SMS"
+    } else if medium.ends_with("push")
+        || medium.contains("mobile")
+        || medium.contains("notification")
+    {
+        "Mobile Push Notifications"
+    } else {
+        "Unassigned"
+    }
+}
+
+struct CompiledRule {
+    source: Option<Regex>,
+    medium: Option<Regex>,
+    campaign: Option<Regex>,
+}
+
+impl CompiledRule {
+    fn new(rule: &ChannelRule) -> Result<Self> {
+        let compile = |pattern: &Option<String>| {
+            pattern
+                .as_deref()
+                .map(|p| RegexBuilder::new(p).case_insensitive(true).build())
+                .transpose()
+                .map_err(|e| {
+                    crate::error::Error::Config(format!("invalid channel rule {}: {}", rule.id, e))
+                })
+        };
+        Ok(Self {
+            source: compile(&rule.source)?,
+            medium: compile(&rule.medium)?,
+            campaign: compile(&rule.campaign)?,
+        })
+    }
+
+    fn matches(&self, attribution: &Attribution) -> bool {
+        let check = |regex: &Option<Regex>, value: Option<&str>| {
+            regex
+                .as_ref()
+                .is_none_or(|regex| value.is_some_and(|v| regex.is_match(v)))
+        };
+        check(&self.source, Some(&attribution.source))
+            && check(&self.medium, Some(&attribution.medium))
+            && check(&self.campaign, attribution.campaign.as_deref())
+    }
+}
+
+/// Fills source, medium, campaign, click IDs and channel group of page views
+pub struct TrafficSources {
+    sites: Arc<SiteRegistry>,
+    rules: Mutex<HashMap<Uuid, Arc<CompiledRule>>>,
+}
+
+impl TrafficSources {
+    pub fn new(sites: Arc<SiteRegistry>) -> Self {
+        Self {
+            sites,
+            rules: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Channel group of an attribution for a measurement ID, honouring its site's rules
+    pub fn channel(&self, measurement_id: &str, attribution: &Attribution) -> Result<String> {
+        if let Some(site) = self.sites.site_by_measurement_id(measurement_id) {
+            for rule in self.sites.channel_rules(site.id) {
+                let compiled = self.compiled(&rule)?;
+                if compiled.matches(attribution) {
+                    return Ok(rule.channel);
+                }
+            }
+        }
+        Ok(default_channel(attribution).to_string())
+    }
+
+    fn compiled(&self, rule: &ChannelRule) -> Result<Arc<CompiledRule>> {
+        let mut rules = self.rules.lock().unwrap();
+        if let Some(compiled) = rules.get(&rule.id) {
+            return Ok(compiled.clone());
+        }
+        let compiled = Arc::new(CompiledRule::new(rule)?);
+        rules.insert(rule.id, compiled.clone());
+        Ok(compiled)
+    }
+}
+
+impl Enricher for TrafficSources {
+    fn name(&self) -> &str {
+        "traffic_source"
+    }
+
+    fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+        let Event::PageView {
+            page_location,
+            page_referrer,
+            ..
+        } = &envelope.event
+        else {
+            return Ok(());
+        };
+        let attribution = attribute(page_location, page_referrer.as_deref());
+        let channel = self.channel(&envelope.measurement_id, &attribution)?;
+
+        let params = envelope.event.params_mut();
+        params.source = Some(attribution.source);
+        params.medium = Some(attribution.medium);
+        params.campaign = attribution.campaign;
+        params.term = attribution.term;
+        params.content = attribution.content;
+        params.gclid = attribution.gclid;
+        params.fbclid = attribution.fbclid;
+        params.msclkid = attribution.msclkid;
+        params.channel_group = Some(channel);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sites::{NewChannelRule, NewSite};
+
+    fn channel(page_location: &str, referrer: Option<&str>) -> (String, String, &'static str) {
+        let attribution = attribute(page_location, referrer);
+        let channel = default_channel(&attribution);
+        (attribution.source, attribution.medium, channel)
+    }
+
+    #[test]
+    fn test_default_channels() {
+        let page = "https://shop.com/";
+        let cases = [
+            (channel(page, None), ("(direct)", "(none)", "Direct")),
+            (
+                channel(page, Some("https://www.google.com.br/")),
+                ("google", "organic", "Organic Search"),
+            ),
+            (
+                channel(page, Some("https://mail.google.com/mail/u/0")),
+                ("gmail", "referral", "Email"),
+            ),
+            (
+                channel(page, Some("https://l.facebook.com/")),
+                ("facebook", "referral", "Organic Social"),
+            ),
+            (
+                channel(page, Some("https://youtu.be/x")),
+                ("youtube", "referral", "Organic Video"),
+            ),
+            (
+                channel(page, Some("https://blog.example.org/post")),
+                ("blog.example.org", "referral", "Referral"),
+            ),
+            (
+                channel(page, Some("https://shop.com/cart")),
+                ("(direct)", "(none)", "Direct"),
+            ),
+            (
+                channel("https://shop.com/?gclid=abc", None),
+                ("google", "cpc", "Paid Search"),
+            ),
+            (
+                channel("https://shop.com/?msclkid=abc", None),
+                ("bing", "cpc", "Paid Search"),
+            ),
+            (
+                channel("https://shop.com/?fbclid=abc", None),
+                ("facebook", "referral", "Organic Social"),
+            ),
+            (
+                channel(
+                    "https://shop.com/?utm_source=IG&utm_medium=paid_social",
+                    None,
+                ),
+                ("ig", "paid_social", "Paid Social"),
+            ),
+            (
+                channel("https://shop.com/?utm_source=news&utm_medium=email", None),
+                ("news", "email", "Email"),
+            ),
+            (
+                channel(
+                    "https://shop.com/?utm_source=partner&utm_medium=affiliate",
+                    None,
+                ),
+                ("partner", "affiliate", "Affiliates"),
+            ),
+            (
+                channel("https://shop.com/?utm_source=x1&utm_medium=banner", None),
+                ("x1", "banner", "Display"),
+            ),
+            (
+                channel("https://shop.com/?utm_source=mystery", None),
+                ("mystery", "(not set)", "Unassigned"),
+            ),
+        ];
+        for (actual, (source, medium, channel)) in cases {
+            assert_eq!(
+                (actual.0.as_str(), actual.1.as_str(), actual.2),
+                (source, medium, channel)
+            );
+        }
+
+        let tagged = attribute(
+            "https://shop.com/?utm_source=google&utm_medium=cpc&utm_campaign=Black_Friday&utm_term=tv&gclid=G1",
+            None,
+        );
+        assert_eq!(tagged.campaign.as_deref(), Some("black_friday"));
+        assert_eq!(tagged.term.as_deref(), Some("tv"));
+        assert_eq!(tagged.gclid.as_deref(), Some("G1"));
+        assert_eq!(default_channel(&tagged), "Paid Search");
+    }
+
+    #[test]
+    fn test_site_channel_rules() {
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Shop".to_string(),
+                domain: "shop.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("G-SHOP".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        sites
+            .create_channel_rule(
+                site.id,
+                NewChannelRule {
+                    channel: "Partners".to_string(),
+                    priority: 1,
+                    source: Some("^partner-".to_string()),
+                    medium: None,
+                    campaign: None,
+                },
+            )
+            .unwrap();
+        assert!(sites
+            .create_channel_rule(
+                site.id,
+                NewChannelRule {
+                    channel: "Broken".to_string(),
+                    priority: 0,
+                    source: Some("(".to_string()),
+                    medium: None,
+                    campaign: None,
+                },
+            )
+            .is_err());
+
+        let enricher = TrafficSources::new(sites);
+        let page_view = |measurement_id: &str| {
+            EventEnvelope::new(
+                measurement_id.to_string(),
+                Event::PageView {
+                    page_title: "Home".to_string(),
+                    page_location: "https://shop.com/?utm_source=Partner-Acme&utm_medium=referral"
+                        .to_string(),
+                    page_referrer: None,
+                    user_id: None,
+                    params: Default::default(),
+                },
+            )
+        };
+
+        let mut own = page_view("G-SHOP");
+        enricher.enrich(&mut own).unwrap();
+        let params = own.event.params();
+        assert_eq!(params.source.as_deref(), Some("partner-acme"));
+        assert_eq!(params.channel_group.as_deref(), Some("Partners"));
+
+        let mut other = page_view("G-OTHER");
+        enricher.enrich(&mut other).unwrap();
+        assert_eq!(
+            other.event.params().channel_group.as_deref(),
+            Some("Referral")
+        );
+    }
+}
@@ -0,0 +1,326 @@
+//! User-agent parsing
+//!
+//! Browser, OS and device come from an ordered regex database in the style of uap-core:
+//! the first matching rule of each list wins, and `$1`..`$9` in a rule's templates are
+//! replaced by its capture groups. A database is embedded in the binary and can be
+//! replaced by a file (`enrichment.user_agent_regexes`) without a code change.
+
+use super::Enricher;
+use crate::crypto::key_id;
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use regex::{Captures, Regex};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::Mutex;
+
+const EMBEDDED: &str = include_str!("user_agents.json");
+
+/// What a user-agent string says about the client
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct UserAgent {
+    pub browser: Option<String>,
+    pub browser_version: Option<String>,
+    pub os: Option<String>,
+    pub os_version: Option<String>,
+    /// `desktop`, `mobile`, `tablet`, `smarttv` or `bot`
+    pub device_category: String,
+    pub device_brand: Option<String>,
+    pub device_model: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Database {
+    bots: Vec<Rule>,
+    browsers: Vec<Rule>,
+    os: Vec<Rule>,
+    devices: Vec<DeviceRule>,
+}
+
+#[derive(Deserialize)]
+struct Rule {
+    regex: String,
+    #[serde(default = "first_group")]
+    name: String,
+    #[serde(default = "second_group")]
+    version: String,
+}
+
+#[derive(Deserialize)]
+struct DeviceRule {
+    regex: String,
+    category: String,
+    brand: Option<String>,
+    model: Option<String>,
+}
+
+fn first_group() -> String {
+    "$1".to_string()
+}
+
+fn second_group() -> String {
+    "$2".to_string()
+}
+
+/// Parses user agents with a regex database, caching results by UA string
+pub struct UserAgentParser {
+    bots: Vec<(Regex, Rule)>,
+    browsers: Vec<(Regex, Rule)>,
+    os: Vec<(Regex, Rule)>,
+    devices: Vec<(Regex, DeviceRule)>,
+    cache: Mutex<HashMap<String, UserAgent>>,
+    cache_size: usize,
+}
+
+impl UserAgentParser {
+    /// Parser using the database built into the binary
+    pub fn embedded() -> Self {
+        Self::from_json(EMBEDDED).expect("embedded user-agent database is valid")
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path).map_err(|e| {
+            Error::Config(format!(
+                "cannot read user-agent database {}: {}",
+                path.display(),
+                e
+            ))
+        })?;
+        Self::from_json(&text)
+    }
+
+    pub fn from_json(text: &str) -> Result<Self> {
+        let db: Database = serde_json::from_str(text)?;
+        Ok(Self {
+            bots: compile(db.bots, |r| &r.regex)?,
+            browsers: compile(db.browsers, |r| &r.regex)?,
+            os: compile(db.os, |r| &r.regex)?,
+            devices: compile(db.devices, |r| &r.regex)?,
+            cache: Mutex::new(HashMap::new()),
+            cache_size: 10_000,
+        })
+    }
+
+    /// Distinct UA strings kept; the cache is cleared when it fills up
+    pub fn with_cache_size(mut self, size: usize) -> Self {
+        self.cache_size = size;
+        self
+    }
+
+    pub fn parse(&self, user_agent: &str) -> UserAgent {
+        if let Some(parsed) = self.cache.lock().unwrap().get(user_agent) {
+            return parsed.clone();
+        }
+        let parsed = self.parse_uncached(user_agent);
+
+        let mut cache = self.cache.lock().unwrap();
+        if cache.len() >= self.cache_size {
+            cache.clear();
+        }
+        if self.cache_size > 0 {
+            cache.insert(user_agent.to_string(), parsed.clone());
+        }
+        parsed
+    }
+
+    fn parse_uncached(&self, user_agent: &str) -> UserAgent {
+        let os = first_match(&self.os, user_agent);
+        let os_name = os
+            .as_ref()
+            .and_then(|(rule, caps)| expand(&rule.name, caps));
+        let os_version = os
+            .as_ref()
+            .and_then(|(rule, caps)| expand(&rule.version, caps))
+            .map(|v| v.replace('_', "."));
+
+        if let Some((rule, caps)) = first_match(&self.bots, user_agent) {
+            return UserAgent {
+                browser: expand(&rule.name, &caps),
+                browser_version: expand(&rule.version, &caps),
+                os: os_name,
+                os_version,
+                device_category: "bot".to_string(),
+                device_brand: None,
+                device_model: None,
+            };
+        }
+
+        let browser = first_match(&self.browsers, user_agent);
+        let device = first_match(&self.devices, user_agent);
+        UserAgent {
+            browser: browser
+                .as_ref()
+                .and_then(|(rule, caps)| expand(&rule.name, caps)),
+            browser_version: browser
+                .as_ref()
+                .and_then(|(rule, caps)| expand(&rule.version, caps)),
+            os: os_name,
+            os_version,
+            device_category: device
+                .as_ref()
+                .map_or_else(|| "desktop".to_string(), |(rule, _)| rule.category.clone()),
+            device_brand: device
+                .as_ref()
+                .and_then(|(rule, caps)| rule.brand.as_deref().and_then(|b| expand(b, caps))),
+            device_model: device
+                .as_ref()
+                .and_then(|(rule, caps)| rule.model.as_deref().and_then(|m| expand(m, caps))),
+        }
+    }
+}
+
+impl Enricher for UserAgentParser {
+    fn name(&self) -> &str {
+        "user_agent"
+    }
+
+    fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+        let params = envelope.event.params_mut();
+        // Still encrypted when no keyring is loaded
+        let Some(user_agent) = params
+            .user_agent
+            .as_deref()
+            .filter(|ua| key_id(ua).is_none())
+        else {
+            return Ok(());
+        };
+        let parsed = self.parse(user_agent);
+        params.browser = parsed.browser;
+        params.browser_version = parsed.browser_version;
+        params.os = parsed.os;
+        params.os_version = parsed.os_version;
+        params.device_category = Some(parsed.device_category);
+        params.device_brand = parsed.device_brand;
+        params.device_model = parsed.device_model;
+        Ok(())
+    }
+}
+
+fn compile<R>(rules: Vec<R>, regex: impl Fn(&R) -> &str) -> Result<Vec<(Regex, R)>> {
+    rules
+        .into_iter()
+        .map(|rule| {
+            let compiled = Regex::new(regex(&rule)).map_err(|e| {
+                Error::Config(format!(
+                    "invalid user-agent regex {:?}: {}",
+                    regex(&rule),
+                    e
+                ))
+            })?;
+            Ok((compiled, rule))
+        })
+        .collect()
+}
+
+fn first_match<'a, 'u, R>(
+    rules: &'a [(Regex, R)],
+    user_agent: &'u str,
+) -> Option<(&'a R, Captures<'u>)> {
+    rules
+        .iter()
+        .find_map(|(regex, rule)| regex.captures(user_agent).map(|caps| (rule, caps)))
+}
+
+/// Fill `$1`..`$9` from the captures; `None` when the result is empty
+fn expand(template: &str, caps: &Captures) -> Option<String> {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, chars.peek().and_then(|d| d.to_digit(10))) {
+            ('$', Some(group)) => {
+                chars.next();
+                out.push_str(caps.get(group as usize).map_or("", |m| m.as_str()));
+            }
+            _ => out.push(c),
+        }
+    }
+    let out = out.trim();
+    (!out.is_empty()).then(|| out.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(ua: &str) -> UserAgent {
+        UserAgentParser::embedded().parse(ua)
+    }
+
+    #[test]
+    fn test_common_user_agents() {
+        let chrome = parse(
+            "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 (KHTML, like Gecko) \
+             Chrome/120.0.6099.129 Safari/537.36",
+        );
+        assert_eq!(chrome.browser.as_deref(), Some("Chrome"));
+        assert_eq!(chrome.browser_version.as_deref(), Some("120.0.6099.129"));
+        assert_eq!(
+            (chrome.os.as_deref(), chrome.os_version.as_deref()),
+            (Some("Windows"), Some("10"))
+        );
+        assert_eq!(chrome.device_category, "desktop");
+
+        let iphone = parse(
+            "Mozilla/5.0 (iPhone; CPU iPhone OS 17_1_2 like Mac OS X) AppleWebKit/605.1.15 \
+             (KHTML, like Gecko) Version/17.1.2 Mobile/15E148 Safari/604.1",
+        );
+        assert_eq!(iphone.browser.as_deref(), Some("Safari"));
+        assert_eq!(
+            (iphone.os.as_deref(), iphone.os_version.as_deref()),
+            (Some("iOS"), Some("17.1.2"))
+        );
+        assert_eq!(iphone.device_category, "mobile");
+        assert_eq!(iphone.device_brand.as_deref(), Some("Apple"));
+
+        let galaxy = parse(
+            "Mozilla/5.0 (Linux; Android 13; SM-S918B) AppleWebKit/537.36 (KHTML, like Gecko) \
+             SamsungBrowser/23.0 Chrome/115.0.0.0 Mobile Safari/537.36",
+        );
+        assert_eq!(galaxy.browser.as_deref(), Some("Samsung Internet"));
+        assert_eq!(galaxy.device_category, "mobile");
+        assert_eq!(
+            (
+                galaxy.device_brand.as_deref(),
+                galaxy.device_model.as_deref()
+            ),
+            (Some("Samsung"), Some("SM-S918B"))
+        );
+
+        let tablet = parse("Mozilla/5.0 (Linux; Android 12; Lenovo TB-J606F) AppleWebKit/537.36 Chrome/118.0 Safari/537.36");
+        assert_eq!(tablet.device_category, "tablet");
+
+        let tv = parse("Mozilla/5.0 (SMART-TV; Linux; Tizen 6.0) AppleWebKit/537.36 SamsungBrowser/4.0 TV Safari/537.36");
+        assert_eq!(tv.device_category, "smarttv");
+        assert_eq!(tv.os.as_deref(), Some("Tizen"));
+
+        let bot = parse("Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)");
+        assert_eq!(bot.device_category, "bot");
+        assert_eq!(bot.browser.as_deref(), Some("Googlebot"));
+        assert_eq!(parse("curl/8.4.0").device_category, "bot");
+    }
+
+    #[test]
+    fn test_custom_database_and_cache() {
+        let parser = UserAgentParser::from_json(
+            r#"{"bots": [], "os": [], "devices": [{"regex": "Kiosk/(\\w+)", "category": "tablet", "model": "$1"}],
+                "browsers": [{"regex": "(KioskBrowser)/(\\d+)"}]}"#,
+        )
+        .unwrap()
+        .with_cache_size(1);
+
+        let kiosk = parser.parse("KioskBrowser/7 Kiosk/K100");
+        assert_eq!(kiosk.browser.as_deref(), Some("KioskBrowser"));
+        assert_eq!(kiosk.browser_version.as_deref(), Some("7"));
+        assert_eq!(kiosk.device_model.as_deref(), Some("K100"));
+        assert_eq!(parser.parse("KioskBrowser/7 Kiosk/K100"), kiosk);
+        assert_eq!(parser.parse("Other").device_category, "desktop");
+        assert_eq!(parser.cache.lock().unwrap().len(), 1);
+
+        assert!(UserAgentParser::from_json(
+            r#"{"bots": [{"regex": "("}], "browsers": [], "os": [], "devices": []}"#
+        )
+        .is_err());
+    }
+}
@@ -34,9 +34,18 @@ pub enum Error {
     #[error("Storage error: {0}")]
     Storage(String),
 
+    #[error("Validation error: {0}")]
+    Validation(String),
+
     #[error("Query error: {0}")]
     Query(String),
 
+    #[error("Not found: {0}")]
+    NotFound(String),
+
+    #[error("Conflict: {0}")]
+    Conflict(String),
+
     #[error("IO error: {0}")]
     Io(#[from] std::io::Error),
 
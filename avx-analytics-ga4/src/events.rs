@@ -167,6 +167,125 @@ pub enum Event {
     },
 }
 
+impl Event {
+    /// GA4 event name (`page_view`, `purchase`, …, or a custom event's `name`)
+    pub fn event_name(&self) -> &str {
+        match self {
+            Event::PageView { .. } => "page_view",
+            Event::Custom { name, .. } => name,
+            Event::Click { .. } => "click",
+            Event::FormSubmit { .. } => "form_submit",
+            Event::ViewItem { .. } => "view_item",
+            Event::AddToCart { .. } => "add_to_cart",
+            Event::RemoveFromCart { .. } => "remove_from_cart",
+            Event::BeginCheckout { .. } => "begin_checkout",
+            Event::Purchase { .. } => "purchase",
+            Event::Refund { .. } => "refund",
+            Event::Search { .. } => "search",
+            Event::VideoStart { .. } => "video_start",
+            Event::VideoProgress { .. } => "video_progress",
+            Event::VideoComplete { .. } => "video_complete",
+            Event::FileDownload { .. } => "file_download",
+            Event::Scroll { .. } => "scroll",
+            Event::SessionStart { .. } => "session_start",
+            Event::UserEngagement { .. } => "user_engagement",
+        }
+    }
+
+    /// Monetary value of e-commerce events
+    pub fn value(&self) -> Option<f64> {
+        match self {
+            Event::BeginCheckout { value, .. } | Event::Purchase { value, .. } => Some(*value),
+            Event::ViewItem { value, .. }
+            | Event::AddToCart { value, .. }
+            | Event::RemoveFromCart { value, .. }
+            | Event::Refund { value, .. } => *value,
+            _ => None,
+        }
+    }
+
+    /// ISO 4217 currency of `value`
+    pub fn currency(&self) -> Option<&str> {
+        match self {
+            Event::BeginCheckout { currency, .. } | Event::Purchase { currency, .. } => {
+                Some(currency)
+            }
+            Event::ViewItem { currency, .. }
+            | Event::AddToCart { currency, .. }
+            | Event::RemoveFromCart { currency, .. }
+            | Event::Refund { currency, .. } => currency.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Page URL of page views
+    pub fn page_location(&self) -> Option<&str> {
+        match self {
+            Event::PageView { page_location, .. } => Some(page_location),
+            _ => None,
+        }
+    }
+
+    /// Signed-in user, from a page view's `user_id` or the common parameters
+    pub fn user_id(&self) -> Option<&str> {
+        match self {
+            Event::PageView {
+                user_id: Some(user_id),
+                ..
+            } => Some(user_id),
+            other => other.params().user_id.as_deref(),
+        }
+    }
+
+    /// Common parameters attached to the event
+    pub fn params(&self) -> &EventParams {
+        match self {
+            Event::PageView { params, .. }
+            | Event::Custom { params, .. }
+            | Event::Click { params, .. }
+            | Event::FormSubmit { params, .. }
+            | Event::ViewItem { params, .. }
+            | Event::AddToCart { params, .. }
+            | Event::RemoveFromCart { params, .. }
+            | Event::BeginCheckout { params, .. }
+            | Event::Purchase { params, .. }
+            | Event::Refund { params, .. }
+            | Event::Search { params, .. }
+            | Event::VideoStart { params, .. }
+            | Event::VideoProgress { params, .. }
+            | Event::VideoComplete { params, .. }
+            | Event::FileDownload { params, .. }
+            | Event::Scroll { params, .. }
+            | Event::SessionStart { params, .. }
+            | Event::UserEngagement { params, .. } => params,
+        }
+    }
+
+    /// Mutable access to the common parameters
+    pub fn params_mut(&mut self) -> &mut EventParams {
+        match self {
+            Event::PageView { params, .. }
+            | Event::Custom { params, .. }
+            | Event::Click { params, .. }
+            | Event::FormSubmit { params, .. }
+            | Event::ViewItem { params, .. }
+            | Event::AddToCart { params, .. }
+            | Event::RemoveFromCart { params, .. }
+            | Event::BeginCheckout { params, .. }
+            | Event::Purchase { params, .. }
+            | Event::Refund { params, .. }
+            | Event::Search { params, .. }
+            | Event::VideoStart { params, .. }
+            | Event::VideoProgress { params, .. }
+            | Event::VideoComplete { params, .. }
+            | Event::FileDownload { params, .. }
+            | Event::Scroll { params, .. }
+            | Event::SessionStart { params, .. }
+            | Event::UserEngagement { params, .. } => params,
+        }
+    }
+}
+
 /// Event parameters (common fields)
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct EventParams {
@@ -197,12 +316,75 @@ pub struct EventParams {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_category: Option<String>,
 
+    /// Device manufacturer, e.g. `Samsung`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_brand: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub device_model: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub os: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub os_version: Option<String>,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub browser: Option<String>,
 
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub browser_version: Option<String>,
+
+    /// Visitor country, when known (e.g. from IP geolocation)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub country: Option<String>,
+
+    /// State or province; the UF code (e.g. `SP`) for Brazil
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub city: Option<String>,
+
+    /// Visitor IANA timezone, e.g. `America/Sao_Paulo`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+
+    /// Traffic source: `utm_source`, a known referrer name (e.g. `google`) or `(direct)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source: Option<String>,
+
+    /// Traffic medium: `utm_medium`, `organic`, `referral`, `cpc` or `(none)`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub medium: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub campaign: Option<String>,
+
+    /// `utm_term`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub term: Option<String>,
+
+    /// `utm_content`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub content: Option<String>,
+
+    /// Google Ads click ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gclid: Option<String>,
+
+    /// Meta click ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fbclid: Option<String>,
+
+    /// Microsoft Advertising click ID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub msclkid: Option<String>,
+
+    /// Channel group, e.g. `Organic Search` or a site-defined channel
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub channel_group: Option<String>,
+
     /// Custom dimensions
     #[serde(skip_serializing_if = "Option::is_none")]
     pub custom_dimensions: Option<HashMap<String, String>>,
@@ -303,6 +485,41 @@ mod tests {
         assert!(json.contains("Home"));
     }
 
+    #[test]
+    fn test_event_name_matches_serde_tag() {
+        let params = EventParams::default;
+        let events = vec![
+            Event::Scroll {
+                percent_scrolled: 50,
+                params: params(),
+            },
+            Event::SessionStart { params: params() },
+            Event::Purchase {
+                transaction_id: "T1".to_string(),
+                value: 10.0,
+                currency: "BRL".to_string(),
+                tax: None,
+                shipping: None,
+                items: Vec::new(),
+                coupon: None,
+                params: params(),
+            },
+        ];
+        for event in &events {
+            let json = serde_json::to_value(event).unwrap();
+            assert_eq!(json["event_type"], event.event_name());
+        }
+        assert_eq!(events[2].value(), Some(10.0));
+        assert_eq!(events[2].currency(), Some("BRL"));
+
+        let custom = Event::Custom {
+            name: "signup".to_string(),
+            params: params(),
+        };
+        assert_eq!(custom.event_name(), "signup");
+        assert_eq!(custom.value(), None);
+    }
+
     #[test]
     fn test_event_envelope() {
         let event = Event::Custom {
@@ -0,0 +1,226 @@
+//! Referrer spam and ghost-hit filtering
+//!
+//! The collector drops two kinds of junk before anything is stored: page views referred
+//! by a known spam domain (an embedded list, extended with `filter.spam_domains`), and
+//! "ghost" hits whose page hostname is neither the site's domain nor one of its
+//! `allowed_hostnames`. Subdomains of a listed domain match it. Measurement IDs without
+//! a registered site have no domain to compare, so only the spam check applies to them.
+
+use crate::config::FilterConfig;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventEnvelope};
+use crate::models::Site;
+use crate::sites::SiteRegistry;
+use serde::Serialize;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+const EMBEDDED: &str = include_str!("spam_domains.txt");
+
+/// Why an event was dropped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FilterReason {
+    ReferrerSpam,
+    GhostHit,
+}
+
+impl FilterReason {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FilterReason::ReferrerSpam => "referrer_spam",
+            FilterReason::GhostHit => "ghost_hit",
+        }
+    }
+}
+
+/// Domains of a list file: one per line, `#` comments
+fn parse_domains(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap_or("").trim())
+        .filter(|line| !line.is_empty())
+        .map(normalize)
+}
+
+fn normalize(host: &str) -> String {
+    host.trim()
+        .trim_start_matches("*.")
+        .trim_start_matches("www.")
+        .trim_end_matches('.')
+        .to_lowercase()
+}
+
+/// Whether `host` is `domain` or one of its subdomains
+fn within(host: &str, domain: &str) -> bool {
+    host == domain
+        || host
+            .strip_suffix(domain)
+            .is_some_and(|rest| rest.ends_with('.'))
+}
+
+fn host_of(url: &str) -> Option<String> {
+    reqwest::Url::parse(url).ok()?.host_str().map(normalize)
+}
+
+/// Decides which collected events are spam or ghost hits
+pub struct HitFilter {
+    sites: Arc<SiteRegistry>,
+    spam_domains: HashSet<String>,
+    referrer_spam: bool,
+    ghost_hits: bool,
+}
+
+impl HitFilter {
+    /// Filter with both checks on and the embedded spam list
+    pub fn new(sites: Arc<SiteRegistry>) -> Self {
+        Self {
+            sites,
+            spam_domains: parse_domains(EMBEDDED).collect(),
+            referrer_spam: true,
+            ghost_hits: true,
+        }
+    }
+
+    pub fn from_config(config: &FilterConfig, sites: Arc<SiteRegistry>) -> Result<Self> {
+        let mut filter = Self::new(sites);
+        filter.referrer_spam = config.referrer_spam;
+        filter.ghost_hits = config.ghost_hits;
+        if let Some(path) = &config.spam_domains {
+            let text = std::fs::read_to_string(path).map_err(|e| {
+                Error::Config(format!("cannot read spam domain list {}: {}", path, e))
+            })?;
+            filter = filter.with_spam_domains(parse_domains(&text));
+        }
+        Ok(filter)
+    }
+
+    /// Add domains to the spam list
+    pub fn with_spam_domains(mut self, domains: impl IntoIterator<Item = String>) -> Self {
+        self.spam_domains
+            .extend(domains.into_iter().map(|d| normalize(&d)));
+        self
+    }
+
+    /// Why the event should be dropped, if it should
+    pub fn check(&self, envelope: &EventEnvelope) -> Option<FilterReason> {
+        if self.referrer_spam {
+            if let Event::PageView {
+                page_referrer: Some(referrer),
+                ..
+            } = &envelope.event
+            {
+                if host_of(referrer).is_some_and(|host| self.is_spam(&host)) {
+                    return Some(FilterReason::ReferrerSpam);
+                }
+            }
+        }
+
+        if self.ghost_hits {
+            let host = envelope.event.page_location().and_then(host_of);
+            let site = self.sites.site_by_measurement_id(&envelope.measurement_id);
+            if let (Some(host), Some(site)) = (host, site) {
+                if !allowed(&site, &host) {
+                    return Some(FilterReason::GhostHit);
+                }
+            }
+        }
+        None
+    }
+
+    fn is_spam(&self, host: &str) -> bool {
+        // Check the host and each parent domain
+        let mut domain = host;
+        loop {
+            if self.spam_domains.contains(domain) {
+                return true;
+            }
+            match domain.split_once('.') {
+                Some((_, parent)) if parent.contains('.') => domain = parent,
+                _ => return false,
+            }
+        }
+    }
+}
+
+fn allowed(site: &Site, host: &str) -> bool {
+    std::iter::once(&site.domain)
+        .chain(&site.allowed_hostnames)
+        .map(|domain| normalize(domain))
+        .any(|domain| within(host, &domain))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventParams;
+    use crate::sites::NewSite;
+
+    fn page_view(measurement_id: &str, location: &str, referrer: Option<&str>) -> EventEnvelope {
+        EventEnvelope::new(
+            measurement_id.to_string(),
+            Event::PageView {
+                page_title: "Home".to_string(),
+                page_location: location.to_string(),
+                page_referrer: referrer.map(str::to_string),
+                user_id: None,
+                params: EventParams::default(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_spam_and_ghost_hits() {
+        let sites = Arc::new(SiteRegistry::in_memory());
+        sites
+            .create_site(NewSite {
+                name: "Shop".to_string(),
+                domain: "www.shop.com".to_string(),
+                allowed_hostnames: vec!["shop-checkout.io".to_string()],
+                measurement_id: Some("G-SHOP".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        let filter = HitFilter::new(sites).with_spam_domains(["Spammy.example".to_string()]);
+        let check = |mid: &str, location: &str, referrer: Option<&str>| {
+            filter.check(&page_view(mid, location, referrer))
+        };
+
+        assert_eq!(check("G-SHOP", "https://shop.com/", None), None);
+        assert_eq!(
+            check(
+                "G-SHOP",
+                "https://blog.shop.com/post",
+                Some("https://www.google.com/")
+            ),
+            None
+        );
+        assert_eq!(check("G-SHOP", "https://pay.shop-checkout.io/", None), None);
+        assert_eq!(
+            check("G-SHOP", "https://notshop.com/", None),
+            Some(FilterReason::GhostHit)
+        );
+        assert_eq!(
+            check("G-SHOP", "https://shop.com.evil.net/", None),
+            Some(FilterReason::GhostHit)
+        );
+        assert_eq!(
+            check(
+                "G-SHOP",
+                "https://shop.com/",
+                Some("http://semalt.com/crawler")
+            ),
+            Some(FilterReason::ReferrerSpam)
+        );
+        assert_eq!(
+            check(
+                "G-OTHER",
+                "https://anything.net/",
+                Some("https://xyz.spammy.example/")
+            ),
+            Some(FilterReason::ReferrerSpam)
+        );
+        // Unregistered sites have no domain to check
+        assert_eq!(check("G-OTHER", "https://anything.net/", None), None);
+    }
+}
@@ -0,0 +1,491 @@
+//! Funnel analysis over ordered funnel steps
+
+use crate::error::{Error, Result};
+use crate::events::{Event, EventEnvelope, EventParams};
+use crate::goals::url_matches;
+use crate::models::{Funnel, FunnelStep};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+/// Where journeys may enter the funnel
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FunnelMode {
+    /// Journeys must start at the first step
+    #[default]
+    Closed,
+    /// Journeys may enter at any step
+    Open,
+}
+
+/// How consecutive steps must follow each other
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StepOrdering {
+    /// Other events may happen between steps
+    #[default]
+    Loose,
+    /// The next step must be the very next event
+    Strict,
+}
+
+/// Funnel report request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunnelQuery {
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    #[serde(default)]
+    pub mode: FunnelMode,
+    #[serde(default)]
+    pub ordering: StepOrdering,
+    /// Dimension to break results down by, e.g. `device_category`
+    pub breakdown: Option<String>,
+}
+
+/// Result for a single funnel step
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FunnelStepResult {
+    pub order: u32,
+    pub name: String,
+    pub entrants: u32,
+    pub completions: u32,
+    pub drop_off: u32,
+    pub drop_off_rate: f64,
+    pub median_seconds_to_next: Option<f64>,
+}
+
+/// Funnel report, optionally broken down by a dimension
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FunnelReport {
+    pub funnel_id: Uuid,
+    pub funnel_name: String,
+    pub start_date: NaiveDate,
+    pub end_date: NaiveDate,
+    pub steps: Vec<FunnelStepResult>,
+    pub breakdown_dimension: Option<String>,
+    pub breakdown: BTreeMap<String, Vec<FunnelStepResult>>,
+}
+
+/// Progress of one walk through the funnel: timestamp reached per step
+type Progress = Vec<Option<DateTime<Utc>>>;
+
+/// Walks of one journey, folded in as its events arrive
+struct Journey {
+    /// Breakdown value of the first event
+    segment: Option<String>,
+    /// Walks still able to go on, by the step they last reached; of walks at the same step
+    /// only the best can matter
+    walks: Vec<Option<Progress>>,
+    /// Best walk that can't go on
+    ended: Option<Progress>,
+}
+
+/// Computes funnel reports from raw events, keeping only per-journey progress
+pub struct FunnelAnalyzer<'a> {
+    funnel: &'a Funnel,
+    steps: Vec<&'a FunnelStep>,
+    query: &'a FunnelQuery,
+    journeys: HashMap<String, Journey>,
+}
+
+impl<'a> FunnelAnalyzer<'a> {
+    pub fn new(funnel: &'a Funnel, query: &'a FunnelQuery) -> Result<Self> {
+        if funnel.steps.is_empty() {
+            return Err(Error::Query(format!("Funnel {} has no steps", funnel.id)));
+        }
+        if let Some(dimension) = &query.breakdown {
+            if !BREAKDOWN_DIMENSIONS.contains(&dimension.as_str()) {
+                return Err(Error::Query(format!(
+                    "Unsupported breakdown dimension: {}",
+                    dimension
+                )));
+            }
+        }
+
+        let mut steps: Vec<&FunnelStep> = funnel.steps.iter().collect();
+        steps.sort_by_key(|s| s.order);
+
+        Ok(Self {
+            funnel,
+            steps,
+            query,
+            journeys: HashMap::new(),
+        })
+    }
+
+    /// Analyze events in any order; journeys are grouped by session (falling back to client)
+    pub fn analyze(mut self, mut events: Vec<EventEnvelope>) -> FunnelReport {
+        events.sort_by_key(|e| e.timestamp);
+        for envelope in &events {
+            self.add(envelope);
+        }
+        self.report()
+    }
+
+    /// Fold the next event into its journey; events must arrive in timestamp order
+    pub fn add(&mut self, envelope: &EventEnvelope) {
+        let params = envelope.event.params();
+        let key = params
+            .session_id
+            .clone()
+            .or_else(|| params.client_id.clone())
+            .unwrap_or_else(|| envelope.event_id.to_string());
+        let journey = self.journeys.entry(key).or_insert_with(|| Journey {
+            segment: self
+                .query
+                .breakdown
+                .as_ref()
+                .map(|dimension| dimension_value(params, dimension))
+                .map(|value| value.unwrap_or_else(|| "(not set)".to_string())),
+            walks: vec![None; self.steps.len()],
+            ended: None,
+        });
+
+        // Every walk moves on at most one step per event, so one pass over the journey
+        // finds the walk through the most steps, the earliest on ties
+        let last = self.steps.len() - 1;
+        let mut walks = vec![None; self.steps.len()];
+        for (step, walk) in std::mem::take(&mut journey.walks).into_iter().enumerate() {
+            let Some(mut walk) = walk else {
+                continue;
+            };
+            if step < last && step_matches(self.steps[step + 1], envelope) {
+                walk[step + 1] = Some(envelope.timestamp);
+                keep_best(&mut walks[step + 1], walk);
+            } else if self.query.ordering == StepOrdering::Loose {
+                keep_best(&mut walks[step], walk);
+            } else {
+                keep_best(&mut journey.ended, walk);
+            }
+        }
+        let entry = match self.query.mode {
+            FunnelMode::Closed => step_matches(self.steps[0], envelope).then_some(0),
+            FunnelMode::Open => self.steps.iter().position(|s| step_matches(s, envelope)),
+        };
+        if let Some(step) = entry {
+            let mut walk = vec![None; self.steps.len()];
+            walk[step] = Some(envelope.timestamp);
+            keep_best(&mut walks[step], walk);
+        }
+        journey.walks = walks;
+    }
+
+    /// Report over every journey added so far
+    pub fn report(self) -> FunnelReport {
+        let mut overall = Vec::new();
+        let mut segmented: BTreeMap<String, Vec<Progress>> = BTreeMap::new();
+
+        for journey in self.journeys.values() {
+            let mut best = journey.ended.clone();
+            for walk in journey.walks.iter().flatten() {
+                keep_best(&mut best, walk.clone());
+            }
+            let Some(progress) = best else {
+                continue;
+            };
+
+            if let Some(segment) = &journey.segment {
+                segmented
+                    .entry(segment.clone())
+                    .or_default()
+                    .push(progress.clone());
+            }
+            overall.push(progress);
+        }
+
+        FunnelReport {
+            funnel_id: self.funnel.id,
+            funnel_name: self.funnel.name.clone(),
+            start_date: self.query.start_date,
+            end_date: self.query.end_date,
+            steps: self.summarize(&overall),
+            breakdown_dimension: self.query.breakdown.clone(),
+            breakdown: segmented
+                .into_iter()
+                .map(|(value, progress)| (value, self.summarize(&progress)))
+                .collect(),
+        }
+    }
+
+    fn summarize(&self, journeys: &[Progress]) -> Vec<FunnelStepResult> {
+        self.steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                let entrants = journeys.iter().filter(|p| p[i].is_some()).count() as u32;
+                let is_last = i + 1 == self.steps.len();
+
+                let mut gaps: Vec<f64> = if is_last {
+                    Vec::new()
+                } else {
+                    journeys
+                        .iter()
+                        .filter_map(|p| match (p[i], p[i + 1]) {
+                            (Some(a), Some(b)) => Some((b - a).num_milliseconds() as f64 / 1000.0),
+                            _ => None,
+                        })
+                        .collect()
+                };
+
+                let completions = if is_last { entrants } else { gaps.len() as u32 };
+                let drop_off = entrants - completions;
+
+                FunnelStepResult {
+                    order: step.order,
+                    name: step.name.clone(),
+                    entrants,
+                    completions,
+                    drop_off,
+                    drop_off_rate: if entrants > 0 {
+                        drop_off as f64 / entrants as f64
+                    } else {
+                        0.0
+                    },
+                    median_seconds_to_next: median(&mut gaps),
+                }
+            })
+            .collect()
+    }
+}
+
+/// Keep `walk` in `best` if it reached more steps, or as many but entered earlier
+fn keep_best(best: &mut Option<Progress>, walk: Progress) {
+    let reached = |p: &Progress| p.iter().filter(|at| at.is_some()).count();
+    let entered = |p: &Progress| p.iter().flatten().min().copied();
+    let better = best.as_ref().is_none_or(|best| {
+        (reached(&walk), std::cmp::Reverse(entered(&walk)))
+            > (reached(best), std::cmp::Reverse(entered(best)))
+    });
+    if better {
+        *best = Some(walk);
+    }
+}
+
+/// Dimensions supported for funnel breakdowns
+pub const BREAKDOWN_DIMENSIONS: &[&str] = &[
+    "device_category",
+    "browser",
+    "os",
+    "language",
+    "screen_resolution",
+];
+
+fn dimension_value(params: &EventParams, dimension: &str) -> Option<String> {
+    match dimension {
+        "device_category" => params.device_category.clone(),
+        "browser" => params.browser.clone(),
+        "os" => params.os.clone(),
+        "language" => params.language.clone(),
+        "screen_resolution" => params.screen_resolution.clone(),
+        _ => None,
+    }
+}
+
+fn step_matches(step: &FunnelStep, envelope: &EventEnvelope) -> bool {
+    let name_ok = step
+        .event_name
+        .as_ref()
+        .map(|name| envelope.event.event_name() == name)
+        .unwrap_or(true);
+
+    let url_ok = step
+        .url_pattern
+        .as_ref()
+        .map(|pattern| match &envelope.event {
+            Event::PageView { page_location, .. } => url_matches(pattern, page_location),
+            _ => false,
+        })
+        .unwrap_or(true);
+
+    (step.event_name.is_some() || step.url_pattern.is_some()) && name_ok && url_ok
+}
+
+fn median(values: &mut [f64]) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let mid = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[mid - 1] + values[mid]) / 2.0
+    } else {
+        values[mid]
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn funnel() -> Funnel {
+        let step = |order: u32, name: &str, event: Option<&str>, url: Option<&str>| FunnelStep {
+            order,
+            name: name.to_string(),
+            event_name: event.map(str::to_string),
+            url_pattern: url.map(str::to_string),
+        };
+
+        Funnel {
+            id: Uuid::new_v4(),
+            site_id: Uuid::new_v4(),
+            name: "Checkout".to_string(),
+            steps: vec![
+                step(1, "Cart", None, Some("/cart")),
+                step(2, "Checkout", Some("begin_checkout"), None),
+                step(3, "Purchase", Some("purchase"), None),
+            ],
+            created_at: Utc::now(),
+        }
+    }
+
+    fn query(mode: FunnelMode, ordering: StepOrdering, breakdown: Option<&str>) -> FunnelQuery {
+        FunnelQuery {
+            start_date: NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            end_date: NaiveDate::from_ymd_opt(2024, 1, 31).unwrap(),
+            mode,
+            ordering,
+            breakdown: breakdown.map(str::to_string),
+        }
+    }
+
+    fn params(session: &str, device: &str) -> EventParams {
+        EventParams {
+            session_id: Some(session.to_string()),
+            device_category: Some(device.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn at(event: Event, offset_secs: i64) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new("G-TEST".to_string(), event);
+        envelope.timestamp = Utc::now() + Duration::seconds(offset_secs);
+        envelope
+    }
+
+    fn cart(session: &str, device: &str, t: i64) -> EventEnvelope {
+        at(
+            Event::PageView {
+                page_title: "Cart".to_string(),
+                page_location: "https://shop.com/cart".to_string(),
+                page_referrer: None,
+                user_id: None,
+                params: params(session, device),
+            },
+            t,
+        )
+    }
+
+    fn checkout(session: &str, device: &str, t: i64) -> EventEnvelope {
+        at(
+            Event::BeginCheckout {
+                items: vec![],
+                value: 10.0,
+                currency: "BRL".to_string(),
+                coupon: None,
+                params: params(session, device),
+            },
+            t,
+        )
+    }
+
+    fn scroll(session: &str, device: &str, t: i64) -> EventEnvelope {
+        at(
+            Event::Scroll {
+                percent_scrolled: 50,
+                params: params(session, device),
+            },
+            t,
+        )
+    }
+
+    #[test]
+    fn test_closed_loose_funnel() {
+        let funnel = funnel();
+        let query = query(FunnelMode::Closed, StepOrdering::Loose, None);
+        let analyzer = FunnelAnalyzer::new(&funnel, &query).unwrap();
+
+        let report = analyzer.analyze(vec![
+            cart("s1", "desktop", 0),
+            scroll("s1", "desktop", 5),
+            checkout("s1", "desktop", 10),
+            cart("s2", "mobile", 0),
+            checkout("s3", "mobile", 0),
+        ]);
+
+        assert_eq!(report.steps[0].entrants, 2);
+        assert_eq!(report.steps[0].completions, 1);
+        assert_eq!(report.steps[0].drop_off, 1);
+        assert_eq!(report.steps[0].median_seconds_to_next, Some(10.0));
+        assert_eq!(report.steps[1].entrants, 1);
+        assert_eq!(report.steps[2].entrants, 0);
+    }
+
+    #[test]
+    fn test_open_strict_funnel() {
+        let funnel = funnel();
+        let query = query(FunnelMode::Open, StepOrdering::Strict, None);
+        let analyzer = FunnelAnalyzer::new(&funnel, &query).unwrap();
+
+        let report = analyzer.analyze(vec![
+            cart("s1", "desktop", 0),
+            scroll("s1", "desktop", 5),
+            checkout("s1", "desktop", 10),
+            checkout("s3", "mobile", 0),
+        ]);
+
+        // s1 is interrupted by a scroll, s3 enters directly at checkout
+        assert_eq!(report.steps[0].entrants, 1);
+        assert_eq!(report.steps[0].completions, 0);
+        assert_eq!(report.steps[1].entrants, 1);
+    }
+
+    #[test]
+    fn test_strict_funnel_retries_entry() {
+        let funnel = funnel();
+        let query = query(FunnelMode::Closed, StepOrdering::Strict, None);
+        let analyzer = FunnelAnalyzer::new(&funnel, &query).unwrap();
+
+        let report = analyzer.analyze(vec![
+            cart("s1", "desktop", 0),
+            scroll("s1", "desktop", 5),
+            cart("s1", "desktop", 10),
+            checkout("s1", "desktop", 15),
+        ]);
+
+        // The first cart view is interrupted; the second leads straight to checkout
+        assert_eq!(report.steps[0].entrants, 1);
+        assert_eq!(report.steps[0].completions, 1);
+        assert_eq!(report.steps[0].median_seconds_to_next, Some(5.0));
+        assert_eq!(report.steps[1].entrants, 1);
+    }
+
+    #[test]
+    fn test_breakdown_by_device() {
+        let funnel = funnel();
+        let query = query(
+            FunnelMode::Closed,
+            StepOrdering::Loose,
+            Some("device_category"),
+        );
+        let analyzer = FunnelAnalyzer::new(&funnel, &query).unwrap();
+
+        let report = analyzer.analyze(vec![
+            cart("s1", "desktop", 0),
+            checkout("s1", "desktop", 10),
+            cart("s2", "mobile", 0),
+        ]);
+
+        assert_eq!(report.breakdown["desktop"][1].entrants, 1);
+        assert_eq!(report.breakdown["mobile"][0].drop_off, 1);
+    }
+
+    #[test]
+    fn test_unknown_breakdown_rejected() {
+        let funnel = funnel();
+        let query = query(FunnelMode::Closed, StepOrdering::Loose, Some("planet"));
+        assert!(FunnelAnalyzer::new(&funnel, &query).is_err());
+    }
+}
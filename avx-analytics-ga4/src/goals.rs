@@ -0,0 +1,329 @@
+//! Goal evaluation and conversion detection
+
+use crate::events::{Event, EventEnvelope};
+use crate::models::{Conversion, Goal, GoalType, Site};
+use crate::sites::SiteRegistry;
+use chrono::{DateTime, Duration, Utc};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Sessions idle for longer than this are forgotten by the evaluator
+const SESSION_IDLE_MINUTES: i64 = 30;
+
+/// Per-session progress used by session-level goals
+#[derive(Debug, Clone)]
+struct SessionProgress {
+    started_at: DateTime<Utc>,
+    last_seen: DateTime<Utc>,
+    page_views: u32,
+    converted: HashSet<Uuid>,
+}
+
+/// Evaluates incoming events against the goals of their site
+pub struct GoalEvaluator {
+    sites: Arc<SiteRegistry>,
+    sessions: HashMap<(String, String), SessionProgress>,
+}
+
+impl GoalEvaluator {
+    pub fn new(sites: Arc<SiteRegistry>) -> Self {
+        Self {
+            sites,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Evaluate an event, returning any conversions it completes.
+    ///
+    /// Each goal converts at most once per session.
+    pub fn evaluate(&mut self, envelope: &EventEnvelope) -> Vec<Conversion> {
+        let Some(site) = self.sites.site_by_measurement_id(&envelope.measurement_id) else {
+            return Vec::new();
+        };
+        let goals = self.sites.goals(site.id);
+        if goals.is_empty() {
+            return Vec::new();
+        }
+
+        let params = envelope.event.params();
+        let session_key = params
+            .session_id
+            .clone()
+            .or_else(|| params.client_id.clone())
+            .unwrap_or_else(|| envelope.event_id.to_string());
+
+        let progress = self
+            .sessions
+            .entry((envelope.measurement_id.clone(), session_key))
+            .or_insert_with(|| SessionProgress {
+                started_at: envelope.timestamp,
+                last_seen: envelope.timestamp,
+                page_views: 0,
+                converted: HashSet::new(),
+            });
+
+        progress.last_seen = progress.last_seen.max(envelope.timestamp);
+        if matches!(envelope.event, Event::PageView { .. }) {
+            progress.page_views += 1;
+        }
+
+        let mut conversions = Vec::new();
+        for goal in goals {
+            if progress.converted.contains(&goal.id) || !goal_matches(&goal, envelope, progress) {
+                continue;
+            }
+            progress.converted.insert(goal.id);
+            conversions.push(build_conversion(&goal, &site, envelope));
+        }
+
+        conversions
+    }
+
+    /// Drop session progress that has been idle past the session timeout
+    pub fn evict_idle(&mut self, now: DateTime<Utc>) {
+        let cutoff = now - Duration::minutes(SESSION_IDLE_MINUTES);
+        self.sessions.retain(|_, p| p.last_seen >= cutoff);
+    }
+
+    /// Number of sessions currently tracked
+    pub fn tracked_sessions(&self) -> usize {
+        self.sessions.len()
+    }
+}
+
+fn goal_matches(goal: &Goal, envelope: &EventEnvelope, progress: &SessionProgress) -> bool {
+    match &goal.goal_type {
+        GoalType::Url { url_pattern } => match &envelope.event {
+            Event::PageView { page_location, .. } => url_matches(url_pattern, page_location),
+            _ => false,
+        },
+        GoalType::Event { event_name } => envelope.event.event_name() == event_name,
+        GoalType::Duration { min_seconds } => {
+            (progress.last_seen - progress.started_at).num_seconds() >= *min_seconds as i64
+        }
+        GoalType::PagesPerSession { min_pages } => progress.page_views >= *min_pages,
+    }
+}
+
+fn build_conversion(goal: &Goal, site: &Site, envelope: &EventEnvelope) -> Conversion {
+    let params = envelope.event.params();
+    let (event_value, event_currency) = event_value(&envelope.event);
+
+    // A goal's fixed value, in the site's currency, wins; otherwise an event goal carries
+    // the event's own value
+    let (value, currency) = match (goal.value, &goal.goal_type) {
+        (Some(value), _) => (Some(value), Some(site.currency.clone())),
+        (None, GoalType::Event { .. }) => (event_value, event_currency),
+        (None, _) => (None, None),
+    };
+
+    Conversion {
+        id: Uuid::new_v4(),
+        site_id: goal.site_id,
+        goal_id: goal.id,
+        goal_name: goal.name.clone(),
+        measurement_id: envelope.measurement_id.clone(),
+        event_id: envelope.event_id,
+        client_id: params.client_id.clone(),
+        user_id: params.user_id.clone(),
+        session_id: params.session_id.clone(),
+        value,
+        currency,
+        timestamp: envelope.timestamp,
+    }
+}
+
+fn event_value(event: &Event) -> (Option<f64>, Option<String>) {
+    match event {
+        Event::Purchase {
+            value, currency, ..
+        }
+        | Event::BeginCheckout {
+            value, currency, ..
+        } => (Some(*value), Some(currency.clone())),
+        Event::ViewItem {
+            value, currency, ..
+        }
+        | Event::AddToCart {
+            value, currency, ..
+        }
+        | Event::RemoveFromCart {
+            value, currency, ..
+        }
+        | Event::Refund {
+            value, currency, ..
+        } => (*value, currency.clone()),
+        _ => (None, None),
+    }
+}
+
+/// Match a URL against a goal pattern.
+///
+/// Patterns may use `*` as a wildcard and match either the full URL or its path.
+pub fn url_matches(pattern: &str, url: &str) -> bool {
+    let path = url
+        .find("://")
+        .and_then(|scheme_end| {
+            let rest = &url[scheme_end + 3..];
+            rest.find('/').map(|i| &rest[i..])
+        })
+        .unwrap_or(url);
+
+    wildcard_match(pattern, url) || wildcard_match(pattern, path)
+}
+
+fn wildcard_match(pattern: &str, text: &str) -> bool {
+    let parts: Vec<&str> = pattern.split('*').collect();
+    if parts.len() == 1 {
+        return pattern == text;
+    }
+
+    let mut rest = text;
+    for (i, part) in parts.iter().enumerate() {
+        if i == 0 {
+            match rest.strip_prefix(part) {
+                Some(r) => rest = r,
+                None => return false,
+            }
+        } else if i == parts.len() - 1 {
+            return rest.ends_with(part);
+        } else {
+            match rest.find(part) {
+                Some(idx) => rest = &rest[idx + part.len()..],
+                None => return false,
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventParams;
+    use crate::sites::{NewGoal, NewSite};
+
+    fn registry_with_goal(goal_type: GoalType, value: Option<f64>) -> Arc<SiteRegistry> {
+        let registry = Arc::new(SiteRegistry::in_memory());
+        let site = registry
+            .create_site(NewSite {
+                name: "Shop".to_string(),
+                domain: "shop.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("G-SHOP".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        registry
+            .create_goal(
+                site.id,
+                NewGoal {
+                    name: "goal".to_string(),
+                    goal_type,
+                    value,
+                },
+            )
+            .unwrap();
+        registry
+    }
+
+    fn page_view(url: &str, session: &str) -> EventEnvelope {
+        EventEnvelope::new(
+            "G-SHOP".to_string(),
+            Event::PageView {
+                page_title: "Page".to_string(),
+                page_location: url.to_string(),
+                page_referrer: None,
+                user_id: None,
+                params: EventParams {
+                    session_id: Some(session.to_string()),
+                    ..Default::default()
+                },
+            },
+        )
+    }
+
+    #[test]
+    fn test_url_goal_converts_once_per_session() {
+        let registry = registry_with_goal(
+            GoalType::Url {
+                url_pattern: "/thank-you*".to_string(),
+            },
+            Some(25.0),
+        );
+        let mut evaluator = GoalEvaluator::new(registry);
+
+        let hits = evaluator.evaluate(&page_view("https://shop.com/thank-you?o=1", "s1"));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].value, Some(25.0));
+        assert_eq!(hits[0].currency.as_deref(), Some("USD"));
+
+        assert!(evaluator
+            .evaluate(&page_view("https://shop.com/thank-you", "s1"))
+            .is_empty());
+        assert_eq!(
+            evaluator
+                .evaluate(&page_view("https://shop.com/thank-you", "s2"))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_event_goal_uses_event_value() {
+        let registry = registry_with_goal(
+            GoalType::Event {
+                event_name: "purchase".to_string(),
+            },
+            None,
+        );
+        let mut evaluator = GoalEvaluator::new(registry);
+
+        let purchase = EventEnvelope::new(
+            "G-SHOP".to_string(),
+            Event::Purchase {
+                transaction_id: "T1".to_string(),
+                value: 99.9,
+                currency: "BRL".to_string(),
+                tax: None,
+                shipping: None,
+                items: vec![],
+                coupon: None,
+                params: EventParams::default(),
+            },
+        );
+
+        let hits = evaluator.evaluate(&purchase);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].value, Some(99.9));
+        assert_eq!(hits[0].currency.as_deref(), Some("BRL"));
+    }
+
+    #[test]
+    fn test_pages_per_session_goal() {
+        let registry = registry_with_goal(GoalType::PagesPerSession { min_pages: 3 }, None);
+        let mut evaluator = GoalEvaluator::new(registry);
+
+        assert!(evaluator
+            .evaluate(&page_view("https://shop.com/a", "s1"))
+            .is_empty());
+        assert!(evaluator
+            .evaluate(&page_view("https://shop.com/b", "s1"))
+            .is_empty());
+        assert_eq!(
+            evaluator
+                .evaluate(&page_view("https://shop.com/c", "s1"))
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_url_matches() {
+        assert!(url_matches("/checkout", "https://shop.com/checkout"));
+        assert!(url_matches("*/cart/*", "https://shop.com/cart/items"));
+        assert!(!url_matches("/checkout", "https://shop.com/checkout/done"));
+    }
+}
@@ -0,0 +1,339 @@
+//! Liveness and readiness checks for the server and its dependencies
+
+use crate::config::HealthConfig;
+use crate::storage::{RedisCache, StorageEngine};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicI64, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Shared counters describing the ingestion pipeline
+#[derive(Debug, Default)]
+pub struct PipelineStats {
+    queued: AtomicUsize,
+    dead_letter: AtomicUsize,
+    /// Unix millis of the last processor heartbeat (0 = never)
+    heartbeat_ms: AtomicI64,
+    /// Unix millis since which events have been waiting for a flush (0 = none)
+    pending_since_ms: AtomicI64,
+    flushes: AtomicU64,
+    flush_failures: AtomicU64,
+}
+
+impl PipelineStats {
+    pub fn new() -> Arc<Self> {
+        Arc::new(Self::default())
+    }
+
+    pub(crate) fn event_enqueued(&self) {
+        self.queued.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn event_dequeued(&self) {
+        // Saturating: events sent without stats attached are never counted
+        let _ = self
+            .queued
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1));
+    }
+
+    pub(crate) fn heartbeat(&self) {
+        self.heartbeat_ms
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub(crate) fn mark_pending(&self) {
+        let _ = self.pending_since_ms.compare_exchange(
+            0,
+            Utc::now().timestamp_millis(),
+            Ordering::Relaxed,
+            Ordering::Relaxed,
+        );
+    }
+
+    pub(crate) fn flush_succeeded(&self, dead_letter: usize) {
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.dead_letter.store(dead_letter, Ordering::Relaxed);
+        if dead_letter == 0 {
+            self.pending_since_ms.store(0, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn flush_failed(&self, dead_letter: usize) {
+        self.flush_failures.fetch_add(1, Ordering::Relaxed);
+        self.dead_letter.store(dead_letter, Ordering::Relaxed);
+    }
+
+    /// Events accepted by the collector but not yet picked up by the processor
+    pub fn queue_depth(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// Events whose flush failed and are awaiting retry
+    pub fn dead_letter_backlog(&self) -> usize {
+        self.dead_letter.load(Ordering::Relaxed)
+    }
+
+    /// Time the oldest unflushed event has been waiting
+    pub fn flush_lag(&self) -> Duration {
+        since_millis(self.pending_since_ms.load(Ordering::Relaxed))
+    }
+
+    /// Time since the processor last reported in, `None` if it never started
+    pub fn heartbeat_age(&self) -> Option<Duration> {
+        match self.heartbeat_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => Some(since_millis(ms)),
+        }
+    }
+
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    pub fn flush_failures(&self) -> u64 {
+        self.flush_failures.load(Ordering::Relaxed)
+    }
+}
+
+fn since_millis(ms: i64) -> Duration {
+    if ms == 0 {
+        return Duration::ZERO;
+    }
+    let elapsed = Utc::now().timestamp_millis() - ms;
+    Duration::from_millis(elapsed.max(0) as u64)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Pass,
+    Fail,
+}
+
+/// Outcome of a single readiness check
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub status: CheckStatus,
+    pub detail: String,
+    pub latency_ms: Option<u64>,
+}
+
+impl CheckResult {
+    fn pass(detail: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Pass,
+            detail: detail.into(),
+            latency_ms: None,
+        }
+    }
+
+    fn fail(detail: impl Into<String>) -> Self {
+        Self {
+            status: CheckStatus::Fail,
+            detail: detail.into(),
+            latency_ms: None,
+        }
+    }
+
+    fn timed(mut self, started: Instant) -> Self {
+        self.latency_ms = Some(started.elapsed().as_millis() as u64);
+        self
+    }
+}
+
+/// Readiness breakdown returned by `/health/ready`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReadinessReport {
+    pub ready: bool,
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+/// Runs dependency and pipeline checks
+pub struct HealthChecker {
+    storage: Arc<dyn StorageEngine>,
+    redis: Option<Arc<RedisCache>>,
+    stats: Arc<PipelineStats>,
+    config: HealthConfig,
+    max_buffer_size: usize,
+}
+
+impl HealthChecker {
+    pub fn new(
+        storage: Arc<dyn StorageEngine>,
+        stats: Arc<PipelineStats>,
+        config: HealthConfig,
+        max_buffer_size: usize,
+    ) -> Self {
+        Self {
+            storage,
+            redis: None,
+            stats,
+            config,
+            max_buffer_size,
+        }
+    }
+
+    /// Also check Redis connectivity
+    pub fn with_redis(mut self, redis: Arc<RedisCache>) -> Self {
+        self.redis = Some(redis);
+        self
+    }
+
+    pub async fn readiness(&self) -> ReadinessReport {
+        let timeout = Duration::from_millis(self.config.check_timeout_ms);
+        let mut checks = BTreeMap::new();
+
+        let started = Instant::now();
+        let storage = match tokio::time::timeout(timeout, self.storage.health_check()).await {
+            Ok(Ok(())) => CheckResult::pass("reachable"),
+            Ok(Err(e)) => CheckResult::fail(e.to_string()),
+            Err(_) => CheckResult::fail("timed out"),
+        };
+        checks.insert("storage".to_string(), storage.timed(started));
+
+        if let Some(redis) = &self.redis {
+            let started = Instant::now();
+            let result = match tokio::time::timeout(timeout, redis.ping()).await {
+                Ok(Ok(())) => CheckResult::pass("reachable"),
+                Ok(Err(e)) => CheckResult::fail(e.to_string()),
+                Err(_) => CheckResult::fail("timed out"),
+            };
+            checks.insert("redis".to_string(), result.timed(started));
+        }
+
+        checks.insert("processor".to_string(), self.check_processor());
+        checks.insert("queue".to_string(), self.check_queue());
+        checks.insert("dead_letter".to_string(), self.check_dead_letter());
+        checks.insert("flush_lag".to_string(), self.check_flush_lag());
+
+        ReadinessReport {
+            ready: checks.values().all(|c| c.status == CheckStatus::Pass),
+            checks,
+        }
+    }
+
+    fn check_processor(&self) -> CheckResult {
+        let stall = Duration::from_secs(self.config.processor_stall_secs);
+        match self.stats.heartbeat_age() {
+            None => CheckResult::fail("processor has not started"),
+            Some(age) if age > stall => {
+                CheckResult::fail(format!("no heartbeat for {}s", age.as_secs()))
+            }
+            Some(age) => CheckResult::pass(format!("last heartbeat {}ms ago", age.as_millis())),
+        }
+    }
+
+    fn check_queue(&self) -> CheckResult {
+        let depth = self.stats.queue_depth();
+        let utilization = depth as f64 / self.max_buffer_size.max(1) as f64;
+        let detail = format!(
+            "{} queued ({:.0}% of {})",
+            depth,
+            utilization * 100.0,
+            self.max_buffer_size
+        );
+        if utilization >= self.config.max_queue_utilization {
+            CheckResult::fail(detail)
+        } else {
+            CheckResult::pass(detail)
+        }
+    }
+
+    fn check_dead_letter(&self) -> CheckResult {
+        let backlog = self.stats.dead_letter_backlog();
+        let detail = format!(
+            "{} events awaiting retry (limit {})",
+            backlog, self.config.max_dead_letter_events
+        );
+        if backlog > self.config.max_dead_letter_events {
+            CheckResult::fail(detail)
+        } else {
+            CheckResult::pass(detail)
+        }
+    }
+
+    fn check_flush_lag(&self) -> CheckResult {
+        let lag = self.stats.flush_lag();
+        let detail = format!(
+            "oldest unflushed event {}s old (limit {}s)",
+            lag.as_secs(),
+            self.config.max_flush_lag_secs
+        );
+        if lag > Duration::from_secs(self.config.max_flush_lag_secs) {
+            CheckResult::fail(detail)
+        } else {
+            CheckResult::pass(detail)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryStorage;
+
+    fn checker(stats: Arc<PipelineStats>) -> HealthChecker {
+        HealthChecker::new(
+            Arc::new(MemoryStorage::new()),
+            stats,
+            HealthConfig::default(),
+            100,
+        )
+    }
+
+    #[tokio::test]
+    async fn test_ready_when_pipeline_healthy() {
+        let stats = PipelineStats::new();
+        stats.heartbeat();
+
+        let report = checker(stats).readiness().await;
+        assert!(report.ready);
+        assert!(report.checks.contains_key("storage"));
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_when_storage_fails() {
+        let stats = PipelineStats::new();
+        stats.heartbeat();
+        let storage = Arc::new(MemoryStorage::new());
+        storage.set_failing(true);
+
+        let report = HealthChecker::new(storage, stats, HealthConfig::default(), 100)
+            .readiness()
+            .await;
+        assert!(!report.ready);
+        assert_eq!(report.checks["storage"].status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_before_processor_starts() {
+        let report = checker(PipelineStats::new()).readiness().await;
+        assert!(!report.ready);
+        assert_eq!(report.checks["processor"].status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_on_dead_letter_backlog() {
+        let stats = PipelineStats::new();
+        stats.heartbeat();
+        stats.flush_failed(HealthConfig::default().max_dead_letter_events + 1);
+
+        let report = checker(stats).readiness().await;
+        assert!(!report.ready);
+        assert_eq!(report.checks["dead_letter"].status, CheckStatus::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_not_ready_on_queue_saturation() {
+        let stats = PipelineStats::new();
+        stats.heartbeat();
+        for _ in 0..95 {
+            stats.event_enqueued();
+        }
+
+        let report = checker(stats).readiness().await;
+        assert_eq!(report.checks["queue"].status, CheckStatus::Fail);
+    }
+}
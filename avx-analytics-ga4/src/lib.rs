@@ -14,7 +14,7 @@
 //! ## Quick Start
 //!
 //! ```rust,no_run
-//! use avila_analytics_ga4::prelude::*;
+//! use avx_analytics_ga4::prelude::*;
 //!
 //! #[tokio::main]
 //! async fn main() -> Result<()> {
@@ -23,24 +23,40 @@
 //!     client.track_event(Event::PageView {
 //!         page_title: "Home".into(),
 //!         page_location: "https://example.com".into(),
+//!         page_referrer: None,
 //!         user_id: Some("user123".into()),
+//!         params: EventParams::default(),
 //!     }).await?;
 //!
 //!     Ok(())
 //! }
 //! ```
 
+pub mod backup;
 pub mod client;
 pub mod collector;
 pub mod config;
+pub mod crypto;
+pub mod enrich;
 pub mod error;
 pub mod events;
+pub mod filter;
+pub mod funnel;
+pub mod goals;
+pub mod health;
+pub mod migrations;
 pub mod models;
 pub mod privacy;
 pub mod processor;
 pub mod query;
+pub mod ratelimit;
+pub mod reload;
+pub mod replay;
+pub mod retention;
+pub mod rollup;
 pub mod server;
 pub mod session;
+pub mod sites;
 pub mod storage;
 pub mod user;
 
@@ -49,16 +65,15 @@ pub mod prelude {
     pub use crate::client::AnalyticsClient;
     pub use crate::config::Config;
     pub use crate::error::{Error, Result};
-    pub use crate::events::{Event, EventParams};
+    pub use crate::events::{Event, EventParams, Item};
     pub use crate::models::*;
     pub use crate::server::AnalyticsServer;
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
-
     #[test]
+    #[allow(clippy::assertions_on_constants)]
     fn test_library_loads() {
         // Basic sanity check
         assert!(true);
@@ -0,0 +1,484 @@
+//! Versioned schema migrations
+//!
+//! SQL lives in `migrations/<backend>/NNNN_name.{up,down}.sql` and is embedded at
+//! build time. Applied versions are recorded in `schema_migrations` together with a
+//! checksum of the up script, so edits to an already-applied migration are detected.
+//!
+//! Databases created before migrations existed (by the old `init_schema` or
+//! `init-db.sql`) already have an `events` table but no history; they adopt 0001 as
+//! applied instead of running it. On PostgreSQL an advisory lock serializes migrations
+//! across processes, so nodes starting together don't race.
+
+use crate::error::{Error, Result};
+use crate::storage::{Backend, Database};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use sqlx::pool::PoolConnection;
+use sqlx::{Executor, Postgres};
+use std::collections::BTreeMap;
+
+/// A single schema change with its rollback
+#[derive(Debug, Clone, Copy)]
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub up: &'static str,
+    pub down: &'static str,
+}
+
+impl Migration {
+    /// SHA-256 of the up script, hex encoded
+    pub fn checksum(&self) -> String {
+        Sha256::digest(self.up.as_bytes())
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+}
+
+/// Embed `migrations/<backend>/<file>.{up,down}.sql`
+macro_rules! migration {
+    ($backend:literal, $version:literal, $name:literal, $file:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            up: include_str!(concat!("../migrations/", $backend, "/", $file, ".up.sql")),
+            down: include_str!(concat!("../migrations/", $backend, "/", $file, ".down.sql")),
+        }
+    };
+}
+
+const POSTGRES_MIGRATIONS: &[Migration] = &[
+    migration!("postgres", 1, "initial_schema", "0001_initial_schema"),
+    migration!("postgres", 2, "partition_events", "0002_partition_events"),
+    migration!(
+        "postgres",
+        3,
+        "promote_event_columns",
+        "0003_promote_event_columns"
+    ),
+    migration!("postgres", 4, "daily_rollups", "0004_daily_rollups"),
+    migration!("postgres", 5, "client_first_seen", "0005_client_first_seen"),
+    migration!("postgres", 6, "sealed_user_ids", "0006_sealed_user_ids"),
+];
+
+const SQLITE_MIGRATIONS: &[Migration] = &[
+    migration!("sqlite", 1, "initial_schema", "0001_initial_schema"),
+    migration!("sqlite", 2, "partition_events", "0002_partition_events"),
+    migration!(
+        "sqlite",
+        3,
+        "promote_event_columns",
+        "0003_promote_event_columns"
+    ),
+    migration!("sqlite", 4, "daily_rollups", "0004_daily_rollups"),
+    migration!("sqlite", 5, "client_first_seen", "0005_client_first_seen"),
+    migration!("sqlite", 6, "sealed_user_ids", "0006_sealed_user_ids"),
+];
+
+/// Migrations for a backend, in version order
+pub fn migrations(backend: Backend) -> &'static [Migration] {
+    match backend {
+        Backend::Postgres => POSTGRES_MIGRATIONS,
+        Backend::Sqlite => SQLITE_MIGRATIONS,
+        Backend::Memory | Backend::Segment => &[],
+    }
+}
+
+/// Newest schema version known to this build; both SQL backends share version numbers
+pub fn schema_version() -> i64 {
+    POSTGRES_MIGRATIONS.last().map_or(0, |m| m.version)
+}
+
+/// State of one migration in a database
+#[derive(Debug, Clone, Serialize)]
+pub struct MigrationStatus {
+    pub version: i64,
+    pub name: String,
+    pub applied_at: Option<DateTime<Utc>>,
+    /// False when the applied script differs from the embedded one
+    pub checksum_ok: bool,
+}
+
+/// Applies and reverts migrations against a connected database
+pub struct Migrator {
+    db: Database,
+    migrations: &'static [Migration],
+}
+
+type AppliedRow = (i64, String, String, DateTime<Utc>);
+
+/// Key of the PostgreSQL advisory lock held while migrating
+const MIGRATION_LOCK_KEY: i64 = 0x6176_696c_615f_6d67;
+
+/// Run `$body` with `$pool` bound to the SQL backend's connection pool
+macro_rules! with_pool {
+    ($db:expr, $pool:ident => $body:expr) => {
+        match $db {
+            Database::Postgres(storage) => {
+                let $pool = storage.pool();
+                $body
+            }
+            Database::Sqlite(storage) => {
+                let $pool = storage.pool();
+                $body
+            }
+            Database::Memory(_) | Database::Segment(_) => Default::default(),
+        }
+    };
+}
+
+impl Migrator {
+    pub fn new(db: Database) -> Self {
+        let migrations = migrations(db.backend());
+        Self { db, migrations }
+    }
+
+    /// Every known migration and whether it has been applied
+    pub async fn status(&self) -> Result<Vec<MigrationStatus>> {
+        let applied = self.applied().await?;
+        let mut status: Vec<MigrationStatus> = self
+            .migrations
+            .iter()
+            .map(|m| {
+                let row = applied.get(&m.version);
+                MigrationStatus {
+                    version: m.version,
+                    name: m.name.to_string(),
+                    applied_at: row.map(|(_, _, at)| *at),
+                    checksum_ok: row.is_none_or(|(_, checksum, _)| *checksum == m.checksum()),
+                }
+            })
+            .collect();
+
+        // Versions recorded in the database but unknown to this build
+        for (version, (name, _, at)) in &applied {
+            if !self.migrations.iter().any(|m| m.version == *version) {
+                status.push(MigrationStatus {
+                    version: *version,
+                    name: name.clone(),
+                    applied_at: Some(*at),
+                    checksum_ok: false,
+                });
+            }
+        }
+        status.sort_by_key(|s| s.version);
+        Ok(status)
+    }
+
+    /// Apply pending migrations up to `target` (all when `None`), returning the versions applied
+    pub async fn up(&self, target: Option<i64>) -> Result<Vec<i64>> {
+        let lock = self.lock().await?;
+        let result = self.up_locked(target).await;
+        self.unlock(lock).await?;
+        result
+    }
+
+    async fn up_locked(&self, target: Option<i64>) -> Result<Vec<i64>> {
+        let mut applied = self.applied().await?;
+        if applied.is_empty() && self.adopt_legacy().await? {
+            applied = self.applied().await?;
+        }
+        self.verify(&applied)?;
+
+        let mut done = Vec::new();
+        for migration in self.migrations {
+            if applied.contains_key(&migration.version)
+                || target.is_some_and(|t| migration.version > t)
+            {
+                continue;
+            }
+            self.apply(migration).await?;
+            done.push(migration.version);
+        }
+        Ok(done)
+    }
+
+    /// Revert the latest `steps` applied migrations, returning the versions reverted
+    pub async fn down(&self, steps: usize) -> Result<Vec<i64>> {
+        let lock = self.lock().await?;
+        let result = self.down_locked(steps).await;
+        self.unlock(lock).await?;
+        result
+    }
+
+    async fn down_locked(&self, steps: usize) -> Result<Vec<i64>> {
+        let applied = self.applied().await?;
+        self.verify(&applied)?;
+
+        let mut done = Vec::new();
+        for version in applied.keys().rev().take(steps) {
+            let migration = self
+                .migrations
+                .iter()
+                .find(|m| m.version == *version)
+                .ok_or_else(|| {
+                    Error::Storage(format!(
+                        "migration {} has no down script in this build",
+                        version
+                    ))
+                })?;
+            self.revert(migration).await?;
+            done.push(*version);
+        }
+        Ok(done)
+    }
+
+    /// Wait for the migration lock; it is held by a dedicated connection (PostgreSQL only)
+    async fn lock(&self) -> Result<Option<PoolConnection<Postgres>>> {
+        let Database::Postgres(storage) = &self.db else {
+            return Ok(None);
+        };
+        let mut conn = storage.pool().acquire().await?;
+        sqlx::query("SELECT pg_advisory_lock($1)")
+            .bind(MIGRATION_LOCK_KEY)
+            .execute(&mut *conn)
+            .await?;
+        Ok(Some(conn))
+    }
+
+    async fn unlock(&self, lock: Option<PoolConnection<Postgres>>) -> Result<()> {
+        if let Some(mut conn) = lock {
+            sqlx::query("SELECT pg_advisory_unlock($1)")
+                .bind(MIGRATION_LOCK_KEY)
+                .execute(&mut *conn)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Record 0001 as applied when a database without history already has its schema
+    async fn adopt_legacy(&self) -> Result<bool> {
+        let Some(baseline) = self.migrations.first() else {
+            return Ok(false);
+        };
+        let exists: bool = match &self.db {
+            Database::Postgres(storage) => sqlx::query_scalar("SELECT to_regclass('events') IS NOT NULL")
+                .fetch_one(storage.pool())
+                .await?,
+            Database::Sqlite(storage) => {
+                sqlx::query_scalar("SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'events')")
+                    .fetch_one(storage.pool())
+                    .await?
+            }
+            Database::Memory(_) | Database::Segment(_) => false,
+        };
+        if !exists {
+            return Ok(false);
+        }
+
+        tracing::info!(
+            "Adopting existing schema as migration {} ({})",
+            baseline.version,
+            baseline.name
+        );
+        with_pool!(&self.db, pool => {
+            sqlx::query(
+                "INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(baseline.version)
+            .bind(baseline.name)
+            .bind(baseline.checksum())
+            .bind(Utc::now())
+            .execute(pool)
+            .await?;
+        });
+        Ok(true)
+    }
+
+    /// Refuse to run when an applied migration was edited or is unknown
+    fn verify(&self, applied: &BTreeMap<i64, (String, String, DateTime<Utc>)>) -> Result<()> {
+        for (version, (name, checksum, _)) in applied {
+            match self.migrations.iter().find(|m| m.version == *version) {
+                Some(m) if m.checksum() != *checksum => {
+                    return Err(Error::Storage(format!(
+                        "checksum mismatch for applied migration {} ({}): the script was modified after it ran",
+                        version, name
+                    )))
+                }
+                Some(_) => {}
+                None => {
+                    return Err(Error::Storage(format!(
+                        "database has migration {} ({}) which this build does not know",
+                        version, name
+                    )))
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn ensure_table(&self) -> Result<()> {
+        let ddl = match self.db.backend() {
+            Backend::Postgres => {
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version BIGINT PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    checksum TEXT NOT NULL,
+                    applied_at TIMESTAMPTZ NOT NULL
+                )"
+            }
+            _ => {
+                "CREATE TABLE IF NOT EXISTS schema_migrations (
+                    version INTEGER PRIMARY KEY,
+                    name TEXT NOT NULL,
+                    checksum TEXT NOT NULL,
+                    applied_at TEXT NOT NULL
+                )"
+            }
+        };
+        with_pool!(&self.db, pool => {
+            pool.execute(ddl).await?;
+        });
+        Ok(())
+    }
+
+    async fn applied(&self) -> Result<BTreeMap<i64, (String, String, DateTime<Utc>)>> {
+        self.ensure_table().await?;
+        let rows: Vec<AppliedRow> = with_pool!(&self.db, pool => {
+            sqlx::query_as("SELECT version, name, checksum, applied_at FROM schema_migrations")
+                .fetch_all(pool)
+                .await?
+        });
+        Ok(rows
+            .into_iter()
+            .map(|(version, name, checksum, at)| (version, (name, checksum, at)))
+            .collect())
+    }
+
+    async fn apply(&self, migration: &Migration) -> Result<()> {
+        tracing::info!(
+            "Applying migration {} ({})",
+            migration.version,
+            migration.name
+        );
+        with_pool!(&self.db, pool => {
+            let mut tx = pool.begin().await?;
+            tx.execute(migration.up).await?;
+            sqlx::query(
+                "INSERT INTO schema_migrations (version, name, checksum, applied_at) VALUES ($1, $2, $3, $4)",
+            )
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(migration.checksum())
+            .bind(Utc::now())
+            .execute(&mut *tx)
+            .await?;
+            tx.commit().await?;
+        });
+        Ok(())
+    }
+
+    async fn revert(&self, migration: &Migration) -> Result<()> {
+        tracing::info!(
+            "Reverting migration {} ({})",
+            migration.version,
+            migration.name
+        );
+        with_pool!(&self.db, pool => {
+            let mut tx = pool.begin().await?;
+            tx.execute(migration.down).await?;
+            sqlx::query("DELETE FROM schema_migrations WHERE version = $1")
+                .bind(migration.version)
+                .execute(&mut *tx)
+                .await?;
+            tx.commit().await?;
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::DatabaseConfig;
+
+    async fn sqlite() -> Database {
+        Database::connect(&DatabaseConfig {
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            min_connections: 1,
+            connection_timeout: 5,
+            partitioning: Default::default(),
+            replicas: Vec::new(),
+        })
+        .await
+        .unwrap()
+    }
+
+    #[test]
+    fn test_versions_are_ordered_and_paired() {
+        for backend in [Backend::Postgres, Backend::Sqlite] {
+            let versions: Vec<i64> = migrations(backend).iter().map(|m| m.version).collect();
+            assert!(versions.windows(2).all(|w| w[0] < w[1]));
+            assert!(migrations(backend)
+                .iter()
+                .all(|m| !m.down.trim().is_empty()));
+        }
+        // Every backend shares the same schema history
+        let pg: Vec<_> = POSTGRES_MIGRATIONS
+            .iter()
+            .map(|m| (m.version, m.name))
+            .collect();
+        let sqlite: Vec<_> = SQLITE_MIGRATIONS
+            .iter()
+            .map(|m| (m.version, m.name))
+            .collect();
+        assert_eq!(pg, sqlite);
+    }
+
+    #[tokio::test]
+    async fn test_up_down_and_status() {
+        let migrator = Migrator::new(sqlite().await);
+
+        assert_eq!(migrator.up(None).await.unwrap(), vec![1, 2, 3, 4, 5, 6]);
+        assert!(migrator.up(None).await.unwrap().is_empty());
+        let status = migrator.status().await.unwrap();
+        assert!(status
+            .iter()
+            .all(|s| s.applied_at.is_some() && s.checksum_ok));
+
+        assert_eq!(migrator.down(2).await.unwrap(), vec![6, 5]);
+        assert!(migrator.status().await.unwrap()[4].applied_at.is_none());
+        assert_eq!(migrator.up(None).await.unwrap(), vec![5, 6]);
+    }
+
+    #[tokio::test]
+    async fn test_legacy_schema_is_adopted() {
+        let db = sqlite().await;
+        if let Database::Sqlite(storage) = &db {
+            storage
+                .pool()
+                .execute(SQLITE_MIGRATIONS[0].up)
+                .await
+                .unwrap();
+        }
+        let migrator = Migrator::new(db);
+
+        assert_eq!(migrator.up(None).await.unwrap(), vec![2, 3, 4, 5, 6]);
+        assert!(migrator
+            .status()
+            .await
+            .unwrap()
+            .iter()
+            .all(|s| s.applied_at.is_some()));
+    }
+
+    #[tokio::test]
+    async fn test_checksum_mismatch_is_rejected() {
+        let db = sqlite().await;
+        let migrator = Migrator::new(db.clone());
+        migrator.up(None).await.unwrap();
+
+        if let Database::Sqlite(storage) = &db {
+            sqlx::query("UPDATE schema_migrations SET checksum = 'edited'")
+                .execute(storage.pool())
+                .await
+                .unwrap();
+        }
+
+        assert!(!migrator.status().await.unwrap()[0].checksum_ok);
+        assert!(migrator.up(None).await.is_err());
+    }
+}
@@ -11,6 +11,9 @@ pub struct Site {
     pub measurement_id: String,
     pub name: String,
     pub domain: String,
+    /// Hostnames besides `domain` (and its subdomains) that may send page views
+    #[serde(default)]
+    pub allowed_hostnames: Vec<String>,
     pub timezone: String,
     pub currency: String,
     pub created_at: DateTime<Utc>,
@@ -32,28 +35,33 @@ pub struct User {
 }
 
 /// Session information
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Session {
     pub id: Uuid,
     pub session_id: String,
-    pub user_id: Uuid,
-    pub site_id: Uuid,
+    pub measurement_id: String,
+    pub client_id: String,
+    pub user_id: Option<String>,
+    /// `None` for measurement IDs without a registered site
+    pub site_id: Option<Uuid>,
     pub started_at: DateTime<Utc>,
+    /// Time of the last event
     pub ended_at: Option<DateTime<Utc>>,
     pub duration_seconds: u32,
     pub page_views: u32,
     pub events_count: u32,
     pub is_bounce: bool,
-    pub entry_page: String,
+    pub entry_page: Option<String>,
     pub exit_page: Option<String>,
     pub referrer: Option<String>,
     pub utm_source: Option<String>,
     pub utm_medium: Option<String>,
     pub utm_campaign: Option<String>,
-    pub device_category: String,
-    pub browser: String,
-    pub os: String,
+    pub device_category: Option<String>,
+    pub browser: Option<String>,
+    pub os: Option<String>,
     pub country: Option<String>,
+    pub region: Option<String>,
     pub city: Option<String>,
 }
 
@@ -90,6 +98,23 @@ pub enum GoalType {
     PagesPerSession { min_pages: u32 },
 }
 
+/// Conversion recorded when an event or session satisfies a goal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Conversion {
+    pub id: Uuid,
+    pub site_id: Uuid,
+    pub goal_id: Uuid,
+    pub goal_name: String,
+    pub measurement_id: String,
+    pub event_id: Uuid,
+    pub client_id: Option<String>,
+    pub user_id: Option<String>,
+    pub session_id: Option<String>,
+    pub value: Option<f64>,
+    pub currency: Option<String>,
+    pub timestamp: DateTime<Utc>,
+}
+
 /// Funnel definition
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Funnel {
@@ -108,6 +133,22 @@ pub struct FunnelStep {
     pub url_pattern: Option<String>,
 }
 
+/// Site-defined channel, checked in `priority` order before the default channel groups.
+///
+/// Conditions are case-insensitive regexes; every condition set must match.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChannelRule {
+    pub id: Uuid,
+    pub site_id: Uuid,
+    /// Channel group assigned on a match, e.g. `Partners`
+    pub channel: String,
+    pub priority: u32,
+    pub source: Option<String>,
+    pub medium: Option<String>,
+    pub campaign: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
 /// Real-time metrics snapshot
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RealtimeSnapshot {
@@ -149,6 +190,18 @@ pub struct AggregatedMetrics {
     pub revenue: Option<f64>,
 }
 
+impl AggregatedMetrics {
+    /// Fill conversion fields from the day's conversion count
+    pub fn apply_conversions(&mut self, conversions: u32) {
+        self.conversions = conversions;
+        self.conversion_rate = if self.sessions > 0 {
+            conversions as f64 / self.sessions as f64
+        } else {
+            0.0
+        };
+    }
+}
+
 /// Device breakdown
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct DeviceMetrics {
@@ -193,6 +246,7 @@ mod tests {
             measurement_id: "G-XXXXXXXXXX".to_string(),
             name: "Test Site".to_string(),
             domain: "example.com".to_string(),
+            allowed_hostnames: Vec::new(),
             timezone: "America/Sao_Paulo".to_string(),
             currency: "BRL".to_string(),
             created_at: Utc::now(),
@@ -203,4 +257,27 @@ mod tests {
         assert_eq!(site.name, "Test Site");
         assert!(site.active);
     }
+
+    #[test]
+    fn test_apply_conversions() {
+        let mut metrics = AggregatedMetrics {
+            date: chrono::NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+            site_id: Uuid::new_v4(),
+            users: 80,
+            new_users: 40,
+            sessions: 100,
+            page_views: 300,
+            events: 900,
+            bounce_rate: 0.4,
+            avg_session_duration: 60.0,
+            pages_per_session: 3.0,
+            conversions: 0,
+            conversion_rate: 0.0,
+            revenue: None,
+        };
+
+        metrics.apply_conversions(5);
+        assert_eq!(metrics.conversions, 5);
+        assert!((metrics.conversion_rate - 0.05).abs() < f64::EPSILON);
+    }
 }
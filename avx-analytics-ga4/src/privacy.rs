@@ -1,38 +1,111 @@
 //! Privacy filters and compliance
 
-use crate::config::PrivacyConfig;
+use crate::config::{PrivacyConfig, SitesConfig};
+use crate::crypto::Keyring;
 use crate::error::Result;
 use crate::events::EventEnvelope;
+use crate::storage::EventFilter;
 use std::net::IpAddr;
+use std::sync::{Arc, RwLock};
 
 /// Privacy filter applies privacy transformations to events
 pub struct PrivacyFilter {
-    config: PrivacyConfig,
+    config: RwLock<PrivacyConfig>,
+    sites: RwLock<SitesConfig>,
+    keyring: RwLock<Option<Arc<Keyring>>>,
 }
 
 impl PrivacyFilter {
     pub fn new(config: PrivacyConfig) -> Self {
-        Self { config }
+        Self {
+            config: RwLock::new(config),
+            sites: RwLock::new(SitesConfig::default()),
+            keyring: RwLock::new(None),
+        }
+    }
+
+    /// Encrypt sensitive fields with these keys
+    pub fn with_keyring(self, keyring: Keyring) -> Self {
+        self.set_keyring(Some(keyring));
+        self
+    }
+
+    /// Replace the keyring at runtime; retired keys must stay until data is rotated
+    pub fn set_keyring(&self, keyring: Option<Keyring>) {
+        *self.keyring.write().unwrap() = keyring.map(Arc::new);
+    }
+
+    pub fn keyring(&self) -> Option<Arc<Keyring>> {
+        self.keyring.read().unwrap().clone()
+    }
+
+    /// Apply per-site privacy overrides
+    pub fn with_sites(self, sites: SitesConfig) -> Self {
+        *self.sites.write().unwrap() = sites;
+        self
+    }
+
+    /// Replace the privacy settings at runtime
+    pub fn update(&self, config: PrivacyConfig, sites: SitesConfig) {
+        *self.config.write().unwrap() = config;
+        *self.sites.write().unwrap() = sites;
+    }
+
+    /// Privacy settings in effect for a measurement ID
+    pub fn effective_config(&self, measurement_id: &str) -> PrivacyConfig {
+        let mut config = self.config.read().unwrap().clone();
+        if let Some(site) = self.sites.read().unwrap().override_for(measurement_id) {
+            if let Some(anonymize_ip) = site.anonymize_ip {
+                config.anonymize_ip = anonymize_ip;
+            }
+            if let Some(respect_dnt) = site.respect_dnt {
+                config.respect_dnt = respect_dnt;
+            }
+            if let Some(days) = site.data_retention_days {
+                config.data_retention_days = days;
+            }
+        }
+        config
+    }
+
+    /// Longest retention across the global setting and every site override
+    pub fn longest_retention_days(&self) -> u32 {
+        let global = self.config.read().unwrap().data_retention_days;
+        self.sites
+            .read()
+            .unwrap()
+            .overrides
+            .values()
+            .filter_map(|site| site.data_retention_days)
+            .fold(global, u32::max)
+    }
+
+    /// Days daily rollups of a measurement ID are kept: its override, else `default`
+    pub fn rollup_retention_days(&self, measurement_id: &str, default: u32) -> u32 {
+        self.sites
+            .read()
+            .unwrap()
+            .override_for(measurement_id)
+            .and_then(|site| site.rollup_retention_days)
+            .unwrap_or(default)
     }
 
     /// Apply privacy filters to event
     pub async fn apply(&self, mut envelope: EventEnvelope) -> Result<EventEnvelope> {
+        let config = self.effective_config(&envelope.measurement_id);
+
         // Anonymize IP if enabled
-        if self.config.anonymize_ip {
+        if config.anonymize_ip {
             envelope = self.anonymize_ip(envelope);
         }
 
         // Check Do Not Track
-        if self.config.respect_dnt {
+        if config.respect_dnt {
             envelope = self.check_dnt(envelope);
         }
 
         // Encrypt sensitive data if enabled
-        if self.config.encryption_enabled {
-            envelope = self.encrypt_sensitive_data(envelope).await?;
-        }
-
-        Ok(envelope)
+        self.encrypt(envelope)
     }
 
     /// Anonymize IP address
@@ -41,11 +114,11 @@ impl PrivacyFilter {
 
         // Helper to anonymize IP string
         let anonymize = |ip_str: Option<String>| -> Option<String> {
-            ip_str.and_then(|ip| {
+            ip_str.map(|ip| {
                 if let Ok(addr) = ip.parse::<IpAddr>() {
-                    Some(Self::mask_ip(addr))
+                    Self::mask_ip(addr)
                 } else {
-                    Some(ip)
+                    ip
                 }
             })
         };
@@ -102,13 +175,38 @@ impl PrivacyFilter {
         envelope
     }
 
-    /// Encrypt sensitive data
-    async fn encrypt_sensitive_data(&self, envelope: EventEnvelope) -> Result<EventEnvelope> {
-        // Implementation would use avila-crypto for encryption
-        // For now, just return as-is
+    /// Encrypt the configured fields when encryption is enabled; without a keyring
+    /// they are stored as-is
+    pub fn encrypt(&self, envelope: EventEnvelope) -> Result<EventEnvelope> {
+        let config = self.effective_config(&envelope.measurement_id);
+        let Some(keyring) = self.keyring().filter(|_| config.encryption_enabled) else {
+            return Ok(envelope);
+        };
+        let mut envelope = envelope;
+        keyring.encrypt_event(&mut envelope, &config.encryption)?;
         Ok(envelope)
     }
 
+    /// Decrypt a stored event for a caller allowed to see personal data.
+    ///
+    /// Without a keyring, encrypted fields are returned as ciphertext.
+    pub fn decrypt(&self, mut envelope: EventEnvelope) -> Result<EventEnvelope> {
+        if let Some(keyring) = self.keyring() {
+            keyring.decrypt_event(&mut envelope)?;
+        }
+        Ok(envelope)
+    }
+
+    /// Filter matching the events of `user_id` whether stored in plaintext or sealed
+    /// under any key of the keyring, for lookups and deletion requests
+    pub fn user_filter(&self, user_id: &str) -> Result<EventFilter> {
+        let filter = EventFilter::new().user_id(user_id);
+        let Some(keyring) = self.keyring() else {
+            return Ok(filter);
+        };
+        Ok(filter.user_id_aliases(keyring.lookup_values("user_id", user_id)?))
+    }
+
     /// Hash user ID for privacy
     pub fn hash_user_id(user_id: &str) -> String {
         use blake3::Hasher;
@@ -119,7 +217,7 @@ impl PrivacyFilter {
 
     /// Check if data should be retained based on policy
     pub fn should_retain(&self, days_old: u32) -> bool {
-        days_old < self.config.data_retention_days
+        days_old < self.config.read().unwrap().data_retention_days
     }
 }
 
@@ -138,7 +236,7 @@ mod tests {
     #[test]
     fn test_hash_user_id() {
         let hashed = PrivacyFilter::hash_user_id("user123");
-        assert!(hashed.len() > 0);
+        assert!(!hashed.is_empty());
 
         // Same input produces same hash
         let hashed2 = PrivacyFilter::hash_user_id("user123");
@@ -153,10 +251,116 @@ mod tests {
             data_retention_days: 365,
             cookie_consent_required: true,
             encryption_enabled: true,
+            encryption: Default::default(),
         };
 
         let filter = PrivacyFilter::new(config);
         assert!(filter.should_retain(100));
         assert!(!filter.should_retain(400));
     }
+
+    #[tokio::test]
+    async fn test_encrypts_sensitive_fields() {
+        use crate::events::{Event, EventParams};
+
+        let config = crate::config::Config::default().privacy;
+        let filter = PrivacyFilter::new(config).with_keyring(Keyring::new("k1", &[3; 32]).unwrap());
+        let envelope = EventEnvelope::new(
+            "G-TEST".to_string(),
+            Event::Custom {
+                name: "signup".to_string(),
+                params: EventParams {
+                    user_id: Some("user-1".to_string()),
+                    ip_address: Some("10.1.2.3".to_string()),
+                    user_agent: Some("Mozilla/5.0".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+
+        let stored = filter.apply(envelope).await.unwrap();
+        let params = stored.event.params();
+        assert!(params.user_id.as_deref().unwrap().starts_with("enc:v1:k1:"));
+        assert!(params
+            .ip_address
+            .as_deref()
+            .unwrap()
+            .starts_with("enc:v1:k1:"));
+        assert_eq!(params.user_agent.as_deref(), Some("Mozilla/5.0"));
+
+        let read = filter.decrypt(stored).unwrap();
+        assert_eq!(read.event.params().user_id.as_deref(), Some("user-1"));
+        assert_eq!(read.event.params().ip_address.as_deref(), Some("10.1.2.0"));
+    }
+
+    #[tokio::test]
+    async fn test_deletes_encrypted_user() {
+        use crate::events::{Event, EventParams};
+        use crate::storage::{MemoryStorage, StorageEngine};
+
+        let mut config = crate::config::Config::default().privacy;
+        config.encryption_enabled = true;
+        let filter = PrivacyFilter::new(config).with_keyring(Keyring::new("k1", &[3; 32]).unwrap());
+        let storage = MemoryStorage::new();
+        for user_id in ["user-1", "user-1", "user-2"] {
+            let envelope = EventEnvelope::new(
+                "G-TEST".to_string(),
+                Event::Custom {
+                    name: "signup".to_string(),
+                    params: EventParams {
+                        user_id: Some(user_id.to_string()),
+                        ..Default::default()
+                    },
+                },
+            );
+            storage
+                .store_events(vec![filter.apply(envelope).await.unwrap()])
+                .await
+                .unwrap();
+        }
+
+        let user = filter.user_filter("user-1").unwrap();
+        assert_eq!(storage.delete_events(&user).await.unwrap(), 2);
+        assert_eq!(
+            storage
+                .delete_events(&filter.user_filter("user-2").unwrap())
+                .await
+                .unwrap(),
+            1
+        );
+    }
+
+    #[test]
+    fn test_site_override_and_update() {
+        use crate::config::{Config, SiteOverride};
+
+        let config = Config::default();
+        let mut sites = SitesConfig::default();
+        sites.overrides.insert(
+            "G-EU".to_string(),
+            SiteOverride {
+                data_retention_days: Some(30),
+                ..Default::default()
+            },
+        );
+
+        sites.overrides.insert(
+            "G-ARCHIVE".to_string(),
+            SiteOverride {
+                data_retention_days: Some(730),
+                ..Default::default()
+            },
+        );
+
+        let filter = PrivacyFilter::new(config.privacy.clone()).with_sites(sites);
+        assert_eq!(filter.effective_config("G-EU").data_retention_days, 30);
+        assert_eq!(filter.effective_config("G-US").data_retention_days, 365);
+        assert_eq!(filter.longest_retention_days(), 730);
+
+        let mut privacy = config.privacy;
+        privacy.anonymize_ip = false;
+        filter.update(privacy, SitesConfig::default());
+        assert!(!filter.effective_config("G-EU").anonymize_ip);
+        assert_eq!(filter.effective_config("G-EU").data_retention_days, 365);
+    }
 }
@@ -1,11 +1,18 @@
 //! Event processor - Transforms and enriches events
 
+use crate::enrich::Enrichment;
 use crate::error::Result;
 use crate::events::EventEnvelope;
-use crate::storage::StorageEngine;
+use crate::goals::GoalEvaluator;
+use crate::health::PipelineStats;
+use crate::models::{Conversion, Session};
+use crate::rollup::RollupJob;
+use crate::session::Sessionizer;
+use crate::storage::{RedisCache, StorageEngine};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 /// Event processor handles event transformation and storage
 pub struct EventProcessor {
@@ -13,6 +20,17 @@ pub struct EventProcessor {
     storage: Arc<dyn StorageEngine>,
     batch_size: usize,
     buffer: Vec<EventEnvelope>,
+    enrichment: Enrichment,
+    goals: Option<GoalEvaluator>,
+    conversions: Vec<Conversion>,
+    sessionizer: Option<Arc<Sessionizer>>,
+    sessions: Vec<Session>,
+    flush_interval: Duration,
+    dead_letter: Vec<EventEnvelope>,
+    dead_letter_limit: usize,
+    stats: Arc<PipelineStats>,
+    realtime: Option<Arc<RedisCache>>,
+    rollups: Option<Arc<RollupJob>>,
 }
 
 impl EventProcessor {
@@ -26,29 +44,104 @@ impl EventProcessor {
             storage,
             batch_size,
             buffer: Vec::with_capacity(batch_size),
+            enrichment: Enrichment::new(),
+            goals: None,
+            conversions: Vec::new(),
+            sessionizer: None,
+            sessions: Vec::new(),
+            flush_interval: Duration::from_secs(5),
+            dead_letter: Vec::new(),
+            dead_letter_limit: 100_000,
+            stats: PipelineStats::new(),
+            realtime: None,
+            rollups: None,
         }
     }
 
+    /// Enrich every event before it is stored
+    pub fn with_enrichment(mut self, enrichment: Enrichment) -> Self {
+        self.enrichment = enrichment;
+        self
+    }
+
+    /// Evaluate site goals against every processed event
+    pub fn with_goals(mut self, evaluator: GoalEvaluator) -> Self {
+        self.goals = Some(evaluator);
+        self
+    }
+
+    /// Group events into sessions, storing each session once it closes
+    pub fn with_sessions(mut self, sessionizer: Arc<Sessionizer>) -> Self {
+        self.sessionizer = Some(sessionizer);
+        self
+    }
+
+    /// Report queue, flush and heartbeat state to shared stats
+    pub fn with_stats(mut self, stats: Arc<PipelineStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// Flush partially filled batches at least this often
+    pub fn with_flush_interval(mut self, interval: Duration) -> Self {
+        self.flush_interval = interval;
+        self
+    }
+
+    /// Record realtime activity in Redis as events are flushed
+    pub fn with_realtime(mut self, cache: Arc<RedisCache>) -> Self {
+        self.realtime = Some(cache);
+        self
+    }
+
+    /// Mark days outside the rollup lookback window for recomputation when late events land
+    pub fn with_rollups(mut self, job: Arc<RollupJob>) -> Self {
+        self.rollups = Some(job);
+        self
+    }
+
+    /// Maximum events kept for retry after failed flushes
+    pub fn with_dead_letter_limit(mut self, limit: usize) -> Self {
+        self.dead_letter_limit = limit;
+        self
+    }
+
     /// Start processing events
     pub async fn run(mut self) -> Result<()> {
         info!("Event processor started");
 
-        while let Some(envelope) = self.receiver.recv().await {
-            match self.process_event(envelope).await {
-                Ok(_) => debug!("Event processed successfully"),
-                Err(e) => error!("Failed to process event: {}", e),
-            }
+        let mut ticker = tokio::time::interval(self.flush_interval);
+        ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
+        self.stats.heartbeat();
 
-            // Flush if buffer is full
-            if self.buffer.len() >= self.batch_size {
-                self.flush().await?;
+        loop {
+            tokio::select! {
+                received = self.receiver.recv() => {
+                    let Some(envelope) = received else { break };
+                    self.stats.event_dequeued();
+
+                    match self.process_event(envelope).await {
+                        Ok(_) => debug!("Event processed successfully"),
+                        Err(e) => error!("Failed to process event: {}", e),
+                    }
+
+                    // Flush if buffer is full
+                    if self.buffer.len() >= self.batch_size {
+                        self.flush_logged().await;
+                    }
+                }
+                _ = ticker.tick() => {
+                    self.stats.heartbeat();
+                    self.flush_logged().await;
+                }
             }
         }
 
         // Flush remaining events
-        if !self.buffer.is_empty() {
-            self.flush().await?;
+        if let Some(sessionizer) = &self.sessionizer {
+            self.sessions.extend(sessionizer.drain());
         }
+        self.flush_logged().await;
 
         info!("Event processor stopped");
         Ok(())
@@ -59,36 +152,170 @@ impl EventProcessor {
         // Enrich event with additional data
         envelope = self.enrich_event(envelope).await?;
 
+        // Assign the server session
+        if let Some(sessionizer) = &self.sessionizer {
+            match sessionizer.track(&mut envelope).await {
+                Ok(closed) => self.sessions.extend(closed),
+                Err(e) => warn!("Failed to sessionize event: {}", e),
+            }
+        }
+
+        // Detect goal conversions
+        if let Some(goals) = self.goals.as_mut() {
+            self.conversions.extend(goals.evaluate(&envelope));
+        }
+
         // Add to buffer
         self.buffer.push(envelope);
+        self.stats.mark_pending();
 
         Ok(())
     }
 
     /// Enrich event with additional data
     async fn enrich_event(&self, envelope: EventEnvelope) -> Result<EventEnvelope> {
-        // TODO: Add enrichment logic
-        // - Geo-location lookup
-        // - User agent parsing
-        // - Session tracking
-        // - User identification
-        Ok(envelope)
+        self.enrichment.apply(envelope)
+    }
+
+    async fn flush_logged(&mut self) {
+        if let Err(e) = self.flush().await {
+            error!(
+                "Flush failed, {} events held for retry: {}",
+                self.dead_letter.len(),
+                e
+            );
+        }
+        if let Err(e) = self.flush_sessions().await {
+            error!(
+                "Session flush failed, {} sessions held for retry: {}",
+                self.sessions.len(),
+                e
+            );
+        }
+    }
+
+    /// Store closed sessions, closing those idle past the timeout first.
+    ///
+    /// When a write fails while storage is healthy, the batch is split to find the sessions
+    /// storage rejects; those are dropped so they can't hold up the rest. Sessions from a
+    /// write that failed because storage is unavailable are kept and retried on the next
+    /// flush.
+    async fn flush_sessions(&mut self) -> Result<()> {
+        if let Some(sessionizer) = &self.sessionizer {
+            self.sessions
+                .extend(sessionizer.close_expired(chrono::Utc::now()).await?);
+        }
+        if self.sessions.is_empty() {
+            return Ok(());
+        }
+        let sessions = std::mem::take(&mut self.sessions);
+        info!("Flushing {} sessions to storage", sessions.len());
+        let Err(e) = self.storage.store_sessions(sessions.clone()).await else {
+            return Ok(());
+        };
+        if self.storage.health_check().await.is_ok() {
+            self.sessions = self.store_sessions_split(sessions).await;
+        } else {
+            self.sessions = sessions;
+        }
+        if self.sessions.is_empty() {
+            return Ok(());
+        }
+        if self.sessions.len() > self.dead_letter_limit {
+            let excess = self.sessions.len() - self.dead_letter_limit;
+            self.sessions.drain(..excess);
+            warn!(
+                "Session retry buffer full, dropped {} oldest sessions",
+                excess
+            );
+        }
+        Err(e)
+    }
+
+    /// Store `sessions` in ever smaller batches after a failed write, dropping the sessions
+    /// storage rejects on their own; returns the sessions to retry if storage went down
+    async fn store_sessions_split(&self, mut sessions: Vec<Session>) -> Vec<Session> {
+        let second = sessions.split_off(sessions.len() / 2);
+        let mut pending = vec![second, sessions];
+        while let Some(mut batch) = pending.pop() {
+            if batch.is_empty() {
+                continue;
+            }
+            let Err(e) = self.storage.store_sessions(batch.clone()).await else {
+                continue;
+            };
+            if batch.len() > 1 {
+                let second = batch.split_off(batch.len() / 2);
+                pending.extend([second, batch]);
+            } else if self.storage.health_check().await.is_err() {
+                pending.push(batch);
+                return pending.into_iter().flatten().collect();
+            } else {
+                error!(
+                    "Dropped session {} of {} rejected by storage: {}",
+                    batch[0].session_id, batch[0].measurement_id, e
+                );
+            }
+        }
+        Vec::new()
     }
 
-    /// Flush buffered events to storage
+    /// Flush buffered events to storage.
+    ///
+    /// Events from a failed flush move to the dead-letter buffer and are retried first
+    /// on the next flush.
     async fn flush(&mut self) -> Result<()> {
-        if self.buffer.is_empty() {
+        if self.buffer.is_empty() && self.dead_letter.is_empty() {
             return Ok(());
         }
 
-        let events = std::mem::replace(
-            &mut self.buffer,
-            Vec::with_capacity(self.batch_size),
-        );
+        // Only new events count towards realtime activity; dead-letter retries already did.
+        // Recording runs in the background so a slow Redis never holds up storage.
+        if let Some(realtime) = &self.realtime {
+            if !self.buffer.is_empty() {
+                let realtime = realtime.clone();
+                let events = self.buffer.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = realtime.record_activity(&events).await {
+                        warn!("Failed to record realtime activity: {}", e);
+                    }
+                });
+            }
+        }
+
+        let mut events = std::mem::take(&mut self.dead_letter);
+        events.append(&mut self.buffer);
 
         info!("Flushing {} events to storage", events.len());
-        self.storage.store_events(events).await?;
+        if let Err(e) = self.storage.store_events(events.clone()).await {
+            self.dead_letter = events;
+            if self.dead_letter.len() > self.dead_letter_limit {
+                let excess = self.dead_letter.len() - self.dead_letter_limit;
+                self.dead_letter.drain(..excess);
+                warn!("Dead-letter buffer full, dropped {} oldest events", excess);
+            }
+            self.stats.flush_failed(self.dead_letter.len());
+            return Err(e);
+        }
+        if let Some(rollups) = &self.rollups {
+            rollups.note_events(&events);
+        }
+
+        if !self.conversions.is_empty() {
+            let conversions = std::mem::take(&mut self.conversions);
+            info!("Flushing {} conversions to storage", conversions.len());
+            if let Err(e) = self.storage.store_conversions(conversions.clone()).await {
+                self.conversions = conversions;
+                self.stats.flush_failed(0);
+                return Err(e);
+            }
+        }
 
+        if let Some(goals) = self.goals.as_mut() {
+            goals.evict_idle(chrono::Utc::now());
+        }
+
+        self.stats.flush_succeeded(0);
         Ok(())
     }
 }
@@ -114,25 +341,13 @@ pub async fn flush_task(
 mod tests {
     use super::*;
     use crate::events::{Event, EventParams};
-    use async_trait::async_trait;
-
-    struct MockStorage;
-
-    #[async_trait]
-    impl StorageEngine for MockStorage {
-        async fn store_events(&self, _events: Vec<EventEnvelope>) -> Result<()> {
-            Ok(())
-        }
-
-        async fn get_event(&self, _id: uuid::Uuid) -> Result<Option<EventEnvelope>> {
-            Ok(None)
-        }
-    }
+    use crate::sites::SiteRegistry;
+    use crate::storage::MemoryStorage;
 
     #[tokio::test]
     async fn test_event_processing() {
-        let (tx, rx) = mpsc::unbounded_channel();
-        let storage = Arc::new(MockStorage);
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let storage = Arc::new(MemoryStorage::new());
         let mut processor = EventProcessor::new(rx, storage, 10);
 
         let event = Event::Custom {
@@ -144,4 +359,134 @@ mod tests {
         processor.process_event(envelope).await.unwrap();
         assert_eq!(processor.buffer.len(), 1);
     }
+
+    #[tokio::test]
+    async fn test_goal_conversions_buffered() {
+        use crate::models::GoalType;
+        use crate::sites::{NewGoal, NewSite, SiteRegistry};
+
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Test".to_string(),
+                domain: "test.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("TEST".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        sites
+            .create_goal(
+                site.id,
+                NewGoal {
+                    name: "Test event".to_string(),
+                    goal_type: GoalType::Event {
+                        event_name: "test".to_string(),
+                    },
+                    value: Some(1.0),
+                },
+            )
+            .unwrap();
+
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let storage = Arc::new(MemoryStorage::new());
+        let mut processor =
+            EventProcessor::new(rx, storage.clone(), 10).with_goals(GoalEvaluator::new(sites));
+
+        let event = Event::Custom {
+            name: "test".to_string(),
+            params: EventParams::default(),
+        };
+        processor
+            .process_event(EventEnvelope::new("TEST".to_string(), event))
+            .await
+            .unwrap();
+
+        assert_eq!(processor.conversions.len(), 1);
+        processor.flush().await.unwrap();
+        assert!(processor.conversions.is_empty());
+        assert_eq!(storage.len(), 1);
+        let now = chrono::Utc::now();
+        let window = (
+            now - chrono::Duration::hours(1),
+            now + chrono::Duration::hours(1),
+        );
+        let stored = storage
+            .conversions(site.id, window.0, window.1)
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_flush_goes_to_dead_letter() {
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let stats = PipelineStats::new();
+        let storage = Arc::new(MemoryStorage::new());
+        storage.set_failing(true);
+        let mut processor = EventProcessor::new(rx, storage, 10)
+            .with_stats(stats.clone())
+            .with_dead_letter_limit(2);
+
+        for _ in 0..3 {
+            let event = Event::Custom {
+                name: "test".to_string(),
+                params: EventParams::default(),
+            };
+            processor
+                .process_event(EventEnvelope::new("TEST".to_string(), event))
+                .await
+                .unwrap();
+        }
+
+        assert!(processor.flush().await.is_err());
+        assert!(processor.buffer.is_empty());
+        assert_eq!(processor.dead_letter.len(), 2);
+        assert_eq!(stats.dead_letter_backlog(), 2);
+        assert_eq!(stats.flush_failures(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_sessions_do_not_block_the_rest() {
+        let (_tx, rx) = mpsc::unbounded_channel();
+        let storage = Arc::new(MemoryStorage::new());
+        let mut processor = EventProcessor::new(rx, storage.clone(), 10);
+        let sessionizer =
+            Sessionizer::new(Arc::new(SiteRegistry::in_memory()), &Default::default());
+        for client in 0..5 {
+            let mut envelope = EventEnvelope::new(
+                "TEST".to_string(),
+                Event::Custom {
+                    name: "test".to_string(),
+                    params: EventParams {
+                        client_id: Some(format!("c{}", client)),
+                        ..Default::default()
+                    },
+                },
+            );
+            sessionizer.track(&mut envelope).await.unwrap();
+        }
+        processor.sessions = sessionizer.drain();
+        let all: Vec<String> = processor
+            .sessions
+            .iter()
+            .map(|s| s.session_id.clone())
+            .collect();
+
+        storage.set_failing(true);
+        assert!(processor.flush_sessions().await.is_err());
+        assert_eq!(processor.sessions.len(), 5);
+
+        storage.set_failing(false);
+        storage.reject_session(&all[3]);
+        processor.flush_sessions().await.unwrap();
+        assert!(processor.sessions.is_empty());
+        let stored = storage
+            .sessions("TEST", chrono::DateTime::UNIX_EPOCH, chrono::Utc::now())
+            .await
+            .unwrap();
+        assert_eq!(stored.len(), 4);
+        assert!(stored.iter().all(|s| s.session_id != all[3]));
+    }
 }
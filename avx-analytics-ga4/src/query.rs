@@ -1,9 +1,20 @@
 //! Query engine for analytics data
+//!
+//! Reports never decrypt: they read event names, pages, session and client IDs and device
+//! dimensions, none of which are sealed, so personal data stays ciphertext for every caller.
 
-use crate::error::Result;
+use crate::error::{Error, Result};
+use crate::funnel::{FunnelAnalyzer, FunnelQuery, FunnelReport};
 use crate::models::*;
-use chrono::NaiveDate;
+use crate::rollup::{load_rollups, local_midnight, site_timezone, DailyRollup};
+use crate::sites::SiteRegistry;
+use crate::storage::{Database, EventFilter};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use futures::TryStreamExt;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
 use uuid::Uuid;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -91,12 +102,13 @@ impl Default for QueryBuilder {
 }
 
 pub struct QueryEngine {
-    pool: sqlx::PgPool,
+    db: Database,
+    sites: Arc<SiteRegistry>,
 }
 
 impl QueryEngine {
-    pub fn new(pool: sqlx::PgPool) -> Self {
-        Self { pool }
+    pub fn new(db: Database, sites: Arc<SiteRegistry>) -> Self {
+        Self { db, sites }
     }
 
     pub async fn execute(&self, _query: QueryBuilder) -> Result<Vec<serde_json::Value>> {
@@ -104,17 +116,170 @@ impl QueryEngine {
         Ok(Vec::new())
     }
 
+    /// Daily metrics from the stored rollups, in the site's timezone
     pub async fn get_aggregated_metrics(
         &self,
-        _site_id: Uuid,
-        _start_date: NaiveDate,
-        _end_date: NaiveDate,
+        site_id: Uuid,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
     ) -> Result<Vec<AggregatedMetrics>> {
-        // TODO: Implement aggregated metrics query
-        Ok(Vec::new())
+        Ok(self
+            .rollups(site_id, start_date, end_date)
+            .await?
+            .into_iter()
+            .map(|rollup| rollup.metrics)
+            .collect())
+    }
+
+    /// Device breakdown per day
+    pub async fn device_metrics(
+        &self,
+        site_id: Uuid,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+    ) -> Result<Vec<DeviceMetrics>> {
+        Ok(self
+            .rollups(site_id, start_date, end_date)
+            .await?
+            .into_iter()
+            .flat_map(|rollup| rollup.devices)
+            .collect())
+    }
+
+    /// Traffic sources over the whole range, most sessions first.
+    ///
+    /// Users are summed over days, so a user returning on several days counts once per day.
+    pub async fn traffic_sources(
+        &self,
+        site_id: Uuid,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+    ) -> Result<Vec<TrafficSource>> {
+        let mut totals: BTreeMap<(String, String, Option<String>), TrafficSource> = BTreeMap::new();
+        for source in self
+            .rollups(site_id, start_date, end_date)
+            .await?
+            .into_iter()
+            .flat_map(|rollup| rollup.traffic_sources)
+        {
+            let key = (
+                source.source.clone(),
+                source.medium.clone(),
+                source.campaign.clone(),
+            );
+            match totals.get_mut(&key) {
+                Some(total) => {
+                    total.users += source.users;
+                    total.sessions += source.sessions;
+                    total.conversions += source.conversions;
+                    total.revenue = sum_revenue(total.revenue, source.revenue);
+                }
+                None => {
+                    totals.insert(key, source);
+                }
+            }
+        }
+        let mut sources: Vec<TrafficSource> = totals.into_values().collect();
+        sources.sort_by_key(|s| std::cmp::Reverse(s.sessions));
+        Ok(sources)
+    }
+
+    /// Countries and cities over the whole range, most sessions first.
+    ///
+    /// Users are summed over days, like [`QueryEngine::traffic_sources`].
+    pub async fn geo(
+        &self,
+        site_id: Uuid,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+    ) -> Result<Vec<GeoData>> {
+        let mut totals: BTreeMap<(String, Option<String>), GeoData> = BTreeMap::new();
+        for geo in self
+            .rollups(site_id, start_date, end_date)
+            .await?
+            .into_iter()
+            .flat_map(|rollup| rollup.geo)
+        {
+            let key = (geo.country.clone(), geo.city.clone());
+            match totals.get_mut(&key) {
+                Some(total) => {
+                    total.users += geo.users;
+                    total.sessions += geo.sessions;
+                    total.revenue = sum_revenue(total.revenue, geo.revenue);
+                }
+                None => {
+                    totals.insert(key, geo);
+                }
+            }
+        }
+        let mut geo: Vec<GeoData> = totals.into_values().collect();
+        geo.sort_by_key(|g| std::cmp::Reverse(g.sessions));
+        Ok(geo)
+    }
+
+    async fn rollups(
+        &self,
+        site_id: Uuid,
+        start_date: NaiveDate,
+        end_date: NaiveDate,
+    ) -> Result<Vec<DailyRollup>> {
+        if self.sites.site(site_id).is_none() {
+            return Err(Error::NotFound(format!("site {}", site_id)));
+        }
+        load_rollups(&self.db, site_id, start_date, end_date).await
+    }
+
+    /// Compute a funnel report for one of the site's funnels
+    pub async fn funnel_report(
+        &self,
+        site_id: Uuid,
+        funnel_id: Uuid,
+        query: &FunnelQuery,
+    ) -> Result<FunnelReport> {
+        let site = self
+            .sites
+            .site(site_id)
+            .ok_or_else(|| Error::NotFound(format!("site {}", site_id)))?;
+        let funnel = self
+            .sites
+            .funnel(site_id, funnel_id)
+            .ok_or_else(|| Error::NotFound(format!("funnel {}", funnel_id)))?;
+        let mut analyzer = FunnelAnalyzer::new(&funnel, query)?;
+
+        // Events stream through the analyzer, which keeps only per-journey progress
+        let (start, end) = day_bounds(site_timezone(&site), query.start_date, query.end_date);
+        let filter = EventFilter::new()
+            .measurement_id(&site.measurement_id)
+            .since(start)
+            .until(end);
+        let engine = self.db.engine();
+        let mut events = engine.scan_events(filter);
+        while let Some(envelope) = events.try_next().await? {
+            analyzer.add(&envelope);
+        }
+        Ok(analyzer.report())
     }
 }
 
+fn sum_revenue(a: Option<f64>, b: Option<f64>) -> Option<f64> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a + b),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Inclusive range of local days in `tz` as a half-open UTC timestamp range
+pub(crate) fn day_bounds(
+    tz: Tz,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> (DateTime<Utc>, DateTime<Utc>) {
+    (
+        local_midnight(tz, start),
+        local_midnight(tz, end + Duration::days(1)),
+    )
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -131,4 +296,100 @@ mod tests {
         assert_eq!(query.metrics.len(), 1);
         assert_eq!(query.limit, Some(100));
     }
+
+    #[test]
+    fn test_day_bounds_follow_the_timezone() {
+        let day = NaiveDate::from_ymd_opt(2024, 3, 1).unwrap();
+        let (start, end) = day_bounds(chrono_tz::America::Sao_Paulo, day, day);
+        assert_eq!(start.to_rfc3339(), "2024-03-01T03:00:00+00:00");
+        assert_eq!(end.to_rfc3339(), "2024-03-02T03:00:00+00:00");
+    }
+
+    /// Seed three page views from two clients, roll up today and return its metrics
+    async fn seeded_daily_metrics(db: Database) -> Vec<AggregatedMetrics> {
+        use crate::events::{Event, EventEnvelope, EventParams};
+        use crate::rollup::RollupJob;
+        use crate::sites::NewSite;
+
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Example".to_string(),
+                domain: "example.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("G-QUERY".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+
+        let page_view = |client: &str| {
+            EventEnvelope::new(
+                "G-QUERY".to_string(),
+                Event::PageView {
+                    page_title: "Home".to_string(),
+                    page_location: "https://example.com/".to_string(),
+                    page_referrer: None,
+                    user_id: None,
+                    params: EventParams {
+                        client_id: Some(client.to_string()),
+                        ..Default::default()
+                    },
+                },
+            )
+        };
+        db.engine()
+            .store_events(vec![page_view("a"), page_view("a"), page_view("b")])
+            .await
+            .unwrap();
+
+        let today = Utc::now().date_naive();
+        RollupJob::new(db.clone(), sites.clone())
+            .backfill(site.id, today, today)
+            .await
+            .unwrap();
+        QueryEngine::new(db, sites)
+            .get_aggregated_metrics(site.id, today, today)
+            .await
+            .unwrap()
+    }
+
+    fn assert_seeded_metrics(metrics: &[AggregatedMetrics]) {
+        assert_eq!(metrics.len(), 1);
+        assert_eq!(metrics[0].users, 2);
+        assert_eq!(metrics[0].new_users, 2);
+        assert_eq!(metrics[0].sessions, 2);
+        assert_eq!(metrics[0].page_views, 3);
+        assert!((metrics[0].bounce_rate - 0.5).abs() < f64::EPSILON);
+        assert!((metrics[0].pages_per_session - 1.5).abs() < f64::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn test_sqlite_aggregated_metrics() {
+        use crate::config::DatabaseConfig;
+        use crate::storage::SqliteStorage;
+
+        let storage = SqliteStorage::connect(&DatabaseConfig {
+            url: "sqlite::memory:".to_string(),
+            max_connections: 1,
+            min_connections: 1,
+            connection_timeout: 5,
+            partitioning: Default::default(),
+            replicas: Vec::new(),
+        })
+        .await
+        .unwrap();
+        let db = Database::Sqlite(Arc::new(storage));
+        db.migrate().await.unwrap();
+
+        assert_seeded_metrics(&seeded_daily_metrics(db).await);
+    }
+
+    #[tokio::test]
+    async fn test_memory_aggregated_metrics() {
+        use crate::storage::MemoryStorage;
+
+        let db = Database::Memory(Arc::new(MemoryStorage::new()));
+        assert_seeded_metrics(&seeded_daily_metrics(db).await);
+    }
 }
@@ -0,0 +1,140 @@
+//! Per-client token bucket rate limiting
+
+use crate::config::RateLimitConfig;
+use ipnetwork::IpNetwork;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, RwLock};
+use std::time::Instant;
+
+/// Buckets kept before idle ones are pruned
+const MAX_TRACKED_CLIENTS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Settings {
+    enabled: bool,
+    rate: f64,
+    capacity: f64,
+    exempt: Vec<IpNetwork>,
+}
+
+impl From<&RateLimitConfig> for Settings {
+    fn from(config: &RateLimitConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            rate: config.requests_per_second as f64,
+            capacity: config.requests_per_second as f64 + config.burst as f64,
+            exempt: config
+                .exempt_cidrs
+                .iter()
+                .filter_map(|c| c.parse().ok())
+                .collect(),
+        }
+    }
+}
+
+/// Rate limiter keyed by client IP
+pub struct RateLimiter {
+    settings: RwLock<Settings>,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Self {
+        Self {
+            settings: RwLock::new(Settings::from(config)),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Replace the limits at runtime; existing buckets keep their tokens
+    pub fn update(&self, config: &RateLimitConfig) {
+        *self.settings.write().unwrap() = Settings::from(config);
+    }
+
+    /// Take a token for `ip`, returning false when the client is over its limit
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let settings = self.settings.read().unwrap().clone();
+        if !settings.enabled || settings.exempt.iter().any(|net| net.contains(ip)) {
+            return true;
+        }
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            // Idle buckets have refilled completely and carry no state
+            buckets.retain(|_, b| {
+                now.duration_since(b.updated).as_secs_f64() * settings.rate < settings.capacity
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket {
+            tokens: settings.capacity,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * settings.rate).min(settings.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(rps: u32, burst: u32) -> RateLimitConfig {
+        RateLimitConfig {
+            enabled: true,
+            requests_per_second: rps,
+            burst,
+            exempt_cidrs: vec!["10.0.0.0/8".to_string()],
+        }
+    }
+
+    #[test]
+    fn test_limits_after_burst() {
+        let limiter = RateLimiter::new(&config(1, 2));
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(limiter.check(ip));
+        assert!(!limiter.check(ip));
+    }
+
+    #[test]
+    fn test_large_limits_do_not_overflow() {
+        let limiter = RateLimiter::new(&config(u32::MAX, u32::MAX));
+        assert!(limiter.check("203.0.113.7".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_exempt_and_update() {
+        let limiter = RateLimiter::new(&config(1, 0));
+        let internal: IpAddr = "10.1.2.3".parse().unwrap();
+        let external: IpAddr = "203.0.113.7".parse().unwrap();
+
+        for _ in 0..10 {
+            assert!(limiter.check(internal));
+        }
+
+        assert!(limiter.check(external));
+        assert!(!limiter.check(external));
+
+        limiter.update(&RateLimitConfig::default());
+        assert!(limiter.check(external));
+    }
+}
@@ -0,0 +1,291 @@
+//! Hot reload of the runtime-safe configuration subset
+
+use crate::config::{flatten, Config, ConfigLoader, ConfigOverrides};
+use crate::crypto::Keyring;
+use crate::enrich::GeoIp;
+use crate::error::Result;
+use crate::privacy::PrivacyFilter;
+use crate::ratelimit::RateLimiter;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tracing::{error, info, warn};
+
+/// Keys that can change without restarting the server
+pub const RELOADABLE_KEYS: &[&str] = &[
+    "privacy",
+    "server.cors_origins",
+    "rate_limit",
+    "sites.overrides",
+];
+
+/// Live components updated when the configuration changes
+#[derive(Clone)]
+pub struct ReloadTargets {
+    pub privacy: Arc<PrivacyFilter>,
+    pub rate_limiter: Arc<RateLimiter>,
+    pub cors_origins: Arc<RwLock<Vec<String>>>,
+    /// Reopened whenever its file changes, independently of the configuration
+    pub geoip: Option<Arc<GeoIp>>,
+}
+
+/// A single changed configuration value
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigChange {
+    pub key: String,
+    pub old: serde_json::Value,
+    pub new: serde_json::Value,
+}
+
+/// Result of a reload
+#[derive(Debug, Default)]
+pub struct ReloadOutcome {
+    /// Changes applied to the running server
+    pub applied: Vec<ConfigChange>,
+    /// Changes that only take effect after a restart
+    pub requires_restart: Vec<ConfigChange>,
+}
+
+/// Reloads configuration on SIGHUP or when the config file changes
+pub struct ConfigWatcher {
+    file: Option<PathBuf>,
+    overrides: ConfigOverrides,
+    current: Config,
+    targets: ReloadTargets,
+    last_modified: Option<SystemTime>,
+}
+
+impl ConfigWatcher {
+    pub fn new(
+        file: Option<PathBuf>,
+        overrides: ConfigOverrides,
+        current: Config,
+        targets: ReloadTargets,
+    ) -> Self {
+        let last_modified = file.as_deref().and_then(modified);
+        Self {
+            file,
+            overrides,
+            current,
+            targets,
+            last_modified,
+        }
+    }
+
+    /// Load the configuration again and apply the reloadable subset.
+    ///
+    /// Invalid configurations are rejected and leave the running settings untouched.
+    pub fn reload(&mut self) -> Result<ReloadOutcome> {
+        let mut loader = ConfigLoader::new().overrides(self.overrides.clone());
+        if let Some(file) = &self.file {
+            loader = loader.file(file);
+        }
+        let next = loader.load()?.config;
+        next.validate()?;
+        // Re-read on every reload, so keys added to the keyring file apply without a restart
+        let keyring = Keyring::open(&next.privacy.encryption)?;
+        self.targets.privacy.set_keyring(keyring);
+        self.reload_geoip();
+        Ok(self.apply(next))
+    }
+
+    /// Apply a new configuration, returning what changed
+    pub fn apply(&mut self, next: Config) -> ReloadOutcome {
+        let mut outcome = ReloadOutcome::default();
+        for change in diff(&self.current, &next) {
+            if is_reloadable(&change.key) {
+                outcome.applied.push(change);
+            } else {
+                outcome.requires_restart.push(change);
+            }
+        }
+
+        if !outcome.applied.is_empty() {
+            self.targets
+                .privacy
+                .update(next.privacy.clone(), next.sites.clone());
+            self.targets.rate_limiter.update(&next.rate_limit);
+            *self.targets.cors_origins.write().unwrap() = next.server.cors_origins.clone();
+
+            self.current.privacy = next.privacy;
+            self.current.server.cors_origins = next.server.cors_origins;
+            self.current.rate_limit = next.rate_limit;
+            self.current.sites.overrides = next.sites.overrides;
+        }
+
+        outcome
+    }
+
+    /// Watch for SIGHUP and file modifications until the task is dropped
+    pub async fn run(mut self, poll_interval: Duration) -> Result<()> {
+        #[cfg(unix)]
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())?;
+
+        let polling =
+            (self.file.is_some() || self.targets.geoip.is_some()) && !poll_interval.is_zero();
+        let mut ticker = tokio::time::interval(poll_interval.max(Duration::from_secs(1)));
+
+        loop {
+            #[cfg(unix)]
+            let signal = hangup.recv();
+            #[cfg(not(unix))]
+            let signal = std::future::pending::<Option<()>>();
+
+            tokio::select! {
+                _ = signal => {
+                    info!("SIGHUP received, reloading configuration");
+                    self.reload_logged();
+                }
+                _ = ticker.tick(), if polling => {
+                    if self.file_changed() {
+                        info!("Configuration file changed, reloading");
+                        self.reload_logged();
+                    } else {
+                        self.reload_geoip();
+                    }
+                }
+            }
+        }
+    }
+
+    fn file_changed(&mut self) -> bool {
+        let current = self.file.as_deref().and_then(modified);
+        if current != self.last_modified {
+            self.last_modified = current;
+            return true;
+        }
+        false
+    }
+
+    fn reload_geoip(&self) {
+        let Some(geoip) = &self.targets.geoip else {
+            return;
+        };
+        match geoip.reload() {
+            Ok(true) => info!(
+                "GeoIP database {} reloaded (built {})",
+                geoip.path().display(),
+                geoip.build_epoch()
+            ),
+            Ok(false) => {}
+            Err(e) => error!("GeoIP database reload rejected: {}", e),
+        }
+    }
+
+    fn reload_logged(&mut self) {
+        match self.reload() {
+            Ok(outcome) => {
+                for change in &outcome.applied {
+                    info!(
+                        "Config reloaded: {} {} -> {}",
+                        change.key, change.old, change.new
+                    );
+                }
+                for change in &outcome.requires_restart {
+                    warn!(
+                        "Config change to {} ({} -> {}) requires a restart",
+                        change.key, change.old, change.new
+                    );
+                }
+                if outcome.applied.is_empty() && outcome.requires_restart.is_empty() {
+                    info!("Configuration unchanged");
+                }
+            }
+            Err(e) => error!("Configuration reload rejected: {}", e),
+        }
+    }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn is_reloadable(key: &str) -> bool {
+    RELOADABLE_KEYS
+        .iter()
+        .any(|prefix| key == *prefix || key.starts_with(&format!("{}.", prefix)))
+}
+
+/// Changed leaf values between two configurations
+fn diff(old: &Config, new: &Config) -> Vec<ConfigChange> {
+    let old = flatten(&serde_json::to_value(old).unwrap_or_default());
+    let new = flatten(&serde_json::to_value(new).unwrap_or_default());
+    let keys: BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+
+    keys.into_iter()
+        .filter_map(|key| {
+            let before = old.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            let after = new.get(key).cloned().unwrap_or(serde_json::Value::Null);
+            (before != after).then(|| ConfigChange {
+                key: key.clone(),
+                old: before,
+                new: after,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::SiteOverride;
+
+    fn watcher(config: &Config) -> ConfigWatcher {
+        let targets = ReloadTargets {
+            privacy: Arc::new(PrivacyFilter::new(config.privacy.clone())),
+            rate_limiter: Arc::new(RateLimiter::new(&config.rate_limit)),
+            cors_origins: Arc::new(RwLock::new(config.server.cors_origins.clone())),
+            geoip: None,
+        };
+        ConfigWatcher::new(None, ConfigOverrides::default(), config.clone(), targets)
+    }
+
+    #[test]
+    fn test_apply_safe_subset() {
+        let config = Config::default();
+        let mut watcher = watcher(&config);
+
+        let mut next = config.clone();
+        next.privacy.anonymize_ip = false;
+        next.server.cors_origins = vec!["https://example.com".to_string()];
+        next.sites.overrides.insert(
+            "G-1".to_string(),
+            SiteOverride {
+                respect_dnt: Some(false),
+                ..Default::default()
+            },
+        );
+        next.server.port = 9999;
+
+        let outcome = watcher.apply(next);
+        let applied: Vec<&str> = outcome.applied.iter().map(|c| c.key.as_str()).collect();
+        assert!(applied.contains(&"privacy.anonymize_ip"));
+        assert!(applied.contains(&"server.cors_origins"));
+        assert!(applied.contains(&"sites.overrides.G-1.respect_dnt"));
+        assert_eq!(outcome.requires_restart.len(), 1);
+        assert_eq!(outcome.requires_restart[0].key, "server.port");
+
+        assert!(!watcher.targets.privacy.effective_config("G-2").anonymize_ip);
+        assert!(!watcher.targets.privacy.effective_config("G-1").respect_dnt);
+        assert_eq!(
+            watcher.targets.cors_origins.read().unwrap()[0],
+            "https://example.com"
+        );
+        assert_eq!(watcher.current.server.port, 8080);
+    }
+
+    #[test]
+    fn test_invalid_reload_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        std::fs::write(&path, "[telemetry]\nsample_rate = 2.0\n").unwrap();
+
+        let config = Config::default();
+        let mut watcher = watcher(&config);
+        watcher.file = Some(path);
+
+        assert!(watcher.reload().is_err());
+        assert!(watcher.targets.privacy.effective_config("G-1").anonymize_ip);
+    }
+}
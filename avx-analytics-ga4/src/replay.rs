@@ -0,0 +1,420 @@
+//! Replay of stored events through the enrichment pipeline
+//!
+//! A replay walks a site's events one local day at a time, enriches each stored envelope
+//! again and overwrites it in place, keeping its ID. The day's stored sessions are rebuilt
+//! from the rewritten events, and a checkpoint records the next day so an interrupted
+//! replay resumes where it stopped. Once every day is done, the range's rollups are
+//! recomputed in one pass, replacing rather than adding to them. Goals are not evaluated
+//! again, so conversions are left as they are.
+
+use crate::enrich::Enrichment;
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::Session;
+use crate::rollup::{local_midnight, site_timezone, RollupJob};
+use crate::session;
+use crate::sites::SiteRegistry;
+use crate::storage::{Database, EventFilter, StorageEngine};
+use chrono::{DateTime, NaiveDate, Utc};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use tracing::info;
+use uuid::Uuid;
+
+/// Progress of a site's replay, persisted after every day
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayCheckpoint {
+    pub site_id: Uuid,
+    /// First local day replayed
+    pub start: NaiveDate,
+    /// Last local day replayed
+    pub end: NaiveDate,
+    /// Next day to replay; `None` once the range is done
+    pub next_day: Option<NaiveDate>,
+    pub events: u64,
+    pub enrichers: Vec<String>,
+    pub started_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl ReplayCheckpoint {
+    pub fn finished(&self) -> bool {
+        self.next_day.is_none()
+    }
+}
+
+/// Re-enriches stored events of a site and date range
+pub struct Replay {
+    db: Database,
+    storage: Arc<dyn StorageEngine>,
+    sites: Arc<SiteRegistry>,
+    enrichment: Enrichment,
+    checkpoint_dir: PathBuf,
+    running: Mutex<HashSet<Uuid>>,
+}
+
+impl Replay {
+    pub fn new(db: Database, sites: Arc<SiteRegistry>, enrichment: Enrichment) -> Self {
+        Self {
+            storage: db.engine(),
+            db,
+            sites,
+            enrichment,
+            checkpoint_dir: PathBuf::from("replay-checkpoints"),
+            running: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Write rewritten events through this engine, e.g. one that fans out to replicas
+    pub fn with_storage(mut self, storage: Arc<dyn StorageEngine>) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    pub fn with_checkpoint_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.checkpoint_dir = dir.into();
+        self
+    }
+
+    /// Whether a replay of `site_id` is in progress in this process
+    pub fn is_running(&self, site_id: Uuid) -> bool {
+        self.running.lock().unwrap().contains(&site_id)
+    }
+
+    /// Last checkpoint stored for a site
+    pub fn checkpoint(&self, site_id: Uuid) -> Result<Option<ReplayCheckpoint>> {
+        let path = self.checkpoint_path(site_id);
+        if !path.exists() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_slice(&fs::read(path)?)?))
+    }
+
+    /// Replay local days `start..=end`, resuming an unfinished checkpoint for the same
+    /// range unless `restart` is set
+    pub async fn run(
+        &self,
+        site_id: Uuid,
+        start: NaiveDate,
+        end: NaiveDate,
+        restart: bool,
+    ) -> Result<ReplayCheckpoint> {
+        if start > end {
+            return Err(Error::Query(format!(
+                "start {} is after end {}",
+                start, end
+            )));
+        }
+        let site = self
+            .sites
+            .site(site_id)
+            .ok_or_else(|| Error::NotFound(format!("site {}", site_id)))?;
+        let _running = RunningGuard::acquire(&self.running, site_id)?;
+
+        let now = Utc::now();
+        let mut checkpoint = match self.checkpoint(site_id)? {
+            Some(previous)
+                if !restart
+                    && !previous.finished()
+                    && previous.start == start
+                    && previous.end == end =>
+            {
+                info!(
+                    "Resuming replay of site {} from {}",
+                    site_id,
+                    previous.next_day.unwrap()
+                );
+                previous
+            }
+            _ => ReplayCheckpoint {
+                site_id,
+                start,
+                end,
+                next_day: Some(start),
+                events: 0,
+                enrichers: self.enrichment.names(),
+                started_at: now,
+                updated_at: now,
+            },
+        };
+
+        let tz = site_timezone(&site);
+        while let Some(day) = checkpoint.next_day {
+            let (since, until) = (
+                local_midnight(tz, day),
+                local_midnight(tz, day.succ_opt().unwrap()),
+            );
+            let filter = EventFilter::new()
+                .measurement_id(&site.measurement_id)
+                .since(since)
+                .until(until);
+
+            // The scan must finish before rewriting, as some backends lock files while scanning
+            let mut enriched = Vec::new();
+            let mut events = self.storage.scan_events(filter);
+            while let Some(event) = events.try_next().await? {
+                enriched.push(self.enrichment.apply(event)?);
+            }
+            drop(events);
+
+            if !enriched.is_empty() {
+                let sessions = self
+                    .rebuild_sessions(&site.measurement_id, since, until, &enriched)
+                    .await?;
+                checkpoint.events += self.storage.replace_events(enriched).await?;
+                if !sessions.is_empty() {
+                    self.storage.store_sessions(sessions).await?;
+                }
+            }
+
+            checkpoint.next_day = day.succ_opt().filter(|next| *next <= end);
+            checkpoint.updated_at = Utc::now();
+            // The last day is saved once the rollups are done, so a crash before then resumes
+            if checkpoint.next_day.is_some() {
+                self.save_checkpoint(&checkpoint)?;
+            }
+        }
+
+        RollupJob::new(self.db.clone(), self.sites.clone())
+            .backfill(site_id, start, end)
+            .await?;
+        self.save_checkpoint(&checkpoint)?;
+        info!(
+            "Replayed {} events of site {} ({} to {})",
+            checkpoint.events, site_id, start, end
+        );
+        Ok(checkpoint)
+    }
+
+    /// Stored sessions of `[since, until)` rebuilt from their rewritten events. Sessions
+    /// split at local midnight, so a day's events hold every event of its sessions.
+    async fn rebuild_sessions(
+        &self,
+        measurement_id: &str,
+        since: DateTime<Utc>,
+        until: DateTime<Utc>,
+        events: &[EventEnvelope],
+    ) -> Result<Vec<Session>> {
+        let stored = self
+            .db
+            .engine()
+            .sessions(measurement_id, since, until)
+            .await?;
+        if stored.is_empty() {
+            return Ok(Vec::new());
+        }
+        let mut by_session: HashMap<&str, Vec<EventEnvelope>> = HashMap::new();
+        for envelope in events {
+            if let Some(session_id) = &envelope.event.params().session_id {
+                by_session
+                    .entry(session_id)
+                    .or_default()
+                    .push(envelope.clone());
+            }
+        }
+        Ok(stored
+            .iter()
+            .filter_map(|session| {
+                let mut events = by_session.remove(session.session_id.as_str())?;
+                events.sort_by_key(|e| e.timestamp);
+                Some(session::rebuild(session, &events))
+            })
+            .collect())
+    }
+
+    fn checkpoint_path(&self, site_id: Uuid) -> PathBuf {
+        self.checkpoint_dir.join(format!("{}.json", site_id))
+    }
+
+    fn save_checkpoint(&self, checkpoint: &ReplayCheckpoint) -> Result<()> {
+        fs::create_dir_all(&self.checkpoint_dir)?;
+        let path = self.checkpoint_path(checkpoint.site_id);
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(checkpoint)?)?;
+        fs::rename(&tmp, &path)?;
+        Ok(())
+    }
+}
+
+/// Marks a site as replaying until dropped
+struct RunningGuard<'a> {
+    running: &'a Mutex<HashSet<Uuid>>,
+    site_id: Uuid,
+}
+
+impl<'a> RunningGuard<'a> {
+    fn acquire(running: &'a Mutex<HashSet<Uuid>>, site_id: Uuid) -> Result<Self> {
+        if !running.lock().unwrap().insert(site_id) {
+            return Err(Error::Query(format!(
+                "a replay of site {} is already running",
+                site_id
+            )));
+        }
+        Ok(Self { running, site_id })
+    }
+}
+
+impl Drop for RunningGuard<'_> {
+    fn drop(&mut self) {
+        self.running.lock().unwrap().remove(&self.site_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::enrich::Enricher;
+    use crate::events::{Event, EventEnvelope, EventParams};
+    use crate::sites::NewSite;
+    use crate::storage::MemoryStorage;
+
+    struct Desktop;
+
+    impl Enricher for Desktop {
+        fn name(&self) -> &str {
+            "desktop"
+        }
+
+        fn enrich(&self, envelope: &mut EventEnvelope) -> Result<()> {
+            envelope.event.params_mut().device_category = Some("desktop".to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_rewrites_events_and_rollups() {
+        let db = Database::Memory(Arc::new(MemoryStorage::new()));
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Example".to_string(),
+                domain: "example.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("G-REPLAY".to_string()),
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        let mut envelope = EventEnvelope::new(
+            "G-REPLAY".to_string(),
+            Event::PageView {
+                page_title: "Home".to_string(),
+                page_location: "https://example.com/".to_string(),
+                page_referrer: None,
+                user_id: None,
+                params: EventParams {
+                    client_id: Some("c1".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        envelope.timestamp = "2026-03-10T12:00:00Z".parse().unwrap();
+        let sessionizer = crate::session::Sessionizer::new(sites.clone(), &Default::default());
+        sessionizer.track(&mut envelope).await.unwrap();
+        db.engine()
+            .store_sessions(sessionizer.drain())
+            .await
+            .unwrap();
+        db.engine()
+            .store_events(vec![envelope.clone()])
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let replay = Replay::new(
+            db.clone(),
+            sites.clone(),
+            Enrichment::new().with_enricher(Desktop),
+        )
+        .with_checkpoint_dir(dir.path());
+        let day = NaiveDate::from_ymd_opt(2026, 3, 10).unwrap();
+        for _ in 0..2 {
+            let checkpoint = replay
+                .run(site.id, day - chrono::Duration::days(1), day, true)
+                .await
+                .unwrap();
+            assert!(checkpoint.finished());
+            assert_eq!(checkpoint.events, 1);
+        }
+        assert_eq!(
+            replay.checkpoint(site.id).unwrap().unwrap().enrichers,
+            vec!["desktop"]
+        );
+
+        let stored = db
+            .engine()
+            .get_event(envelope.event_id)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            stored.event.params().device_category.as_deref(),
+            Some("desktop")
+        );
+
+        // Replaying twice recomputes the rollup instead of adding to it
+        let rollups = crate::rollup::load_rollups(&db, site.id, day, day)
+            .await
+            .unwrap();
+        assert_eq!(rollups[0].metrics.page_views, 1);
+        assert_eq!(rollups[0].devices[0].device_category, "desktop");
+
+        let until = envelope.timestamp + chrono::Duration::seconds(1);
+        let sessions = db
+            .engine()
+            .sessions("G-REPLAY", envelope.timestamp, until)
+            .await
+            .unwrap();
+        assert_eq!(sessions.len(), 1);
+        assert_eq!(
+            (
+                sessions[0].device_category.as_deref(),
+                sessions[0].page_views
+            ),
+            (Some("desktop"), 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resumes_from_checkpoint() {
+        let db = Database::Memory(Arc::new(MemoryStorage::new()));
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Example".to_string(),
+                domain: "example.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: None,
+                timezone: None,
+                currency: None,
+            })
+            .unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let replay = Replay::new(db, sites, Enrichment::new()).with_checkpoint_dir(dir.path());
+
+        let (start, end) = (
+            NaiveDate::from_ymd_opt(2026, 3, 1).unwrap(),
+            NaiveDate::from_ymd_opt(2026, 3, 5).unwrap(),
+        );
+        let interrupted = ReplayCheckpoint {
+            site_id: site.id,
+            start,
+            end,
+            next_day: NaiveDate::from_ymd_opt(2026, 3, 4),
+            events: 7,
+            enrichers: Vec::new(),
+            started_at: Utc::now(),
+            updated_at: Utc::now(),
+        };
+        replay.save_checkpoint(&interrupted).unwrap();
+
+        let resumed = replay.run(site.id, start, end, false).await.unwrap();
+        assert!(resumed.finished());
+        assert_eq!(resumed.events, 7);
+        assert_eq!(resumed.started_at, interrupted.started_at);
+    }
+}
@@ -0,0 +1,447 @@
+//! Retention enforcement
+//!
+//! The retention job purges data past its retention period. Raw data (events, conversions,
+//! sessions and users) follows `privacy.data_retention_days`; daily rollups follow the
+//! longer `retention.rollup_retention_days`, so reports outlive the events they summarize.
+//! Both can be overridden per site in `[sites.overrides]`.
+//!
+//! Each registered site is purged with its own policy. A final pass purges events of any
+//! measurement ID past the longest retention in effect, which covers unregistered IDs
+//! without cutting short a site that keeps data longer.
+//!
+//! The primary database and every replica in `database.replicas` are purged alike, so no
+//! copy of the data outlives its retention period; each purge record names its target.
+//!
+//! Every purge that removed data is appended to an NDJSON audit log, including events
+//! partitions expired by PostgreSQL partition maintenance. Dry runs report what would be
+//! purged and leave both the data and the audit log untouched.
+
+use crate::error::Result;
+use crate::privacy::PrivacyFilter;
+use crate::rollup::{delete_rollups, site_timezone};
+use crate::sites::SiteRegistry;
+use crate::storage::{Database, EventFilter, ExpiryFilter, PartitionReport};
+use chrono::{DateTime, Days, Duration, NaiveDate, NaiveTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Name of the primary database in purge records
+pub const PRIMARY_TARGET: &str = "primary";
+
+fn primary_target() -> String {
+    PRIMARY_TARGET.to_string()
+}
+
+/// One purge of one site, or of all measurement IDs when `site_id` is `None`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PurgeRecord {
+    pub id: Uuid,
+    pub at: DateTime<Utc>,
+    pub dry_run: bool,
+    /// `primary`, or the name of the replica that was purged
+    #[serde(default = "primary_target")]
+    pub target: String,
+    pub site_id: Option<Uuid>,
+    /// `*` for the pass over every measurement ID
+    pub measurement_id: String,
+    pub retention_days: u32,
+    pub rollup_retention_days: Option<u32>,
+    /// Raw data older than this was purged
+    pub events_before: DateTime<Utc>,
+    /// Rollups of local days before this were purged
+    pub rollups_before: Option<NaiveDate>,
+    pub events: u64,
+    pub conversions: u64,
+    pub sessions: u64,
+    pub users: u64,
+    pub rollups: u64,
+    /// Events partitions dropped or detached, whose events are counted in `events`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub partitions: Vec<String>,
+}
+
+/// Purges data past its retention period
+pub struct RetentionJob {
+    /// Databases to purge, by name
+    targets: Vec<(String, Database)>,
+    sites: Arc<SiteRegistry>,
+    privacy: Arc<PrivacyFilter>,
+    rollup_retention_days: u32,
+    audit_log: Option<PathBuf>,
+}
+
+impl RetentionJob {
+    /// Raw retention and per-site overrides come from `privacy`, which follows config reloads
+    pub fn new(db: Database, sites: Arc<SiteRegistry>, privacy: Arc<PrivacyFilter>) -> Self {
+        Self {
+            targets: vec![(primary_target(), db)],
+            sites,
+            privacy,
+            rollup_retention_days: 1825,
+            audit_log: None,
+        }
+    }
+
+    /// Purge a replica too, named `name` in the audit log
+    pub fn with_replica(mut self, name: impl Into<String>, db: Database) -> Self {
+        self.targets.push((name.into(), db));
+        self
+    }
+
+    /// Keep daily rollups this many days, unless a site overrides it
+    pub fn with_rollup_retention_days(mut self, days: u32) -> Self {
+        self.rollup_retention_days = days;
+        self
+    }
+
+    /// Append a record of every purge that removed data to this NDJSON file
+    pub fn with_audit_log(mut self, path: impl Into<PathBuf>) -> Self {
+        self.audit_log = Some(path.into());
+        self
+    }
+
+    /// Purge every site on every target, or with `dry_run` only count what would be purged
+    pub async fn run(&self, dry_run: bool) -> Result<Vec<PurgeRecord>> {
+        let now = Utc::now();
+        let mut records = Vec::new();
+        for (target, db) in &self.targets {
+            self.purge(target, db, now, dry_run, &mut records).await?;
+        }
+        Ok(records)
+    }
+
+    async fn purge(
+        &self,
+        target: &str,
+        db: &Database,
+        now: DateTime<Utc>,
+        dry_run: bool,
+        records: &mut Vec<PurgeRecord>,
+    ) -> Result<()> {
+        let engine = db.engine();
+        for site in self.sites.sites() {
+            let retention_days = self
+                .privacy
+                .effective_config(&site.measurement_id)
+                .data_retention_days;
+            let rollup_days = self
+                .privacy
+                .rollup_retention_days(&site.measurement_id, self.rollup_retention_days);
+            let events_before = now - Duration::days(retention_days as i64);
+            let today = now.with_timezone(&site_timezone(&site)).date_naive();
+            let rollups_before = today
+                .checked_sub_days(Days::new(rollup_days as u64))
+                .unwrap_or(NaiveDate::MIN);
+            let expired = ExpiryFilter::new(events_before)
+                .measurement_id(&site.measurement_id)
+                .dry_run(dry_run);
+            let events = EventFilter::new()
+                .measurement_id(&site.measurement_id)
+                .until(events_before);
+            let record = PurgeRecord {
+                id: Uuid::new_v4(),
+                at: now,
+                dry_run,
+                target: target.to_string(),
+                site_id: Some(site.id),
+                measurement_id: site.measurement_id.clone(),
+                retention_days,
+                rollup_retention_days: Some(rollup_days),
+                events_before,
+                rollups_before: Some(rollups_before),
+                events: purge_events(db, &events, dry_run).await?,
+                conversions: engine.delete_conversions(&expired).await?,
+                sessions: engine.delete_sessions(&expired).await?,
+                users: engine.delete_users(&expired).await?,
+                rollups: delete_rollups(db, site.id, rollups_before, dry_run).await?,
+                partitions: Vec::new(),
+            };
+            self.finish(record, records)?;
+        }
+
+        // Anything older than the longest retention is past every site's policy
+        let retention_days = self.privacy.longest_retention_days();
+        let events_before = now - Duration::days(retention_days as i64);
+        let mut events =
+            purge_events(db, &EventFilter::new().until(events_before), dry_run).await?;
+        if dry_run {
+            // Registered sites already counted these
+            for site in self.sites.sites() {
+                let filter = EventFilter::new()
+                    .measurement_id(&site.measurement_id)
+                    .until(events_before);
+                events = events.saturating_sub(purge_events(db, &filter, true).await?);
+            }
+        }
+        let expired = ExpiryFilter::new(events_before).dry_run(dry_run);
+        let record = PurgeRecord {
+            id: Uuid::new_v4(),
+            at: now,
+            dry_run,
+            target: target.to_string(),
+            site_id: None,
+            measurement_id: "*".to_string(),
+            retention_days,
+            rollup_retention_days: None,
+            events_before,
+            rollups_before: None,
+            events,
+            conversions: engine.delete_conversions(&expired).await?,
+            sessions: engine.delete_sessions(&expired).await?,
+            users: engine.delete_users(&expired).await?,
+            rollups: 0,
+            partitions: Vec::new(),
+        };
+        self.finish(record, records)
+    }
+
+    /// Audit the events partitions a maintenance run of `target` expired; dry runs are not
+    /// audited
+    pub fn record_partitions(
+        &self,
+        target: &str,
+        report: &PartitionReport,
+    ) -> Result<Option<PurgeRecord>> {
+        let Some(cutoff) = report.cutoff.filter(|_| !report.expired.is_empty()) else {
+            return Ok(None);
+        };
+        let now = Utc::now();
+        let record = PurgeRecord {
+            id: Uuid::new_v4(),
+            at: now,
+            dry_run: report.dry_run,
+            target: target.to_string(),
+            site_id: None,
+            measurement_id: "*".to_string(),
+            retention_days: (now.date_naive() - cutoff).num_days() as u32,
+            rollup_retention_days: None,
+            events_before: cutoff.and_time(NaiveTime::MIN).and_utc(),
+            rollups_before: None,
+            events: report.expired_events,
+            conversions: 0,
+            sessions: 0,
+            users: 0,
+            rollups: 0,
+            partitions: report.expired.clone(),
+        };
+        let mut records = Vec::new();
+        self.finish(record, &mut records)?;
+        Ok(records.pop())
+    }
+
+    /// Log and audit a purge that removed data
+    fn finish(&self, record: PurgeRecord, records: &mut Vec<PurgeRecord>) -> Result<()> {
+        let purged =
+            record.events + record.conversions + record.sessions + record.users + record.rollups;
+        if !record.dry_run && (purged > 0 || !record.partitions.is_empty()) {
+            tracing::info!(
+                "Retention purged {} events, {} conversions, {} sessions, {} users and {} rollups of {} from {}",
+                record.events,
+                record.conversions,
+                record.sessions,
+                record.users,
+                record.rollups,
+                record.measurement_id,
+                record.target
+            );
+            if let Some(path) = &self.audit_log {
+                let mut line = serde_json::to_vec(&record)?;
+                line.push(b'\n');
+                let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+                file.write_all(&line)?;
+                file.sync_data()?;
+            }
+        }
+        records.push(record);
+        Ok(())
+    }
+}
+
+/// Count (dry run) or delete the events matching `filter`
+async fn purge_events(db: &Database, filter: &EventFilter, dry_run: bool) -> Result<u64> {
+    let engine = db.engine();
+    if dry_run {
+        Ok(engine.count_by_event_name(filter).await?.values().sum())
+    } else {
+        engine.delete_events(filter).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{PrivacyConfig, SiteOverride, SitesConfig};
+    use crate::events::{Event, EventEnvelope, EventParams};
+    use crate::rollup::RollupJob;
+    use crate::session::Sessionizer;
+    use crate::sites::NewSite;
+    use crate::storage::MemoryStorage;
+
+    fn event(measurement_id: &str, days_old: i64) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new(
+            measurement_id.to_string(),
+            Event::Custom {
+                name: "click".to_string(),
+                params: EventParams::default(),
+            },
+        );
+        envelope.timestamp = Utc::now() - Duration::days(days_old);
+        envelope
+    }
+
+    #[tokio::test]
+    async fn test_retention_per_site_with_dry_run_and_audit() {
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let mut site_ids = Vec::new();
+        for measurement_id in ["G-SHORT", "G-LONG"] {
+            let site = sites
+                .create_site(NewSite {
+                    name: measurement_id.to_string(),
+                    domain: "example.com".to_string(),
+                    allowed_hostnames: Vec::new(),
+                    measurement_id: Some(measurement_id.to_string()),
+                    timezone: None,
+                    currency: None,
+                })
+                .unwrap();
+            site_ids.push(site.id);
+        }
+        let mut overrides = SitesConfig::default();
+        overrides.overrides.insert(
+            "G-SHORT".to_string(),
+            SiteOverride {
+                data_retention_days: Some(30),
+                rollup_retention_days: Some(60),
+                ..Default::default()
+            },
+        );
+        let privacy = Arc::new(
+            PrivacyFilter::new(PrivacyConfig {
+                anonymize_ip: true,
+                respect_dnt: true,
+                data_retention_days: 365,
+                cookie_consent_required: false,
+                encryption_enabled: false,
+                encryption: Default::default(),
+            })
+            .with_sites(overrides),
+        );
+
+        let storage = Arc::new(MemoryStorage::new());
+        let db = Database::Memory(storage.clone());
+        db.engine()
+            .store_events(vec![
+                event("G-SHORT", 100),
+                event("G-SHORT", 1),
+                event("G-LONG", 100),
+                event("G-LONG", 400),
+                event("G-UNKNOWN", 400),
+            ])
+            .await
+            .unwrap();
+        let today = Utc::now().date_naive();
+        RollupJob::new(db.clone(), sites.clone())
+            .backfill(site_ids[0], today - Days::new(100), today - Days::new(99))
+            .await
+            .unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let audit = dir.path().join("audit.ndjson");
+        let job = RetentionJob::new(db, sites, privacy).with_audit_log(&audit);
+
+        let planned = job.run(true).await.unwrap();
+        let counts: Vec<(u64, u64)> = planned.iter().map(|r| (r.events, r.rollups)).collect();
+        assert_eq!(counts, vec![(1, 2), (1, 0), (1, 0)]);
+        assert_eq!(storage.len(), 5);
+        assert!(!audit.exists());
+
+        let purged = job.run(false).await.unwrap();
+        assert_eq!(purged.iter().map(|r| r.events).sum::<u64>(), 3);
+        assert_eq!(storage.len(), 2);
+        let audited: Vec<PurgeRecord> = std::fs::read_to_string(&audit)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(audited.len(), 3);
+        assert_eq!(audited[0].site_id, Some(site_ids[0]));
+        assert_eq!(audited[0].rollups, 2);
+    }
+
+    #[tokio::test]
+    async fn test_partition_expiry_is_audited() {
+        let privacy = Arc::new(PrivacyFilter::new(crate::config::Config::default().privacy));
+        let db = Database::Memory(Arc::new(MemoryStorage::new()));
+        let dir = tempfile::tempdir().unwrap();
+        let audit = dir.path().join("audit.ndjson");
+        let job = RetentionJob::new(db, Arc::new(SiteRegistry::in_memory()), privacy)
+            .with_audit_log(&audit);
+
+        let mut report = PartitionReport {
+            dry_run: true,
+            created: vec!["events_p2026_11".to_string()],
+            expired: vec!["events_p2025_09".to_string()],
+            expired_events: 42,
+            cutoff: Some(Utc::now().date_naive() - Days::new(365)),
+        };
+        job.record_partitions(PRIMARY_TARGET, &report).unwrap();
+        assert!(!audit.exists());
+
+        report.dry_run = false;
+        let record = job
+            .record_partitions(PRIMARY_TARGET, &report)
+            .unwrap()
+            .unwrap();
+        assert_eq!((record.events, record.retention_days), (42, 365));
+        let audited: PurgeRecord =
+            serde_json::from_str(std::fs::read_to_string(&audit).unwrap().trim()).unwrap();
+        assert_eq!(audited.partitions, vec!["events_p2025_09".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_replicas_are_purged_and_audited() {
+        let privacy = Arc::new(PrivacyFilter::new(crate::config::Config::default().privacy));
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let sessionizer = Sessionizer::new(sites.clone(), &Default::default());
+        let mut expired = event("G-OLD", 400);
+        expired.event.params_mut().client_id = Some("c1".to_string());
+        sessionizer.track(&mut expired).await.unwrap();
+        let sessions = sessionizer.drain();
+
+        let primary = Arc::new(MemoryStorage::new());
+        let replica = Arc::new(MemoryStorage::new());
+        for storage in [&primary, &replica] {
+            let engine = Database::Memory(storage.clone()).engine();
+            engine.store_events(vec![expired.clone()]).await.unwrap();
+            engine.store_sessions(sessions.clone()).await.unwrap();
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let audit = dir.path().join("audit.ndjson");
+        let job = RetentionJob::new(Database::Memory(primary.clone()), sites, privacy)
+            .with_replica("backup", Database::Memory(replica.clone()))
+            .with_audit_log(&audit);
+        let records = job.run(false).await.unwrap();
+
+        let targets: Vec<(&str, u64, u64)> = records
+            .iter()
+            .map(|r| (r.target.as_str(), r.events, r.sessions))
+            .collect();
+        assert_eq!(targets, vec![(PRIMARY_TARGET, 1, 1), ("backup", 1, 1)]);
+        for storage in [&primary, &replica] {
+            assert_eq!(storage.len(), 0);
+            let left = Database::Memory(storage.clone())
+                .engine()
+                .sessions("G-OLD", DateTime::UNIX_EPOCH, Utc::now())
+                .await
+                .unwrap();
+            assert!(left.is_empty());
+        }
+        let audited = std::fs::read_to_string(&audit).unwrap();
+        assert_eq!(audited.lines().count(), 2);
+    }
+}
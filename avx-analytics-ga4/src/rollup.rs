@@ -0,0 +1,1100 @@
+//! Daily report rollups
+//!
+//! The rollup job turns raw events into one [`DailyRollup`] per site and local day, in the
+//! site's timezone: the headline [`AggregatedMetrics`] plus device, traffic source and
+//! geographic breakdowns. Rollups are upserted, so recomputing a day is idempotent.
+//!
+//! Every run recomputes the last `rollup.lookback_days` days of each site, plus older days
+//! that received late events since the previous run. Ranges can be backfilled with
+//! `avila-analytics-cli rollup --start … --end …`. Reports read rollups, never raw events.
+
+use crate::enrich::traffic;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventEnvelope};
+use crate::models::{AggregatedMetrics, DeviceMetrics, GeoData, Site, TrafficSource};
+use crate::sites::SiteRegistry;
+use crate::storage::{Database, EventFilter};
+use chrono::{DateTime, Days, Duration, NaiveDate, NaiveTime, TimeZone, Utc};
+use chrono_tz::Tz;
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Local days computed per pass over the event history
+const DAYS_PER_PASS: usize = 31;
+
+/// Client IDs looked up per SQLite query, under its bound-parameter limit
+const FIRST_SEEN_BATCH: usize = 500;
+
+const NOT_SET: &str = "(not set)";
+
+/// One site's reports for one local day
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DailyRollup {
+    pub metrics: AggregatedMetrics,
+    /// Timezone `metrics.date` is expressed in
+    pub timezone: String,
+    pub devices: Vec<DeviceMetrics>,
+    pub traffic_sources: Vec<TrafficSource>,
+    pub geo: Vec<GeoData>,
+    pub computed_at: DateTime<Utc>,
+}
+
+/// Where a session came from
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct SourceMedium {
+    pub source: String,
+    pub medium: String,
+    pub campaign: Option<String>,
+}
+
+/// Source of a landing page view, as the traffic source enricher attributes it
+pub(crate) fn source_medium(page_location: &str, referrer: Option<&str>) -> SourceMedium {
+    let attribution = traffic::attribute(page_location, referrer);
+    SourceMedium {
+        source: attribution.source,
+        medium: attribution.medium,
+        campaign: attribution.campaign,
+    }
+}
+
+pub(crate) fn site_timezone(site: &Site) -> Tz {
+    site.timezone.parse().unwrap_or_else(|_| {
+        tracing::warn!(
+            "Site {} has unknown timezone {:?}, using UTC",
+            site.id,
+            site.timezone
+        );
+        Tz::UTC
+    })
+}
+
+/// Start of a local day as a UTC instant
+pub(crate) fn local_midnight(tz: Tz, day: NaiveDate) -> DateTime<Utc> {
+    let midnight = day.and_time(NaiveTime::MIN);
+    // Where DST starts at midnight the day begins an hour later
+    (0..=2)
+        .find_map(|hours| {
+            tz.from_local_datetime(&(midnight + Duration::hours(hours)))
+                .earliest()
+        })
+        .map(|start| start.with_timezone(&Utc))
+        .unwrap_or_else(|| midnight.and_utc())
+}
+
+fn add_revenue(total: &mut Option<f64>, value: Option<f64>) {
+    if let Some(value) = value {
+        *total.get_or_insert(0.0) += value;
+    }
+}
+
+#[derive(Default)]
+struct SessionTotals {
+    client: String,
+    page_views: u32,
+    first: Option<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+    revenue: Option<f64>,
+    conversions: u32,
+    device: Option<String>,
+    source: Option<SourceMedium>,
+    country: Option<String>,
+    city: Option<String>,
+}
+
+#[derive(Default)]
+struct DayTotals {
+    clients: HashSet<String>,
+    page_views: u32,
+    events: u64,
+    revenue: Option<f64>,
+    conversions: u32,
+    sessions: HashMap<String, SessionTotals>,
+}
+
+/// Users, sessions, bounces, conversions and revenue of one breakdown row
+#[derive(Default)]
+struct Group {
+    clients: HashSet<String>,
+    sessions: u32,
+    bounces: u32,
+    conversions: u32,
+    revenue: Option<f64>,
+}
+
+impl Group {
+    fn add(&mut self, session: &SessionTotals) {
+        self.clients.insert(session.client.clone());
+        self.sessions += 1;
+        self.bounces += (session.page_views <= 1) as u32;
+        self.conversions += session.conversions;
+        add_revenue(&mut self.revenue, session.revenue);
+    }
+}
+
+/// Breakdown rows with the most sessions first
+fn ranked<K: Ord>(groups: BTreeMap<K, Group>) -> Vec<(K, Group)> {
+    let mut rows: Vec<(K, Group)> = groups.into_iter().collect();
+    rows.sort_by_key(|(_, group)| std::cmp::Reverse(group.sessions));
+    rows
+}
+
+fn ratio(numerator: f64, denominator: f64) -> f64 {
+    if denominator > 0.0 {
+        numerator / denominator
+    } else {
+        0.0
+    }
+}
+
+impl DayTotals {
+    fn add_event(&mut self, envelope: &EventEnvelope) {
+        let event = &envelope.event;
+        let params = event.params();
+        let client = params
+            .client_id
+            .clone()
+            .unwrap_or_else(|| envelope.event_id.to_string());
+        let session_id = params.session_id.clone().unwrap_or_else(|| client.clone());
+        let is_page_view = event.event_name() == "page_view";
+        let revenue = match event {
+            Event::Purchase { value, .. } => Some(*value),
+            _ => None,
+        };
+
+        self.clients.insert(client.clone());
+        self.events += 1;
+        self.page_views += is_page_view as u32;
+        add_revenue(&mut self.revenue, revenue);
+
+        let session = self.sessions.entry(session_id).or_default();
+        session.client = client;
+        session.page_views += is_page_view as u32;
+        session.first = session.first.or(Some(envelope.timestamp));
+        session.last = Some(envelope.timestamp);
+        add_revenue(&mut session.revenue, revenue);
+        session.device = session
+            .device
+            .take()
+            .or_else(|| params.device_category.clone());
+        session.country = session.country.take().or_else(|| params.country.clone());
+        session.city = session.city.take().or_else(|| params.city.clone());
+        // Scans are in timestamp order, so the first page view is the landing page
+        if let (
+            None,
+            Event::PageView {
+                page_location,
+                page_referrer,
+                ..
+            },
+        ) = (&session.source, event)
+        {
+            session.source = Some(source_medium(page_location, page_referrer.as_deref()));
+        }
+    }
+
+    fn add_conversion(&mut self, session_id: Option<&str>) {
+        self.conversions += 1;
+        if let Some(session) = session_id.and_then(|id| self.sessions.get_mut(id)) {
+            session.conversions += 1;
+        }
+    }
+
+    fn finish(self, site_id: Uuid, date: NaiveDate, new_users: u32, timezone: Tz) -> DailyRollup {
+        let sessions = self.sessions.len() as f64;
+        let bounces = self.sessions.values().filter(|s| s.page_views <= 1).count() as f64;
+        let duration: f64 = self
+            .sessions
+            .values()
+            .filter_map(|s| Some((s.last? - s.first?).num_milliseconds() as f64 / 1000.0))
+            .sum();
+
+        let mut metrics = AggregatedMetrics {
+            date,
+            site_id,
+            users: self.clients.len() as u32,
+            new_users,
+            sessions: self.sessions.len() as u32,
+            page_views: self.page_views,
+            events: self.events,
+            bounce_rate: ratio(bounces, sessions),
+            avg_session_duration: ratio(duration, sessions),
+            pages_per_session: ratio(self.page_views as f64, sessions),
+            conversions: 0,
+            conversion_rate: 0.0,
+            revenue: self.revenue,
+        };
+        metrics.apply_conversions(self.conversions);
+
+        let mut devices: BTreeMap<String, Group> = BTreeMap::new();
+        let mut sources: BTreeMap<SourceMedium, Group> = BTreeMap::new();
+        let mut geo: BTreeMap<(String, Option<String>), Group> = BTreeMap::new();
+        for session in self.sessions.into_values() {
+            let device = session
+                .device
+                .clone()
+                .unwrap_or_else(|| NOT_SET.to_string());
+            devices.entry(device).or_default().add(&session);
+            let source = session.source.clone().unwrap_or_else(|| SourceMedium {
+                source: "(direct)".to_string(),
+                medium: "(none)".to_string(),
+                campaign: None,
+            });
+            sources.entry(source).or_default().add(&session);
+            let country = session
+                .country
+                .clone()
+                .unwrap_or_else(|| NOT_SET.to_string());
+            geo.entry((country, session.city.clone()))
+                .or_default()
+                .add(&session);
+        }
+
+        DailyRollup {
+            metrics,
+            timezone: timezone.name().to_string(),
+            devices: ranked(devices)
+                .into_iter()
+                .map(|(device_category, g)| DeviceMetrics {
+                    date,
+                    device_category,
+                    users: g.clients.len() as u32,
+                    sessions: g.sessions,
+                    bounce_rate: ratio(g.bounces as f64, g.sessions as f64),
+                    revenue: g.revenue,
+                })
+                .collect(),
+            traffic_sources: ranked(sources)
+                .into_iter()
+                .map(|(s, g)| TrafficSource {
+                    source: s.source,
+                    medium: s.medium,
+                    campaign: s.campaign,
+                    users: g.clients.len() as u32,
+                    sessions: g.sessions,
+                    conversions: g.conversions,
+                    revenue: g.revenue,
+                })
+                .collect(),
+            geo: ranked(geo)
+                .into_iter()
+                .map(|((country, city), g)| GeoData {
+                    country,
+                    city,
+                    users: g.clients.len() as u32,
+                    sessions: g.sessions,
+                    revenue: g.revenue,
+                })
+                .collect(),
+            computed_at: Utc::now(),
+        }
+    }
+}
+
+/// Builds and stores daily rollups
+pub struct RollupJob {
+    db: Database,
+    sites: Arc<SiteRegistry>,
+    lookback_days: u32,
+    /// Older days per site that received events since the last run
+    late_days: Mutex<HashMap<Uuid, BTreeSet<NaiveDate>>>,
+}
+
+impl RollupJob {
+    pub fn new(db: Database, sites: Arc<SiteRegistry>) -> Self {
+        Self {
+            db,
+            sites,
+            lookback_days: 2,
+            late_days: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Recompute this many recent local days on every run, today included
+    pub fn with_lookback_days(mut self, days: u32) -> Self {
+        self.lookback_days = days.max(1);
+        self
+    }
+
+    /// Local days recomputed on every run
+    fn recent_days(&self, tz: Tz, now: DateTime<Utc>) -> BTreeSet<NaiveDate> {
+        let today = now.with_timezone(&tz).date_naive();
+        (0..self.lookback_days as u64)
+            .filter_map(|back| today.checked_sub_days(Days::new(back)))
+            .collect()
+    }
+
+    /// Remember days outside the lookback window that just received events
+    pub fn note_events(&self, events: &[EventEnvelope]) {
+        let now = Utc::now();
+        let mut sites: HashMap<&str, Option<(Uuid, Tz, NaiveDate)>> = HashMap::new();
+        let mut late_days = self.late_days.lock().unwrap();
+        for envelope in events {
+            let site = sites.entry(&envelope.measurement_id).or_insert_with(|| {
+                let site = self
+                    .sites
+                    .site_by_measurement_id(&envelope.measurement_id)?;
+                let tz = site_timezone(&site);
+                let oldest = *self.recent_days(tz, now).first()?;
+                Some((site.id, tz, oldest))
+            });
+            if let Some((site_id, tz, oldest)) = site {
+                let day = envelope.timestamp.with_timezone(tz).date_naive();
+                if day < *oldest {
+                    late_days.entry(*site_id).or_default().insert(day);
+                }
+            }
+        }
+    }
+
+    /// Recompute recent and late days of every site, returning how many days were stored
+    pub async fn run(&self) -> Result<usize> {
+        let mut late_days = std::mem::take(&mut *self.late_days.lock().unwrap());
+        let now = Utc::now();
+        let mut stored = 0;
+        let mut failure = None;
+        for site in self.sites.sites().into_iter().filter(|s| s.active) {
+            let mut days = self.recent_days(site_timezone(&site), now);
+            let late = late_days.remove(&site.id).unwrap_or_default();
+            days.extend(late.iter().copied());
+
+            match self.rollup_days(&site, &days).await {
+                Ok(count) => stored += count,
+                Err(e) => {
+                    tracing::error!("Rollup of site {} failed: {}", site.id, e);
+                    if !late.is_empty() {
+                        self.late_days
+                            .lock()
+                            .unwrap()
+                            .entry(site.id)
+                            .or_default()
+                            .extend(late);
+                    }
+                    failure = Some(e);
+                }
+            }
+        }
+        failure.map_or(Ok(stored), Err)
+    }
+
+    /// Recompute every local day in `[start, end]` for one site
+    pub async fn backfill(&self, site_id: Uuid, start: NaiveDate, end: NaiveDate) -> Result<usize> {
+        let site = self
+            .sites
+            .site(site_id)
+            .ok_or_else(|| Error::NotFound(format!("site {}", site_id)))?;
+        let days: BTreeSet<NaiveDate> = start.iter_days().take_while(|d| *d <= end).collect();
+        self.rollup_days(&site, &days).await
+    }
+
+    async fn rollup_days(&self, site: &Site, days: &BTreeSet<NaiveDate>) -> Result<usize> {
+        let tz = site_timezone(site);
+        let days: Vec<NaiveDate> = days.iter().copied().collect();
+        let mut stored = 0;
+        for chunk in days.chunks(DAYS_PER_PASS) {
+            let rollups = self.compute(site, tz, chunk).await?;
+            save_rollups(&self.db, site.id, &rollups).await?;
+            stored += rollups.len();
+        }
+        Ok(stored)
+    }
+
+    /// Record the first day of every client seen before `before`, once per site.
+    ///
+    /// Sites rolled up before first-seen days were stored have none yet; their history is
+    /// read in full this one time.
+    async fn seed_first_seen(&self, site: &Site, tz: Tz, before: DateTime<Utc>) -> Result<()> {
+        let mut first_seen: HashMap<String, NaiveDate> = HashMap::new();
+        let engine = self.db.engine();
+        let mut events = engine.scan_events(
+            EventFilter::new()
+                .measurement_id(&site.measurement_id)
+                .until(before),
+        );
+        while let Some(envelope) = events.try_next().await? {
+            if let Some(client) = envelope.event.params().client_id.clone() {
+                let day = envelope.timestamp.with_timezone(&tz).date_naive();
+                let first = first_seen.entry(client).or_insert(day);
+                *first = (*first).min(day);
+            }
+        }
+        save_first_seen(&self.db, site.id, &first_seen).await
+    }
+
+    /// Compute rollups for sorted local `days` in one pass over events in their range.
+    ///
+    /// New users are told apart from returning ones by the site's stored first-seen days,
+    /// which are updated as clients are seen.
+    async fn compute(&self, site: &Site, tz: Tz, days: &[NaiveDate]) -> Result<Vec<DailyRollup>> {
+        let (Some(first), Some(last)) = (days.first(), days.last()) else {
+            return Ok(Vec::new());
+        };
+        let start = local_midnight(tz, *first);
+        let end = local_midnight(tz, *last + Days::new(1));
+
+        let mut totals: BTreeMap<NaiveDate, DayTotals> = days
+            .iter()
+            .map(|day| (*day, DayTotals::default()))
+            .collect();
+        let mut first_seen: HashMap<String, NaiveDate> = HashMap::new();
+
+        if !has_first_seen(&self.db, site.id).await? {
+            self.seed_first_seen(site, tz, start).await?;
+        }
+        let engine = self.db.engine();
+        let mut events = engine.scan_events(
+            EventFilter::new()
+                .measurement_id(&site.measurement_id)
+                .since(start)
+                .until(end),
+        );
+        while let Some(envelope) = events.try_next().await? {
+            let day = envelope.timestamp.with_timezone(&tz).date_naive();
+            if let Some(client) = &envelope.event.params().client_id {
+                match first_seen.get_mut(client) {
+                    Some(first) => *first = (*first).min(day),
+                    None => {
+                        first_seen.insert(client.clone(), day);
+                    }
+                }
+            }
+            if let Some(day_totals) = totals.get_mut(&day) {
+                day_totals.add_event(&envelope);
+            }
+        }
+
+        for conversion in self.db.engine().conversions(site.id, start, end).await? {
+            let day = conversion.timestamp.with_timezone(&tz).date_naive();
+            if let Some(day_totals) = totals.get_mut(&day) {
+                day_totals.add_conversion(conversion.session_id.as_deref());
+            }
+        }
+
+        // A client is new on the earliest day it was ever seen, in range or before it
+        let clients: Vec<String> = first_seen.keys().cloned().collect();
+        let stored = load_first_seen(&self.db, site.id, &clients).await?;
+        let mut earlier = HashMap::new();
+        for (client, day) in first_seen.iter_mut() {
+            match stored.get(client) {
+                Some(known) if *known <= *day => *day = *known,
+                _ => {
+                    earlier.insert(client.clone(), *day);
+                }
+            }
+        }
+        save_first_seen(&self.db, site.id, &earlier).await?;
+
+        let mut new_users: HashMap<NaiveDate, u32> = HashMap::new();
+        for day in first_seen.into_values() {
+            *new_users.entry(day).or_insert(0) += 1;
+        }
+        Ok(totals
+            .into_iter()
+            .map(|(day, day_totals)| {
+                let new_users = new_users.get(&day).copied().unwrap_or(0);
+                day_totals.finish(site.id, day, new_users, tz)
+            })
+            .collect())
+    }
+}
+
+type RollupRow<J> = (
+    NaiveDate,
+    String,
+    i64,
+    i64,
+    i64,
+    i64,
+    i64,
+    f64,
+    f64,
+    f64,
+    i64,
+    Option<f64>,
+    J,
+    J,
+    J,
+    DateTime<Utc>,
+);
+
+fn from_row<J>(
+    site_id: Uuid,
+    row: RollupRow<J>,
+    json: impl Fn(J) -> Result<serde_json::Value>,
+) -> Result<DailyRollup> {
+    let (
+        date,
+        timezone,
+        users,
+        new_users,
+        sessions,
+        page_views,
+        events,
+        bounce_rate,
+        avg_session_duration,
+        pages_per_session,
+        conversions,
+        revenue,
+        devices,
+        traffic_sources,
+        geo,
+        computed_at,
+    ) = row;
+    let mut metrics = AggregatedMetrics {
+        date,
+        site_id,
+        users: users as u32,
+        new_users: new_users as u32,
+        sessions: sessions as u32,
+        page_views: page_views as u32,
+        events: events as u64,
+        bounce_rate,
+        avg_session_duration,
+        pages_per_session,
+        conversions: 0,
+        conversion_rate: 0.0,
+        revenue,
+    };
+    metrics.apply_conversions(conversions as u32);
+    Ok(DailyRollup {
+        metrics,
+        timezone,
+        devices: serde_json::from_value(json(devices)?)?,
+        traffic_sources: serde_json::from_value(json(traffic_sources)?)?,
+        geo: serde_json::from_value(json(geo)?)?,
+        computed_at,
+    })
+}
+
+const POSTGRES_UPSERT: &str = r#"
+    INSERT INTO daily_rollups (site_id, day, timezone, users, new_users, sessions, page_views, events,
+                               bounce_rate, avg_session_duration, pages_per_session, conversions, revenue,
+                               devices, traffic_sources, geo, computed_at)
+    VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11, $12, $13, $14, $15, $16, $17)
+    ON CONFLICT (site_id, day) DO UPDATE SET
+        timezone = EXCLUDED.timezone, users = EXCLUDED.users, new_users = EXCLUDED.new_users,
+        sessions = EXCLUDED.sessions, page_views = EXCLUDED.page_views, events = EXCLUDED.events,
+        bounce_rate = EXCLUDED.bounce_rate, avg_session_duration = EXCLUDED.avg_session_duration,
+        pages_per_session = EXCLUDED.pages_per_session, conversions = EXCLUDED.conversions,
+        revenue = EXCLUDED.revenue, devices = EXCLUDED.devices, traffic_sources = EXCLUDED.traffic_sources,
+        geo = EXCLUDED.geo, computed_at = EXCLUDED.computed_at
+"#;
+
+const SELECT_COLUMNS: &str = "day, timezone, users, new_users, sessions, page_views, events, bounce_rate, \
+    avg_session_duration, pages_per_session, conversions, revenue, devices, traffic_sources, geo, computed_at";
+
+/// Insert or replace rollups, one row per (site, day)
+async fn save_rollups(db: &Database, site_id: Uuid, rollups: &[DailyRollup]) -> Result<()> {
+    match db {
+        Database::Postgres(storage) => {
+            let mut tx = storage.pool().begin().await?;
+            for rollup in rollups {
+                let m = &rollup.metrics;
+                sqlx::query(POSTGRES_UPSERT)
+                    .bind(site_id)
+                    .bind(m.date)
+                    .bind(&rollup.timezone)
+                    .bind(m.users as i64)
+                    .bind(m.new_users as i64)
+                    .bind(m.sessions as i64)
+                    .bind(m.page_views as i64)
+                    .bind(m.events as i64)
+                    .bind(m.bounce_rate)
+                    .bind(m.avg_session_duration)
+                    .bind(m.pages_per_session)
+                    .bind(m.conversions as i64)
+                    .bind(m.revenue)
+                    .bind(serde_json::to_value(&rollup.devices)?)
+                    .bind(serde_json::to_value(&rollup.traffic_sources)?)
+                    .bind(serde_json::to_value(&rollup.geo)?)
+                    .bind(rollup.computed_at)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+        Database::Sqlite(storage) => {
+            let mut tx = storage.pool().begin().await?;
+            for rollup in rollups {
+                let m = &rollup.metrics;
+                sqlx::query(&POSTGRES_UPSERT.replace('$', "?"))
+                    .bind(site_id.to_string())
+                    .bind(m.date)
+                    .bind(&rollup.timezone)
+                    .bind(m.users as i64)
+                    .bind(m.new_users as i64)
+                    .bind(m.sessions as i64)
+                    .bind(m.page_views as i64)
+                    .bind(m.events as i64)
+                    .bind(m.bounce_rate)
+                    .bind(m.avg_session_duration)
+                    .bind(m.pages_per_session)
+                    .bind(m.conversions as i64)
+                    .bind(m.revenue)
+                    .bind(serde_json::to_string(&rollup.devices)?)
+                    .bind(serde_json::to_string(&rollup.traffic_sources)?)
+                    .bind(serde_json::to_string(&rollup.geo)?)
+                    .bind(rollup.computed_at)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+        }
+        Database::Memory(storage) => storage.put_rollups(rollups.to_vec()),
+        Database::Segment(storage) => storage.put_rollups(rollups.to_vec()).await?,
+    }
+    Ok(())
+}
+
+/// Stored rollups of a site for local days in `[start, end]`, oldest first
+pub(crate) async fn load_rollups(
+    db: &Database,
+    site_id: Uuid,
+    start: NaiveDate,
+    end: NaiveDate,
+) -> Result<Vec<DailyRollup>> {
+    match db {
+        Database::Postgres(storage) => {
+            let rows: Vec<RollupRow<serde_json::Value>> = sqlx::query_as(&format!(
+                "SELECT {} FROM daily_rollups WHERE site_id = $1 AND day >= $2 AND day <= $3 ORDER BY day",
+                SELECT_COLUMNS
+            ))
+            .bind(site_id)
+            .bind(start)
+            .bind(end)
+            .fetch_all(storage.pool())
+            .await?;
+            rows.into_iter()
+                .map(|row| from_row(site_id, row, Ok))
+                .collect()
+        }
+        Database::Sqlite(storage) => {
+            let rows: Vec<RollupRow<String>> = sqlx::query_as(&format!(
+                "SELECT {} FROM daily_rollups WHERE site_id = ?1 AND day >= ?2 AND day <= ?3 ORDER BY day",
+                SELECT_COLUMNS
+            ))
+            .bind(site_id.to_string())
+            .bind(start)
+            .bind(end)
+            .fetch_all(storage.pool())
+            .await?;
+            rows.into_iter()
+                .map(|row| from_row(site_id, row, |text| Ok(serde_json::from_str(&text)?)))
+                .collect()
+        }
+        Database::Memory(storage) => Ok(storage.rollups(site_id, start, end)),
+        Database::Segment(storage) => storage.rollups(site_id, start, end).await,
+    }
+}
+
+/// Delete a site's rollups of local days before `before`, returning how many were removed;
+/// with `dry_run` only count them
+pub(crate) async fn delete_rollups(
+    db: &Database,
+    site_id: Uuid,
+    before: NaiveDate,
+    dry_run: bool,
+) -> Result<u64> {
+    if dry_run {
+        let Some(last) = before.pred_opt() else {
+            return Ok(0);
+        };
+        return Ok(load_rollups(db, site_id, NaiveDate::MIN, last).await?.len() as u64);
+    }
+    let sql = "DELETE FROM daily_rollups WHERE site_id = $1 AND day < $2";
+    match db {
+        Database::Postgres(storage) => Ok(sqlx::query(sql)
+            .bind(site_id)
+            .bind(before)
+            .execute(storage.pool())
+            .await?
+            .rows_affected()),
+        Database::Sqlite(storage) => Ok(sqlx::query(&sql.replace('$', "?"))
+            .bind(site_id.to_string())
+            .bind(before)
+            .execute(storage.pool())
+            .await?
+            .rows_affected()),
+        Database::Memory(storage) => Ok(storage.delete_rollups(site_id, before)),
+        Database::Segment(storage) => storage.delete_rollups(site_id, before).await,
+    }
+}
+
+/// Whether any first-seen day is stored for a site
+async fn has_first_seen(db: &Database, site_id: Uuid) -> Result<bool> {
+    match db {
+        Database::Postgres(storage) => Ok(sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM client_first_seen WHERE site_id = $1)",
+        )
+        .bind(site_id)
+        .fetch_one(storage.pool())
+        .await?),
+        Database::Sqlite(storage) => Ok(sqlx::query_scalar(
+            "SELECT EXISTS (SELECT 1 FROM client_first_seen WHERE site_id = ?1)",
+        )
+        .bind(site_id.to_string())
+        .fetch_one(storage.pool())
+        .await?),
+        Database::Memory(storage) => Ok(storage.has_first_seen(site_id)),
+        Database::Segment(storage) => Ok(storage.has_first_seen(site_id)),
+    }
+}
+
+/// Stored first days of the given clients of a site
+async fn load_first_seen(
+    db: &Database,
+    site_id: Uuid,
+    clients: &[String],
+) -> Result<HashMap<String, NaiveDate>> {
+    if clients.is_empty() {
+        return Ok(HashMap::new());
+    }
+    match db {
+        Database::Postgres(storage) => {
+            let rows: Vec<(String, NaiveDate)> = sqlx::query_as(
+                "SELECT client_id, first_day FROM client_first_seen WHERE site_id = $1 AND client_id = ANY($2)",
+            )
+            .bind(site_id)
+            .bind(clients)
+            .fetch_all(storage.pool())
+            .await?;
+            Ok(rows.into_iter().collect())
+        }
+        Database::Sqlite(storage) => {
+            let mut first_seen = HashMap::new();
+            for chunk in clients.chunks(FIRST_SEEN_BATCH) {
+                let mut query = sqlx::QueryBuilder::new(
+                    "SELECT client_id, first_day FROM client_first_seen WHERE site_id = ",
+                );
+                query
+                    .push_bind(site_id.to_string())
+                    .push(" AND client_id IN (");
+                let mut separated = query.separated(", ");
+                for client in chunk {
+                    separated.push_bind(client);
+                }
+                query.push(")");
+                let rows: Vec<(String, NaiveDate)> =
+                    query.build_query_as().fetch_all(storage.pool()).await?;
+                first_seen.extend(rows);
+            }
+            Ok(first_seen)
+        }
+        Database::Memory(storage) => Ok(storage.first_seen(site_id, clients)),
+        Database::Segment(storage) => storage.first_seen(site_id, clients).await,
+    }
+}
+
+/// Record first days, keeping the earlier day for clients already stored
+async fn save_first_seen(
+    db: &Database,
+    site_id: Uuid,
+    first_seen: &HashMap<String, NaiveDate>,
+) -> Result<()> {
+    if first_seen.is_empty() {
+        return Ok(());
+    }
+    match db {
+        Database::Postgres(storage) => {
+            let (clients, days): (Vec<String>, Vec<NaiveDate>) =
+                first_seen.iter().map(|(c, d)| (c.clone(), *d)).unzip();
+            sqlx::query(
+                "INSERT INTO client_first_seen (site_id, client_id, first_day) \
+                 SELECT $1, * FROM UNNEST($2::TEXT[], $3::DATE[]) \
+                 ON CONFLICT (site_id, client_id) \
+                 DO UPDATE SET first_day = LEAST(client_first_seen.first_day, EXCLUDED.first_day)",
+            )
+            .bind(site_id)
+            .bind(clients)
+            .bind(days)
+            .execute(storage.pool())
+            .await?;
+        }
+        Database::Sqlite(storage) => {
+            let mut tx = storage.pool().begin().await?;
+            for (client, day) in first_seen {
+                sqlx::query(
+                    "INSERT INTO client_first_seen (site_id, client_id, first_day) VALUES (?1, ?2, ?3) \
+                     ON CONFLICT (site_id, client_id) \
+                     DO UPDATE SET first_day = MIN(client_first_seen.first_day, excluded.first_day)",
+                )
+                .bind(site_id.to_string())
+                .bind(client)
+                .bind(day)
+                .execute(&mut *tx)
+                .await?;
+            }
+            tx.commit().await?;
+        }
+        Database::Memory(storage) => storage.put_first_seen(site_id, first_seen),
+        Database::Segment(storage) => storage.put_first_seen(site_id, first_seen).await?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::EventParams;
+    use crate::sites::NewSite;
+    use crate::storage::MemoryStorage;
+
+    fn page_view(
+        client: &str,
+        session: &str,
+        timestamp: &str,
+        location: &str,
+        device: &str,
+    ) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new(
+            "G-ROLLUP".to_string(),
+            Event::PageView {
+                page_title: "Page".to_string(),
+                page_location: location.to_string(),
+                page_referrer: None,
+                user_id: None,
+                params: EventParams {
+                    client_id: Some(client.to_string()),
+                    session_id: Some(session.to_string()),
+                    device_category: Some(device.to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        envelope.timestamp = timestamp.parse().unwrap();
+        envelope
+    }
+
+    async fn setup(timezone: &str) -> (RollupJob, Database, Site) {
+        let sites = Arc::new(SiteRegistry::in_memory());
+        let site = sites
+            .create_site(NewSite {
+                name: "Example".to_string(),
+                domain: "example.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("G-ROLLUP".to_string()),
+                timezone: Some(timezone.to_string()),
+                currency: None,
+            })
+            .unwrap();
+        let db = Database::Memory(Arc::new(MemoryStorage::new()));
+        (RollupJob::new(db.clone(), sites), db, site)
+    }
+
+    #[test]
+    fn test_source_medium() {
+        let utm = source_medium(
+            "https://example.com/?utm_source=News&utm_medium=email&utm_campaign=Fall",
+            None,
+        );
+        assert_eq!(
+            (utm.source.as_str(), utm.medium.as_str()),
+            ("news", "email")
+        );
+        assert_eq!(utm.campaign.as_deref(), Some("fall"));
+
+        let referral = source_medium(
+            "https://example.com/",
+            Some("https://www.google.com/search"),
+        );
+        assert_eq!(
+            (referral.source.as_str(), referral.medium.as_str()),
+            ("google", "organic")
+        );
+        let referral = source_medium(
+            "https://example.com/",
+            Some("https://blog.example.org/post"),
+        );
+        assert_eq!(
+            (referral.source.as_str(), referral.medium.as_str()),
+            ("blog.example.org", "referral")
+        );
+        let internal = source_medium("https://example.com/a", Some("https://example.com/b"));
+        assert_eq!(
+            (internal.source.as_str(), internal.medium.as_str()),
+            ("(direct)", "(none)")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_days_follow_site_timezone() {
+        let (job, db, site) = setup("America/Sao_Paulo").await;
+        db.engine()
+            .store_events(vec![
+                // 22:30 on March 1st in São Paulo (UTC-3)
+                page_view(
+                    "a",
+                    "s1",
+                    "2026-03-02T01:30:00Z",
+                    "https://example.com/?utm_source=news&utm_medium=email",
+                    "mobile",
+                ),
+                page_view(
+                    "a",
+                    "s1",
+                    "2026-03-02T01:35:00Z",
+                    "https://example.com/pricing",
+                    "mobile",
+                ),
+                page_view(
+                    "b",
+                    "s2",
+                    "2026-03-02T12:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+            ])
+            .await
+            .unwrap();
+
+        let day = |d: &str| d.parse::<NaiveDate>().unwrap();
+        assert_eq!(
+            job.backfill(site.id, day("2026-03-01"), day("2026-03-02"))
+                .await
+                .unwrap(),
+            2
+        );
+        let rollups = load_rollups(&db, site.id, day("2026-03-01"), day("2026-03-02"))
+            .await
+            .unwrap();
+
+        let first = &rollups[0];
+        assert_eq!(first.timezone, "America/Sao_Paulo");
+        assert_eq!(
+            (
+                first.metrics.users,
+                first.metrics.sessions,
+                first.metrics.page_views
+            ),
+            (1, 1, 2)
+        );
+        assert_eq!(first.metrics.avg_session_duration, 300.0);
+        assert_eq!(first.devices[0].device_category, "mobile");
+        assert_eq!(first.traffic_sources[0].source, "news");
+        assert_eq!(first.traffic_sources[0].medium, "email");
+        assert_eq!(first.geo[0].country, NOT_SET);
+
+        let second = &rollups[1];
+        assert_eq!((second.metrics.users, second.metrics.new_users), (1, 1));
+        assert_eq!(second.metrics.bounce_rate, 1.0);
+        assert_eq!(second.traffic_sources[0].source, "(direct)");
+    }
+
+    #[tokio::test]
+    async fn test_recompute_is_idempotent_and_picks_up_late_events() {
+        let (job, db, site) = setup("UTC").await;
+        let old_day = Utc::now().date_naive() - Days::new(10);
+        let at = |hour: u32| {
+            old_day
+                .and_hms_opt(hour, 0, 0)
+                .unwrap()
+                .and_utc()
+                .to_rfc3339()
+        };
+        db.engine()
+            .store_events(vec![page_view(
+                "a",
+                "s1",
+                &at(9),
+                "https://example.com/",
+                "desktop",
+            )])
+            .await
+            .unwrap();
+        job.backfill(site.id, old_day, old_day).await.unwrap();
+        job.backfill(site.id, old_day, old_day).await.unwrap();
+        let users =
+            |rollups: Vec<DailyRollup>| rollups.iter().map(|r| r.metrics.users).collect::<Vec<_>>();
+        assert_eq!(
+            users(load_rollups(&db, site.id, old_day, old_day).await.unwrap()),
+            vec![1]
+        );
+
+        // A late event for the old day is recomputed by the next scheduled run
+        let late = page_view("b", "s2", &at(10), "https://example.com/", "desktop");
+        db.engine().store_events(vec![late.clone()]).await.unwrap();
+        job.note_events(&[late]);
+        assert_eq!(job.run().await.unwrap(), 3);
+        assert_eq!(
+            users(load_rollups(&db, site.id, old_day, old_day).await.unwrap()),
+            vec![2]
+        );
+        assert!(job.late_days.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_new_users_across_runs() {
+        let (job, db, site) = setup("UTC").await;
+        let day = |d: &str| d.parse::<NaiveDate>().unwrap();
+        db.engine()
+            .store_events(vec![
+                page_view(
+                    "a",
+                    "s1",
+                    "2026-03-01T09:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+                page_view(
+                    "a",
+                    "s2",
+                    "2026-03-02T09:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+                page_view(
+                    "b",
+                    "s3",
+                    "2026-03-02T10:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+                page_view(
+                    "b",
+                    "s4",
+                    "2026-03-03T09:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+                page_view(
+                    "c",
+                    "s5",
+                    "2026-03-03T10:00:00Z",
+                    "https://example.com/",
+                    "desktop",
+                ),
+            ])
+            .await
+            .unwrap();
+        let new_users = |rollups: Vec<DailyRollup>| {
+            rollups
+                .iter()
+                .map(|r| r.metrics.new_users)
+                .collect::<Vec<_>>()
+        };
+
+        // The first run seeds first-seen days from earlier history
+        job.backfill(site.id, day("2026-03-02"), day("2026-03-02"))
+            .await
+            .unwrap();
+        // Later runs read only their own range
+        job.backfill(site.id, day("2026-03-03"), day("2026-03-03"))
+            .await
+            .unwrap();
+        let rollups = load_rollups(&db, site.id, day("2026-03-02"), day("2026-03-03"))
+            .await
+            .unwrap();
+        assert_eq!(new_users(rollups), vec![1, 1]);
+
+        // Recomputing a range keeps its new users
+        job.backfill(site.id, day("2026-03-01"), day("2026-03-03"))
+            .await
+            .unwrap();
+        let rollups = load_rollups(&db, site.id, day("2026-03-01"), day("2026-03-03"))
+            .await
+            .unwrap();
+        assert_eq!(new_users(rollups), vec![1, 1, 1]);
+    }
+}
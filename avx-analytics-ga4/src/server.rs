@@ -1,52 +1,208 @@
 //! HTTP server for analytics API
 
 use crate::collector::EventCollector;
-use crate::config::Config;
-use crate::error::Result;
+use crate::config::{Config, ConfigOverrides, SessionState};
+use crate::crypto::Keyring;
+use crate::enrich::{Enrichment, GeoIp};
+use crate::error::{Error, Result};
 use crate::events::{EventBatch, EventEnvelope};
+use crate::filter::HitFilter;
+use crate::funnel::FunnelQuery;
+use crate::goals::GoalEvaluator;
+use crate::health::{HealthChecker, PipelineStats};
 use crate::privacy::PrivacyFilter;
 use crate::processor::EventProcessor;
-use crate::storage::PostgresStorage;
+use crate::query::QueryEngine;
+use crate::ratelimit::RateLimiter;
+use crate::reload::{ConfigWatcher, ReloadTargets};
+use crate::replay::{Replay, ReplayCheckpoint};
+use crate::retention::{RetentionJob, PRIMARY_TARGET};
+use crate::rollup::RollupJob;
+use crate::session::Sessionizer;
+use crate::sites::{NewChannelRule, NewFunnel, NewGoal, NewSite, SiteRegistry};
+use crate::storage::{Database, FanOutStorage, RedisCache};
 use axum::{
-    extract::{Json, State},
+    extract::{ConnectInfo, Json, Path, Query, Request, State},
     http::StatusCode,
+    middleware::{self, Next},
     response::IntoResponse,
-    routing::{get, post},
+    routing::{delete, get, post},
     Router,
 };
-use std::sync::Arc;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 use tokio::sync::mpsc;
-use tower_http::cors::CorsLayer;
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
 use tower_http::trace::TraceLayer;
-use tracing::info;
+use tracing::{info, warn};
+use uuid::Uuid;
 
 pub struct AnalyticsServer {
     config: Config,
+    config_file: Option<PathBuf>,
+    overrides: ConfigOverrides,
 }
 
 impl AnalyticsServer {
     pub fn new(config: Config) -> Self {
-        Self { config }
+        Self {
+            config,
+            config_file: None,
+            overrides: ConfigOverrides::default(),
+        }
+    }
+
+    /// Where the configuration came from, so it can be reloaded at runtime
+    pub fn with_config_source(mut self, file: Option<PathBuf>, overrides: ConfigOverrides) -> Self {
+        self.config_file = file;
+        self.overrides = overrides;
+        self
     }
 
     pub async fn run(self) -> Result<()> {
-        // Initialize storage
-        let storage = Arc::new(
-            PostgresStorage::new(&self.config.database.url).await?,
+        // Initialize storage; the backend is chosen by the database URL scheme
+        let db = Database::open(&self.config).await?;
+        db.migrate().await?;
+        let mut storage = db.engine();
+        let mut databases = vec![(PRIMARY_TARGET.to_string(), db.clone())];
+
+        // Replicas receive copies of every write; reads stay on the primary
+        let mut replicas = None;
+        if !self.config.database.replicas.is_empty() {
+            let mut fanout = FanOutStorage::new(storage);
+            for replica in &self.config.database.replicas {
+                let mut config = self.config.clone();
+                config.database.url = replica.url.clone();
+                let replica_db = Database::open(&config).await?;
+                replica_db.migrate().await?;
+                fanout = fanout.with_replica(replica, replica_db.engine());
+                databases.push((replica.name.clone(), replica_db));
+            }
+            let fanout = Arc::new(fanout);
+            storage = fanout.clone();
+            replicas = Some(fanout);
+        }
+
+        let sites = Arc::new(match &self.config.sites.registry_path {
+            Some(path) => SiteRegistry::open(path)?,
+            None => SiteRegistry::in_memory(),
+        });
+
+        let rollups = Arc::new(
+            RollupJob::new(db.clone(), sites.clone())
+                .with_lookback_days(self.config.rollup.lookback_days),
         );
-        storage.init_schema().await?;
 
         // Create event processing pipeline
         let (tx, rx) = mpsc::unbounded_channel();
 
-        let privacy_filter = Arc::new(PrivacyFilter::new(self.config.privacy.clone()));
-        let collector = Arc::new(EventCollector::new(tx, privacy_filter));
+        let stats = PipelineStats::new();
+        let privacy_filter = Arc::new(
+            PrivacyFilter::new(self.config.privacy.clone()).with_sites(self.config.sites.clone()),
+        );
+        match Keyring::open(&self.config.privacy.encryption)? {
+            Some(keyring) => {
+                info!(
+                    "Encrypting sensitive fields with key {}",
+                    keyring.active_key_id()
+                );
+                privacy_filter.set_keyring(Some(keyring));
+            }
+            None if self.config.privacy.encryption_enabled => {
+                warn!("privacy.encryption_enabled is set but no keyring is configured; sensitive fields are stored in plaintext");
+            }
+            None => {}
+        }
+        let query = Arc::new(QueryEngine::new(db.clone(), sites.clone()));
+        let geoip = GeoIp::configured(&self.config.enrichment)?.map(Arc::new);
+        let rate_limiter = Arc::new(RateLimiter::new(&self.config.rate_limit));
+        let cors_origins = Arc::new(RwLock::new(self.config.server.cors_origins.clone()));
+        let watcher = ConfigWatcher::new(
+            self.config_file.clone(),
+            self.overrides.clone(),
+            self.config.clone(),
+            ReloadTargets {
+                privacy: privacy_filter.clone(),
+                rate_limiter: rate_limiter.clone(),
+                cors_origins: cors_origins.clone(),
+                geoip: geoip.clone(),
+            },
+        );
+        let enrichment = Enrichment::from_config(&self.config, sites.clone())?
+            .with_privacy(privacy_filter.clone());
+        // New events are located by the collector, before their IP is masked
+        let replay_enrichment = match &geoip {
+            Some(geoip) => enrichment.clone().with_enricher(geoip.clone()),
+            None => enrichment.clone(),
+        };
+        let replay = Arc::new(
+            Replay::new(db.clone(), sites.clone(), replay_enrichment)
+                .with_storage(storage.clone())
+                .with_checkpoint_dir(&self.config.replay.checkpoint_dir),
+        );
+        let retention = privacy_filter.clone();
+        let mut retention_job = RetentionJob::new(db, sites.clone(), privacy_filter.clone())
+            .with_rollup_retention_days(self.config.retention.rollup_retention_days)
+            .with_audit_log(&self.config.retention.audit_log);
+        for (name, replica) in databases.iter().skip(1) {
+            retention_job = retention_job.with_replica(name, replica.clone());
+        }
+        let retention_job = Arc::new(retention_job);
+        let hit_filter = HitFilter::from_config(&self.config.filter, sites.clone())?;
+        let mut collector = EventCollector::new(tx, privacy_filter)
+            .with_stats(stats.clone())
+            .with_filter(Arc::new(hit_filter));
+        if let Some(geoip) = geoip {
+            info!(
+                "Locating events with GeoIP database {}",
+                geoip.path().display()
+            );
+            collector = collector.with_geoip(geoip);
+        }
+        let collector = Arc::new(collector);
+
+        let redis = Arc::new(
+            RedisCache::new(&self.config.redis.url)?
+                .with_ttl_cache(self.config.redis.ttl_cache)
+                .with_ttl_session(self.config.redis.ttl_session),
+        );
 
         // Start event processor
-        let processor = EventProcessor::new(
-            rx,
-            storage.clone(),
-            self.config.storage.batch_size,
+        let processor = EventProcessor::new(rx, storage.clone(), self.config.storage.batch_size)
+            .with_enrichment(enrichment)
+            .with_goals(GoalEvaluator::new(sites.clone()))
+            .with_stats(stats.clone())
+            .with_flush_interval(Duration::from_secs(self.config.storage.flush_interval_secs))
+            .with_dead_letter_limit(self.config.storage.max_buffer_size)
+            .with_realtime(redis.clone());
+        let processor = if self.config.rollup.enabled {
+            processor.with_rollups(rollups.clone())
+        } else {
+            processor
+        };
+        let processor = if self.config.session.enabled {
+            let sessionizer = Sessionizer::new(sites.clone(), &self.config.session);
+            let sessionizer = match self.config.session.state {
+                SessionState::Memory => sessionizer,
+                SessionState::Redis => sessionizer.with_redis(redis.clone()),
+            };
+            processor.with_sessions(Arc::new(sessionizer))
+        } else {
+            processor
+        };
+
+        let health = Arc::new(
+            HealthChecker::new(
+                storage.clone(),
+                stats,
+                self.config.health.clone(),
+                self.config.storage.max_buffer_size,
+            )
+            .with_redis(redis.clone()),
         );
 
         tokio::spawn(async move {
@@ -55,21 +211,167 @@ impl AnalyticsServer {
             }
         });
 
+        let poll_interval = Duration::from_secs(self.config.server.config_poll_secs);
+        tokio::spawn(async move {
+            if let Err(e) = watcher.run(poll_interval).await {
+                tracing::error!("Config watcher error: {}", e);
+            }
+        });
+
+        // Recompute recent daily rollups, plus older days that received late events
+        if self.config.rollup.enabled {
+            let every = Duration::from_secs(self.config.rollup.interval_secs);
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(every);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = rollups.run().await {
+                        tracing::error!("Rollup error: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Purge data past its retention period
+        if self.config.retention.enabled {
+            let every = Duration::from_secs(self.config.retention.interval_secs);
+            let retention_job = retention_job.clone();
+            tokio::spawn(async move {
+                let mut ticker = tokio::time::interval(every);
+                loop {
+                    ticker.tick().await;
+                    if let Err(e) = retention_job.run(false).await {
+                        tracing::error!("Retention error: {}", e);
+                    }
+                }
+            });
+        }
+
+        // Keep events partitions ahead of incoming data and expire them with the retention
+        // period (the longest per-site retention wins, so no site loses data early) under the
+        // retention audit log, and compact segment stores; replicas get the same maintenance
+        // as the primary
+        for (target, database) in databases {
+            match database {
+                Database::Postgres(postgres) => {
+                    let partitioning = self.config.database.partitioning.clone();
+                    let retention = retention.clone();
+                    let retention_job = retention_job.clone();
+                    tokio::spawn(async move {
+                        let mut ticker = tokio::time::interval(Duration::from_secs(
+                            partitioning.maintenance_interval_secs,
+                        ));
+                        loop {
+                            ticker.tick().await;
+                            let days = retention.longest_retention_days();
+                            let report = postgres
+                                .maintain_partitions(&partitioning, days, false)
+                                .await;
+                            if let Err(e) = report.and_then(|report| {
+                                retention_job.record_partitions(&target, &report)
+                            }) {
+                                tracing::error!("Partition maintenance error: {}", e);
+                            }
+                        }
+                    });
+                }
+                Database::Segment(segments) => {
+                    let every = Duration::from_secs(self.config.storage.compaction_interval_secs);
+                    tokio::spawn(async move {
+                        let mut ticker = tokio::time::interval(every);
+                        loop {
+                            ticker.tick().await;
+                            if let Err(e) = segments.compact().await {
+                                tracing::error!("Segment compaction error: {}", e);
+                            }
+                        }
+                    });
+                }
+                Database::Sqlite(_) | Database::Memory(_) => {}
+            }
+        }
+
+        let state = AppState {
+            collector,
+            sites,
+            query,
+            health,
+            rate_limiter,
+            redis,
+            replicas,
+            replay,
+        };
+
+        let collect_routes = Router::new()
+            .route("/api/v1/collect", post(collect_event))
+            .route("/api/v1/collect/batch", post(collect_batch))
+            .route_layer(middleware::from_fn_with_state(state.clone(), rate_limit));
+
         // Build router
         let app = Router::new()
             .route("/health", get(health_check))
-            .route("/api/v1/collect", post(collect_event))
-            .route("/api/v1/collect/batch", post(collect_batch))
+            .route("/health/live", get(health_check))
+            .route("/health/ready", get(readiness_check))
+            .merge(collect_routes)
             .route("/api/v1/metrics", get(get_metrics))
-            .layer(CorsLayer::permissive())
+            .route("/api/v1/realtime/:measurement_id", get(realtime_snapshot))
+            .route(
+                "/api/v1/realtime/:measurement_id/users",
+                get(realtime_users),
+            )
+            .route("/api/v1/sites", get(list_sites).post(create_site))
+            .route(
+                "/api/v1/sites/:site_id/goals",
+                get(list_goals).post(create_goal),
+            )
+            .route("/api/v1/sites/:site_id/goals/:goal_id", delete(delete_goal))
+            .route(
+                "/api/v1/sites/:site_id/funnels",
+                get(list_funnels).post(create_funnel),
+            )
+            .route(
+                "/api/v1/sites/:site_id/funnels/:funnel_id",
+                delete(delete_funnel),
+            )
+            .route(
+                "/api/v1/sites/:site_id/funnels/:funnel_id/report",
+                post(funnel_report),
+            )
+            .route(
+                "/api/v1/sites/:site_id/channels",
+                get(list_channel_rules).post(create_channel_rule),
+            )
+            .route(
+                "/api/v1/sites/:site_id/channels/:rule_id",
+                delete(delete_channel_rule),
+            )
+            .route("/api/v1/sites/:site_id/reports/daily", get(daily_report))
+            .route(
+                "/api/v1/sites/:site_id/reports/devices",
+                get(devices_report),
+            )
+            .route(
+                "/api/v1/sites/:site_id/reports/sources",
+                get(sources_report),
+            )
+            .route("/api/v1/sites/:site_id/reports/geo", get(geo_report))
+            .route(
+                "/api/v1/sites/:site_id/replay",
+                get(replay_status).post(start_replay),
+            )
+            .layer(cors_layer(cors_origins))
             .layer(TraceLayer::new_for_http())
-            .with_state(AppState { collector });
+            .with_state(state);
 
         let addr = format!("{}:{}", self.config.server.host, self.config.server.port);
         info!("Starting server on {}", addr);
 
         let listener = tokio::net::TcpListener::bind(&addr).await?;
-        axum::serve(listener, app).await?;
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await?;
 
         Ok(())
     }
@@ -78,12 +380,59 @@ impl AnalyticsServer {
 #[derive(Clone)]
 struct AppState {
     collector: Arc<EventCollector>,
+    sites: Arc<SiteRegistry>,
+    query: Arc<QueryEngine>,
+    health: Arc<HealthChecker>,
+    rate_limiter: Arc<RateLimiter>,
+    redis: Arc<RedisCache>,
+    replicas: Option<Arc<FanOutStorage>>,
+    replay: Arc<Replay>,
+}
+
+/// CORS policy backed by the reloadable origin list; `*` allows any origin
+fn cors_layer(origins: Arc<RwLock<Vec<String>>>) -> CorsLayer {
+    CorsLayer::new()
+        .allow_origin(AllowOrigin::predicate(move |origin, _| {
+            origins
+                .read()
+                .unwrap()
+                .iter()
+                .any(|allowed| allowed == "*" || allowed.as_bytes() == origin.as_bytes())
+        }))
+        .allow_methods(Any)
+        .allow_headers(Any)
+}
+
+async fn rate_limit(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    request: Request,
+    next: Next,
+) -> axum::response::Response {
+    let ip = connect_info
+        .map(|ConnectInfo(addr)| addr.ip())
+        .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+
+    if !state.rate_limiter.check(ip) {
+        return error_response(Error::RateLimit);
+    }
+    next.run(request).await
 }
 
 async fn health_check() -> impl IntoResponse {
     (StatusCode::OK, "OK")
 }
 
+async fn readiness_check(State(state): State<AppState>) -> impl IntoResponse {
+    let report = state.health.readiness().await;
+    let status = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
 async fn collect_event(
     State(state): State<AppState>,
     Json(envelope): Json<EventEnvelope>,
@@ -116,9 +465,312 @@ async fn get_metrics(State(state): State<AppState>) -> impl IntoResponse {
         "events_collected": metrics.events_collected(),
         "batches_collected": metrics.batches_collected(),
         "errors": metrics.errors(),
+        "filtered": metrics.filtered(),
+        "storage_replicas": state.replicas.as_ref().map(|r| r.replica_stats()),
     }))
 }
 
+async fn list_sites(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.sites.sites())
+}
+
+async fn create_site(
+    State(state): State<AppState>,
+    Json(new_site): Json<NewSite>,
+) -> impl IntoResponse {
+    match state.sites.create_site(new_site) {
+        Ok(site) => (StatusCode::CREATED, Json(site)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn list_goals(State(state): State<AppState>, Path(site_id): Path<Uuid>) -> impl IntoResponse {
+    if state.sites.site(site_id).is_none() {
+        return error_response(Error::NotFound(format!("site {}", site_id)));
+    }
+    Json(state.sites.goals(site_id)).into_response()
+}
+
+async fn create_goal(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Json(new_goal): Json<NewGoal>,
+) -> impl IntoResponse {
+    match state.sites.create_goal(site_id, new_goal) {
+        Ok(goal) => (StatusCode::CREATED, Json(goal)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_goal(
+    State(state): State<AppState>,
+    Path((site_id, goal_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    match state.sites.delete_goal(site_id, goal_id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn list_funnels(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if state.sites.site(site_id).is_none() {
+        return error_response(Error::NotFound(format!("site {}", site_id)));
+    }
+    Json(state.sites.funnels(site_id)).into_response()
+}
+
+async fn create_funnel(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Json(new_funnel): Json<NewFunnel>,
+) -> impl IntoResponse {
+    match state.sites.create_funnel(site_id, new_funnel) {
+        Ok(funnel) => (StatusCode::CREATED, Json(funnel)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_funnel(
+    State(state): State<AppState>,
+    Path((site_id, funnel_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    match state.sites.delete_funnel(site_id, funnel_id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn list_channel_rules(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+) -> impl IntoResponse {
+    if state.sites.site(site_id).is_none() {
+        return error_response(Error::NotFound(format!("site {}", site_id)));
+    }
+    Json(state.sites.channel_rules(site_id)).into_response()
+}
+
+async fn create_channel_rule(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Json(new_rule): Json<NewChannelRule>,
+) -> impl IntoResponse {
+    match state.sites.create_channel_rule(site_id, new_rule) {
+        Ok(rule) => (StatusCode::CREATED, Json(rule)).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn delete_channel_rule(
+    State(state): State<AppState>,
+    Path((site_id, rule_id)): Path<(Uuid, Uuid)>,
+) -> impl IntoResponse {
+    match state.sites.delete_channel_rule(site_id, rule_id) {
+        Ok(_) => StatusCode::NO_CONTENT.into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn funnel_report(
+    State(state): State<AppState>,
+    Path((site_id, funnel_id)): Path<(Uuid, Uuid)>,
+    Json(query): Json<FunnelQuery>,
+) -> impl IntoResponse {
+    match state.query.funnel_report(site_id, funnel_id, &query).await {
+        Ok(report) => Json(report).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+/// Inclusive range of local days in the site's timezone
+#[derive(Debug, Deserialize)]
+struct ReportRange {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+impl ReportRange {
+    fn check(&self) -> Result<()> {
+        if self.start > self.end {
+            return Err(Error::Query(format!(
+                "start {} is after end {}",
+                self.start, self.end
+            )));
+        }
+        Ok(())
+    }
+}
+
+async fn daily_report(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Query(range): Query<ReportRange>,
+) -> impl IntoResponse {
+    let report = async {
+        range.check()?;
+        state
+            .query
+            .get_aggregated_metrics(site_id, range.start, range.end)
+            .await
+    };
+    match report.await {
+        Ok(metrics) => Json(metrics).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn devices_report(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Query(range): Query<ReportRange>,
+) -> impl IntoResponse {
+    let report = async {
+        range.check()?;
+        state
+            .query
+            .device_metrics(site_id, range.start, range.end)
+            .await
+    };
+    match report.await {
+        Ok(devices) => Json(devices).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn sources_report(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Query(range): Query<ReportRange>,
+) -> impl IntoResponse {
+    let report = async {
+        range.check()?;
+        state
+            .query
+            .traffic_sources(site_id, range.start, range.end)
+            .await
+    };
+    match report.await {
+        Ok(sources) => Json(sources).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn geo_report(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Query(range): Query<ReportRange>,
+) -> impl IntoResponse {
+    let report = async {
+        range.check()?;
+        state.query.geo(site_id, range.start, range.end).await
+    };
+    match report.await {
+        Ok(geo) => Json(geo).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn realtime_snapshot(
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+) -> impl IntoResponse {
+    match state.redis.realtime_snapshot(&measurement_id, 10).await {
+        Ok(snapshot) => Json(snapshot).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+async fn realtime_users(
+    State(state): State<AppState>,
+    Path(measurement_id): Path<String>,
+) -> impl IntoResponse {
+    match state.redis.get_realtime_users(&measurement_id).await {
+        Ok(users) => Json(users).into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayRequest {
+    start: NaiveDate,
+    end: NaiveDate,
+    /// Start over instead of resuming an unfinished replay of the same range
+    #[serde(default)]
+    restart: bool,
+}
+
+#[derive(Serialize)]
+struct ReplayStatus {
+    running: bool,
+    #[serde(flatten)]
+    checkpoint: Option<ReplayCheckpoint>,
+}
+
+/// Start replaying a site's events in the background
+async fn start_replay(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+    Query(request): Query<ReplayRequest>,
+) -> impl IntoResponse {
+    if request.start > request.end {
+        return error_response(Error::Query(format!(
+            "start {} is after end {}",
+            request.start, request.end
+        )));
+    }
+    if state.sites.site(site_id).is_none() {
+        return error_response(Error::NotFound(format!("site {}", site_id)));
+    }
+    if state.replay.is_running(site_id) {
+        return (StatusCode::CONFLICT, "Replay already running".to_string()).into_response();
+    }
+
+    let replay = state.replay.clone();
+    tokio::spawn(async move {
+        if let Err(e) = replay
+            .run(site_id, request.start, request.end, request.restart)
+            .await
+        {
+            tracing::error!("Replay of site {} failed: {}", site_id, e);
+        }
+    });
+    (StatusCode::ACCEPTED, "Replay started".to_string()).into_response()
+}
+
+async fn replay_status(
+    State(state): State<AppState>,
+    Path(site_id): Path<Uuid>,
+) -> impl IntoResponse {
+    let running = state.replay.is_running(site_id);
+    match state.replay.checkpoint(site_id) {
+        Ok(None) if !running => {
+            error_response(Error::NotFound(format!("replay of site {}", site_id)))
+        }
+        Ok(checkpoint) => Json(ReplayStatus {
+            running,
+            checkpoint,
+        })
+        .into_response(),
+        Err(e) => error_response(e),
+    }
+}
+
+fn error_response(err: Error) -> axum::response::Response {
+    let status = match err {
+        Error::NotFound(_) => StatusCode::NOT_FOUND,
+        Error::Conflict(_) => StatusCode::CONFLICT,
+        Error::RateLimit => StatusCode::TOO_MANY_REQUESTS,
+        Error::Config(_) | Error::InvalidEvent(_) | Error::Query(_) | Error::Validation(_) => {
+            StatusCode::BAD_REQUEST
+        }
+        _ => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, format!("Error: {}", err)).into_response()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -1,87 +1,513 @@
-//! Session management
+//! Server-side sessionization
+//!
+//! Events are grouped into sessions per client (`measurement_id` + `client_id`). The next
+//! event starts a new session when the client was inactive for longer than the timeout,
+//! when it falls on another day in the site's timezone, or when it is a page view landing
+//! from a different campaign (`utm_source`/`utm_medium`/`utm_campaign`). Events get the
+//! server session ID in `session_id`, replacing whatever the client sent.
+//!
+//! Open sessions live in the processor or in Redis; a session is closed, and handed back
+//! for storage, when it is split or once it has been idle past the timeout. In Redis an
+//! update is only saved if no other node changed the session since it was read, and is
+//! otherwise redone on the fresh copy.
 
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use crate::config::SessionConfig;
+use crate::error::{Error, Result};
+use crate::events::{Event, EventEnvelope};
+use crate::models::Session;
+use crate::rollup::site_timezone;
+use crate::sites::SiteRegistry;
+use crate::storage::RedisCache;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use chrono_tz::Tz;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
 use uuid::Uuid;
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct SessionTracker {
-    pub session_id: String,
-    pub user_id: Option<String>,
-    pub started_at: DateTime<Utc>,
-    pub last_activity: DateTime<Utc>,
-    pub page_views: u32,
-    pub events_count: u32,
-    pub is_active: bool,
+/// Longest UTM value kept on a session
+const MAX_UTM_LEN: usize = 100;
+
+/// Longest device category kept on a session
+const MAX_DEVICE_CATEGORY_LEN: usize = 50;
+
+/// Longest browser, OS, region and city kept on a session
+const MAX_CLIENT_FIELD_LEN: usize = 100;
+
+/// Times an event is folded into a session that other nodes keep changing
+const MAX_SAVE_ATTEMPTS: usize = 16;
+
+enum Store {
+    Memory(Mutex<HashMap<(String, String), Session>>),
+    Redis(Arc<RedisCache>),
+}
+
+/// UTM parameters of a landing page
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Campaign {
+    source: String,
+    medium: Option<String>,
+    name: Option<String>,
+}
+
+impl Campaign {
+    /// Campaign of a page URL tagged with at least `utm_source`
+    fn from_page(page_location: &str) -> Option<Self> {
+        let url = reqwest::Url::parse(page_location).ok()?;
+        let utm = |key: &str| {
+            url.query_pairs()
+                .find(|(k, v)| k == key && !v.is_empty())
+                .map(|(_, v)| {
+                    v.to_lowercase()
+                        .chars()
+                        .take(MAX_UTM_LEN)
+                        .collect::<String>()
+                })
+        };
+        Some(Self {
+            source: utm("utm_source")?,
+            medium: utm("utm_medium"),
+            name: utm("utm_campaign"),
+        })
+    }
+
+    fn of(session: &Session) -> Option<Self> {
+        Some(Self {
+            source: session.utm_source.clone()?,
+            medium: session.utm_medium.clone(),
+            name: session.utm_campaign.clone(),
+        })
+    }
+}
+
+/// Path of a page URL, or the raw value when it does not parse
+fn page_path(location: &str) -> String {
+    reqwest::Url::parse(location)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| location.to_string())
 }
 
-impl SessionTracker {
-    pub fn new(user_id: Option<String>) -> Self {
+/// Groups events into sessions
+pub struct Sessionizer {
+    sites: Arc<SiteRegistry>,
+    timeout: Duration,
+    store: Store,
+}
+
+impl Sessionizer {
+    /// Sessionizer keeping open sessions in memory
+    pub fn new(sites: Arc<SiteRegistry>, config: &SessionConfig) -> Self {
         Self {
-            session_id: Uuid::new_v4().to_string(),
-            user_id,
-            started_at: Utc::now(),
-            last_activity: Utc::now(),
-            page_views: 0,
-            events_count: 0,
-            is_active: true,
+            sites,
+            timeout: Duration::seconds(config.timeout_secs as i64),
+            store: Store::Memory(Mutex::new(HashMap::new())),
         }
     }
 
-    pub fn update_activity(&mut self) {
-        self.last_activity = Utc::now();
-        self.events_count += 1;
+    /// Keep open sessions in Redis, shared by every node (`session.state = "redis"`)
+    pub fn with_redis(mut self, cache: Arc<RedisCache>) -> Self {
+        self.store = Store::Redis(cache);
+        self
     }
 
-    pub fn increment_page_views(&mut self) {
-        self.page_views += 1;
-        self.update_activity();
+    /// Assign the event to its client's session, starting a new one when needed.
+    /// Returns the session closed by the split, if any. Events without a client ID
+    /// are left alone.
+    pub async fn track(&self, envelope: &mut EventEnvelope) -> Result<Vec<Session>> {
+        let Some(client_id) = envelope.event.params().client_id.clone() else {
+            return Ok(Vec::new());
+        };
+        let measurement_id = envelope.measurement_id.clone();
+        let site = self.sites.site_by_measurement_id(&measurement_id);
+        let tz = site.as_ref().map_or(Tz::UTC, site_timezone);
+
+        for _ in 0..MAX_SAVE_ATTEMPTS {
+            let (open, previous) = self.load(&measurement_id, &client_id).await?;
+            let mut closed = Vec::new();
+            let mut session = match open {
+                Some(session) if !self.splits(&session, envelope, tz) => session,
+                open => {
+                    closed.extend(open);
+                    new_session(
+                        measurement_id.clone(),
+                        client_id.clone(),
+                        site.as_ref().map(|s| s.id),
+                        envelope.timestamp,
+                    )
+                }
+            };
+            if session.session_id.is_empty() {
+                session.session_id = session.id.to_string();
+            }
+            update(&mut session, envelope);
+
+            if self.save(&session, previous.as_deref()).await? {
+                envelope.event.params_mut().session_id = Some(session.session_id.clone());
+                return Ok(closed);
+            }
+        }
+        Err(Error::Redis(format!(
+            "session of client {} on {} changed on every attempt to update it",
+            client_id, measurement_id
+        )))
     }
 
-    pub fn duration_seconds(&self) -> i64 {
-        (self.last_activity - self.started_at).num_seconds()
+    /// Close the sessions idle past the timeout at `now`
+    pub async fn close_expired(&self, now: DateTime<Utc>) -> Result<Vec<Session>> {
+        match &self.store {
+            Store::Memory(open) => {
+                let mut open = open.lock().unwrap();
+                let expired: Vec<_> = open
+                    .iter()
+                    .filter(|(_, session)| self.deadline(session) <= now)
+                    .map(|(key, _)| key.clone())
+                    .collect();
+                Ok(expired.iter().filter_map(|key| open.remove(key)).collect())
+            }
+            Store::Redis(cache) => {
+                let mut closed = Vec::new();
+                for state in cache.take_due_sessions(now).await? {
+                    let session: Session = serde_json::from_str(&state)?;
+                    // Extended by another node after it was claimed; put it back unless a
+                    // new session was started meanwhile
+                    if self.deadline(&session) > now && self.save(&session, None).await? {
+                        continue;
+                    }
+                    closed.push(session);
+                }
+                Ok(closed)
+            }
+        }
     }
 
-    pub fn is_expired(&self, timeout_minutes: i64) -> bool {
-        let elapsed = Utc::now() - self.last_activity;
-        elapsed.num_minutes() > timeout_minutes
+    /// Close every open session kept in memory, e.g. on shutdown. Sessions in Redis
+    /// stay open for the other nodes.
+    pub fn drain(&self) -> Vec<Session> {
+        match &self.store {
+            Store::Memory(open) => open
+                .lock()
+                .unwrap()
+                .drain()
+                .map(|(_, session)| session)
+                .collect(),
+            Store::Redis(_) => Vec::new(),
+        }
+    }
+
+    fn deadline(&self, session: &Session) -> DateTime<Utc> {
+        session.ended_at.unwrap_or(session.started_at) + self.timeout
+    }
+
+    /// Whether the event starts a new session rather than continuing `session`
+    fn splits(&self, session: &Session, envelope: &EventEnvelope, tz: Tz) -> bool {
+        let last = session.ended_at.unwrap_or(session.started_at);
+        let local_day = |at: DateTime<Utc>| -> NaiveDate { at.with_timezone(&tz).date_naive() };
+        if envelope.timestamp - last > self.timeout
+            || local_day(envelope.timestamp) != local_day(last)
+        {
+            return true;
+        }
+        match &envelope.event {
+            Event::PageView { page_location, .. } => Campaign::from_page(page_location)
+                .is_some_and(|campaign| Campaign::of(session) != Some(campaign)),
+            _ => false,
+        }
+    }
+
+    /// The client's open session, with the Redis document it was read from
+    async fn load(
+        &self,
+        measurement_id: &str,
+        client_id: &str,
+    ) -> Result<(Option<Session>, Option<String>)> {
+        match &self.store {
+            Store::Memory(open) => Ok((
+                open.lock()
+                    .unwrap()
+                    .get(&(measurement_id.to_string(), client_id.to_string()))
+                    .cloned(),
+                None,
+            )),
+            Store::Redis(cache) => {
+                let state = cache.load_session(measurement_id, client_id).await?;
+                let session = state.as_deref().map(serde_json::from_str).transpose()?;
+                Ok((session, state))
+            }
+        }
+    }
+
+    /// Save the session unless its Redis document is no longer `previous`
+    async fn save(&self, session: &Session, previous: Option<&str>) -> Result<bool> {
+        match &self.store {
+            Store::Memory(open) => {
+                open.lock().unwrap().insert(
+                    (session.measurement_id.clone(), session.client_id.clone()),
+                    session.clone(),
+                );
+                Ok(true)
+            }
+            Store::Redis(cache) => {
+                let state = serde_json::to_string(session)?;
+                cache
+                    .save_session(
+                        &session.measurement_id,
+                        &session.client_id,
+                        previous,
+                        &state,
+                        self.deadline(session),
+                    )
+                    .await
+            }
+        }
     }
 }
 
-pub struct SessionManager {
-    timeout_minutes: i64,
+fn new_session(
+    measurement_id: String,
+    client_id: String,
+    site_id: Option<Uuid>,
+    started_at: DateTime<Utc>,
+) -> Session {
+    Session {
+        id: Uuid::new_v4(),
+        session_id: String::new(),
+        measurement_id,
+        client_id,
+        user_id: None,
+        site_id,
+        started_at,
+        ended_at: None,
+        duration_seconds: 0,
+        page_views: 0,
+        events_count: 0,
+        is_bounce: true,
+        entry_page: None,
+        exit_page: None,
+        referrer: None,
+        utm_source: None,
+        utm_medium: None,
+        utm_campaign: None,
+        device_category: None,
+        browser: None,
+        os: None,
+        country: None,
+        region: None,
+        city: None,
+    }
 }
 
-impl SessionManager {
-    pub fn new(timeout_minutes: i64) -> Self {
-        Self { timeout_minutes }
+/// Rebuild a stored session from its time-ordered events, e.g. after they were enriched
+/// again; the session keeps its IDs
+pub(crate) fn rebuild(stored: &Session, events: &[EventEnvelope]) -> Session {
+    let started_at = events.first().map_or(stored.started_at, |e| e.timestamp);
+    let mut session = new_session(
+        stored.measurement_id.clone(),
+        stored.client_id.clone(),
+        stored.site_id,
+        started_at,
+    );
+    session.id = stored.id;
+    session.session_id = stored.session_id.clone();
+    for envelope in events {
+        update(&mut session, envelope);
     }
+    session
+}
+
+/// Fold an event into its session
+fn update(session: &mut Session, envelope: &EventEnvelope) {
+    let event = &envelope.event;
+    let params = event.params();
+    let last = session
+        .ended_at
+        .map_or(envelope.timestamp, |at| at.max(envelope.timestamp));
+    session.started_at = session.started_at.min(envelope.timestamp);
+    session.ended_at = Some(last);
+    session.duration_seconds = (last - session.started_at).num_seconds().max(0) as u32;
+    session.events_count += 1;
 
-    pub fn create_session(&self, user_id: Option<String>) -> SessionTracker {
-        SessionTracker::new(user_id)
+    if let Event::PageView {
+        page_location,
+        page_referrer,
+        ..
+    } = event
+    {
+        session.page_views += 1;
+        let page = page_path(page_location);
+        if session.entry_page.is_none() {
+            session.entry_page = Some(page.clone());
+            session.referrer = page_referrer.clone();
+            if let Some(campaign) = Campaign::from_page(page_location) {
+                session.utm_source = Some(campaign.source);
+                session.utm_medium = campaign.medium;
+                session.utm_campaign = campaign.name;
+            }
+        }
+        session.exit_page = Some(page);
     }
+    session.is_bounce = session.page_views <= 1;
 
-    pub fn should_end_session(&self, session: &SessionTracker) -> bool {
-        session.is_expired(self.timeout_minutes)
+    // Sealed user IDs are deterministic, so this stays stable across the session
+    if let Some(user_id) = event.user_id() {
+        session.user_id = Some(user_id.to_string());
+    }
+    // Client-supplied, so bounded to fit the session columns
+    let fill = |field: &mut Option<String>, value: &Option<String>, max_len: usize| {
+        if field.is_none() {
+            *field = value.as_ref().map(|v| v.chars().take(max_len).collect());
+        }
+    };
+    fill(
+        &mut session.device_category,
+        &params.device_category,
+        MAX_DEVICE_CATEGORY_LEN,
+    );
+    fill(&mut session.browser, &params.browser, MAX_CLIENT_FIELD_LEN);
+    fill(&mut session.os, &params.os, MAX_CLIENT_FIELD_LEN);
+    fill(&mut session.region, &params.region, MAX_CLIENT_FIELD_LEN);
+    fill(&mut session.city, &params.city, MAX_CLIENT_FIELD_LEN);
+    // Only ISO 3166-1 alpha-2 codes; a cut-off country name would be wrong
+    if session.country.is_none() {
+        session.country = params
+            .country
+            .as_ref()
+            .filter(|c| c.len() == 2 && c.chars().all(|c| c.is_ascii_alphabetic()))
+            .map(|c| c.to_ascii_uppercase());
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::events::EventParams;
+    use crate::sites::NewSite;
+    use chrono::TimeZone;
 
-    #[test]
-    fn test_session_creation() {
-        let session = SessionTracker::new(Some("user123".to_string()));
-        assert!(session.is_active);
-        assert_eq!(session.page_views, 0);
+    fn sessionizer() -> Sessionizer {
+        let sites = Arc::new(SiteRegistry::in_memory());
+        sites
+            .create_site(NewSite {
+                name: "Test".to_string(),
+                domain: "test.com".to_string(),
+                allowed_hostnames: Vec::new(),
+                measurement_id: Some("TEST".to_string()),
+                timezone: Some("America/Sao_Paulo".to_string()),
+                currency: None,
+            })
+            .unwrap();
+        Sessionizer::new(sites, &SessionConfig::default())
     }
 
-    #[test]
-    fn test_session_activity() {
-        let mut session = SessionTracker::new(None);
-        session.increment_page_views();
-        assert_eq!(session.page_views, 1);
-        assert_eq!(session.events_count, 1);
+    fn page_view(at: DateTime<Utc>, location: &str) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new(
+            "TEST".to_string(),
+            Event::PageView {
+                page_title: "Page".to_string(),
+                page_location: location.to_string(),
+                page_referrer: Some("https://www.google.com/".to_string()),
+                user_id: None,
+                params: EventParams {
+                    client_id: Some("client-1".to_string()),
+                    session_id: Some("from-client".to_string()),
+                    country: Some("BR".to_string()),
+                    ..Default::default()
+                },
+            },
+        );
+        envelope.timestamp = at;
+        envelope
+    }
+
+    #[tokio::test]
+    async fn test_session_fields_and_timeout() {
+        let sessionizer = sessionizer();
+        let start = Utc.with_ymd_and_hms(2024, 3, 1, 15, 0, 0).unwrap();
+
+        let mut first = page_view(start, "https://test.com/?utm_source=News&utm_medium=email");
+        assert!(sessionizer.track(&mut first).await.unwrap().is_empty());
+        let mut second = page_view(start + Duration::minutes(10), "https://test.com/pricing");
+        assert!(sessionizer.track(&mut second).await.unwrap().is_empty());
+        let session_id = first.event.params().session_id.clone().unwrap();
+        assert_ne!(session_id, "from-client");
+        assert_eq!(second.event.params().session_id.as_ref(), Some(&session_id));
+
+        // Nothing is due until the timeout has passed
+        assert!(sessionizer
+            .close_expired(start + Duration::minutes(30))
+            .await
+            .unwrap()
+            .is_empty());
+
+        let mut later = page_view(start + Duration::minutes(45), "https://test.com/");
+        let closed = sessionizer.track(&mut later).await.unwrap();
+        assert_eq!(closed.len(), 1);
+        let session = &closed[0];
+        assert_eq!(session.session_id, session_id);
+        assert_eq!((session.page_views, session.events_count), (2, 2));
+        assert_eq!(session.duration_seconds, 600);
+        assert!(!session.is_bounce);
+        assert_eq!(session.entry_page.as_deref(), Some("/"));
+        assert_eq!(session.exit_page.as_deref(), Some("/pricing"));
+        assert_eq!(session.referrer.as_deref(), Some("https://www.google.com/"));
+        assert_eq!(session.utm_source.as_deref(), Some("news"));
+        assert_eq!(session.utm_medium.as_deref(), Some("email"));
+        assert_eq!(session.country.as_deref(), Some("BR"));
+        assert!(session.site_id.is_some());
+
+        let expired = sessionizer
+            .close_expired(start + Duration::hours(2))
+            .await
+            .unwrap();
+        assert_eq!(expired.len(), 1);
+        assert!(expired[0].is_bounce);
+        assert!(sessionizer.drain().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_midnight_and_campaign_splits() {
+        let sessionizer = sessionizer();
+        // 23:50 and 00:05 in São Paulo (UTC-3)
+        let evening = Utc.with_ymd_and_hms(2024, 3, 2, 2, 50, 0).unwrap();
+        let mut before = page_view(evening, "https://test.com/");
+        sessionizer.track(&mut before).await.unwrap();
+        let mut after = page_view(evening + Duration::minutes(15), "https://test.com/");
+        assert_eq!(sessionizer.track(&mut after).await.unwrap().len(), 1);
+
+        let mut tagged = page_view(
+            evening + Duration::minutes(16),
+            "https://test.com/?utm_source=ads&utm_campaign=a",
+        );
+        assert_eq!(sessionizer.track(&mut tagged).await.unwrap().len(), 1);
+        let mut same = page_view(
+            evening + Duration::minutes(17),
+            "https://test.com/x?utm_source=ads&utm_campaign=a",
+        );
+        assert!(sessionizer.track(&mut same).await.unwrap().is_empty());
+        let mut other = page_view(
+            evening + Duration::minutes(18),
+            "https://test.com/?utm_source=ads&utm_campaign=b",
+        );
+        assert_eq!(sessionizer.track(&mut other).await.unwrap().len(), 1);
+
+        let open = sessionizer.drain();
+        assert_eq!(open.len(), 1);
+        assert_eq!(open[0].utm_campaign.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn test_client_fields_are_bounded() {
+        let sessionizer = sessionizer();
+        let mut envelope = page_view(Utc::now(), "https://test.com/");
+        let params = envelope.event.params_mut();
+        params.country = Some("Brazil".to_string());
+        params.device_category = Some("d".repeat(60));
+        params.browser = Some("b".repeat(500));
+        params.city = Some("São Paulo".repeat(20));
+        sessionizer.track(&mut envelope).await.unwrap();
+
+        let session = &sessionizer.drain()[0];
+        assert_eq!(session.country, None);
+        assert_eq!(session.device_category.as_ref().unwrap().len(), 50);
+        assert_eq!(session.browser.as_ref().unwrap().len(), 100);
+        assert_eq!(session.city.as_ref().unwrap().chars().count(), 100);
     }
 }
@@ -0,0 +1,601 @@
+//! Site registry - sites with their goal, funnel and channel definitions
+
+use crate::error::{Error, Result};
+use crate::models::{ChannelRule, Funnel, FunnelStep, Goal, GoalType, Site};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::RwLock;
+use uuid::Uuid;
+
+/// Persisted registry contents
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RegistrySnapshot {
+    pub sites: Vec<Site>,
+    pub goals: Vec<Goal>,
+    #[serde(default)]
+    pub funnels: Vec<Funnel>,
+    #[serde(default)]
+    pub channel_rules: Vec<ChannelRule>,
+}
+
+/// Request body for creating a site
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewSite {
+    pub name: String,
+    pub domain: String,
+    /// Hostnames besides `domain` (and its subdomains) that may send page views
+    #[serde(default)]
+    pub allowed_hostnames: Vec<String>,
+    pub measurement_id: Option<String>,
+    pub timezone: Option<String>,
+    pub currency: Option<String>,
+}
+
+/// Request body for creating a goal
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewGoal {
+    pub name: String,
+    pub goal_type: GoalType,
+    pub value: Option<f64>,
+}
+
+/// Request body for creating a funnel
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewFunnel {
+    pub name: String,
+    pub steps: Vec<FunnelStep>,
+}
+
+/// Request body for creating a channel rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct NewChannelRule {
+    pub channel: String,
+    #[serde(default)]
+    pub priority: u32,
+    pub source: Option<String>,
+    pub medium: Option<String>,
+    pub campaign: Option<String>,
+}
+
+/// Registry of sites, goals, funnels and channel rules, optionally persisted to a JSON file
+pub struct SiteRegistry {
+    path: Option<PathBuf>,
+    state: RwLock<RegistrySnapshot>,
+}
+
+impl SiteRegistry {
+    /// Create a registry that lives only in memory
+    pub fn in_memory() -> Self {
+        Self {
+            path: None,
+            state: RwLock::new(RegistrySnapshot::default()),
+        }
+    }
+
+    /// Open a registry backed by a JSON file, creating it on first write
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let path = path.into();
+        let state = if path.exists() {
+            let data = std::fs::read(&path)?;
+            serde_json::from_slice(&data)?
+        } else {
+            RegistrySnapshot::default()
+        };
+
+        Ok(Self {
+            path: Some(path),
+            state: RwLock::new(state),
+        })
+    }
+
+    /// Copy of the current registry contents
+    pub fn snapshot(&self) -> RegistrySnapshot {
+        self.state.read().unwrap().clone()
+    }
+
+    pub fn create_site(&self, new_site: NewSite) -> Result<Site> {
+        if let Some(timezone) = &new_site.timezone {
+            timezone
+                .parse::<chrono_tz::Tz>()
+                .map_err(|_| Error::Validation(format!("Unknown timezone {:?}", timezone)))?;
+        }
+        let now = Utc::now();
+        let site = Site {
+            id: Uuid::new_v4(),
+            measurement_id: new_site
+                .measurement_id
+                .unwrap_or_else(generate_measurement_id),
+            name: new_site.name,
+            domain: new_site.domain,
+            allowed_hostnames: new_site.allowed_hostnames,
+            timezone: new_site.timezone.unwrap_or_else(|| "UTC".to_string()),
+            currency: new_site.currency.unwrap_or_else(|| "USD".to_string()),
+            created_at: now,
+            updated_at: now,
+            active: true,
+        };
+
+        self.update(|state| {
+            if state
+                .sites
+                .iter()
+                .any(|s| s.measurement_id == site.measurement_id)
+            {
+                return Err(Error::Conflict(format!(
+                    "Measurement ID {} already registered",
+                    site.measurement_id
+                )));
+            }
+            state.sites.push(site.clone());
+            Ok(())
+        })?;
+
+        Ok(site)
+    }
+
+    pub fn sites(&self) -> Vec<Site> {
+        self.state.read().unwrap().sites.clone()
+    }
+
+    pub fn site(&self, site_id: Uuid) -> Option<Site> {
+        self.state
+            .read()
+            .unwrap()
+            .sites
+            .iter()
+            .find(|s| s.id == site_id)
+            .cloned()
+    }
+
+    pub fn site_by_measurement_id(&self, measurement_id: &str) -> Option<Site> {
+        self.state
+            .read()
+            .unwrap()
+            .sites
+            .iter()
+            .find(|s| s.measurement_id == measurement_id)
+            .cloned()
+    }
+
+    pub fn create_goal(&self, site_id: Uuid, new_goal: NewGoal) -> Result<Goal> {
+        validate_goal(&new_goal)?;
+        let goal = Goal {
+            id: Uuid::new_v4(),
+            site_id,
+            name: new_goal.name,
+            goal_type: new_goal.goal_type,
+            value: new_goal.value,
+            created_at: Utc::now(),
+        };
+
+        self.update(|state| {
+            if !state.sites.iter().any(|s| s.id == site_id) {
+                return Err(Error::NotFound(format!("site {}", site_id)));
+            }
+            state.goals.push(goal.clone());
+            Ok(())
+        })?;
+
+        Ok(goal)
+    }
+
+    /// Goals configured for a site
+    pub fn goals(&self, site_id: Uuid) -> Vec<Goal> {
+        self.state
+            .read()
+            .unwrap()
+            .goals
+            .iter()
+            .filter(|g| g.site_id == site_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn delete_goal(&self, site_id: Uuid, goal_id: Uuid) -> Result<()> {
+        self.update(|state| {
+            let before = state.goals.len();
+            state
+                .goals
+                .retain(|g| !(g.site_id == site_id && g.id == goal_id));
+            if state.goals.len() == before {
+                return Err(Error::NotFound(format!("goal {}", goal_id)));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn create_funnel(&self, site_id: Uuid, new_funnel: NewFunnel) -> Result<Funnel> {
+        if new_funnel.steps.len() < 2 {
+            return Err(Error::Validation(
+                "A funnel needs at least two steps".to_string(),
+            ));
+        }
+        if let Some(step) = new_funnel
+            .steps
+            .iter()
+            .find(|s| s.event_name.is_none() && s.url_pattern.is_none())
+        {
+            return Err(Error::Validation(format!(
+                "Funnel step {:?} needs an event_name or url_pattern",
+                step.name
+            )));
+        }
+
+        let funnel = Funnel {
+            id: Uuid::new_v4(),
+            site_id,
+            name: new_funnel.name,
+            steps: new_funnel.steps,
+            created_at: Utc::now(),
+        };
+
+        self.update(|state| {
+            if !state.sites.iter().any(|s| s.id == site_id) {
+                return Err(Error::NotFound(format!("site {}", site_id)));
+            }
+            state.funnels.push(funnel.clone());
+            Ok(())
+        })?;
+
+        Ok(funnel)
+    }
+
+    /// Funnels configured for a site
+    pub fn funnels(&self, site_id: Uuid) -> Vec<Funnel> {
+        self.state
+            .read()
+            .unwrap()
+            .funnels
+            .iter()
+            .filter(|f| f.site_id == site_id)
+            .cloned()
+            .collect()
+    }
+
+    pub fn funnel(&self, site_id: Uuid, funnel_id: Uuid) -> Option<Funnel> {
+        self.state
+            .read()
+            .unwrap()
+            .funnels
+            .iter()
+            .find(|f| f.site_id == site_id && f.id == funnel_id)
+            .cloned()
+    }
+
+    pub fn delete_funnel(&self, site_id: Uuid, funnel_id: Uuid) -> Result<()> {
+        self.update(|state| {
+            let before = state.funnels.len();
+            state
+                .funnels
+                .retain(|f| !(f.site_id == site_id && f.id == funnel_id));
+            if state.funnels.len() == before {
+                return Err(Error::NotFound(format!("funnel {}", funnel_id)));
+            }
+            Ok(())
+        })
+    }
+
+    pub fn create_channel_rule(
+        &self,
+        site_id: Uuid,
+        new_rule: NewChannelRule,
+    ) -> Result<ChannelRule> {
+        let conditions = [&new_rule.source, &new_rule.medium, &new_rule.campaign];
+        if conditions.iter().all(|c| c.is_none()) {
+            return Err(Error::Validation(
+                "A channel rule needs a source, medium or campaign condition".to_string(),
+            ));
+        }
+        for pattern in conditions.into_iter().flatten() {
+            regex::Regex::new(pattern).map_err(|e| {
+                Error::Validation(format!("Invalid channel rule pattern {:?}: {}", pattern, e))
+            })?;
+        }
+
+        let rule = ChannelRule {
+            id: Uuid::new_v4(),
+            site_id,
+            channel: new_rule.channel,
+            priority: new_rule.priority,
+            source: new_rule.source,
+            medium: new_rule.medium,
+            campaign: new_rule.campaign,
+            created_at: Utc::now(),
+        };
+
+        self.update(|state| {
+            if !state.sites.iter().any(|s| s.id == site_id) {
+                return Err(Error::NotFound(format!("site {}", site_id)));
+            }
+            state.channel_rules.push(rule.clone());
+            Ok(())
+        })?;
+
+        Ok(rule)
+    }
+
+    /// Channel rules of a site, in the order they are checked
+    pub fn channel_rules(&self, site_id: Uuid) -> Vec<ChannelRule> {
+        let mut rules: Vec<ChannelRule> = self
+            .state
+            .read()
+            .unwrap()
+            .channel_rules
+            .iter()
+            .filter(|r| r.site_id == site_id)
+            .cloned()
+            .collect();
+        rules.sort_by_key(|r| (r.priority, r.created_at));
+        rules
+    }
+
+    pub fn delete_channel_rule(&self, site_id: Uuid, rule_id: Uuid) -> Result<()> {
+        self.update(|state| {
+            let before = state.channel_rules.len();
+            state
+                .channel_rules
+                .retain(|r| !(r.site_id == site_id && r.id == rule_id));
+            if state.channel_rules.len() == before {
+                return Err(Error::NotFound(format!("channel rule {}", rule_id)));
+            }
+            Ok(())
+        })
+    }
+
+    /// Add the sites, goals, funnels and channel rules of `snapshot` whose IDs are not registered yet,
+    /// returning how many were added
+    pub fn import(&self, snapshot: RegistrySnapshot) -> Result<usize> {
+        let mut added = 0;
+        self.update(|state| {
+            for site in snapshot.sites {
+                if state.sites.iter().any(|s| s.id == site.id) {
+                    continue;
+                }
+                if state
+                    .sites
+                    .iter()
+                    .any(|s| s.measurement_id == site.measurement_id)
+                {
+                    return Err(Error::Conflict(format!(
+                        "Measurement ID {} already registered",
+                        site.measurement_id
+                    )));
+                }
+                state.sites.push(site);
+                added += 1;
+            }
+            for goal in snapshot.goals {
+                if !state.goals.iter().any(|g| g.id == goal.id) {
+                    state.goals.push(goal);
+                    added += 1;
+                }
+            }
+            for funnel in snapshot.funnels {
+                if !state.funnels.iter().any(|f| f.id == funnel.id) {
+                    state.funnels.push(funnel);
+                    added += 1;
+                }
+            }
+            for rule in snapshot.channel_rules {
+                if !state.channel_rules.iter().any(|r| r.id == rule.id) {
+                    state.channel_rules.push(rule);
+                    added += 1;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(added)
+    }
+
+    /// Apply a mutation and persist the result
+    fn update<F>(&self, f: F) -> Result<()>
+    where
+        F: FnOnce(&mut RegistrySnapshot) -> Result<()>,
+    {
+        let mut state = self.state.write().unwrap();
+        let mut next = state.clone();
+        f(&mut next)?;
+
+        if let Some(path) = &self.path {
+            let tmp = path.with_extension("json.tmp");
+            std::fs::write(&tmp, serde_json::to_vec_pretty(&next)?)?;
+            std::fs::rename(&tmp, path)?;
+        }
+
+        *state = next;
+        Ok(())
+    }
+}
+
+/// Reject goals that could never match an event or session
+fn validate_goal(goal: &NewGoal) -> Result<()> {
+    if goal.name.trim().is_empty() {
+        return Err(Error::Validation("A goal needs a name".to_string()));
+    }
+    match &goal.goal_type {
+        GoalType::Event { event_name } if event_name.trim().is_empty() => {
+            return Err(Error::Validation(
+                "An event goal needs an event_name".to_string(),
+            ));
+        }
+        // URL patterns are `*` wildcards matched against the page URL or its path
+        GoalType::Url { url_pattern }
+            if url_pattern.is_empty() || url_pattern.chars().any(char::is_whitespace) =>
+        {
+            return Err(Error::Validation(format!(
+                "Invalid goal url_pattern {:?}",
+                url_pattern
+            )));
+        }
+        GoalType::Duration { min_seconds: 0 } | GoalType::PagesPerSession { min_pages: 0 } => {
+            return Err(Error::Validation(
+                "A goal threshold must be greater than zero".to_string(),
+            ));
+        }
+        _ => {}
+    }
+    if goal.value.is_some_and(|v| !v.is_finite() || v < 0.0) {
+        return Err(Error::Validation(
+            "A goal value must be a non-negative number".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Generate a GA4-style measurement ID
+pub fn generate_measurement_id() -> String {
+    format!(
+        "G-{}",
+        &Uuid::new_v4().simple().to_string().to_uppercase()[..10]
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_site() -> NewSite {
+        NewSite {
+            name: "Test Site".to_string(),
+            domain: "example.com".to_string(),
+            allowed_hostnames: Vec::new(),
+            measurement_id: Some("G-TEST".to_string()),
+            timezone: None,
+            currency: None,
+        }
+    }
+
+    #[test]
+    fn test_goal_lifecycle() {
+        let registry = SiteRegistry::in_memory();
+        let site = registry.create_site(new_site()).unwrap();
+
+        let goal = registry
+            .create_goal(
+                site.id,
+                NewGoal {
+                    name: "Signup".to_string(),
+                    goal_type: GoalType::Event {
+                        event_name: "sign_up".to_string(),
+                    },
+                    value: Some(10.0),
+                },
+            )
+            .unwrap();
+
+        assert_eq!(registry.goals(site.id).len(), 1);
+        registry.delete_goal(site.id, goal.id).unwrap();
+        assert!(registry.goals(site.id).is_empty());
+    }
+
+    #[test]
+    fn test_goal_validation() {
+        let registry = SiteRegistry::in_memory();
+        let site = registry.create_site(new_site()).unwrap();
+        let goal = |goal_type, value| NewGoal {
+            name: "Goal".to_string(),
+            goal_type,
+            value,
+        };
+
+        let invalid = [
+            goal(
+                GoalType::Event {
+                    event_name: " ".to_string(),
+                },
+                None,
+            ),
+            goal(
+                GoalType::Url {
+                    url_pattern: String::new(),
+                },
+                None,
+            ),
+            goal(GoalType::Duration { min_seconds: 0 }, None),
+            goal(GoalType::PagesPerSession { min_pages: 3 }, Some(-1.0)),
+        ];
+        for new_goal in invalid {
+            let result = registry.create_goal(site.id, new_goal);
+            assert!(matches!(result, Err(Error::Validation(_))));
+        }
+        assert!(registry.goals(site.id).is_empty());
+
+        registry
+            .create_goal(
+                site.id,
+                goal(
+                    GoalType::Url {
+                        url_pattern: "/thanks*".to_string(),
+                    },
+                    Some(5.0),
+                ),
+            )
+            .unwrap();
+        assert_eq!(registry.goals(site.id).len(), 1);
+    }
+
+    #[test]
+    fn test_site_validation() {
+        let registry = SiteRegistry::in_memory();
+        registry.create_site(new_site()).unwrap();
+
+        let duplicate = registry.create_site(new_site());
+        assert!(matches!(duplicate, Err(Error::Conflict(_))));
+        let bad_timezone = registry.create_site(NewSite {
+            measurement_id: Some("G-OTHER".to_string()),
+            timezone: Some("Mars/Olympus".to_string()),
+            ..new_site()
+        });
+        assert!(matches!(bad_timezone, Err(Error::Validation(_))));
+        assert_eq!(registry.sites().len(), 1);
+    }
+
+    #[test]
+    fn test_funnel_validation() {
+        let registry = SiteRegistry::in_memory();
+        let site = registry.create_site(new_site()).unwrap();
+        let step = |order: u32, event_name: Option<&str>| FunnelStep {
+            order,
+            name: format!("step {}", order),
+            event_name: event_name.map(str::to_string),
+            url_pattern: None,
+        };
+        let funnel = |steps| NewFunnel {
+            name: "Checkout".to_string(),
+            steps,
+        };
+
+        let one_step = registry.create_funnel(site.id, funnel(vec![step(1, Some("add_to_cart"))]));
+        assert!(matches!(one_step, Err(Error::Validation(_))));
+        let empty_step = registry.create_funnel(
+            site.id,
+            funnel(vec![step(1, Some("add_to_cart")), step(2, None)]),
+        );
+        assert!(matches!(empty_step, Err(Error::Validation(_))));
+
+        registry
+            .create_funnel(
+                site.id,
+                funnel(vec![
+                    step(1, Some("add_to_cart")),
+                    step(2, Some("purchase")),
+                ]),
+            )
+            .unwrap();
+        assert_eq!(registry.funnels(site.id).len(), 1);
+    }
+
+    #[test]
+    fn test_registry_persistence() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sites.json");
+
+        let registry = SiteRegistry::open(&path).unwrap();
+        let site = registry.create_site(new_site()).unwrap();
+
+        let reopened = SiteRegistry::open(&path).unwrap();
+        assert!(reopened.site_by_measurement_id("G-TEST").is_some());
+        assert_eq!(reopened.site(site.id).unwrap().domain, "example.com");
+    }
+}
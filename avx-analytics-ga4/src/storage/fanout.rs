@@ -0,0 +1,435 @@
+//! Storage engine that writes to several engines
+//!
+//! Used to dual-write during migrations, e.g. PostgreSQL plus a local segment archive, or
+//! an old and a new schema. Every batch goes to the primary and to each replica
+//! concurrently; reads are served by the primary alone.
+//!
+//! A failing `required` replica fails the batch, so the processor retries it everywhere
+//! (engines ignore or replace IDs they already hold). A failing `best_effort` replica keeps
+//! the events in a bounded retry buffer and receives them again with the next batch.
+
+use super::{Capabilities, EventFilter, ExpiryFilter, StorageEngine};
+use crate::config::{ReplicaConfig, WritePolicy};
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::future::{join_all, BoxFuture};
+use futures::stream::BoxStream;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use uuid::Uuid;
+
+/// Write counters of one replica
+#[derive(Debug, Clone, Serialize)]
+pub struct ReplicaStats {
+    pub name: String,
+    pub policy: WritePolicy,
+    /// Batches written successfully
+    pub writes: u64,
+    pub errors: u64,
+    /// Events waiting in the retry buffer
+    pub pending: usize,
+    /// Events dropped because the retry buffer was full
+    pub dropped: u64,
+}
+
+struct Replica {
+    name: String,
+    engine: Arc<dyn StorageEngine>,
+    policy: WritePolicy,
+    retry_buffer: usize,
+    pending: Mutex<VecDeque<EventEnvelope>>,
+    writes: AtomicU64,
+    errors: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl Replica {
+    /// Error to return for a failed operation, or `None` when the policy absorbs it
+    fn failed(&self, operation: &str, error: Error) -> Option<Error> {
+        self.errors.fetch_add(1, Ordering::Relaxed);
+        match self.policy {
+            WritePolicy::Required => Some(Error::Storage(format!(
+                "replica {} {} failed: {}",
+                self.name, operation, error
+            ))),
+            WritePolicy::BestEffort => {
+                tracing::warn!("Replica {} {} failed: {}", self.name, operation, error);
+                None
+            }
+        }
+    }
+
+    async fn store_events(&self, events: &[EventEnvelope]) -> Result<()> {
+        let batch: Vec<EventEnvelope> = match self.policy {
+            WritePolicy::Required => events.to_vec(),
+            WritePolicy::BestEffort => {
+                let mut pending = std::mem::take(&mut *self.pending.lock().unwrap());
+                pending.extend(events.iter().cloned());
+                pending.into()
+            }
+        };
+        if batch.is_empty() {
+            return Ok(());
+        }
+
+        let retry = (self.policy == WritePolicy::BestEffort).then(|| batch.clone());
+        match self.engine.store_events(batch).await {
+            Ok(()) => {
+                self.writes.fetch_add(1, Ordering::Relaxed);
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(retry) = retry {
+                    self.requeue(retry);
+                }
+                self.failed("write", e).map_or(Ok(()), Err)
+            }
+        }
+    }
+
+    /// Put failed events back ahead of any queued meanwhile, dropping the oldest beyond the limit
+    fn requeue(&self, failed: Vec<EventEnvelope>) {
+        let mut pending = self.pending.lock().unwrap();
+        let queued = std::mem::take(&mut *pending);
+        pending.extend(failed);
+        pending.extend(queued);
+
+        let excess = pending.len().saturating_sub(self.retry_buffer);
+        if excess > 0 {
+            pending.drain(..excess);
+            self.dropped.fetch_add(excess as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn stats(&self) -> ReplicaStats {
+        ReplicaStats {
+            name: self.name.clone(),
+            policy: self.policy,
+            writes: self.writes.load(Ordering::Relaxed),
+            errors: self.errors.load(Ordering::Relaxed),
+            pending: self.pending.lock().unwrap().len(),
+            dropped: self.dropped.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Forwards writes to a primary engine and any number of replicas
+pub struct FanOutStorage {
+    primary: Arc<dyn StorageEngine>,
+    replicas: Vec<Replica>,
+}
+
+impl FanOutStorage {
+    /// Fan out from `primary`, which also serves every read
+    pub fn new(primary: Arc<dyn StorageEngine>) -> Self {
+        Self {
+            primary,
+            replicas: Vec::new(),
+        }
+    }
+
+    /// Add a replica with the name, policy and retry buffer from `config`
+    pub fn with_replica(mut self, config: &ReplicaConfig, engine: Arc<dyn StorageEngine>) -> Self {
+        self.replicas.push(Replica {
+            name: config.name.clone(),
+            engine,
+            policy: config.policy,
+            retry_buffer: config.retry_buffer,
+            pending: Mutex::new(VecDeque::new()),
+            writes: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        self
+    }
+
+    /// Run `delete` on the primary and, unless it is a dry run, on every replica;
+    /// returns the primary's count
+    async fn expire<'a>(
+        &'a self,
+        filter: &ExpiryFilter,
+        delete: impl Fn(&'a dyn StorageEngine) -> BoxFuture<'a, Result<u64>>,
+    ) -> Result<u64> {
+        if filter.dry_run {
+            return delete(self.primary.as_ref()).await;
+        }
+        self.everywhere("delete", delete).await
+    }
+
+    /// Run `apply` on the primary and every replica; returns the primary's count
+    async fn everywhere<'a>(
+        &'a self,
+        operation: &'static str,
+        apply: impl Fn(&'a dyn StorageEngine) -> BoxFuture<'a, Result<u64>>,
+    ) -> Result<u64> {
+        let replicas = join_all(self.replicas.iter().map(|replica| {
+            let applied = apply(replica.engine.as_ref());
+            async move {
+                match applied.await {
+                    Ok(_) => Ok(()),
+                    Err(e) => replica.failed(operation, e).map_or(Ok(()), Err),
+                }
+            }
+        }));
+        let (applied, replicas) = tokio::join!(apply(self.primary.as_ref()), replicas);
+        let applied = applied?;
+        first_error(replicas)?;
+        Ok(applied)
+    }
+
+    /// Write counters per replica
+    pub fn replica_stats(&self) -> Vec<ReplicaStats> {
+        self.replicas.iter().map(Replica::stats).collect()
+    }
+}
+
+/// The first error among `results`
+fn first_error(results: Vec<Result<()>>) -> Result<()> {
+    results.into_iter().collect()
+}
+
+#[async_trait]
+impl StorageEngine for FanOutStorage {
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()> {
+        let replicas = join_all(self.replicas.iter().map(|r| r.store_events(&events)));
+        let (primary, replicas) = tokio::join!(self.primary.store_events(events.clone()), replicas);
+        primary?;
+        first_error(replicas)
+    }
+
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>> {
+        self.primary.get_event(id).await
+    }
+
+    /// Conversions are forwarded like events but best-effort failures are not retried
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()> {
+        let replicas = join_all(self.replicas.iter().map(|replica| {
+            let conversions = conversions.clone();
+            async move {
+                match replica.engine.store_conversions(conversions).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => replica.failed("conversion write", e).map_or(Ok(()), Err),
+                }
+            }
+        }));
+        let (primary, replicas) = tokio::join!(
+            self.primary.store_conversions(conversions.clone()),
+            replicas
+        );
+        primary?;
+        first_error(replicas)
+    }
+
+    /// Sessions are forwarded like conversions
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        let replicas = join_all(self.replicas.iter().map(|replica| {
+            let sessions = sessions.clone();
+            async move {
+                match replica.engine.store_sessions(sessions).await {
+                    Ok(()) => Ok(()),
+                    Err(e) => replica.failed("session write", e).map_or(Ok(()), Err),
+                }
+            }
+        }));
+        let (primary, replicas) =
+            tokio::join!(self.primary.store_sessions(sessions.clone()), replicas);
+        primary?;
+        first_error(replicas)
+    }
+
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>> {
+        self.primary.conversions(site_id, start, end).await
+    }
+
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>> {
+        self.primary.sessions(measurement_id, start, end).await
+    }
+
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>> {
+        self.primary.scan_events(filter)
+    }
+
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>> {
+        self.primary.count_by_event_name(filter).await
+    }
+
+    /// Delete from every engine, including events still waiting in retry buffers;
+    /// returns the primary's count
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        let replicas = join_all(self.replicas.iter().map(|replica| async move {
+            replica
+                .pending
+                .lock()
+                .unwrap()
+                .retain(|e| !filter.matches(e));
+            match replica.engine.delete_events(filter).await {
+                Ok(_) => Ok(()),
+                Err(e) => replica.failed("delete", e).map_or(Ok(()), Err),
+            }
+        }));
+        let (deleted, replicas) = tokio::join!(self.primary.delete_events(filter), replicas);
+        let deleted = deleted?;
+        first_error(replicas)?;
+        Ok(deleted)
+    }
+
+    /// Expire conversions on every engine; returns the primary's count
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire(filter, |engine| engine.delete_conversions(filter))
+            .await
+    }
+
+    /// Expire sessions on every engine; returns the primary's count
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire(filter, |engine| engine.delete_sessions(filter))
+            .await
+    }
+
+    /// Expire users on every engine; returns the primary's count
+    async fn delete_users(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire(filter, |engine| engine.delete_users(filter))
+            .await
+    }
+
+    async fn user_ids(&self) -> Result<BTreeSet<String>> {
+        self.primary.user_ids().await
+    }
+
+    /// Replace user IDs on every engine; returns the primary's count
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64> {
+        self.everywhere("replace", |engine| engine.replace_user_ids(replacements))
+            .await
+    }
+
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64> {
+        let replicas = join_all(self.replicas.iter().map(|replica| {
+            // Queued copies would otherwise reach the replica with the old contents
+            for queued in replica.pending.lock().unwrap().iter_mut() {
+                if let Some(event) = events.iter().find(|e| e.event_id == queued.event_id) {
+                    *queued = event.clone();
+                }
+            }
+            let events = events.clone();
+            async move {
+                match replica.engine.replace_events(events).await {
+                    Ok(_) => Ok(()),
+                    Err(e) => replica.failed("replace", e).map_or(Ok(()), Err),
+                }
+            }
+        }));
+        let (replaced, replicas) = tokio::join!(self.primary.replace_events(events), replicas);
+        let replaced = replaced?;
+        first_error(replicas)?;
+        Ok(replaced)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        self.primary.capabilities()
+    }
+
+    /// The primary and every required replica must be reachable
+    async fn health_check(&self) -> Result<()> {
+        self.primary.health_check().await?;
+        for replica in self
+            .replicas
+            .iter()
+            .filter(|r| r.policy == WritePolicy::Required)
+        {
+            replica
+                .engine
+                .health_check()
+                .await
+                .map_err(|e| Error::Storage(format!("replica {}: {}", replica.name, e)))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventParams};
+    use crate::storage::MemoryStorage;
+
+    fn events(n: usize) -> Vec<EventEnvelope> {
+        (0..n)
+            .map(|_| {
+                EventEnvelope::new(
+                    "G-TEST".to_string(),
+                    Event::Custom {
+                        name: "test".to_string(),
+                        params: EventParams::default(),
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn replica(name: &str, policy: WritePolicy, retry_buffer: usize) -> ReplicaConfig {
+        ReplicaConfig {
+            name: name.to_string(),
+            url: "memory://".to_string(),
+            policy,
+            retry_buffer,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_best_effort_replica_buffers_and_catches_up() {
+        let primary = Arc::new(MemoryStorage::new());
+        let archive = Arc::new(MemoryStorage::new());
+        let storage = FanOutStorage::new(primary.clone()).with_replica(
+            &replica("archive", WritePolicy::BestEffort, 3),
+            archive.clone(),
+        );
+
+        archive.set_failing(true);
+        storage.store_events(events(2)).await.unwrap();
+        storage.store_events(events(2)).await.unwrap();
+        let stats = &storage.replica_stats()[0];
+        assert_eq!((stats.errors, stats.pending, stats.dropped), (2, 3, 1));
+        assert_eq!(primary.len(), 4);
+
+        archive.set_failing(false);
+        storage.store_events(events(1)).await.unwrap();
+        let stats = &storage.replica_stats()[0];
+        assert_eq!((stats.writes, stats.pending), (1, 0));
+        assert_eq!(archive.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_required_replica_fails_the_batch() {
+        let primary = Arc::new(MemoryStorage::new());
+        let mirror = Arc::new(MemoryStorage::new());
+        let storage = FanOutStorage::new(primary.clone()).with_replica(
+            &replica("mirror", WritePolicy::Required, 10),
+            mirror.clone(),
+        );
+
+        mirror.set_failing(true);
+        let batch = events(2);
+        let id = batch[0].event_id;
+        assert!(storage.store_events(batch).await.is_err());
+        assert_eq!(storage.replica_stats()[0].pending, 0);
+
+        // Reads come from the primary, which did store the batch
+        assert!(storage.get_event(id).await.unwrap().is_some());
+        assert_eq!(storage.capabilities().backend, "memory");
+    }
+}
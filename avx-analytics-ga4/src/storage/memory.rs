@@ -0,0 +1,532 @@
+//! In-memory storage backend for tests, demos and examples
+
+use super::{Capabilities, EventFilter, ExpiryFilter, StorageEngine};
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use crate::rollup::DailyRollup;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use futures::stream::{self, BoxStream, StreamExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use uuid::Uuid;
+
+#[derive(Default)]
+struct Inner {
+    /// Events ordered by timestamp, so range scans and age eviction are cheap
+    events: BTreeMap<(DateTime<Utc>, Uuid), EventEnvelope>,
+    by_id: HashMap<Uuid, DateTime<Utc>>,
+    conversions: Vec<Conversion>,
+    sessions: BTreeMap<(DateTime<Utc>, Uuid), Session>,
+    rollups: BTreeMap<(Uuid, NaiveDate), DailyRollup>,
+    first_seen: BTreeMap<(Uuid, String), NaiveDate>,
+    /// Session IDs whose writes fail, as rows a database rejects
+    rejected_sessions: HashSet<String>,
+}
+
+/// Storage engine that keeps events in process memory.
+///
+/// Retention is bounded by event count and/or age; the oldest events are evicted first.
+#[derive(Default)]
+pub struct MemoryStorage {
+    inner: RwLock<Inner>,
+    max_events: Option<usize>,
+    max_age: Option<Duration>,
+    failing: AtomicBool,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build from a `memory://` URL, e.g. `memory://?max_events=100000&max_age_secs=86400`
+    pub fn from_url(url: &str) -> Result<Self> {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| Error::Config(format!("invalid memory URL {:?}: {}", url, e)))?;
+
+        let mut storage = Self::new();
+        for (key, value) in parsed.query_pairs() {
+            let number = value.parse::<u64>().map_err(|_| {
+                Error::Config(format!(
+                    "memory URL parameter {} must be a number, got {:?}",
+                    key, value
+                ))
+            })?;
+            storage = match key.as_ref() {
+                "max_events" => storage.with_max_events(number as usize),
+                "max_age_secs" => storage.with_max_age(Duration::seconds(number as i64)),
+                other => {
+                    return Err(Error::Config(format!(
+                        "unknown memory URL parameter {:?}",
+                        other
+                    )))
+                }
+            };
+        }
+        Ok(storage)
+    }
+
+    /// Keep at most `max_events` events
+    pub fn with_max_events(mut self, max_events: usize) -> Self {
+        self.max_events = Some(max_events);
+        self
+    }
+
+    /// Drop events older than `max_age`
+    pub fn with_max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Fail every write and health check until cleared, to exercise error handling
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::Relaxed);
+    }
+
+    /// Fail writes of any batch holding the session `session_id`, as a database rejects a
+    /// row that doesn't fit its columns
+    pub fn reject_session(&self, session_id: &str) {
+        self.inner
+            .write()
+            .unwrap()
+            .rejected_sessions
+            .insert(session_id.to_string());
+    }
+
+    fn check_available(&self) -> Result<()> {
+        if self.failing.load(Ordering::Relaxed) {
+            return Err(Error::Storage("memory storage is unavailable".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Number of stored events
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Events matching `filter`, in timestamp order
+    pub fn scan(&self, filter: &EventFilter) -> Vec<EventEnvelope> {
+        let inner = self.inner.read().unwrap();
+        let lower = filter
+            .start
+            .map(|start| (start, Uuid::nil()))
+            .unwrap_or((DateTime::<Utc>::MIN_UTC, Uuid::nil()));
+
+        inner
+            .events
+            .range(lower..)
+            .map(|(_, event)| event)
+            .take_while(|event| filter.end.is_none_or(|end| event.timestamp < end))
+            .filter(|event| filter.matches(event))
+            .take(filter.limit.unwrap_or(usize::MAX))
+            .cloned()
+            .collect()
+    }
+
+    /// Insert or replace daily rollups
+    pub fn put_rollups(&self, rollups: Vec<DailyRollup>) {
+        let mut inner = self.inner.write().unwrap();
+        for rollup in rollups {
+            inner
+                .rollups
+                .insert((rollup.metrics.site_id, rollup.metrics.date), rollup);
+        }
+    }
+
+    /// Stored rollups for a site within `[start, end]`, oldest first
+    pub fn rollups(&self, site_id: Uuid, start: NaiveDate, end: NaiveDate) -> Vec<DailyRollup> {
+        if start > end {
+            return Vec::new();
+        }
+        self.inner
+            .read()
+            .unwrap()
+            .rollups
+            .range((site_id, start)..=(site_id, end))
+            .map(|(_, rollup)| rollup.clone())
+            .collect()
+    }
+
+    /// Whether any first-seen day is stored for a site
+    pub fn has_first_seen(&self, site_id: Uuid) -> bool {
+        self.inner
+            .read()
+            .unwrap()
+            .first_seen
+            .range((site_id, String::new())..)
+            .next()
+            .is_some_and(|((site, _), _)| *site == site_id)
+    }
+
+    /// Stored first days of the given clients of a site; unknown clients are left out
+    pub fn first_seen(&self, site_id: Uuid, clients: &[String]) -> HashMap<String, NaiveDate> {
+        let inner = self.inner.read().unwrap();
+        clients
+            .iter()
+            .filter_map(|client| {
+                let day = inner.first_seen.get(&(site_id, client.clone()))?;
+                Some((client.clone(), *day))
+            })
+            .collect()
+    }
+
+    /// Record first days, keeping the earlier day when a client is already known
+    pub fn put_first_seen(&self, site_id: Uuid, first_seen: &HashMap<String, NaiveDate>) {
+        let mut inner = self.inner.write().unwrap();
+        for (client, day) in first_seen {
+            let stored = inner
+                .first_seen
+                .entry((site_id, client.clone()))
+                .or_insert(*day);
+            *stored = (*stored).min(*day);
+        }
+    }
+
+    /// Delete a site's rollups for days before `before`, returning how many were removed
+    pub fn delete_rollups(&self, site_id: Uuid, before: NaiveDate) -> u64 {
+        let mut inner = self.inner.write().unwrap();
+        let count = inner.rollups.len();
+        inner
+            .rollups
+            .retain(|(site, day), _| !(*site == site_id && *day < before));
+        (count - inner.rollups.len()) as u64
+    }
+
+    fn enforce_retention(&self, inner: &mut Inner) {
+        if let Some(max_age) = self.max_age {
+            let cutoff = Utc::now() - max_age;
+            inner.events = inner.events.split_off(&(cutoff, Uuid::nil()));
+            inner.by_id.retain(|_, ts| *ts >= cutoff);
+            inner.conversions.retain(|c| c.timestamp >= cutoff);
+            inner.sessions = inner.sessions.split_off(&(cutoff, Uuid::nil()));
+        }
+
+        if let Some(max_events) = self.max_events {
+            while inner.events.len() > max_events {
+                if let Some(((_, id), _)) = inner.events.pop_first() {
+                    inner.by_id.remove(&id);
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl StorageEngine for MemoryStorage {
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()> {
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        for event in events {
+            // Re-storing an event replaces it, matching a primary key upsert
+            if let Some(previous) = inner.by_id.insert(event.event_id, event.timestamp) {
+                inner.events.remove(&(previous, event.event_id));
+            }
+            inner
+                .events
+                .insert((event.timestamp, event.event_id), event);
+        }
+        self.enforce_retention(&mut inner);
+        Ok(())
+    }
+
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>> {
+        let inner = self.inner.read().unwrap();
+        Ok(inner
+            .by_id
+            .get(&id)
+            .and_then(|ts| inner.events.get(&(*ts, id)))
+            .cloned())
+    }
+
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()> {
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        inner.conversions.extend(conversions);
+        self.enforce_retention(&mut inner);
+        Ok(())
+    }
+
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        if let Some(rejected) = sessions
+            .iter()
+            .find(|s| inner.rejected_sessions.contains(&s.session_id))
+        {
+            return Err(Error::Storage(format!(
+                "session {} was rejected",
+                rejected.session_id
+            )));
+        }
+        for session in sessions {
+            inner
+                .sessions
+                .insert((session.started_at, session.id), session);
+        }
+        self.enforce_retention(&mut inner);
+        Ok(())
+    }
+
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>> {
+        let mut conversions: Vec<Conversion> = self
+            .inner
+            .read()
+            .unwrap()
+            .conversions
+            .iter()
+            .filter(|c| c.site_id == site_id && c.timestamp >= start && c.timestamp < end)
+            .cloned()
+            .collect();
+        conversions.sort_by_key(|c| (c.timestamp, c.id));
+        Ok(conversions)
+    }
+
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>> {
+        Ok(self
+            .inner
+            .read()
+            .unwrap()
+            .sessions
+            .range((start, Uuid::nil())..(end, Uuid::nil()))
+            .map(|(_, session)| session)
+            .filter(|s| s.measurement_id == measurement_id)
+            .cloned()
+            .collect())
+    }
+
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>> {
+        stream::iter(self.scan(&filter).into_iter().map(Ok)).boxed()
+    }
+
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>> {
+        let mut counts = BTreeMap::new();
+        for event in self
+            .inner
+            .read()
+            .unwrap()
+            .events
+            .values()
+            .filter(|e| filter.matches(e))
+        {
+            *counts
+                .entry(event.event.event_name().to_string())
+                .or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        let before = inner.events.len();
+        inner.events.retain(|_, event| !filter.matches(event));
+        let Inner { events, by_id, .. } = &mut *inner;
+        by_id.retain(|id, ts| events.contains_key(&(*ts, *id)));
+        Ok((before - inner.events.len()) as u64)
+    }
+
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64> {
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        let mut replaced = 0;
+        for event in events {
+            if let Some(stored) = inner.events.get_mut(&(event.timestamp, event.event_id)) {
+                *stored = event;
+                replaced += 1;
+            }
+        }
+        Ok(replaced)
+    }
+
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        let mut inner = self.inner.write().unwrap();
+        let count = inner.conversions.len();
+        if filter.dry_run {
+            return Ok(inner
+                .conversions
+                .iter()
+                .filter(|c| filter.matches(&c.measurement_id, c.timestamp))
+                .count() as u64);
+        }
+        self.check_available()?;
+        inner
+            .conversions
+            .retain(|c| !filter.matches(&c.measurement_id, c.timestamp));
+        Ok((count - inner.conversions.len()) as u64)
+    }
+
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        let mut inner = self.inner.write().unwrap();
+        let count = inner.sessions.len();
+        if filter.dry_run {
+            return Ok(inner
+                .sessions
+                .values()
+                .filter(|s| filter.matches(&s.measurement_id, s.started_at))
+                .count() as u64);
+        }
+        self.check_available()?;
+        inner
+            .sessions
+            .retain(|_, s| !filter.matches(&s.measurement_id, s.started_at));
+        Ok((count - inner.sessions.len()) as u64)
+    }
+
+    /// Users are not stored in memory
+    async fn delete_users(&self, _filter: &ExpiryFilter) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn user_ids(&self) -> Result<BTreeSet<String>> {
+        let inner = self.inner.read().unwrap();
+        let sessions = inner.sessions.values().map(|s| &s.user_id);
+        let conversions = inner.conversions.iter().map(|c| &c.user_id);
+        Ok(sessions.chain(conversions).flatten().cloned().collect())
+    }
+
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64> {
+        self.check_available()?;
+        let mut inner = self.inner.write().unwrap();
+        let inner = &mut *inner;
+        let sessions = inner.sessions.values_mut().map(|s| &mut s.user_id);
+        let conversions = inner.conversions.iter_mut().map(|c| &mut c.user_id);
+        let mut replaced = 0;
+        for user_id in sessions.chain(conversions).flatten() {
+            if let Some(replacement) = replacements.get(user_id) {
+                *user_id = replacement.clone();
+                replaced += 1;
+            }
+        }
+        Ok(replaced)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            backend: "memory",
+            durable: false,
+            streaming_scan: false,
+            concurrent_writers: false,
+            time_partitioned: false,
+            bounded_retention: self.max_events.is_some() || self.max_age.is_some(),
+        }
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        self.check_available()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventParams};
+
+    fn event(measurement_id: &str, age_secs: i64) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new(
+            measurement_id.to_string(),
+            Event::Custom {
+                name: "test".to_string(),
+                params: EventParams::default(),
+            },
+        );
+        envelope.timestamp = Utc::now() - Duration::seconds(age_secs);
+        envelope
+    }
+
+    #[tokio::test]
+    async fn test_store_get_and_scan() {
+        let storage = MemoryStorage::new();
+        let old = event("G-A", 300);
+        let recent = event("G-A", 10);
+        let other = event("G-B", 10);
+        let id = recent.event_id;
+        storage
+            .store_events(vec![old, recent, other])
+            .await
+            .unwrap();
+
+        assert_eq!(storage.get_event(id).await.unwrap().unwrap().event_id, id);
+        assert!(storage.get_event(Uuid::new_v4()).await.unwrap().is_none());
+
+        let filter = EventFilter::new()
+            .measurement_id("G-A")
+            .since(Utc::now() - Duration::seconds(60));
+        let found = storage.scan(&filter);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].event_id, id);
+        assert_eq!(
+            storage
+                .scan(&EventFilter::new().measurement_id("G-A"))
+                .len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retention_by_count_and_age() {
+        let storage = MemoryStorage::from_url("memory://?max_events=2&max_age_secs=120").unwrap();
+        let expired = event("G-A", 600);
+        let expired_id = expired.event_id;
+        storage
+            .store_events(vec![
+                expired,
+                event("G-A", 30),
+                event("G-A", 20),
+                event("G-A", 10),
+            ])
+            .await
+            .unwrap();
+
+        assert_eq!(storage.len(), 2);
+        assert!(storage.get_event(expired_id).await.unwrap().is_none());
+        assert!(MemoryStorage::from_url("memory://?retention=1").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_counts_and_deletes() {
+        let storage = MemoryStorage::new();
+        let mut tagged = event("G-A", 10);
+        tagged.event.params_mut().user_id = Some("u1".to_string());
+        storage
+            .store_events(vec![tagged, event("G-A", 20), event("G-B", 30)])
+            .await
+            .unwrap();
+
+        let counts = storage
+            .count_by_event_name(&EventFilter::new().measurement_id("G-A"))
+            .await
+            .unwrap();
+        assert_eq!(counts.get("test"), Some(&2));
+
+        assert!(storage.delete_events(&EventFilter::new()).await.is_err());
+        assert_eq!(
+            storage
+                .delete_events(&EventFilter::new().user_id("u1"))
+                .await
+                .unwrap(),
+            1
+        );
+        let scanned: Vec<_> = storage.scan_events(EventFilter::new()).collect().await;
+        assert_eq!(scanned.len(), 2);
+        assert!(!storage.capabilities().durable);
+    }
+}
@@ -0,0 +1,614 @@
+//! Storage engine interface and implementations
+
+mod fanout;
+mod memory;
+mod partitions;
+mod postgres;
+mod realtime;
+mod segment;
+mod session_state;
+mod sqlite;
+
+pub use fanout::{FanOutStorage, ReplicaStats};
+pub use memory::MemoryStorage;
+pub use partitions::PartitionReport;
+pub use postgres::PostgresStorage;
+pub use realtime::ActiveUsers;
+pub use segment::SegmentStorage;
+pub use sqlite::SqliteStorage;
+
+use crate::config::{Config, DatabaseConfig};
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use serde::Serialize;
+use sqlx::QueryBuilder;
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Storage engine trait
+#[async_trait]
+pub trait StorageEngine: Send + Sync {
+    /// Store a batch of events
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()>;
+
+    /// Get an event by ID
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>>;
+
+    /// Store a batch of goal conversions
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()>;
+
+    /// Store closed sessions, replacing any stored with the same session ID
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()>;
+
+    /// Stored conversions for a site within `[start, end)`, oldest first
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>>;
+
+    /// Stored sessions of a measurement ID that started within `[start, end)`, oldest first
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>>;
+
+    /// Stream events matching `filter` in timestamp order
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>>;
+
+    /// Count events matching `filter` per event name; `limit` is ignored
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>>;
+
+    /// Delete events matching `filter`, returning how many were removed.
+    ///
+    /// The filter must be bounded (see [`EventFilter::check_deletable`]), so a
+    /// default filter can never wipe the whole store.
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64>;
+
+    /// Overwrite stored events with the same ID and timestamp, returning how many were
+    /// replaced. Events that aren't stored are ignored.
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64>;
+
+    /// Delete conversions that happened before `filter.before`, returning how many were
+    /// removed (or would be, for a dry run)
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64>;
+
+    /// Delete sessions that started before `filter.before`, returning how many were removed
+    /// (or would be, for a dry run)
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64>;
+
+    /// Delete users last seen before `filter.before`, returning how many were removed (or
+    /// would be, for a dry run)
+    async fn delete_users(&self, filter: &ExpiryFilter) -> Result<u64>;
+
+    /// Distinct user IDs of stored sessions and conversions
+    async fn user_ids(&self) -> Result<BTreeSet<String>>;
+
+    /// Swap the user IDs of sessions and conversions found in `replacements` for their
+    /// replacement, e.g. after re-sealing them under another key, returning how many
+    /// records changed
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64>;
+
+    /// What this backend supports
+    fn capabilities(&self) -> Capabilities;
+
+    /// Check that the backend is reachable
+    async fn health_check(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Features of a storage backend, so callers can choose a strategy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+pub struct Capabilities {
+    pub backend: &'static str,
+    /// Data survives a process restart
+    pub durable: bool,
+    /// Scans stream rows from the database instead of materializing the result
+    pub streaming_scan: bool,
+    /// Several processes can write concurrently
+    pub concurrent_writers: bool,
+    /// The events table is partitioned by time
+    pub time_partitioned: bool,
+    /// Events may be evicted before their retention period expires
+    pub bounded_retention: bool,
+}
+
+/// Criteria for scanning stored events
+#[derive(Debug, Clone, Default)]
+pub struct EventFilter {
+    pub measurement_id: Option<String>,
+    /// Inclusive lower bound
+    pub start: Option<DateTime<Utc>>,
+    /// Exclusive upper bound
+    pub end: Option<DateTime<Utc>>,
+    pub event_name: Option<String>,
+    pub client_id: Option<String>,
+    pub user_id: Option<String>,
+    /// Other stored forms of `user_id`, such as its ciphertexts
+    pub user_id_aliases: Vec<String>,
+    pub limit: Option<usize>,
+}
+
+impl EventFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn measurement_id(mut self, id: impl Into<String>) -> Self {
+        self.measurement_id = Some(id.into());
+        self
+    }
+
+    pub fn since(mut self, start: DateTime<Utc>) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn until(mut self, end: DateTime<Utc>) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    pub fn event_name(mut self, name: impl Into<String>) -> Self {
+        self.event_name = Some(name.into());
+        self
+    }
+
+    pub fn client_id(mut self, id: impl Into<String>) -> Self {
+        self.client_id = Some(id.into());
+        self
+    }
+
+    pub fn user_id(mut self, id: impl Into<String>) -> Self {
+        self.user_id = Some(id.into());
+        self
+    }
+
+    /// Also match events storing `user_id` as any of `aliases`
+    pub fn user_id_aliases(mut self, aliases: impl IntoIterator<Item = String>) -> Self {
+        self.user_id_aliases.extend(aliases);
+        self
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// Reject filters that could delete events beyond one user, client or time window
+    pub fn check_deletable(&self) -> Result<()> {
+        if self.user_id.is_none() && self.client_id.is_none() && self.end.is_none() {
+            return Err(Error::Query(
+                "delete requires a user_id, client_id or end bound".to_string(),
+            ));
+        }
+        if self.limit.is_some() {
+            return Err(Error::Query("delete does not support a limit".to_string()));
+        }
+        Ok(())
+    }
+
+    /// Append a `WHERE` clause for every criterion except `limit`
+    fn push_where<'a, DB>(&'a self, query: &mut QueryBuilder<'a, DB>)
+    where
+        DB: sqlx::Database,
+        &'a str: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+        DateTime<Utc>: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    {
+        query.push(" WHERE 1 = 1");
+        if let Some(id) = &self.measurement_id {
+            query.push(" AND measurement_id = ").push_bind(id.as_str());
+        }
+        if let Some(start) = self.start {
+            query.push(" AND timestamp >= ").push_bind(start);
+        }
+        if let Some(end) = self.end {
+            query.push(" AND timestamp < ").push_bind(end);
+        }
+        if let Some(name) = &self.event_name {
+            query.push(" AND event_name = ").push_bind(name.as_str());
+        }
+        if let Some(id) = &self.client_id {
+            query.push(" AND client_id = ").push_bind(id.as_str());
+        }
+        if let Some(id) = &self.user_id {
+            query.push(" AND user_id IN (");
+            let mut ids = query.separated(", ");
+            for id in std::iter::once(id).chain(&self.user_id_aliases) {
+                ids.push_bind(id.as_str());
+            }
+            query.push(")");
+        }
+    }
+
+    /// Whether `event` satisfies every criterion except `limit`
+    pub fn matches(&self, event: &EventEnvelope) -> bool {
+        let params = event.event.params();
+        self.measurement_id
+            .as_ref()
+            .is_none_or(|id| *id == event.measurement_id)
+            && self.start.is_none_or(|start| event.timestamp >= start)
+            && self.end.is_none_or(|end| event.timestamp < end)
+            && self
+                .event_name
+                .as_ref()
+                .is_none_or(|name| name == event.event.event_name())
+            && self
+                .client_id
+                .as_ref()
+                .is_none_or(|id| params.client_id.as_ref() == Some(id))
+            && self.user_id.as_ref().is_none_or(|id| {
+                event.event.user_id().is_some_and(|stored| {
+                    stored == id || self.user_id_aliases.iter().any(|alias| alias == stored)
+                })
+            })
+    }
+}
+
+/// Conversions, sessions or users past their retention period
+#[derive(Debug, Clone)]
+pub struct ExpiryFilter {
+    /// Every measurement ID when `None`
+    pub measurement_id: Option<String>,
+    /// Exclusive upper bound on the conversion time, session start or last visit
+    pub before: DateTime<Utc>,
+    /// Count the matching rows instead of deleting them
+    pub dry_run: bool,
+}
+
+impl ExpiryFilter {
+    pub fn new(before: DateTime<Utc>) -> Self {
+        Self {
+            measurement_id: None,
+            before,
+            dry_run: false,
+        }
+    }
+
+    pub fn measurement_id(mut self, id: impl Into<String>) -> Self {
+        self.measurement_id = Some(id.into());
+        self
+    }
+
+    pub fn dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Whether a row of `measurement_id` dated `at` matches
+    pub fn matches(&self, measurement_id: &str, at: DateTime<Utc>) -> bool {
+        at < self.before
+            && self
+                .measurement_id
+                .as_deref()
+                .is_none_or(|id| id == measurement_id)
+    }
+
+    /// Count (dry run) or delete statement over `table`, dated by `column`
+    fn statement<'a, DB>(&'a self, table: &str, column: &str) -> QueryBuilder<'a, DB>
+    where
+        DB: sqlx::Database,
+        &'a str: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+        DateTime<Utc>: sqlx::Encode<'a, DB> + sqlx::Type<DB>,
+    {
+        let verb = if self.dry_run {
+            "SELECT COUNT(*) FROM "
+        } else {
+            "DELETE FROM "
+        };
+        let mut query = QueryBuilder::new(verb);
+        query
+            .push(table)
+            .push(" WHERE ")
+            .push(column)
+            .push(" < ")
+            .push_bind(self.before);
+        if let Some(id) = &self.measurement_id {
+            query.push(" AND measurement_id = ").push_bind(id.as_str());
+        }
+        query
+    }
+}
+
+/// Storage backends selectable through `database.url`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    /// `postgres://` or `postgresql://`
+    Postgres,
+    /// `sqlite://path/to/file.db` or `sqlite::memory:`
+    Sqlite,
+    /// `memory://`, optionally with `?max_events=N&max_age_secs=N`
+    Memory,
+    /// `segment:///path/to/dir`, local segment files
+    Segment,
+}
+
+impl Backend {
+    /// Pick the backend from the URL scheme
+    pub fn from_url(url: &str) -> Result<Self> {
+        let scheme = url.split(':').next().unwrap_or_default();
+        match scheme.to_ascii_lowercase().as_str() {
+            "postgres" | "postgresql" => Ok(Backend::Postgres),
+            "sqlite" => Ok(Backend::Sqlite),
+            "memory" => Ok(Backend::Memory),
+            "segment" => Ok(Backend::Segment),
+            _ => Err(Error::Config(format!(
+                "unsupported database URL scheme {:?} (expected postgres://, sqlite://, memory:// or segment://)",
+                scheme
+            ))),
+        }
+    }
+}
+
+/// A connected storage backend
+#[derive(Clone)]
+pub enum Database {
+    Postgres(Arc<PostgresStorage>),
+    Sqlite(Arc<SqliteStorage>),
+    Memory(Arc<MemoryStorage>),
+    Segment(Arc<SegmentStorage>),
+}
+
+impl Database {
+    /// Connect to the backend named by `config.url`
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        Ok(match Backend::from_url(&config.url)? {
+            Backend::Postgres => {
+                Database::Postgres(Arc::new(PostgresStorage::connect(config).await?))
+            }
+            Backend::Sqlite => Database::Sqlite(Arc::new(SqliteStorage::connect(config).await?)),
+            Backend::Memory => Database::Memory(Arc::new(MemoryStorage::from_url(&config.url)?)),
+            Backend::Segment => Database::Segment(Arc::new(SegmentStorage::from_url(&config.url)?)),
+        })
+    }
+
+    /// Connect using the `[database]` section, with backend options from `[storage]`
+    pub async fn open(config: &Config) -> Result<Self> {
+        match Backend::from_url(&config.database.url)? {
+            Backend::Segment => Ok(Database::Segment(Arc::new(
+                SegmentStorage::from_url(&config.database.url)?
+                    .with_compression(config.storage.compression_enabled),
+            ))),
+            _ => Self::connect(&config.database).await,
+        }
+    }
+
+    pub fn backend(&self) -> Backend {
+        match self {
+            Database::Postgres(_) => Backend::Postgres,
+            Database::Sqlite(_) => Backend::Sqlite,
+            Database::Memory(_) => Backend::Memory,
+            Database::Segment(_) => Backend::Segment,
+        }
+    }
+
+    /// Apply pending schema migrations
+    pub async fn migrate(&self) -> Result<()> {
+        crate::migrations::Migrator::new(self.clone())
+            .up(None)
+            .await?;
+        Ok(())
+    }
+
+    /// The backend as a storage engine for the event pipeline
+    pub fn engine(&self) -> Arc<dyn StorageEngine> {
+        match self {
+            Database::Postgres(storage) => storage.clone(),
+            Database::Sqlite(storage) => storage.clone(),
+            Database::Memory(storage) => storage.clone(),
+            Database::Segment(storage) => storage.clone(),
+        }
+    }
+}
+
+// Counts are INTEGER in Postgres; widen them so both backends decode `i64`
+pub(super) const SESSION_COLUMNS: &str = "id, session_id, measurement_id, site_id, client_id, user_id, started_at, ended_at, \
+    CAST(duration_seconds AS BIGINT) AS duration_seconds, CAST(page_views AS BIGINT) AS page_views, \
+    CAST(events_count AS BIGINT) AS events_count, is_bounce, landing_page, exit_page, referrer, campaign_source, \
+    campaign_medium, campaign_name, device_category, browser, os, country, region, city";
+
+/// Map a `SESSION_COLUMNS` row; `id` reads a UUID column, which SQLite stores as text
+pub(super) fn session_from_row<R>(
+    row: &R,
+    id: impl Fn(&R, &str) -> Result<Option<Uuid>>,
+) -> Result<Session>
+where
+    R: sqlx::Row,
+    for<'c> &'c str: sqlx::ColumnIndex<R>,
+    for<'r> String: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> Option<String>: sqlx::Decode<'r, R::Database>,
+    for<'r> DateTime<Utc>: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> Option<DateTime<Utc>>: sqlx::Decode<'r, R::Database>,
+    for<'r> i64: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+    for<'r> bool: sqlx::Decode<'r, R::Database> + sqlx::Type<R::Database>,
+{
+    let count = |column: &str| -> Result<u32> { Ok(row.try_get::<i64, _>(column)? as u32) };
+    Ok(Session {
+        id: id(row, "id")?.ok_or_else(|| Error::Storage("session without id".to_string()))?,
+        session_id: row.try_get("session_id")?,
+        measurement_id: row.try_get("measurement_id")?,
+        site_id: id(row, "site_id")?,
+        client_id: row.try_get("client_id")?,
+        user_id: row.try_get("user_id")?,
+        started_at: row.try_get("started_at")?,
+        ended_at: row.try_get("ended_at")?,
+        duration_seconds: count("duration_seconds")?,
+        page_views: count("page_views")?,
+        events_count: count("events_count")?,
+        is_bounce: row.try_get("is_bounce")?,
+        entry_page: row.try_get("landing_page")?,
+        exit_page: row.try_get("exit_page")?,
+        referrer: row.try_get("referrer")?,
+        utm_source: row.try_get("campaign_source")?,
+        utm_medium: row.try_get("campaign_medium")?,
+        utm_campaign: row.try_get("campaign_name")?,
+        device_category: row.try_get("device_category")?,
+        browser: row.try_get("browser")?,
+        os: row.try_get("os")?,
+        country: row.try_get("country")?,
+        region: row.try_get("region")?,
+        city: row.try_get("city")?,
+    })
+}
+
+pub(super) const CONVERSION_COLUMNS: &str =
+    "id, site_id, goal_id, conversion_name, measurement_id, event_id, \
+    client_id, user_id, session_id, conversion_value, currency, timestamp";
+
+pub(super) type ConversionRow<I> = (
+    I,
+    I,
+    I,
+    String,
+    String,
+    I,
+    Option<String>,
+    Option<String>,
+    Option<String>,
+    Option<f64>,
+    Option<String>,
+    DateTime<Utc>,
+);
+
+pub(super) fn conversion<I>(
+    row: ConversionRow<I>,
+    id: impl Fn(I) -> Result<Uuid>,
+) -> Result<Conversion> {
+    let (
+        conversion_id,
+        site_id,
+        goal_id,
+        goal_name,
+        measurement_id,
+        event_id,
+        client_id,
+        user_id,
+        session_id,
+        value,
+        currency,
+        timestamp,
+    ) = row;
+    Ok(Conversion {
+        id: id(conversion_id)?,
+        site_id: id(site_id)?,
+        goal_id: id(goal_id)?,
+        goal_name,
+        measurement_id,
+        event_id: id(event_id)?,
+        client_id,
+        user_id,
+        session_id,
+        value,
+        currency,
+        timestamp,
+    })
+}
+
+/// Redis cache for real-time data
+pub struct RedisCache {
+    client: redis::Client,
+    ttl_cache: u64,
+    ttl_session: u64,
+}
+
+impl RedisCache {
+    pub fn new(redis_url: &str) -> Result<Self> {
+        let client = redis::Client::open(redis_url)?;
+        Ok(Self {
+            client,
+            ttl_cache: 300,
+            ttl_session: 3600,
+        })
+    }
+
+    /// Seconds cached data outlives its use (`redis.ttl_cache`)
+    pub fn with_ttl_cache(mut self, seconds: u64) -> Self {
+        self.ttl_cache = seconds;
+        self
+    }
+
+    /// Seconds an open session is kept after its last event (`redis.ttl_session`)
+    pub fn with_ttl_session(mut self, seconds: u64) -> Self {
+        self.ttl_session = seconds;
+        self
+    }
+
+    pub async fn increment_counter(&self, key: &str) -> Result<i64> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let value: i64 = redis::cmd("INCR").arg(key).query_async(&mut conn).await?;
+        Ok(value)
+    }
+
+    pub async fn ping(&self) -> Result<()> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        redis::cmd("PING")
+            .query_async::<_, String>(&mut conn)
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redis_cache_creation() {
+        let result = RedisCache::new("redis://localhost:6379");
+        assert!(result.is_ok() || result.is_err()); // Just check it compiles
+    }
+
+    #[test]
+    fn test_backend_from_url() {
+        assert_eq!(
+            Backend::from_url("postgres://localhost/db").unwrap(),
+            Backend::Postgres
+        );
+        assert_eq!(
+            Backend::from_url("postgresql://localhost/db").unwrap(),
+            Backend::Postgres
+        );
+        assert_eq!(
+            Backend::from_url("sqlite://analytics.db").unwrap(),
+            Backend::Sqlite
+        );
+        assert_eq!(
+            Backend::from_url("sqlite::memory:").unwrap(),
+            Backend::Sqlite
+        );
+        assert_eq!(Backend::from_url("memory://").unwrap(), Backend::Memory);
+        assert_eq!(
+            Backend::from_url("segment:///var/lib/avila").unwrap(),
+            Backend::Segment
+        );
+        assert!(Backend::from_url("mysql://localhost/db").is_err());
+    }
+
+    #[test]
+    fn test_delete_filter_must_be_bounded() {
+        assert!(EventFilter::new().check_deletable().is_err());
+        assert!(EventFilter::new()
+            .measurement_id("G-A")
+            .check_deletable()
+            .is_err());
+        assert!(EventFilter::new()
+            .user_id("u1")
+            .limit(10)
+            .check_deletable()
+            .is_err());
+        assert!(EventFilter::new().user_id("u1").check_deletable().is_ok());
+        assert!(EventFilter::new()
+            .until(Utc::now())
+            .check_deletable()
+            .is_ok());
+    }
+}
@@ -0,0 +1,274 @@
+//! Time partitions of the PostgreSQL events table
+//!
+//! `events` is range-partitioned by `timestamp` (migration 0002). Maintenance creates the
+//! current and upcoming partitions, moves rows that landed in `events_default` into their
+//! own partition, and drops or detaches partitions older than the retention period.
+//! Dry runs only report what maintenance would do.
+//! Partitions are named `events_pYYYY_MM` (monthly) or `events_pYYYY_MM_DD` (daily).
+
+use crate::config::{PartitionConfig, PartitionInterval};
+use crate::error::Result;
+use chrono::{DateTime, Datelike, Days, Months, NaiveDate, Utc};
+use serde::Serialize;
+use sqlx::{Executor, PgPool};
+
+const PREFIX: &str = "events_p";
+
+/// UTC date range `[start, end)` covered by one partition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+struct Period {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+impl Period {
+    fn containing(interval: PartitionInterval, day: NaiveDate) -> Self {
+        match interval {
+            PartitionInterval::Daily => Self {
+                start: day,
+                end: day + Days::new(1),
+            },
+            PartitionInterval::Monthly => {
+                let start = day.with_day(1).unwrap_or(day);
+                Self {
+                    start,
+                    end: start + Months::new(1),
+                }
+            }
+        }
+    }
+
+    fn interval(&self) -> PartitionInterval {
+        if self.end - self.start == chrono::Duration::days(1) {
+            PartitionInterval::Daily
+        } else {
+            PartitionInterval::Monthly
+        }
+    }
+
+    fn next(self) -> Self {
+        Self::containing(self.interval(), self.end)
+    }
+
+    fn name(&self) -> String {
+        let suffix = match self.interval() {
+            PartitionInterval::Daily => self.start.format("%Y_%m_%d"),
+            PartitionInterval::Monthly => self.start.format("%Y_%m"),
+        };
+        format!("{}{}", PREFIX, suffix)
+    }
+
+    /// Inverse of `name`; `None` for tables this module did not create
+    fn parse(name: &str) -> Option<Self> {
+        let parts: Vec<u32> = name
+            .strip_prefix(PREFIX)?
+            .split('_')
+            .map(|p| p.parse().ok())
+            .collect::<Option<_>>()?;
+        match parts.as_slice() {
+            [year, month] => {
+                let day = NaiveDate::from_ymd_opt(*year as i32, *month, 1)?;
+                Some(Self::containing(PartitionInterval::Monthly, day))
+            }
+            [year, month, day] => {
+                let day = NaiveDate::from_ymd_opt(*year as i32, *month, *day)?;
+                Some(Self::containing(PartitionInterval::Daily, day))
+            }
+            _ => None,
+        }
+    }
+
+    fn overlaps(&self, other: &Period) -> bool {
+        self.start < other.end && other.start < self.end
+    }
+}
+
+/// What a maintenance run changed, or would change on a dry run
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct PartitionReport {
+    pub dry_run: bool,
+    pub created: Vec<String>,
+    /// Partitions dropped, or detached when `detach_expired` is set
+    pub expired: Vec<String>,
+    /// Events held by the expired partitions
+    pub expired_events: u64,
+    /// Partitions ending on or before this UTC day expire
+    pub cutoff: Option<NaiveDate>,
+}
+
+/// Periods to create: current and `premake` upcoming ones, plus any holding default-partition rows
+fn planned(
+    config: &PartitionConfig,
+    today: NaiveDate,
+    default_days: &[NaiveDate],
+    existing: &[Period],
+) -> Vec<Period> {
+    let mut period = Period::containing(config.interval, today);
+    let mut wanted = vec![period];
+    for _ in 0..config.premake {
+        period = period.next();
+        wanted.push(period);
+    }
+    wanted.extend(
+        default_days
+            .iter()
+            .map(|day| Period::containing(config.interval, *day)),
+    );
+    wanted.sort();
+    wanted.dedup();
+
+    // An interval change leaves partitions of the old size behind; never overlap them
+    wanted.retain(|p| !existing.iter().any(|e| e.overlaps(p)));
+    wanted
+}
+
+/// Create, backfill and expire partitions as of `now`
+pub(crate) async fn maintain(
+    pool: &PgPool,
+    config: &PartitionConfig,
+    retention_days: u32,
+    now: DateTime<Utc>,
+    dry_run: bool,
+) -> Result<PartitionReport> {
+    let names: Vec<(String,)> = sqlx::query_as(
+        "SELECT c.relname::text FROM pg_inherits i JOIN pg_class c ON c.oid = i.inhrelid
+         WHERE i.inhparent = 'events'::regclass",
+    )
+    .fetch_all(pool)
+    .await?;
+    let mut existing: Vec<Period> = names.iter().filter_map(|(n,)| Period::parse(n)).collect();
+
+    let default_days: Vec<(NaiveDate,)> =
+        sqlx::query_as("SELECT DISTINCT (timestamp AT TIME ZONE 'UTC')::date FROM events_default")
+            .fetch_all(pool)
+            .await?;
+    let default_days: Vec<NaiveDate> = default_days.into_iter().map(|(d,)| d).collect();
+
+    let mut report = PartitionReport {
+        dry_run,
+        ..Default::default()
+    };
+    for period in planned(config, now.date_naive(), &default_days, &existing) {
+        if !dry_run {
+            create(pool, &period).await?;
+        }
+        report.created.push(period.name());
+        existing.push(period);
+    }
+
+    let cutoff = (now - chrono::Duration::days(retention_days as i64)).date_naive();
+    report.cutoff = Some(cutoff);
+    existing.sort();
+    for period in existing.iter().filter(|p| p.end <= cutoff) {
+        let name = period.name();
+        // Partitions planned on a dry run don't exist yet and hold nothing
+        if !(dry_run && report.created.contains(&name)) {
+            let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM \"{}\"", name))
+                .fetch_one(pool)
+                .await?;
+            report.expired_events += count as u64;
+        }
+        if !dry_run {
+            let sql = if config.detach_expired {
+                format!("ALTER TABLE events DETACH PARTITION \"{}\"", name)
+            } else {
+                format!("DROP TABLE \"{}\"", name)
+            };
+            pool.execute(sql.as_str()).await?;
+            tracing::info!("Expired events partition {}", name);
+        }
+        report.expired.push(name);
+    }
+    Ok(report)
+}
+
+/// Create a partition, moving any rows for its range out of the default partition first
+async fn create(pool: &PgPool, period: &Period) -> Result<()> {
+    let name = period.name();
+    let (start, end) = (
+        format!("'{} 00:00:00+00'", period.start),
+        format!("'{} 00:00:00+00'", period.end),
+    );
+
+    let mut tx = pool.begin().await?;
+    tx.execute(format!("CREATE TABLE \"{}\" (LIKE events INCLUDING DEFAULTS)", name).as_str())
+        .await?;
+    tx.execute(
+        format!(
+            "WITH moved AS (
+                 DELETE FROM events_default WHERE timestamp >= {start} AND timestamp < {end} RETURNING *
+             )
+             INSERT INTO \"{name}\" SELECT * FROM moved"
+        )
+        .as_str(),
+    )
+    .await?;
+    tx.execute(
+        format!(
+            "ALTER TABLE events ATTACH PARTITION \"{name}\" FOR VALUES FROM ({start}) TO ({end})"
+        )
+        .as_str(),
+    )
+    .await?;
+    tx.commit().await?;
+
+    tracing::info!("Created events partition {}", name);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_period_names_round_trip() {
+        let month = Period::containing(PartitionInterval::Monthly, date("2026-02-17"));
+        assert_eq!(
+            (month.start, month.end),
+            (date("2026-02-01"), date("2026-03-01"))
+        );
+        assert_eq!(month.name(), "events_p2026_02");
+        assert_eq!(month.next().end, date("2026-04-01"));
+
+        let day = Period::containing(PartitionInterval::Daily, date("2026-12-31"));
+        assert_eq!(day.name(), "events_p2026_12_31");
+        assert_eq!(day.next().start, date("2027-01-01"));
+
+        for period in [month, day] {
+            assert_eq!(Period::parse(&period.name()), Some(period));
+        }
+        assert_eq!(Period::parse("events_default"), None);
+        assert_eq!(Period::parse("events_p2026_13"), None);
+    }
+
+    #[test]
+    fn test_plan_premakes_backfills_and_skips_overlaps() {
+        let config = PartitionConfig {
+            interval: PartitionInterval::Daily,
+            premake: 2,
+            ..Default::default()
+        };
+        // A monthly partition from before the switch to daily covers October
+        let existing = vec![Period::parse("events_p2026_10").unwrap()];
+        let plan = planned(
+            &config,
+            date("2026-10-31"),
+            &[date("2026-09-03")],
+            &existing,
+        );
+
+        let names: Vec<String> = plan.iter().map(Period::name).collect();
+        assert_eq!(
+            names,
+            vec![
+                "events_p2026_09_03",
+                "events_p2026_11_01",
+                "events_p2026_11_02"
+            ]
+        );
+    }
+}
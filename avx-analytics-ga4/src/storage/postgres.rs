@@ -0,0 +1,512 @@
+//! PostgreSQL storage backend
+
+use super::{
+    conversion, partitions, session_from_row, Capabilities, ConversionRow, EventFilter,
+    ExpiryFilter, PartitionReport, StorageEngine, CONVERSION_COLUMNS, SESSION_COLUMNS,
+};
+use crate::config::{DatabaseConfig, PartitionConfig};
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use sqlx::postgres::PgPoolOptions;
+use sqlx::{Postgres, QueryBuilder, Row};
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
+use tokio::task::JoinSet;
+use uuid::Uuid;
+
+/// Rows per `INSERT … SELECT FROM UNNEST` statement
+const INSERT_CHUNK: usize = 2_000;
+
+/// PostgreSQL storage implementation
+pub struct PostgresStorage {
+    pool: sqlx::PgPool,
+}
+
+impl PostgresStorage {
+    pub async fn new(database_url: &str) -> Result<Self> {
+        let pool = sqlx::PgPool::connect(database_url).await?;
+        Ok(Self { pool })
+    }
+
+    /// Connect with a pool sized by `max_connections`/`min_connections`/`connection_timeout`
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        let pool = PgPoolOptions::new()
+            .max_connections(config.max_connections.max(1))
+            .min_connections(config.min_connections)
+            .acquire_timeout(Duration::from_secs(config.connection_timeout))
+            .connect(&config.url)
+            .await?;
+        Ok(Self { pool })
+    }
+
+    /// Underlying connection pool, shared with the query engine
+    pub fn pool(&self) -> &sqlx::PgPool {
+        &self.pool
+    }
+
+    /// Create upcoming events partitions and expire those older than `retention_days`;
+    /// with `dry_run` only report what would change
+    pub async fn maintain_partitions(
+        &self,
+        config: &PartitionConfig,
+        retention_days: u32,
+        dry_run: bool,
+    ) -> Result<PartitionReport> {
+        partitions::maintain(&self.pool, config, retention_days, Utc::now(), dry_run).await
+    }
+
+    /// Count (dry run) or delete the rows of `table` matching `filter`, dated by `column`
+    async fn expire(&self, table: &str, column: &str, filter: &ExpiryFilter) -> Result<u64> {
+        let mut query = filter.statement::<Postgres>(table, column);
+        Ok(if filter.dry_run {
+            query
+                .build_query_scalar::<i64>()
+                .fetch_one(&self.pool)
+                .await? as u64
+        } else {
+            query.build().execute(&self.pool).await?.rows_affected()
+        })
+    }
+}
+
+/// Column-oriented copy of an event chunk, bound as one array per column
+#[derive(Default)]
+struct EventColumns {
+    ids: Vec<Uuid>,
+    measurement_ids: Vec<String>,
+    event_names: Vec<String>,
+    event_params: Vec<serde_json::Value>,
+    timestamps: Vec<DateTime<Utc>>,
+    processed: Vec<bool>,
+    client_ids: Vec<Option<String>>,
+    user_ids: Vec<Option<String>>,
+    session_ids: Vec<Option<String>>,
+    page_locations: Vec<Option<String>>,
+    values: Vec<Option<f64>>,
+    currencies: Vec<Option<String>>,
+}
+
+impl EventColumns {
+    fn from_events(events: &[EventEnvelope]) -> Result<Self> {
+        let mut columns = Self::default();
+        for envelope in events {
+            let event = &envelope.event;
+            let params = event.params();
+            columns.ids.push(envelope.event_id);
+            columns
+                .measurement_ids
+                .push(envelope.measurement_id.clone());
+            columns.event_names.push(event.event_name().to_string());
+            columns.event_params.push(serde_json::to_value(event)?);
+            columns.timestamps.push(envelope.timestamp);
+            columns.processed.push(envelope.processed);
+            columns.client_ids.push(params.client_id.clone());
+            columns.user_ids.push(event.user_id().map(str::to_string));
+            columns.session_ids.push(params.session_id.clone());
+            columns
+                .page_locations
+                .push(event.page_location().map(str::to_string));
+            columns.values.push(event.value());
+            columns
+                .currencies
+                .push(event.currency().map(str::to_string));
+        }
+        Ok(columns)
+    }
+
+    async fn insert(self, pool: &sqlx::PgPool) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO events (id, measurement_id, event_name, event_params, timestamp, processed,
+                                client_id, user_id, session_id, page_location, value, currency)
+            SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::jsonb[], $5::timestamptz[], $6::bool[],
+                                 $7::text[], $8::text[], $9::text[], $10::text[], $11::float8[], $12::text[])
+            ON CONFLICT (id, timestamp) DO NOTHING
+            "#,
+        )
+        .bind(self.ids)
+        .bind(self.measurement_ids)
+        .bind(self.event_names)
+        .bind(self.event_params)
+        .bind(self.timestamps)
+        .bind(self.processed)
+        .bind(self.client_ids)
+        .bind(self.user_ids)
+        .bind(self.session_ids)
+        .bind(self.page_locations)
+        .bind(self.values)
+        .bind(self.currencies)
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Overwrite the rows with matching `(id, timestamp)`, returning how many were updated
+    async fn update(self, pool: &sqlx::PgPool) -> Result<u64> {
+        let result = sqlx::query(
+            r#"
+            UPDATE events SET event_name = u.event_name, event_params = u.event_params,
+                processed = u.processed, client_id = u.client_id, user_id = u.user_id,
+                session_id = u.session_id, page_location = u.page_location, value = u.value,
+                currency = u.currency
+            FROM UNNEST($1::uuid[], $2::timestamptz[], $3::text[], $4::jsonb[], $5::bool[], $6::text[],
+                        $7::text[], $8::text[], $9::text[], $10::float8[], $11::text[])
+                AS u(id, timestamp, event_name, event_params, processed, client_id, user_id, session_id,
+                     page_location, value, currency)
+            WHERE events.id = u.id AND events.timestamp = u.timestamp
+            "#,
+        )
+        .bind(self.ids)
+        .bind(self.timestamps)
+        .bind(self.event_names)
+        .bind(self.event_params)
+        .bind(self.processed)
+        .bind(self.client_ids)
+        .bind(self.user_ids)
+        .bind(self.session_ids)
+        .bind(self.page_locations)
+        .bind(self.values)
+        .bind(self.currencies)
+        .execute(pool)
+        .await?;
+        Ok(result.rows_affected())
+    }
+}
+
+#[async_trait]
+impl StorageEngine for PostgresStorage {
+    /// Insert events with one multi-row `UNNEST` statement per chunk.
+    ///
+    /// Chunks run concurrently across the pool, so a batch is not atomic; inserts ignore
+    /// ids that already exist, which makes retrying a partially written batch safe.
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()> {
+        if events.len() <= INSERT_CHUNK {
+            return EventColumns::from_events(&events)?.insert(&self.pool).await;
+        }
+
+        let mut inserts = JoinSet::new();
+        for chunk in events.chunks(INSERT_CHUNK) {
+            let columns = EventColumns::from_events(chunk)?;
+            let pool = self.pool.clone();
+            inserts.spawn(async move { columns.insert(&pool).await });
+        }
+
+        let mut result = Ok(());
+        while let Some(joined) = inserts.join_next().await {
+            let inserted = joined
+                .map_err(|e| Error::Storage(format!("insert task failed: {}", e)))
+                .and_then(|r| r);
+            if result.is_ok() {
+                result = inserted;
+            }
+        }
+        result
+    }
+
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>> {
+        let row = sqlx::query_as::<_, (Uuid, String, String, serde_json::Value, chrono::DateTime<chrono::Utc>, bool)>(
+            r#"
+            SELECT id, measurement_id, event_name, event_params, timestamp, processed
+            FROM events
+            WHERE id = $1
+            "#,
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(
+            |(id, measurement_id, _, event_data, timestamp, processed)| {
+                Ok(EventEnvelope {
+                    event_id: id,
+                    measurement_id,
+                    timestamp,
+                    event: serde_json::from_value(event_data)?,
+                    processed,
+                })
+            },
+        )
+        .transpose()
+    }
+
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()> {
+        for chunk in conversions.chunks(INSERT_CHUNK) {
+            sqlx::query(
+                r#"
+                INSERT INTO conversions (id, measurement_id, site_id, goal_id, conversion_name, event_id,
+                                         client_id, user_id, session_id, conversion_value, currency, timestamp)
+                SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::uuid[], $4::uuid[], $5::text[], $6::uuid[],
+                                     $7::text[], $8::text[], $9::text[], $10::float8[]::numeric[], $11::text[],
+                                     $12::timestamptz[])
+                ON CONFLICT (id) DO NOTHING
+                "#,
+            )
+            .bind(chunk.iter().map(|c| c.id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.measurement_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.site_id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.goal_id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.goal_name.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.event_id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.client_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.user_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.session_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.value).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.currency.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|c| c.timestamp).collect::<Vec<_>>())
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        for chunk in sessions.chunks(INSERT_CHUNK) {
+            let text = |f: fn(&Session) -> Option<&String>| {
+                chunk.iter().map(|s| f(s).cloned()).collect::<Vec<_>>()
+            };
+            sqlx::query(
+                r#"
+                INSERT INTO sessions (id, session_id, measurement_id, site_id, client_id, user_id, started_at,
+                                      ended_at, duration_seconds, page_views, events_count, is_bounce,
+                                      landing_page, exit_page, referrer, campaign_source, campaign_medium,
+                                      campaign_name, device_category, browser, os, country, region, city)
+                SELECT * FROM UNNEST($1::uuid[], $2::text[], $3::text[], $4::uuid[], $5::text[], $6::text[],
+                                     $7::timestamptz[], $8::timestamptz[], $9::int4[], $10::int4[], $11::int4[],
+                                     $12::bool[], $13::text[], $14::text[], $15::text[], $16::text[], $17::text[],
+                                     $18::text[], $19::text[], $20::text[], $21::text[], $22::text[], $23::text[],
+                                     $24::text[])
+                ON CONFLICT (session_id) DO UPDATE SET
+                    user_id = EXCLUDED.user_id, started_at = EXCLUDED.started_at,
+                    ended_at = EXCLUDED.ended_at, duration_seconds = EXCLUDED.duration_seconds,
+                    page_views = EXCLUDED.page_views, events_count = EXCLUDED.events_count,
+                    is_bounce = EXCLUDED.is_bounce, landing_page = EXCLUDED.landing_page,
+                    exit_page = EXCLUDED.exit_page, referrer = EXCLUDED.referrer,
+                    campaign_source = EXCLUDED.campaign_source, campaign_medium = EXCLUDED.campaign_medium,
+                    campaign_name = EXCLUDED.campaign_name, device_category = EXCLUDED.device_category,
+                    browser = EXCLUDED.browser, os = EXCLUDED.os, country = EXCLUDED.country,
+                    region = EXCLUDED.region, city = EXCLUDED.city
+                "#,
+            )
+            .bind(chunk.iter().map(|s| s.id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.session_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.measurement_id.clone()).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.site_id).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.client_id.clone()).collect::<Vec<_>>())
+            .bind(text(|s| s.user_id.as_ref()))
+            .bind(chunk.iter().map(|s| s.started_at).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.ended_at).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.duration_seconds as i32).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.page_views as i32).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.events_count as i32).collect::<Vec<_>>())
+            .bind(chunk.iter().map(|s| s.is_bounce).collect::<Vec<_>>())
+            .bind(text(|s| s.entry_page.as_ref()))
+            .bind(text(|s| s.exit_page.as_ref()))
+            .bind(text(|s| s.referrer.as_ref()))
+            .bind(text(|s| s.utm_source.as_ref()))
+            .bind(text(|s| s.utm_medium.as_ref()))
+            .bind(text(|s| s.utm_campaign.as_ref()))
+            .bind(text(|s| s.device_category.as_ref()))
+            .bind(text(|s| s.browser.as_ref()))
+            .bind(text(|s| s.os.as_ref()))
+            .bind(text(|s| s.country.as_ref()))
+            .bind(text(|s| s.region.as_ref()))
+            .bind(text(|s| s.city.as_ref()))
+            .execute(&self.pool)
+            .await?;
+        }
+        Ok(())
+    }
+
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>> {
+        Box::pin(try_stream! {
+            let mut query = QueryBuilder::<Postgres>::new(
+                "SELECT id, measurement_id, event_params, timestamp, processed FROM events",
+            );
+            filter.push_where(&mut query);
+            query.push(" ORDER BY timestamp, id");
+            if let Some(limit) = filter.limit {
+                query.push(" LIMIT ").push_bind(limit as i64);
+            }
+
+            let mut rows = query
+                .build_query_as::<(Uuid, String, serde_json::Value, DateTime<Utc>, bool)>()
+                .fetch(&self.pool);
+            while let Some((id, measurement_id, event_params, timestamp, processed)) = rows.try_next().await? {
+                yield EventEnvelope {
+                    event_id: id,
+                    measurement_id,
+                    timestamp,
+                    event: serde_json::from_value(event_params)?,
+                    processed,
+                };
+            }
+        })
+    }
+
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>> {
+        let mut query = QueryBuilder::<Postgres>::new("SELECT event_name, COUNT(*) FROM events");
+        filter.push_where(&mut query);
+        query.push(" GROUP BY event_name");
+
+        let rows: Vec<(String, i64)> = query.build_query_as().fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, count)| (name, count as u64))
+            .collect())
+    }
+
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        let mut query = QueryBuilder::<Postgres>::new("DELETE FROM events");
+        filter.push_where(&mut query);
+        Ok(query.build().execute(&self.pool).await?.rows_affected())
+    }
+
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>> {
+        let rows: Vec<ConversionRow<Uuid>> = sqlx::query_as(&format!(
+            "SELECT {} FROM conversions WHERE site_id = $1 AND timestamp >= $2 AND timestamp < $3 \
+             ORDER BY timestamp, id",
+            CONVERSION_COLUMNS.replace("conversion_value", "conversion_value::float8")
+        ))
+        .bind(site_id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter().map(|row| conversion(row, Ok)).collect()
+    }
+
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM sessions WHERE measurement_id = $1 AND started_at >= $2 \
+             AND started_at < $3 ORDER BY started_at, id",
+            SESSION_COLUMNS
+        ))
+        .bind(measurement_id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| {
+            session_from_row(row, |row, column| {
+                Ok(row.try_get::<Option<Uuid>, _>(column)?)
+            })
+        })
+        .collect()
+    }
+
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64> {
+        let mut replaced = 0;
+        for chunk in events.chunks(INSERT_CHUNK) {
+            replaced += EventColumns::from_events(chunk)?.update(&self.pool).await?;
+        }
+        Ok(replaced)
+    }
+
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("conversions", "timestamp", filter).await
+    }
+
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("sessions", "started_at", filter).await
+    }
+
+    async fn delete_users(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("users", "last_seen_at", filter).await
+    }
+
+    async fn user_ids(&self) -> Result<BTreeSet<String>> {
+        Ok(sqlx::query_scalar(
+            "SELECT user_id FROM sessions WHERE user_id IS NOT NULL
+             UNION SELECT user_id FROM conversions WHERE user_id IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64> {
+        let old: Vec<String> = replacements.keys().cloned().collect();
+        let new: Vec<String> = replacements.values().cloned().collect();
+        let mut tx = self.pool.begin().await?;
+        let mut replaced = 0;
+        for table in ["sessions", "conversions"] {
+            let sql = format!(
+                "UPDATE {table} SET user_id = r.new
+                 FROM UNNEST($1::text[], $2::text[]) AS r(old, new)
+                 WHERE {table}.user_id = r.old"
+            );
+            replaced += sqlx::query(&sql)
+                .bind(&old)
+                .bind(&new)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(replaced)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            backend: "postgres",
+            durable: true,
+            streaming_scan: true,
+            concurrent_writers: true,
+            time_partitioned: true,
+            bounded_retention: false,
+        }
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventParams};
+
+    #[test]
+    fn test_event_columns_line_up() {
+        let events: Vec<EventEnvelope> = (0..3)
+            .map(|_| {
+                EventEnvelope::new(
+                    "G-TEST".to_string(),
+                    Event::Custom {
+                        name: "signup".to_string(),
+                        params: EventParams::default(),
+                    },
+                )
+            })
+            .collect();
+
+        let columns = EventColumns::from_events(&events).unwrap();
+        assert_eq!(columns.ids.len(), 3);
+        assert_eq!(columns.event_params.len(), 3);
+        assert_eq!(columns.timestamps.len(), 3);
+        assert_eq!(columns.ids[2], events[2].event_id);
+        assert_eq!(columns.event_names[0], "signup");
+        assert_eq!(columns.values, vec![None; 3]);
+    }
+}
@@ -0,0 +1,306 @@
+//! Realtime activity kept in Redis
+//!
+//! Processed events are recorded into per-minute buckets shared by every node:
+//! HyperLogLogs of active clients and sessions (overall, per page and per traffic
+//! source), sorted sets naming the pages and sources seen in the minute, and an
+//! events counter. Window queries merge buckets with a multi-key `PFCOUNT`, so a
+//! client seen on several nodes or minutes is counted once.
+//!
+//! Keys carry the measurement ID as a hash tag (`rt:{G-XXX}:…`) so one site's buckets
+//! share a Redis Cluster slot.
+
+use super::RedisCache;
+use crate::error::Result;
+use crate::events::{Event, EventEnvelope};
+use crate::models::{RealtimeSnapshot, TopPage, TopSource};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+
+/// Longest window served, in minutes
+const MAX_WINDOW_MINUTES: i64 = 30;
+
+/// Active users over the standard realtime windows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct ActiveUsers {
+    pub last_5_minutes: u64,
+    pub last_30_minutes: u64,
+}
+
+/// Minutes since the Unix epoch
+fn minute_of(timestamp: DateTime<Utc>) -> i64 {
+    timestamp.timestamp().div_euclid(60)
+}
+
+/// Bucket for an event, or `None` when it is too old to affect any window.
+/// Future timestamps (client clock skew) count as now.
+fn activity_minute(timestamp: DateTime<Utc>, now: DateTime<Utc>) -> Option<i64> {
+    let minute = minute_of(timestamp.min(now));
+    (minute_of(now) - minute < MAX_WINDOW_MINUTES).then_some(minute)
+}
+
+/// The `minutes` buckets ending with the current one
+fn window(now: DateTime<Utc>, minutes: i64) -> impl Iterator<Item = i64> {
+    let current = minute_of(now);
+    (current - minutes + 1)..=current
+}
+
+fn key(measurement_id: &str, kind: &str, minute: i64) -> String {
+    format!("rt:{{{}}}:{}:{}", measurement_id, kind, minute)
+}
+
+fn member_key(measurement_id: &str, kind: &str, minute: i64, member: &str) -> String {
+    format!("rt:{{{}}}:{}:{}:{}", measurement_id, kind, minute, member)
+}
+
+/// Path of a page URL, or the raw value when it does not parse
+fn page_path(location: &str) -> String {
+    reqwest::Url::parse(location)
+        .map(|url| url.path().to_string())
+        .unwrap_or_else(|_| location.to_string())
+}
+
+/// Traffic source of a page view: `utm_source`, else a click ID or the external referrer, else `(direct)`
+fn traffic_source(page_location: &str, referrer: Option<&str>) -> String {
+    crate::rollup::source_medium(page_location, referrer).source
+}
+
+/// Candidates with the highest summed scores across buckets
+fn top_members(buckets: Vec<Vec<(String, f64)>>, limit: usize) -> Vec<String> {
+    let mut totals: HashMap<String, f64> = HashMap::new();
+    for (member, score) in buckets.into_iter().flatten() {
+        *totals.entry(member).or_insert(0.0) += score;
+    }
+    let mut ranked: Vec<(String, f64)> = totals.into_iter().collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    ranked
+        .into_iter()
+        .take(limit)
+        .map(|(member, _)| member)
+        .collect()
+}
+
+impl RedisCache {
+    /// Record processed events into the realtime buckets
+    pub async fn record_activity(&self, events: &[EventEnvelope]) -> Result<()> {
+        let now = Utc::now();
+        // Buckets outlive the longest window by the configured cache TTL
+        let ttl = (MAX_WINDOW_MINUTES * 60) as u64 + self.ttl_cache;
+
+        let mut pipe = redis::pipe();
+        let mut touched = HashSet::new();
+        let mut touch = |key: String| -> String {
+            touched.insert(key.clone());
+            key
+        };
+
+        for envelope in events {
+            let Some(minute) = activity_minute(envelope.timestamp, now) else {
+                continue;
+            };
+            let mid = envelope.measurement_id.as_str();
+            let event = &envelope.event;
+            let params = event.params();
+            let client = params
+                .client_id
+                .clone()
+                .unwrap_or_else(|| envelope.event_id.to_string());
+
+            pipe.pfadd(touch(key(mid, "users", minute)), &client)
+                .ignore();
+            pipe.incr(touch(key(mid, "events", minute)), 1).ignore();
+            if let Some(session) = &params.session_id {
+                pipe.pfadd(touch(key(mid, "sessions", minute)), session)
+                    .ignore();
+            }
+
+            if let Some(location) = event.page_location() {
+                let page = page_path(location);
+                pipe.zincr(touch(key(mid, "pages", minute)), &page, 1)
+                    .ignore();
+                pipe.pfadd(touch(member_key(mid, "page", minute, &page)), &client)
+                    .ignore();
+            }
+            if let Event::PageView {
+                page_location,
+                page_referrer,
+                ..
+            } = event
+            {
+                let source = traffic_source(page_location, page_referrer.as_deref());
+                pipe.zincr(touch(key(mid, "sources", minute)), &source, 1)
+                    .ignore();
+                pipe.pfadd(touch(member_key(mid, "source", minute, &source)), &client)
+                    .ignore();
+            }
+        }
+
+        if touched.is_empty() {
+            return Ok(());
+        }
+        for key in touched {
+            pipe.expire(key, ttl as i64).ignore();
+        }
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        pipe.query_async::<_, ()>(&mut conn).await?;
+        Ok(())
+    }
+
+    /// Distinct clients active in the last `minutes` (at most 30)
+    pub async fn active_users(&self, measurement_id: &str, minutes: u32) -> Result<u64> {
+        let minutes = (minutes as i64).clamp(1, MAX_WINDOW_MINUTES);
+        let keys: Vec<String> = window(Utc::now(), minutes)
+            .map(|minute| key(measurement_id, "users", minute))
+            .collect();
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        Ok(redis::cmd("PFCOUNT")
+            .arg(keys)
+            .query_async(&mut conn)
+            .await?)
+    }
+
+    /// Active users over the last 5 and 30 minutes
+    pub async fn get_realtime_users(&self, measurement_id: &str) -> Result<ActiveUsers> {
+        Ok(ActiveUsers {
+            last_5_minutes: self.active_users(measurement_id, 5).await?,
+            last_30_minutes: self.active_users(measurement_id, 30).await?,
+        })
+    }
+
+    /// Activity over the last 30 minutes with the `top` busiest pages and sources
+    pub async fn realtime_snapshot(
+        &self,
+        measurement_id: &str,
+        top: usize,
+    ) -> Result<RealtimeSnapshot> {
+        let now = Utc::now();
+        let minutes: Vec<i64> = window(now, MAX_WINDOW_MINUTES).collect();
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+
+        let count = |kind: &str| -> Vec<String> {
+            minutes
+                .iter()
+                .map(|m| key(measurement_id, kind, *m))
+                .collect()
+        };
+        let active_users: u64 = redis::cmd("PFCOUNT")
+            .arg(count("users"))
+            .query_async(&mut conn)
+            .await?;
+        let active_sessions: u64 = redis::cmd("PFCOUNT")
+            .arg(count("sessions"))
+            .query_async(&mut conn)
+            .await?;
+        // The current minute is still filling up, so report the last complete one
+        let events_per_minute: Option<u64> = redis::cmd("GET")
+            .arg(key(measurement_id, "events", minute_of(now) - 1))
+            .query_async(&mut conn)
+            .await?;
+
+        let mut ranked = Vec::new();
+        for (kind, member_kind) in [("pages", "page"), ("sources", "source")] {
+            let mut pipe = redis::pipe();
+            for key in count(kind) {
+                pipe.zrange_withscores(key, 0, -1);
+            }
+            let buckets: Vec<Vec<(String, f64)>> = pipe.query_async(&mut conn).await?;
+
+            let mut members = Vec::new();
+            for member in top_members(buckets, top) {
+                let keys: Vec<String> = minutes
+                    .iter()
+                    .map(|m| member_key(measurement_id, member_kind, *m, &member))
+                    .collect();
+                let users: u64 = redis::cmd("PFCOUNT")
+                    .arg(keys)
+                    .query_async(&mut conn)
+                    .await?;
+                members.push((member, users as u32));
+            }
+            members.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            ranked.push(members);
+        }
+        let top_sources = ranked.pop().unwrap_or_default();
+        let top_pages = ranked.pop().unwrap_or_default();
+
+        Ok(RealtimeSnapshot {
+            timestamp: now,
+            active_users: active_users as u32,
+            active_sessions: active_sessions as u32,
+            events_per_minute: events_per_minute.unwrap_or(0) as u32,
+            top_pages: top_pages
+                .into_iter()
+                .map(|(page_path, active_users)| TopPage {
+                    page_path,
+                    active_users,
+                })
+                .collect(),
+            top_sources: top_sources
+                .into_iter()
+                .map(|(source, active_users)| TopSource {
+                    source,
+                    active_users,
+                })
+                .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_buckets_and_windows() {
+        let now = Utc::now();
+        assert_eq!(activity_minute(now, now), Some(minute_of(now)));
+        assert_eq!(
+            activity_minute(now + Duration::hours(1), now),
+            Some(minute_of(now))
+        );
+        assert!(activity_minute(now - Duration::minutes(29), now).is_some());
+        assert_eq!(activity_minute(now - Duration::minutes(31), now), None);
+
+        let five: Vec<i64> = window(now, 5).collect();
+        assert_eq!(five.len(), 5);
+        assert_eq!(*five.last().unwrap(), minute_of(now));
+        assert_eq!(key("G-A", "users", 7), "rt:{G-A}:users:7");
+    }
+
+    #[test]
+    fn test_page_paths_and_sources() {
+        assert_eq!(
+            page_path("https://example.com/pricing?plan=pro"),
+            "/pricing"
+        );
+        assert_eq!(
+            traffic_source(
+                "https://example.com/?utm_source=Newsletter",
+                Some("https://google.com/")
+            ),
+            "newsletter"
+        );
+        assert_eq!(
+            traffic_source(
+                "https://example.com/",
+                Some("https://www.google.com/search")
+            ),
+            "google"
+        );
+        assert_eq!(
+            traffic_source("https://www.example.com/a", Some("https://example.com/b")),
+            "(direct)"
+        );
+        assert_eq!(traffic_source("https://example.com/", None), "(direct)");
+    }
+
+    #[test]
+    fn test_top_members_sums_buckets() {
+        let buckets = vec![
+            vec![("/a".to_string(), 3.0), ("/b".to_string(), 1.0)],
+            vec![("/b".to_string(), 5.0), ("/c".to_string(), 2.0)],
+        ];
+        assert_eq!(top_members(buckets, 2), vec!["/b", "/a"]);
+    }
+}
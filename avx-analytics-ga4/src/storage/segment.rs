@@ -0,0 +1,1130 @@
+//! Embedded append-only segment store
+//!
+//! Events, conversions and sessions live in a local directory as immutable segment files, each
+//! holding records from one hour of event time. Every write creates new segments under a
+//! temporary name and renames them into place, so a crash never leaves a partial segment.
+//! Records are length-prefixed JSON frames, gzip-compressed when `storage.compression_enabled`
+//! is set.
+//!
+//! Each segment starts with a small header (time range, measurement IDs, record count). The
+//! headers form a sparse in-memory index, rebuilt on open, so scans only read segments
+//! that can match. Compaction merges the segments of each closed hour into one and drops
+//! duplicate IDs. Deletes rewrite the affected segments in place.
+
+use super::{Capabilities, EventFilter, ExpiryFilter, StorageEngine};
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use crate::rollup::DailyRollup;
+use async_stream::try_stream;
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use uuid::Uuid;
+
+const MAGIC: &[u8; 4] = b"AVSG";
+const FORMAT_VERSION: u8 = 1;
+const FLAG_GZIP: u8 = 1;
+
+/// Seconds of event time covered by one bucket
+const BUCKET_SECS: i64 = 3600;
+
+/// Segments the current hour may accumulate before it is compacted early
+const MAX_OPEN_SEGMENTS: usize = 64;
+
+/// A record kept in segments
+trait Record: Serialize + DeserializeOwned + Send + 'static {
+    fn id(&self) -> Uuid;
+    fn timestamp(&self) -> DateTime<Utc>;
+    fn measurement_id(&self) -> &str;
+}
+
+impl Record for EventEnvelope {
+    fn id(&self) -> Uuid {
+        self.event_id
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    fn measurement_id(&self) -> &str {
+        &self.measurement_id
+    }
+}
+
+impl Record for Conversion {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.timestamp
+    }
+
+    fn measurement_id(&self) -> &str {
+        &self.measurement_id
+    }
+}
+
+impl Record for Session {
+    fn id(&self) -> Uuid {
+        self.id
+    }
+
+    /// Sessions are bucketed by start time
+    fn timestamp(&self) -> DateTime<Utc> {
+        self.started_at
+    }
+
+    fn measurement_id(&self) -> &str {
+        &self.measurement_id
+    }
+}
+
+/// A record holding a user ID
+trait UserRecord: Record {
+    fn user_id(&mut self) -> &mut Option<String>;
+}
+
+impl UserRecord for Conversion {
+    fn user_id(&mut self) -> &mut Option<String> {
+        &mut self.user_id
+    }
+}
+
+impl UserRecord for Session {
+    fn user_id(&mut self) -> &mut Option<String> {
+        &mut self.user_id
+    }
+}
+
+fn bucket_of(timestamp: DateTime<Utc>) -> i64 {
+    timestamp.timestamp().div_euclid(BUCKET_SECS)
+}
+
+/// Index entry stored at the start of every segment
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Header {
+    min_timestamp: DateTime<Utc>,
+    max_timestamp: DateTime<Utc>,
+    records: usize,
+    measurement_ids: BTreeSet<String>,
+}
+
+impl Header {
+    /// Header for a non-empty set of records
+    fn of<T: Record>(records: &[T]) -> Self {
+        Self {
+            min_timestamp: records
+                .iter()
+                .map(Record::timestamp)
+                .min()
+                .unwrap_or_default(),
+            max_timestamp: records
+                .iter()
+                .map(Record::timestamp)
+                .max()
+                .unwrap_or_default(),
+            records: records.len(),
+            measurement_ids: records
+                .iter()
+                .map(|r| r.measurement_id().to_string())
+                .collect(),
+        }
+    }
+
+    /// Whether the segment can hold records of `measurement_id` within `[start, end)`
+    fn may_match(
+        &self,
+        measurement_id: Option<&str>,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> bool {
+        measurement_id.is_none_or(|id| self.measurement_ids.contains(id))
+            && start.is_none_or(|start| self.max_timestamp >= start)
+            && end.is_none_or(|end| self.min_timestamp < end)
+    }
+}
+
+#[derive(Debug)]
+struct Segment {
+    bucket: i64,
+    seq: u64,
+    path: PathBuf,
+    header: Header,
+}
+
+fn segment_name(bucket: i64, seq: u64) -> String {
+    format!("{}-{:010}.seg", bucket, seq)
+}
+
+/// Inverse of `segment_name`
+fn parse_segment_name(name: &str) -> Option<(i64, u64)> {
+    let (bucket, seq) = name.strip_suffix(".seg")?.rsplit_once('-')?;
+    Some((bucket.parse().ok()?, seq.parse().ok()?))
+}
+
+/// Write records as segment `seq` of `bucket`, replacing any segment with that name
+fn write_segment<T: Record>(
+    dir: &Path,
+    bucket: i64,
+    seq: u64,
+    mut records: Vec<T>,
+    compress: bool,
+) -> Result<Segment> {
+    records.sort_by_key(|r| (r.timestamp(), r.id()));
+    let header = Header::of(&records);
+
+    let mut body = Vec::new();
+    for record in &records {
+        let frame = serde_json::to_vec(record)?;
+        body.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+        body.extend_from_slice(&frame);
+    }
+    if compress {
+        let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(&body)?;
+        body = encoder.finish()?;
+    }
+
+    let header_json = serde_json::to_vec(&header)?;
+    let name = segment_name(bucket, seq);
+    let path = dir.join(&name);
+    let tmp = dir.join(format!(".{}.tmp", name));
+    let mut file = File::create(&tmp)?;
+    file.write_all(MAGIC)?;
+    file.write_all(&[FORMAT_VERSION, if compress { FLAG_GZIP } else { 0 }])?;
+    file.write_all(&(header_json.len() as u32).to_le_bytes())?;
+    file.write_all(&header_json)?;
+    file.write_all(&body)?;
+    file.sync_all()?;
+    fs::rename(&tmp, &path)?;
+
+    Ok(Segment {
+        bucket,
+        seq,
+        path,
+        header,
+    })
+}
+
+/// Read the flags and header that start a segment
+fn read_prelude(reader: &mut impl Read, path: &Path) -> Result<(u8, Header)> {
+    let mut prelude = [0u8; 10];
+    reader.read_exact(&mut prelude)?;
+    if &prelude[..4] != MAGIC || prelude[4] != FORMAT_VERSION {
+        return Err(Error::Storage(format!(
+            "{} is not a version {} segment",
+            path.display(),
+            FORMAT_VERSION
+        )));
+    }
+    let length = u32::from_le_bytes([prelude[6], prelude[7], prelude[8], prelude[9]]) as usize;
+    let mut header = vec![0u8; length];
+    reader.read_exact(&mut header)?;
+    Ok((prelude[5], serde_json::from_slice(&header)?))
+}
+
+fn read_header(path: &Path) -> Result<Header> {
+    let (_, header) = read_prelude(&mut BufReader::new(File::open(path)?), path)?;
+    Ok(header)
+}
+
+fn read_records<T: Record>(path: &Path) -> Result<Vec<T>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let (flags, header) = read_prelude(&mut reader, path)?;
+    let mut body: Box<dyn Read> = if flags & FLAG_GZIP != 0 {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+
+    let mut records = Vec::with_capacity(header.records);
+    let mut length = [0u8; 4];
+    for _ in 0..header.records {
+        body.read_exact(&mut length)?;
+        let mut frame = vec![0u8; u32::from_le_bytes(length) as usize];
+        body.read_exact(&mut frame)?;
+        records.push(serde_json::from_slice(&frame)?);
+    }
+    Ok(records)
+}
+
+/// Records of one bucket's segments in timestamp order; the oldest copy of an ID wins
+fn read_bucket<T: Record>(segments: &[Arc<Segment>]) -> Result<Vec<T>> {
+    let mut seen = HashSet::new();
+    let mut records = Vec::new();
+    for segment in segments {
+        for record in read_records::<T>(&segment.path)? {
+            if seen.insert(record.id()) {
+                records.push(record);
+            }
+        }
+    }
+    records.sort_by_key(|r| (r.timestamp(), r.id()));
+    Ok(records)
+}
+
+/// Split segments sorted by bucket into one group per bucket
+fn by_bucket(segments: &[Arc<Segment>]) -> Vec<Vec<Arc<Segment>>> {
+    segments
+        .chunk_by(|a, b| a.bucket == b.bucket)
+        .map(<[_]>::to_vec)
+        .collect()
+}
+
+async fn blocking<R: Send + 'static>(
+    task: impl FnOnce() -> Result<R> + Send + 'static,
+) -> Result<R> {
+    tokio::task::spawn_blocking(task)
+        .await
+        .map_err(|e| Error::Storage(format!("segment task failed: {}", e)))?
+}
+
+/// Rollup files of a site directory with their days, if it exists
+fn rollup_files(site_dir: &Path) -> Result<Vec<(NaiveDate, PathBuf)>> {
+    let entries = match fs::read_dir(site_dir) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry?.path();
+        let day = path.file_name().and_then(|name| {
+            name.to_str()?
+                .strip_suffix(".json")?
+                .parse::<NaiveDate>()
+                .ok()
+        });
+        if let Some(day) = day {
+            files.push((day, path));
+        }
+    }
+    Ok(files)
+}
+
+/// A site's client → first day map, empty when the site has none yet
+fn read_first_seen(path: &Path) -> Result<HashMap<String, NaiveDate>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(HashMap::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Segments of one record type, indexed by their headers
+struct Table {
+    dir: PathBuf,
+    /// Sorted by bucket, then sequence number
+    segments: RwLock<Vec<Arc<Segment>>>,
+}
+
+impl Table {
+    /// Index the segments in `dir`, removing leftovers of interrupted writes
+    fn open(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .unwrap_or_default();
+            if name.ends_with(".tmp") {
+                fs::remove_file(&path)?;
+                continue;
+            }
+            let Some((bucket, seq)) = parse_segment_name(name) else {
+                continue;
+            };
+            match read_header(&path) {
+                Ok(header) => segments.push(Arc::new(Segment {
+                    bucket,
+                    seq,
+                    path,
+                    header,
+                })),
+                Err(e) => tracing::warn!("Skipping unreadable segment {}: {}", path.display(), e),
+            }
+        }
+        segments.sort_by_key(|s| (s.bucket, s.seq));
+        Ok(Self {
+            dir,
+            segments: RwLock::new(segments),
+        })
+    }
+
+    fn all(&self) -> Vec<Arc<Segment>> {
+        self.segments.read().unwrap().clone()
+    }
+
+    fn candidates(
+        &self,
+        measurement_id: Option<&str>,
+        start: Option<DateTime<Utc>>,
+        end: Option<DateTime<Utc>>,
+    ) -> Vec<Arc<Segment>> {
+        self.segments
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|s| s.header.may_match(measurement_id, start, end))
+            .cloned()
+            .collect()
+    }
+
+    fn max_seq(&self) -> u64 {
+        self.segments
+            .read()
+            .unwrap()
+            .iter()
+            .map(|s| s.seq)
+            .max()
+            .unwrap_or(0)
+    }
+
+    /// Drop the segments numbered `removed` from the index and add `added`
+    fn replace(&self, removed: &[u64], added: Vec<Segment>) {
+        let mut segments = self.segments.write().unwrap();
+        segments.retain(|s| !removed.contains(&s.seq));
+        segments.extend(added.into_iter().map(Arc::new));
+        segments.sort_by_key(|s| (s.bucket, s.seq));
+    }
+}
+
+/// Storage engine that keeps events in local segment files, with no database server.
+///
+/// A single process should own the directory; concurrent writers are not coordinated.
+pub struct SegmentStorage {
+    events: Table,
+    conversions: Table,
+    sessions: Table,
+    /// Daily rollups as `<site>/<day>.json`, replaced in place
+    rollups: PathBuf,
+    /// First day of each client as `<site>.json`, a client → day map
+    first_seen: PathBuf,
+    compression: bool,
+    next_seq: AtomicU64,
+    /// Shared by readers, exclusive for compaction and deletes, which remove files
+    files: tokio::sync::RwLock<()>,
+}
+
+impl SegmentStorage {
+    /// Open (or create) a segment directory; compression is on by default
+    pub fn open(dir: impl AsRef<Path>) -> Result<Self> {
+        let dir = dir.as_ref();
+        let events = Table::open(dir.join("events"))?;
+        let conversions = Table::open(dir.join("conversions"))?;
+        let sessions = Table::open(dir.join("sessions"))?;
+        let next_seq = events
+            .max_seq()
+            .max(conversions.max_seq())
+            .max(sessions.max_seq())
+            + 1;
+        Ok(Self {
+            events,
+            conversions,
+            sessions,
+            rollups: dir.join("rollups"),
+            first_seen: dir.join("first_seen"),
+            compression: true,
+            next_seq: AtomicU64::new(next_seq),
+            files: tokio::sync::RwLock::new(()),
+        })
+    }
+
+    /// Open the directory named by a `segment://` URL, e.g. `segment:///var/lib/avila/segments`
+    pub fn from_url(url: &str) -> Result<Self> {
+        match url.strip_prefix("segment://") {
+            Some(path) if !path.is_empty() => Self::open(path),
+            _ => Err(Error::Config(format!(
+                "segment URL {:?} must name a directory, e.g. segment:///var/lib/avila/segments",
+                url
+            ))),
+        }
+    }
+
+    /// Gzip new segments (`storage.compression_enabled`); existing ones are read either way
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.compression = enabled;
+        self
+    }
+
+    /// Number of event segments on disk
+    pub fn segment_count(&self) -> usize {
+        self.events.segments.read().unwrap().len()
+    }
+
+    /// Insert or replace daily rollups
+    pub async fn put_rollups(&self, rollups: Vec<DailyRollup>) -> Result<()> {
+        let dir = self.rollups.clone();
+        blocking(move || {
+            for rollup in rollups {
+                let site_dir = dir.join(rollup.metrics.site_id.to_string());
+                fs::create_dir_all(&site_dir)?;
+                let name = format!("{}.json", rollup.metrics.date);
+                let tmp = site_dir.join(format!(".{}.tmp", name));
+                let mut file = File::create(&tmp)?;
+                file.write_all(&serde_json::to_vec(&rollup)?)?;
+                file.sync_all()?;
+                fs::rename(&tmp, site_dir.join(name))?;
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    /// Stored rollups for a site within `[start, end]`, oldest first
+    pub async fn rollups(
+        &self,
+        site_id: Uuid,
+        start: NaiveDate,
+        end: NaiveDate,
+    ) -> Result<Vec<DailyRollup>> {
+        let site_dir = self.rollups.join(site_id.to_string());
+        blocking(move || {
+            let mut rollups = Vec::new();
+            for (day, path) in rollup_files(&site_dir)? {
+                if day >= start && day <= end {
+                    rollups.push(serde_json::from_slice::<DailyRollup>(&fs::read(&path)?)?);
+                }
+            }
+            rollups.sort_by_key(|r| r.metrics.date);
+            Ok(rollups)
+        })
+        .await
+    }
+
+    /// Whether any first-seen day is stored for a site
+    pub fn has_first_seen(&self, site_id: Uuid) -> bool {
+        self.first_seen.join(format!("{}.json", site_id)).exists()
+    }
+
+    /// Stored first days of the given clients of a site; unknown clients are left out
+    pub async fn first_seen(
+        &self,
+        site_id: Uuid,
+        clients: &[String],
+    ) -> Result<HashMap<String, NaiveDate>> {
+        let path = self.first_seen.join(format!("{}.json", site_id));
+        let clients = clients.to_vec();
+        blocking(move || {
+            let stored = read_first_seen(&path)?;
+            Ok(clients
+                .into_iter()
+                .filter_map(|client| stored.get(&client).map(|day| (client, *day)))
+                .collect())
+        })
+        .await
+    }
+
+    /// Record first days, keeping the earlier day when a client is already known
+    pub async fn put_first_seen(
+        &self,
+        site_id: Uuid,
+        first_seen: &HashMap<String, NaiveDate>,
+    ) -> Result<()> {
+        let dir = self.first_seen.clone();
+        let first_seen = first_seen.clone();
+        blocking(move || {
+            fs::create_dir_all(&dir)?;
+            let path = dir.join(format!("{}.json", site_id));
+            let mut stored = read_first_seen(&path)?;
+            for (client, day) in first_seen {
+                let entry = stored.entry(client).or_insert(day);
+                *entry = (*entry).min(day);
+            }
+            let tmp = dir.join(format!(".{}.json.tmp", site_id));
+            let mut file = File::create(&tmp)?;
+            file.write_all(&serde_json::to_vec(&stored)?)?;
+            file.sync_all()?;
+            fs::rename(&tmp, &path)?;
+            Ok(())
+        })
+        .await
+    }
+
+    /// Delete a site's rollups for days before `before`, returning how many were removed
+    pub async fn delete_rollups(&self, site_id: Uuid, before: NaiveDate) -> Result<u64> {
+        let site_dir = self.rollups.join(site_id.to_string());
+        blocking(move || {
+            let mut deleted = 0;
+            for (day, path) in rollup_files(&site_dir)? {
+                if day < before {
+                    fs::remove_file(&path)?;
+                    deleted += 1;
+                }
+            }
+            Ok(deleted)
+        })
+        .await
+    }
+
+    /// Count (dry run) or delete the records of `table` matching `filter`
+    async fn expire<T: Record>(&self, table: &Table, filter: &ExpiryFilter) -> Result<u64> {
+        let expired = {
+            let filter = filter.clone();
+            move |r: &T| filter.matches(r.measurement_id(), r.timestamp())
+        };
+        if filter.dry_run {
+            let _files = self.files.read().await;
+            let segments =
+                table.candidates(filter.measurement_id.as_deref(), None, Some(filter.before));
+            let records: Vec<T> = blocking(move || read_bucket(&segments)).await?;
+            return Ok(records.iter().filter(|r| expired(r)).count() as u64);
+        }
+        let _files = self.files.write().await;
+        let segments =
+            table.candidates(filter.measurement_id.as_deref(), None, Some(filter.before));
+        let removed = self.delete_records(table, segments, expired).await?;
+        Ok(removed.len() as u64)
+    }
+
+    /// Rewrite `segments` without the records matching `remove`, returning the distinct
+    /// IDs removed. Callers hold the exclusive file lock.
+    async fn delete_records<T: Record>(
+        &self,
+        table: &Table,
+        segments: Vec<Arc<Segment>>,
+        remove: impl Fn(&T) -> bool + Clone + Send + 'static,
+    ) -> Result<HashSet<Uuid>> {
+        let mut deleted = HashSet::new();
+        for segment in segments {
+            let (dir, compress, remove) = (table.dir.clone(), self.compression, remove.clone());
+            let seq = segment.seq;
+            let (removed, rewritten) = blocking(move || {
+                let records: Vec<T> = read_records(&segment.path)?;
+                let (removed, kept): (Vec<_>, Vec<_>) =
+                    records.into_iter().partition(|r| remove(r));
+                if removed.is_empty() {
+                    return Ok((removed, None));
+                }
+                // Same name, so the rename replaces the old segment atomically
+                let rewritten = if kept.is_empty() {
+                    fs::remove_file(&segment.path)?;
+                    None
+                } else {
+                    Some(write_segment(
+                        &dir,
+                        segment.bucket,
+                        segment.seq,
+                        kept,
+                        compress,
+                    )?)
+                };
+                Ok((removed, rewritten))
+            })
+            .await?;
+
+            if !removed.is_empty() {
+                table.replace(&[seq], rewritten.into_iter().collect());
+                deleted.extend(removed.iter().map(Record::id));
+            }
+        }
+        Ok(deleted)
+    }
+
+    /// Rewrite `segments` with the records in `replacements` swapped in, returning the
+    /// distinct IDs replaced. Callers hold the exclusive file lock.
+    async fn replace_records<T: Record + Clone + Sync>(
+        &self,
+        table: &Table,
+        segments: Vec<Arc<Segment>>,
+        replacements: Arc<HashMap<Uuid, T>>,
+    ) -> Result<HashSet<Uuid>> {
+        self.rewrite_records(table, segments, move |record: &mut T| {
+            match replacements.get(&record.id()) {
+                Some(replacement) if replacement.timestamp() == record.timestamp() => {
+                    *record = replacement.clone();
+                    true
+                }
+                _ => false,
+            }
+        })
+        .await
+    }
+
+    /// Rewrite the segments holding records that `edit` changed, returning the distinct IDs
+    /// changed. Callers hold the exclusive file lock.
+    async fn rewrite_records<T: Record>(
+        &self,
+        table: &Table,
+        segments: Vec<Arc<Segment>>,
+        edit: impl Fn(&mut T) -> bool + Clone + Send + 'static,
+    ) -> Result<HashSet<Uuid>> {
+        let mut changed = HashSet::new();
+        for segment in segments {
+            let (dir, compress, edit) = (table.dir.clone(), self.compression, edit.clone());
+            let seq = segment.seq;
+            let (ids, rewritten) = blocking(move || {
+                let mut records: Vec<T> = read_records(&segment.path)?;
+                let mut ids = Vec::new();
+                for record in records.iter_mut() {
+                    if edit(record) {
+                        ids.push(record.id());
+                    }
+                }
+                if ids.is_empty() {
+                    return Ok((ids, None));
+                }
+                let rewritten =
+                    write_segment(&dir, segment.bucket, segment.seq, records, compress)?;
+                Ok((ids, Some(rewritten)))
+            })
+            .await?;
+
+            if let Some(rewritten) = rewritten {
+                table.replace(&[seq], vec![rewritten]);
+                changed.extend(ids);
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Distinct user IDs of the records in `table`
+    async fn user_ids_of<T: UserRecord>(&self, table: &Table) -> Result<BTreeSet<String>> {
+        let _files = self.files.read().await;
+        let segments = table.all();
+        blocking(move || {
+            let mut user_ids = BTreeSet::new();
+            for segment in segments {
+                for mut record in read_records::<T>(&segment.path)? {
+                    user_ids.extend(record.user_id().take());
+                }
+            }
+            Ok(user_ids)
+        })
+        .await
+    }
+
+    async fn replace_user_ids_of<T: UserRecord>(
+        &self,
+        table: &Table,
+        replacements: Arc<BTreeMap<String, String>>,
+    ) -> Result<u64> {
+        let _files = self.files.write().await;
+        let replaced = self
+            .rewrite_records(table, table.all(), move |record: &mut T| {
+                let user_id = record.user_id();
+                match user_id.as_ref().and_then(|u| replacements.get(u)) {
+                    Some(replacement) => {
+                        *user_id = Some(replacement.clone());
+                        true
+                    }
+                    None => false,
+                }
+            })
+            .await?;
+        Ok(replaced.len() as u64)
+    }
+
+    /// Merge the segments of every closed hour, and of the current hour once it has
+    /// accumulated many, returning how many segments were merged away
+    pub async fn compact(&self) -> Result<usize> {
+        let _files = self.files.write().await;
+        let merged = self.compact_table::<EventEnvelope>(&self.events).await?
+            + self.compact_table::<Conversion>(&self.conversions).await?
+            + self.compact_table::<Session>(&self.sessions).await?;
+        if merged > 0 {
+            tracing::debug!("Compacted {} segments", merged);
+        }
+        Ok(merged)
+    }
+
+    async fn compact_table<T: Record>(&self, table: &Table) -> Result<usize> {
+        let current = bucket_of(Utc::now());
+        let mut merged = 0;
+        for group in by_bucket(&table.all()) {
+            let bucket = group[0].bucket;
+            if group.len() < 2 || (bucket >= current && group.len() < MAX_OPEN_SEGMENTS) {
+                continue;
+            }
+
+            let removed: Vec<u64> = group.iter().map(|s| s.seq).collect();
+            let (dir, seq, compress) = (table.dir.clone(), self.next_seq(), self.compression);
+            let count = group.len();
+            let segment = blocking(move || {
+                let records = read_bucket::<T>(&group)?;
+                let segment = write_segment(&dir, bucket, seq, records, compress)?;
+                for old in &group {
+                    fs::remove_file(&old.path)?;
+                }
+                Ok(segment)
+            })
+            .await?;
+            table.replace(&removed, vec![segment]);
+            merged += count - 1;
+        }
+        Ok(merged)
+    }
+
+    fn next_seq(&self) -> u64 {
+        self.next_seq.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Write records as one new segment per hour bucket
+    async fn append<T: Record>(&self, table: &Table, records: Vec<T>) -> Result<()> {
+        let mut buckets: BTreeMap<i64, Vec<T>> = BTreeMap::new();
+        for record in records {
+            buckets
+                .entry(bucket_of(record.timestamp()))
+                .or_default()
+                .push(record);
+        }
+        let batches: Vec<(i64, u64, Vec<T>)> = buckets
+            .into_iter()
+            .map(|(bucket, records)| (bucket, self.next_seq(), records))
+            .collect();
+        if batches.is_empty() {
+            return Ok(());
+        }
+
+        let (dir, compress) = (table.dir.clone(), self.compression);
+        let results = blocking(move || {
+            Ok(batches
+                .into_iter()
+                .map(|(bucket, seq, records)| write_segment(&dir, bucket, seq, records, compress))
+                .collect::<Vec<_>>())
+        })
+        .await?;
+
+        // Index whatever was written even if a later bucket failed, so it stays visible
+        let mut written = Vec::new();
+        let mut result = Ok(());
+        for segment in results {
+            match segment {
+                Ok(segment) => written.push(segment),
+                Err(e) if result.is_ok() => result = Err(e),
+                Err(_) => {}
+            }
+        }
+        table.replace(&[], written);
+        result
+    }
+}
+
+#[async_trait]
+impl StorageEngine for SegmentStorage {
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()> {
+        self.append(&self.events, events).await
+    }
+
+    /// There is no ID index, so this reads segments newest first until the event is found
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>> {
+        let _files = self.files.read().await;
+        let segments = self.events.all();
+        blocking(move || {
+            for segment in segments.iter().rev() {
+                let events: Vec<EventEnvelope> = read_records(&segment.path)?;
+                if let Some(event) = events.into_iter().find(|e| e.event_id == id) {
+                    return Ok(Some(event));
+                }
+            }
+            Ok(None)
+        })
+        .await
+    }
+
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()> {
+        self.append(&self.conversions, conversions).await
+    }
+
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        self.append(&self.sessions, sessions).await
+    }
+
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>> {
+        let _files = self.files.read().await;
+        let segments = self.conversions.candidates(None, Some(start), Some(end));
+        let conversions: Vec<Conversion> = blocking(move || read_bucket(&segments)).await?;
+        let mut conversions: Vec<Conversion> = conversions
+            .into_iter()
+            .filter(|c| c.site_id == site_id && c.timestamp >= start && c.timestamp < end)
+            .collect();
+        conversions.sort_by_key(|c| (c.timestamp, c.id));
+        Ok(conversions)
+    }
+
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>> {
+        let _files = self.files.read().await;
+        let segments = self
+            .sessions
+            .candidates(Some(measurement_id), Some(start), Some(end));
+        let sessions: Vec<Session> = blocking(move || read_bucket(&segments)).await?;
+        // A session stored again (e.g. rebuilt by a replay) replaces the earlier record
+        let mut latest: HashMap<String, Session> = HashMap::new();
+        for session in sessions {
+            if session.measurement_id == measurement_id
+                && session.started_at >= start
+                && session.started_at < end
+            {
+                latest.insert(session.session_id.clone(), session);
+            }
+        }
+        let mut sessions: Vec<Session> = latest.into_values().collect();
+        sessions.sort_by_key(|s| (s.started_at, s.id));
+        Ok(sessions)
+    }
+
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>> {
+        Box::pin(try_stream! {
+            let _files = self.files.read().await;
+            let segments = self
+                .events
+                .candidates(filter.measurement_id.as_deref(), filter.start, filter.end);
+            let mut remaining = filter.limit.unwrap_or(usize::MAX);
+
+            // Buckets cover disjoint hours, so reading them in order keeps timestamps sorted
+            for group in by_bucket(&segments) {
+                if remaining == 0 {
+                    break;
+                }
+                let events: Vec<EventEnvelope> = blocking(move || read_bucket(&group)).await?;
+                let mut matching: Vec<EventEnvelope> =
+                    events.into_iter().filter(|e| filter.matches(e)).collect();
+                matching.truncate(remaining);
+                remaining -= matching.len();
+                for event in matching {
+                    yield event;
+                }
+            }
+        })
+    }
+
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>> {
+        let mut filter = filter.clone();
+        filter.limit = None;
+        let mut events = self.scan_events(filter);
+        let mut counts = BTreeMap::new();
+        while let Some(event) = events.try_next().await? {
+            *counts
+                .entry(event.event.event_name().to_string())
+                .or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        let _files = self.files.write().await;
+        let segments =
+            self.events
+                .candidates(filter.measurement_id.as_deref(), filter.start, filter.end);
+        let filter = filter.clone();
+        let removed = self
+            .delete_records(&self.events, segments, move |e: &EventEnvelope| {
+                filter.matches(e)
+            })
+            .await?;
+        Ok(removed.len() as u64)
+    }
+
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64> {
+        let (Some(start), Some(end)) = (
+            events.iter().map(|e| e.timestamp).min(),
+            events.iter().map(|e| e.timestamp).max(),
+        ) else {
+            return Ok(0);
+        };
+        let _files = self.files.write().await;
+        let segments =
+            self.events
+                .candidates(None, Some(start), Some(end + Duration::nanoseconds(1)));
+        let replacements = Arc::new(events.into_iter().map(|e| (e.event_id, e)).collect());
+        let replaced = self
+            .replace_records(&self.events, segments, replacements)
+            .await?;
+        Ok(replaced.len() as u64)
+    }
+
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire::<Conversion>(&self.conversions, filter).await
+    }
+
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire::<Session>(&self.sessions, filter).await
+    }
+
+    /// Users are not stored in segments
+    async fn delete_users(&self, _filter: &ExpiryFilter) -> Result<u64> {
+        Ok(0)
+    }
+
+    async fn user_ids(&self) -> Result<BTreeSet<String>> {
+        let mut user_ids = self.user_ids_of::<Session>(&self.sessions).await?;
+        user_ids.extend(self.user_ids_of::<Conversion>(&self.conversions).await?);
+        Ok(user_ids)
+    }
+
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64> {
+        let replacements = Arc::new(replacements.clone());
+        Ok(self
+            .replace_user_ids_of::<Session>(&self.sessions, replacements.clone())
+            .await?
+            + self
+                .replace_user_ids_of::<Conversion>(&self.conversions, replacements)
+                .await?)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            backend: "segment",
+            durable: true,
+            streaming_scan: true,
+            concurrent_writers: false,
+            time_partitioned: true,
+            bounded_retention: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventParams};
+    use chrono::Duration;
+    use futures::StreamExt;
+
+    fn event_at(measurement_id: &str, timestamp: DateTime<Utc>) -> EventEnvelope {
+        let mut envelope = EventEnvelope::new(
+            measurement_id.to_string(),
+            Event::Custom {
+                name: "test".to_string(),
+                params: EventParams::default(),
+            },
+        );
+        envelope.timestamp = timestamp;
+        envelope
+    }
+
+    fn event(measurement_id: &str, age_hours: i64) -> EventEnvelope {
+        event_at(measurement_id, Utc::now() - Duration::hours(age_hours))
+    }
+
+    #[tokio::test]
+    async fn test_store_scan_and_reopen() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SegmentStorage::open(dir.path()).unwrap();
+        let old = event("G-A", 5);
+        let recent = event("G-A", 1);
+        let id = recent.event_id;
+        storage
+            .store_events(vec![recent.clone(), old.clone(), event("G-B", 1)])
+            .await
+            .unwrap();
+        // A retried batch writes the same IDs again
+        storage.store_events(vec![recent]).await.unwrap();
+
+        let scanned: Vec<EventEnvelope> = storage
+            .scan_events(EventFilter::new().measurement_id("G-A"))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(
+            scanned.iter().map(|e| e.event_id).collect::<Vec<_>>(),
+            vec![old.event_id, id]
+        );
+
+        // Reopening rebuilds the index from segment headers, uncompressed ones included
+        drop(storage);
+        let storage = SegmentStorage::open(dir.path())
+            .unwrap()
+            .with_compression(false);
+        storage.store_events(vec![event("G-A", 1)]).await.unwrap();
+        assert_eq!(storage.get_event(id).await.unwrap().unwrap().event_id, id);
+        assert!(storage.get_event(Uuid::new_v4()).await.unwrap().is_none());
+        let limited: Vec<_> = storage
+            .scan_events(EventFilter::new().measurement_id("G-A").limit(2))
+            .collect()
+            .await;
+        assert_eq!(limited.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_merges_closed_hours() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SegmentStorage::open(dir.path()).unwrap();
+        let closed_hour = Utc::now() - Duration::hours(3);
+        let duplicate = event_at("G-A", closed_hour);
+        for _ in 0..3 {
+            storage
+                .store_events(vec![duplicate.clone(), event_at("G-A", closed_hour)])
+                .await
+                .unwrap();
+        }
+        storage.store_events(vec![event("G-A", 0)]).await.unwrap();
+        storage.store_events(vec![event("G-A", 0)]).await.unwrap();
+        assert_eq!(storage.segment_count(), 5);
+
+        // The closed hour is merged; the current one keeps filling
+        assert_eq!(storage.compact().await.unwrap(), 2);
+        assert_eq!(storage.segment_count(), 3);
+        let counts = storage
+            .count_by_event_name(&EventFilter::new())
+            .await
+            .unwrap();
+        assert_eq!(counts.get("test"), Some(&6));
+    }
+
+    #[tokio::test]
+    async fn test_delete_rewrites_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = SegmentStorage::open(dir.path()).unwrap();
+        let mut tagged = event("G-A", 2);
+        tagged.event.params_mut().user_id = Some("u1".to_string());
+        storage
+            .store_events(vec![tagged, event("G-A", 2), event("G-B", 30)])
+            .await
+            .unwrap();
+
+        assert!(storage.delete_events(&EventFilter::new()).await.is_err());
+        assert_eq!(
+            storage
+                .delete_events(&EventFilter::new().user_id("u1"))
+                .await
+                .unwrap(),
+            1
+        );
+        let cutoff = Utc::now() - Duration::hours(24);
+        assert_eq!(
+            storage
+                .delete_events(&EventFilter::new().until(cutoff))
+                .await
+                .unwrap(),
+            1
+        );
+        assert_eq!(storage.segment_count(), 1);
+
+        let reopened = SegmentStorage::open(dir.path()).unwrap();
+        let remaining: Vec<EventEnvelope> = reopened
+            .scan_events(EventFilter::new())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert!(remaining[0].event.user_id().is_none());
+    }
+}
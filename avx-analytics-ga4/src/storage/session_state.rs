@@ -0,0 +1,118 @@
+//! Open sessions kept in Redis
+//!
+//! Each open session is a JSON document at `sess:{G-XXX}:<client_id>`, expiring after
+//! `redis.ttl_session`. A shared sorted set (`sess:open`) scores every open session by
+//! the time it times out, so any node can close it; a node claims a due session by
+//! winning the `ZREM` before reading it. Saves are compare-and-set against the document
+//! the caller loaded, so concurrent nodes never overwrite each other's updates.
+
+use super::RedisCache;
+use crate::error::Result;
+use chrono::{DateTime, Utc};
+
+const OPEN: &str = "sess:open";
+
+/// Save `ARGV[2]` at `KEYS[1]` only if it still holds `ARGV[1]` (empty for no session)
+const COMPARE_AND_SAVE: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if (current or '') ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[4], KEYS[1])
+return 1
+"#;
+
+/// Most sessions claimed per call, so one sweep stays short
+const TAKE_LIMIT: isize = 1000;
+
+fn key(measurement_id: &str, client_id: &str) -> String {
+    format!("sess:{{{}}}:{}", measurement_id, client_id)
+}
+
+impl RedisCache {
+    /// The open session of a client, as saved by `save_session`
+    pub async fn load_session(
+        &self,
+        measurement_id: &str,
+        client_id: &str,
+    ) -> Result<Option<String>> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let state: Option<String> = redis::cmd("GET")
+            .arg(key(measurement_id, client_id))
+            .query_async(&mut conn)
+            .await?;
+        Ok(state)
+    }
+
+    /// Save a client's open session, due for closing at `deadline`, unless it changed
+    /// since `previous` was loaded (`None`: there was no open session). Returns whether
+    /// it was saved; on `false` the caller reloads and tries again.
+    pub async fn save_session(
+        &self,
+        measurement_id: &str,
+        client_id: &str,
+        previous: Option<&str>,
+        state: &str,
+        deadline: DateTime<Utc>,
+    ) -> Result<bool> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let saved: i64 = redis::Script::new(COMPARE_AND_SAVE)
+            .key(key(measurement_id, client_id))
+            .key(OPEN)
+            .arg(previous.unwrap_or(""))
+            .arg(state)
+            .arg(self.ttl_session)
+            .arg(deadline.timestamp())
+            .invoke_async(&mut conn)
+            .await?;
+        Ok(saved == 1)
+    }
+
+    /// Forget a client's open session, e.g. when it was closed to start a new one
+    pub async fn remove_session(&self, measurement_id: &str, client_id: &str) -> Result<()> {
+        let key = key(measurement_id, client_id);
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        redis::pipe()
+            .del(&key)
+            .ignore()
+            .zrem(OPEN, &key)
+            .ignore()
+            .query_async::<_, ()>(&mut conn)
+            .await?;
+        Ok(())
+    }
+
+    /// Claim and remove the sessions due for closing by `now`. Sessions whose document
+    /// already expired are skipped.
+    pub async fn take_due_sessions(&self, now: DateTime<Utc>) -> Result<Vec<String>> {
+        let mut conn = self.client.get_multiplexed_async_connection().await?;
+        let due: Vec<String> = redis::cmd("ZRANGEBYSCORE")
+            .arg(OPEN)
+            .arg("-inf")
+            .arg(now.timestamp())
+            .arg("LIMIT")
+            .arg(0)
+            .arg(TAKE_LIMIT)
+            .query_async(&mut conn)
+            .await?;
+
+        let mut sessions = Vec::new();
+        for key in due {
+            let claimed: i64 = redis::cmd("ZREM")
+                .arg(OPEN)
+                .arg(&key)
+                .query_async(&mut conn)
+                .await?;
+            if claimed == 0 {
+                continue;
+            }
+            let state: Option<String> = redis::cmd("GETDEL")
+                .arg(&key)
+                .query_async(&mut conn)
+                .await?;
+            sessions.extend(state);
+        }
+        Ok(sessions)
+    }
+}
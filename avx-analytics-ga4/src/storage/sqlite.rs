@@ -0,0 +1,564 @@
+//! SQLite storage backend for single-node deployments
+
+use super::{
+    conversion, session_from_row, Capabilities, ConversionRow, EventFilter, ExpiryFilter,
+    StorageEngine, CONVERSION_COLUMNS, SESSION_COLUMNS,
+};
+use crate::config::DatabaseConfig;
+use crate::error::{Error, Result};
+use crate::events::EventEnvelope;
+use crate::models::{Conversion, Session};
+use async_stream::try_stream;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::stream::BoxStream;
+use futures::TryStreamExt;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePoolOptions, SqliteSynchronous};
+use sqlx::{QueryBuilder, Row, Sqlite};
+use std::collections::{BTreeMap, BTreeSet};
+use std::str::FromStr;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Rows per multi-row `INSERT`, well below SQLite's bound-parameter limit
+const INSERT_CHUNK: usize = 500;
+
+/// SQLite storage implementation
+pub struct SqliteStorage {
+    pool: sqlx::SqlitePool,
+    in_memory: bool,
+}
+
+impl SqliteStorage {
+    /// Open (creating if missing) the database at `config.url` in WAL mode
+    pub async fn connect(config: &DatabaseConfig) -> Result<Self> {
+        let options = SqliteConnectOptions::from_str(&config.url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Normal)
+            .busy_timeout(Duration::from_secs(config.connection_timeout));
+
+        // Every connection to `:memory:` is a separate database, so keep exactly one alive
+        let in_memory = config.url.contains(":memory:");
+        let pool = if in_memory {
+            SqlitePoolOptions::new()
+                .max_connections(1)
+                .min_connections(1)
+                .idle_timeout(None)
+                .max_lifetime(None)
+        } else {
+            SqlitePoolOptions::new()
+                .max_connections(config.max_connections.max(1))
+                .min_connections(config.min_connections)
+        }
+        .acquire_timeout(Duration::from_secs(config.connection_timeout))
+        .connect_with(options)
+        .await?;
+
+        Ok(Self { pool, in_memory })
+    }
+
+    /// Underlying connection pool, shared with the query engine
+    pub fn pool(&self) -> &sqlx::SqlitePool {
+        &self.pool
+    }
+
+    /// Count (dry run) or delete the rows of `table` matching `filter`, dated by `column`
+    async fn expire(&self, table: &str, column: &str, filter: &ExpiryFilter) -> Result<u64> {
+        let mut query = filter.statement::<Sqlite>(table, column);
+        Ok(if filter.dry_run {
+            query
+                .build_query_scalar::<i64>()
+                .fetch_one(&self.pool)
+                .await? as u64
+        } else {
+            query.build().execute(&self.pool).await?.rows_affected()
+        })
+    }
+}
+
+#[async_trait]
+impl StorageEngine for SqliteStorage {
+    async fn store_events(&self, events: Vec<EventEnvelope>) -> Result<()> {
+        let mut rows = Vec::with_capacity(events.len());
+        for event in &events {
+            rows.push((event, serde_json::to_string(&event.event)?));
+        }
+
+        let mut tx = self.pool.begin().await?;
+        for chunk in rows.chunks(INSERT_CHUNK) {
+            let mut insert = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO events (id, measurement_id, event_name, event_params, timestamp, processed, \
+                 client_id, user_id, session_id, page_location, value, currency) ",
+            );
+            insert.push_values(chunk, |mut row, (envelope, event_params)| {
+                let event = &envelope.event;
+                row.push_bind(envelope.event_id.to_string())
+                    .push_bind(&envelope.measurement_id)
+                    .push_bind(event.event_name())
+                    .push_bind(event_params)
+                    .push_bind(envelope.timestamp)
+                    .push_bind(envelope.processed)
+                    .push_bind(&event.params().client_id)
+                    .push_bind(event.user_id())
+                    .push_bind(&event.params().session_id)
+                    .push_bind(event.page_location())
+                    .push_bind(event.value())
+                    .push_bind(event.currency());
+            });
+            insert.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn get_event(&self, id: Uuid) -> Result<Option<EventEnvelope>> {
+        let row = sqlx::query_as::<_, (String, String, chrono::DateTime<chrono::Utc>, bool)>(
+            r#"
+            SELECT measurement_id, event_params, timestamp, processed
+            FROM events
+            WHERE id = ?1
+            "#,
+        )
+        .bind(id.to_string())
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.map(|(measurement_id, event_params, timestamp, processed)| {
+            Ok(EventEnvelope {
+                event_id: id,
+                measurement_id,
+                timestamp,
+                event: serde_json::from_str(&event_params)?,
+                processed,
+            })
+        })
+        .transpose()
+    }
+
+    async fn store_conversions(&self, conversions: Vec<Conversion>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for chunk in conversions.chunks(INSERT_CHUNK) {
+            let mut insert = QueryBuilder::<Sqlite>::new(
+                "INSERT INTO conversions (id, measurement_id, site_id, goal_id, conversion_name, event_id, \
+                 client_id, user_id, session_id, conversion_value, currency, timestamp) ",
+            );
+            insert.push_values(chunk, |mut row, conversion| {
+                row.push_bind(conversion.id.to_string())
+                    .push_bind(&conversion.measurement_id)
+                    .push_bind(conversion.site_id.to_string())
+                    .push_bind(conversion.goal_id.to_string())
+                    .push_bind(&conversion.goal_name)
+                    .push_bind(conversion.event_id.to_string())
+                    .push_bind(&conversion.client_id)
+                    .push_bind(&conversion.user_id)
+                    .push_bind(&conversion.session_id)
+                    .push_bind(conversion.value)
+                    .push_bind(&conversion.currency)
+                    .push_bind(conversion.timestamp);
+            });
+            insert.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn store_sessions(&self, sessions: Vec<Session>) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        for chunk in sessions.chunks(INSERT_CHUNK) {
+            let mut insert = QueryBuilder::<Sqlite>::new(
+                "INSERT OR REPLACE INTO sessions (id, session_id, measurement_id, site_id, client_id, user_id, \
+                 started_at, ended_at, duration_seconds, page_views, events_count, is_bounce, landing_page, \
+                 exit_page, referrer, campaign_source, campaign_medium, campaign_name, device_category, browser, \
+                 os, country, region, city) ",
+            );
+            insert.push_values(chunk, |mut row, session| {
+                row.push_bind(session.id.to_string())
+                    .push_bind(&session.session_id)
+                    .push_bind(&session.measurement_id)
+                    .push_bind(session.site_id.map(|id| id.to_string()))
+                    .push_bind(&session.client_id)
+                    .push_bind(&session.user_id)
+                    .push_bind(session.started_at)
+                    .push_bind(session.ended_at)
+                    .push_bind(session.duration_seconds as i64)
+                    .push_bind(session.page_views as i64)
+                    .push_bind(session.events_count as i64)
+                    .push_bind(session.is_bounce)
+                    .push_bind(&session.entry_page)
+                    .push_bind(&session.exit_page)
+                    .push_bind(&session.referrer)
+                    .push_bind(&session.utm_source)
+                    .push_bind(&session.utm_medium)
+                    .push_bind(&session.utm_campaign)
+                    .push_bind(&session.device_category)
+                    .push_bind(&session.browser)
+                    .push_bind(&session.os)
+                    .push_bind(&session.country)
+                    .push_bind(&session.region)
+                    .push_bind(&session.city);
+            });
+            insert.build().execute(&mut *tx).await?;
+        }
+        tx.commit().await?;
+        Ok(())
+    }
+
+    fn scan_events(&self, filter: EventFilter) -> BoxStream<'_, Result<EventEnvelope>> {
+        Box::pin(try_stream! {
+            let mut query = QueryBuilder::<Sqlite>::new(
+                "SELECT id, measurement_id, event_params, timestamp, processed FROM events",
+            );
+            filter.push_where(&mut query);
+            query.push(" ORDER BY timestamp, id");
+            if let Some(limit) = filter.limit {
+                query.push(" LIMIT ").push_bind(limit as i64);
+            }
+
+            let mut rows = query
+                .build_query_as::<(String, String, String, DateTime<Utc>, bool)>()
+                .fetch(&self.pool);
+            while let Some((id, measurement_id, event_params, timestamp, processed)) = rows.try_next().await? {
+                yield EventEnvelope {
+                    event_id: Uuid::parse_str(&id)
+                        .map_err(|e| Error::Storage(format!("invalid event id {:?}: {}", id, e)))?,
+                    measurement_id,
+                    timestamp,
+                    event: serde_json::from_str(&event_params)?,
+                    processed,
+                };
+            }
+        })
+    }
+
+    async fn count_by_event_name(&self, filter: &EventFilter) -> Result<BTreeMap<String, u64>> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT event_name, COUNT(*) FROM events");
+        filter.push_where(&mut query);
+        query.push(" GROUP BY event_name");
+
+        let rows: Vec<(String, i64)> = query.build_query_as().fetch_all(&self.pool).await?;
+        Ok(rows
+            .into_iter()
+            .map(|(name, count)| (name, count as u64))
+            .collect())
+    }
+
+    async fn delete_events(&self, filter: &EventFilter) -> Result<u64> {
+        filter.check_deletable()?;
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM events");
+        filter.push_where(&mut query);
+        Ok(query.build().execute(&self.pool).await?.rows_affected())
+    }
+
+    async fn conversions(
+        &self,
+        site_id: Uuid,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Conversion>> {
+        let rows: Vec<ConversionRow<String>> = sqlx::query_as(&format!(
+            "SELECT {} FROM conversions WHERE site_id = ?1 AND timestamp >= ?2 AND timestamp < ?3 \
+             ORDER BY timestamp, id",
+            CONVERSION_COLUMNS
+        ))
+        .bind(site_id.to_string())
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.into_iter()
+            .map(|row| {
+                conversion(row, |id| {
+                    Uuid::parse_str(&id).map_err(|e| {
+                        Error::Storage(format!("invalid conversion id {:?}: {}", id, e))
+                    })
+                })
+            })
+            .collect()
+    }
+
+    async fn sessions(
+        &self,
+        measurement_id: &str,
+        start: DateTime<Utc>,
+        end: DateTime<Utc>,
+    ) -> Result<Vec<Session>> {
+        sqlx::query(&format!(
+            "SELECT {} FROM sessions WHERE measurement_id = ?1 AND started_at >= ?2 \
+             AND started_at < ?3 ORDER BY started_at, id",
+            SESSION_COLUMNS
+        ))
+        .bind(measurement_id)
+        .bind(start)
+        .bind(end)
+        .fetch_all(&self.pool)
+        .await?
+        .iter()
+        .map(|row| {
+            session_from_row(row, |row, column| {
+                row.try_get::<Option<String>, _>(column)?
+                    .map(|id| {
+                        Uuid::parse_str(&id).map_err(|e| {
+                            Error::Storage(format!("invalid session {} {:?}: {}", column, id, e))
+                        })
+                    })
+                    .transpose()
+            })
+        })
+        .collect()
+    }
+
+    async fn replace_events(&self, events: Vec<EventEnvelope>) -> Result<u64> {
+        let mut replaced = 0;
+        let mut tx = self.pool.begin().await?;
+        for envelope in &events {
+            let event = &envelope.event;
+            replaced += sqlx::query(
+                "UPDATE events SET event_name = ?1, event_params = ?2, processed = ?3, client_id = ?4, \
+                 user_id = ?5, session_id = ?6, page_location = ?7, value = ?8, currency = ?9 \
+                 WHERE id = ?10 AND timestamp = ?11",
+            )
+            .bind(event.event_name())
+            .bind(serde_json::to_string(event)?)
+            .bind(envelope.processed)
+            .bind(&event.params().client_id)
+            .bind(event.user_id())
+            .bind(&event.params().session_id)
+            .bind(event.page_location())
+            .bind(event.value())
+            .bind(event.currency())
+            .bind(envelope.event_id.to_string())
+            .bind(envelope.timestamp)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        }
+        tx.commit().await?;
+        Ok(replaced)
+    }
+
+    async fn delete_conversions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("conversions", "timestamp", filter).await
+    }
+
+    async fn delete_sessions(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("sessions", "started_at", filter).await
+    }
+
+    async fn delete_users(&self, filter: &ExpiryFilter) -> Result<u64> {
+        self.expire("users", "last_seen_at", filter).await
+    }
+
+    async fn user_ids(&self) -> Result<BTreeSet<String>> {
+        Ok(sqlx::query_scalar(
+            "SELECT user_id FROM sessions WHERE user_id IS NOT NULL
+             UNION SELECT user_id FROM conversions WHERE user_id IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await?
+        .into_iter()
+        .collect())
+    }
+
+    async fn replace_user_ids(&self, replacements: &BTreeMap<String, String>) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let mut replaced = 0;
+        for (old, new) in replacements {
+            for table in ["sessions", "conversions"] {
+                let sql = format!("UPDATE {} SET user_id = ? WHERE user_id = ?", table);
+                replaced += sqlx::query(&sql)
+                    .bind(new)
+                    .bind(old)
+                    .execute(&mut *tx)
+                    .await?
+                    .rows_affected();
+            }
+        }
+        tx.commit().await?;
+        Ok(replaced)
+    }
+
+    fn capabilities(&self) -> Capabilities {
+        Capabilities {
+            backend: "sqlite",
+            durable: !self.in_memory,
+            streaming_scan: true,
+            concurrent_writers: false,
+            time_partitioned: false,
+            bounded_retention: false,
+        }
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::{Event, EventParams};
+    use crate::storage::Database;
+    use std::sync::Arc;
+
+    async fn memory_storage() -> Arc<SqliteStorage> {
+        let config = DatabaseConfig {
+            url: "sqlite::memory:".to_string(),
+            max_connections: 4,
+            min_connections: 1,
+            connection_timeout: 5,
+            partitioning: Default::default(),
+            replicas: Vec::new(),
+        };
+        let storage = SqliteStorage::connect(&config).await.unwrap();
+        let storage = Arc::new(storage);
+        Database::Sqlite(storage.clone()).migrate().await.unwrap();
+        storage
+    }
+
+    #[tokio::test]
+    async fn test_store_and_get_event() {
+        let storage = memory_storage().await;
+        let envelope = EventEnvelope::new(
+            "G-TEST".to_string(),
+            Event::PageView {
+                page_title: "Home".to_string(),
+                page_location: "https://example.com/".to_string(),
+                page_referrer: None,
+                user_id: None,
+                params: EventParams::default(),
+            },
+        );
+        let id = envelope.event_id;
+
+        let mut batch = vec![envelope];
+        batch.extend((0..1200).map(|_| {
+            EventEnvelope::new(
+                "G-TEST".to_string(),
+                Event::Scroll {
+                    percent_scrolled: 50,
+                    params: EventParams::default(),
+                },
+            )
+        }));
+        storage.store_events(batch).await.unwrap();
+
+        let stored = storage.get_event(id).await.unwrap().unwrap();
+        assert_eq!(stored.measurement_id, "G-TEST");
+        assert!(matches!(stored.event, Event::PageView { .. }));
+
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM events")
+            .fetch_one(storage.pool())
+            .await
+            .unwrap();
+        assert_eq!(count, 1201);
+        assert!(storage.get_event(Uuid::new_v4()).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_scan_count_and_delete() {
+        let storage = memory_storage().await;
+        let events: Vec<EventEnvelope> = (0..5)
+            .map(|i| {
+                let params = EventParams {
+                    client_id: Some(format!("c{}", i % 2)),
+                    user_id: Some(format!("u{}", i)),
+                    ..Default::default()
+                };
+                EventEnvelope::new(
+                    "G-TEST".to_string(),
+                    Event::Scroll {
+                        percent_scrolled: 90,
+                        params,
+                    },
+                )
+            })
+            .collect();
+        storage.store_events(events).await.unwrap();
+
+        let filter = EventFilter::new().measurement_id("G-TEST").client_id("c0");
+        let scanned: Vec<EventEnvelope> = storage
+            .scan_events(filter.clone())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(scanned.len(), 3);
+        assert!(scanned.windows(2).all(|w| w[0].timestamp <= w[1].timestamp));
+
+        let counts = storage
+            .count_by_event_name(&EventFilter::new())
+            .await
+            .unwrap();
+        assert_eq!(counts.get("scroll"), Some(&5));
+
+        assert_eq!(storage.delete_events(&filter).await.unwrap(), 3);
+        let remaining: Vec<EventEnvelope> = storage
+            .scan_events(EventFilter::new().limit(10))
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(remaining.len(), 2);
+
+        let user = EventFilter::new()
+            .user_id("u1")
+            .user_id_aliases(["u3".to_string()]);
+        assert_eq!(storage.delete_events(&user).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_store_and_read_sessions() {
+        let storage = memory_storage().await;
+        let started_at = chrono::Utc::now();
+        let session = Session {
+            id: Uuid::new_v4(),
+            session_id: "s-1".to_string(),
+            measurement_id: "G-TEST".to_string(),
+            client_id: "c-1".to_string(),
+            user_id: None,
+            site_id: Some(Uuid::new_v4()),
+            started_at,
+            ended_at: Some(started_at + chrono::Duration::seconds(90)),
+            duration_seconds: 90,
+            page_views: 3,
+            events_count: 5,
+            is_bounce: false,
+            entry_page: Some("/".to_string()),
+            exit_page: Some("/checkout".to_string()),
+            referrer: None,
+            utm_source: Some("news".to_string()),
+            utm_medium: Some("email".to_string()),
+            utm_campaign: None,
+            device_category: Some("mobile".to_string()),
+            browser: None,
+            os: None,
+            country: Some("BR".to_string()),
+            region: Some("SP".to_string()),
+            city: None,
+        };
+        storage.store_sessions(vec![session.clone()]).await.unwrap();
+        // Re-storing a session updates it in place
+        let updated = Session {
+            page_views: 4,
+            ..session.clone()
+        };
+        storage.store_sessions(vec![updated.clone()]).await.unwrap();
+
+        let window = (
+            started_at - chrono::Duration::hours(1),
+            started_at + chrono::Duration::hours(1),
+        );
+        assert_eq!(
+            storage
+                .sessions("G-TEST", window.0, window.1)
+                .await
+                .unwrap(),
+            vec![updated]
+        );
+        assert!(storage
+            .sessions("G-OTHER", window.0, window.1)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+}
@@ -1,6 +1,6 @@
 //! E-commerce tracking example
 
-use avila_analytics_ga4::prelude::*;
+use avx_analytics_ga4::prelude::*;
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -1,6 +1,6 @@
 //! Real-time dashboard example
 
-use avila_analytics_ga4::prelude::*;
+use avx_analytics_ga4::prelude::*;
 use std::time::Duration;
 use tokio::time::sleep;
 